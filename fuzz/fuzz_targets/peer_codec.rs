@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rusty_bit::download::peers::{decode_all, PeerPieceMsgType};
+
+// Run with `cargo fuzz run peer_codec`, any panic is a bug in the decoder.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frames) = decode_all(data) {
+        for frame in frames {
+            let _ = PeerPieceMsgType::from_bytes(frame.data());
+        }
+    }
+});
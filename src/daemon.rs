@@ -0,0 +1,831 @@
+use crate::download::{
+    progress::{ProgressMeter, Speeds},
+    watch::Watcher,
+};
+use crate::error::RustyBitError;
+use crate::helper::{base64_decode, base64_encode};
+use crate::{Event, Progress, Session, TorrentHandle, TorrentSource, TorrentState};
+use anyhow::{anyhow, bail, Context};
+use futures_util::{SinkExt, Stream, StreamExt};
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderValue},
+    service::{make_service_fn, service_fn},
+    upgrade::Upgraded,
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::{json, Map, Value};
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    path::Path,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+use tracing::{debug, info, warn};
+
+/*
+ * Runs a session without a terminal and lets other programs control it over the RPC protocol of
+ * Transmission, so its remote GUIs and transmission-remote work with it. A request is a POST of
+ *
+ *     {"method": "torrent-get", "arguments": {"fields": ["id", "name"]}, "tag": 7}
+ *
+ * to /transmission/rpc and is answered with {"result": "success", "arguments": {...}, "tag": 7},
+ * or the error message as the result. The methods are session-get, session-stats, torrent-add,
+ * torrent-get, torrent-start and torrent-stop. Like Transmission, the first request is answered
+ * with 409 and a session id the client has to send back with every request, so that a web page
+ * cannot make a browser send requests without reading the answer first.
+ *
+ * A WebSocket opened on /rusty-bit/events gets the events of the session as JSON text messages,
+ * see download::events. Browsers cannot set headers on WebSockets, so the session id may also
+ * come as the session-id query parameter there.
+ */
+
+pub const RPC_PATH: &str = "/transmission/rpc";
+
+pub const EVENTS_PATH: &str = "/rusty-bit/events";
+
+pub const DEFAULT_RPC_ADDRESS: &str = "127.0.0.1:9091";
+
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+// Requests are read up to this size, a .torrent file in base64 is usually well below.
+const MAX_REQUEST_LENGTH: usize = 32 * 1024 * 1024;
+
+// How often the speeds of the torrents are sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// The version of the Transmission RPC protocol the answers follow.
+const RPC_VERSION: u64 = 17;
+
+// Torrent status codes of Transmission, the ones waiting in a queue are never used.
+const STATUS_STOPPED: u64 = 0;
+const STATUS_CHECKING: u64 = 2;
+const STATUS_DOWNLOADING: u64 = 4;
+const STATUS_SEEDING: u64 = 6;
+
+// Error code of a torrent that failed on our side, Transmission's "local error".
+const ERROR_LOCAL: u64 = 3;
+
+pub struct Daemon {
+    session: Session,
+    torrents: Mutex<Torrents>,
+    // sent back by the clients with every request
+    session_id: String,
+    // the Authorization header the clients have to send, if any
+    authorization: Option<String>,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct Torrents {
+    entries: Vec<Entry>,
+    // ids are never reused, the first torrent is 1
+    last_id: u64,
+}
+
+struct Entry {
+    id: u64,
+    // shared with the requests that pause the torrent, which is awaited without the lock
+    torrent: Arc<TorrentHandle>,
+    hash_string: String,
+    // seconds since the Unix epoch
+    added: u64,
+    meter: ProgressMeter,
+    speeds: Speeds,
+}
+
+impl Daemon {
+    // Credentials are USER:PASSWORD, without them every request is answered.
+    pub fn new(session: Session, credentials: Option<&str>) -> Daemon {
+        Daemon {
+            session,
+            torrents: Mutex::default(),
+            session_id: rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(48)
+                .map(char::from)
+                .collect(),
+            authorization: credentials
+                .map(|credentials| format!("Basic {}", base64_encode(credentials.as_bytes()))),
+            started: Instant::now(),
+        }
+    }
+
+    // Adds a torrent to the session and starts it, returns its id.
+    pub fn add_torrent(&self, source: TorrentSource) -> anyhow::Result<u64> {
+        let torrent = self.session.add_torrent(source)?;
+        let mut torrents = self.torrents.lock().unwrap();
+        torrents.last_id += 1;
+        let id = torrents.last_id;
+        torrents.entries.push(Entry {
+            id,
+            hash_string: torrent
+                .info_hash()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+            torrent: Arc::new(torrent),
+            added: unix_time(),
+            meter: ProgressMeter::new(Instant::now()),
+            speeds: Speeds::default(),
+        });
+        Ok(id)
+    }
+
+    // Answers RPC requests on the address until shutdown resolves and the open requests are
+    // answered, and samples the speeds of the torrents meanwhile.
+    pub fn serve(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> anyhow::Result<(SocketAddr, JoinHandle<()>)> {
+        let daemon = self.clone();
+        let make_service = make_service_fn(move |_| {
+            let daemon = daemon.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let daemon = daemon.clone();
+                    async move { Ok::<_, Infallible>(daemon.respond(request).await) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&addr)
+            .with_context(|| format!("Binding the RPC server to {addr}"))?
+            .serve(make_service);
+        let local_addr = server.local_addr();
+        let server = server.with_graceful_shutdown(shutdown);
+        let daemon = self.clone();
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                result = server => {
+                    if let Err(e) = result {
+                        warn!("RPC server stopped: {e}");
+                    }
+                }
+                _ = daemon.sample() => {}
+            }
+        });
+        Ok((local_addr, handle))
+    }
+
+    // Pauses every torrent so that the next start continues where this one stopped.
+    pub async fn pause_all(&self) {
+        let torrents: Vec<_> = self
+            .torrents
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|entry| entry.torrent.clone())
+            .collect();
+        for torrent in torrents {
+            torrent.pause().await;
+        }
+    }
+
+    // Never returns.
+    async fn sample(&self) {
+        let mut ticks = tokio::time::interval(SAMPLE_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let now = Instant::now();
+            for entry in &mut self.torrents.lock().unwrap().entries {
+                entry.speeds = entry.meter.sample(&entry.torrent.progress(), now);
+            }
+        }
+    }
+
+    async fn respond(&self, request: Request<Body>) -> Response<Body> {
+        let path = request.uri().path();
+        if path != RPC_PATH && path != EVENTS_PATH {
+            return status(StatusCode::NOT_FOUND);
+        }
+        if let Some(authorization) = &self.authorization {
+            if header_value(&request, header::AUTHORIZATION.as_str()) != Some(authorization) {
+                let mut response = status(StatusCode::UNAUTHORIZED);
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Basic realm=\"Rusty-Bit\""),
+                );
+                return response;
+            }
+        }
+        if session_id(&request) != Some(&self.session_id) {
+            let mut response = status(StatusCode::CONFLICT);
+            response
+                .headers_mut()
+                .insert(SESSION_ID_HEADER, self.session_id.parse().unwrap());
+            return response;
+        }
+        if request.uri().path() == EVENTS_PATH {
+            return self.stream_events(request);
+        }
+        if request.method() != Method::POST {
+            return status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let body = match read_body(request.into_body()).await {
+            Ok(body) => body,
+            Err(code) => return status(code),
+        };
+        let Result::Ok(call) = serde_json::from_slice::<Value>(&body) else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+        let mut response = Response::new(Body::from(self.call(&call).await.to_string()));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        response
+    }
+
+    // Upgrades the connection to a WebSocket that is sent the events until the client closes it.
+    fn stream_events(&self, request: Request<Body>) -> Response<Body> {
+        let websocket = header_value(&request, header::UPGRADE.as_str())
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+        let Some(key) = header_value(&request, header::SEC_WEBSOCKET_KEY.as_str()) else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+        if !websocket {
+            return status(StatusCode::BAD_REQUEST);
+        }
+        let accept = derive_accept_key(key.as_bytes());
+        // subscribed before the answer, so that nothing after it is missed
+        let events = self.session.events();
+        tokio::spawn(async move {
+            match hyper::upgrade::on(request).await {
+                Result::Ok(upgraded) => {
+                    let socket =
+                        WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                    send_events(socket, events).await;
+                }
+                Err(e) => debug!("event stream not opened: {e}"),
+            }
+        });
+        let mut response = status(StatusCode::SWITCHING_PROTOCOLS);
+        let headers = response.headers_mut();
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept.parse().unwrap());
+        response
+    }
+
+    async fn call(&self, call: &Value) -> Value {
+        let method = call["method"].as_str().unwrap_or_default();
+        let no_arguments = Map::new();
+        let arguments = call["arguments"].as_object().unwrap_or(&no_arguments);
+        let result = match method {
+            "session-get" => Ok(self.session_get()),
+            "session-stats" => Ok(self.session_stats()),
+            "torrent-add" => self.torrent_add(arguments).await,
+            "torrent-get" => self.torrent_get(arguments),
+            "torrent-start" => Ok(self.torrent_start(arguments)),
+            "torrent-stop" => Ok(self.torrent_stop(arguments).await),
+            _ => Err(anyhow!("method name not recognized")),
+        };
+        let mut answer = match result {
+            Ok(arguments) => json!({"result": "success", "arguments": arguments}),
+            Err(e) => json!({"result": format!("{e:#}"), "arguments": {}}),
+        };
+        if let Some(tag) = call.get("tag") {
+            answer["tag"] = tag.clone();
+        }
+        answer
+    }
+
+    fn session_get(&self) -> Value {
+        json!({
+            "version": format!("Rusty-Bit {}", env!("CARGO_PKG_VERSION")),
+            "rpc-version": RPC_VERSION,
+            "rpc-version-minimum": RPC_VERSION,
+            "download-dir": self.session.config().download_dir.display().to_string(),
+            "session-id": self.session_id,
+        })
+    }
+
+    fn session_stats(&self) -> Value {
+        let torrents = self.torrents.lock().unwrap();
+        let progress: Vec<Progress> = torrents
+            .entries
+            .iter()
+            .map(|entry| entry.torrent.progress())
+            .collect();
+        let active = progress
+            .iter()
+            .filter(|progress| !progress.state.is_done())
+            .count();
+        // nothing is kept across runs, so the cumulative stats are those of this run
+        let stats = json!({
+            "downloadedBytes": progress.iter().map(|progress| progress.downloaded).sum::<u64>(),
+            "uploadedBytes": progress.iter().map(|progress| progress.uploaded).sum::<u64>(),
+            "filesAdded": torrents.entries.len(),
+            "sessionCount": 1,
+            "secondsActive": self.started.elapsed().as_secs(),
+        });
+        json!({
+            "activeTorrentCount": active,
+            "pausedTorrentCount": torrents.entries.len() - active,
+            "torrentCount": torrents.entries.len(),
+            "downloadSpeed": torrents.entries.iter().map(|entry| entry.speeds.download as u64).sum::<u64>(),
+            "uploadSpeed": torrents.entries.iter().map(|entry| entry.speeds.upload as u64).sum::<u64>(),
+            "cumulative-stats": stats,
+            "current-stats": stats,
+        })
+    }
+
+    // Adds the .torrent file named by filename or given as base64 in metainfo. The torrents of a
+    // session all download to the same directory.
+    async fn torrent_add(&self, arguments: &Map<String, Value>) -> anyhow::Result<Value> {
+        let download_dir = &self.session.config().download_dir;
+        if let Some(dir) = arguments.get("download-dir").and_then(Value::as_str) {
+            if Path::new(dir) != download_dir {
+                bail!("every torrent downloads to {}", download_dir.display());
+            }
+        }
+        let source = if let Some(metainfo) = arguments.get("metainfo").and_then(Value::as_str) {
+            TorrentSource::Bytes(base64_decode(metainfo).context("metainfo is not base64")?)
+        } else if let Some(filename) = arguments.get("filename").and_then(Value::as_str) {
+            if filename.starts_with("magnet:") {
+                TorrentSource::Magnet(filename.to_string())
+            } else if filename.starts_with("http://") || filename.starts_with("https://") {
+                bail!("fetching .torrent files from URLs is not supported, send the metainfo");
+            } else {
+                TorrentSource::File(filename.into())
+            }
+        } else {
+            bail!("no filename or metainfo given");
+        };
+
+        let id = match self.add_torrent(source) {
+            Ok(id) => id,
+            Err(e) => {
+                let Some(RustyBitError::Duplicate { info_hash, .. }) = RustyBitError::find(&e)
+                else {
+                    return Err(e);
+                };
+                let hash_string: String =
+                    info_hash.iter().map(|byte| format!("{byte:02x}")).collect();
+                let duplicate = self
+                    .brief(|entry| entry.hash_string == hash_string)
+                    .ok_or(e)?;
+                return Ok(json!({ "torrent-duplicate": duplicate }));
+            }
+        };
+        if arguments.get("paused").and_then(Value::as_bool) == Some(true) {
+            let torrent = self.torrent(id).context("the torrent was removed")?;
+            torrent.pause().await;
+        }
+        let added = self
+            .brief(|entry| entry.id == id)
+            .context("the torrent was removed")?;
+        Ok(json!({ "torrent-added": added }))
+    }
+
+    // The fields of the selected torrents, fields the daemon does not know are left out.
+    fn torrent_get(&self, arguments: &Map<String, Value>) -> anyhow::Result<Value> {
+        let fields = arguments
+            .get("fields")
+            .and_then(Value::as_array)
+            .context("no fields given")?;
+        let ids = arguments.get("ids");
+        let download_dir = self.session.config().download_dir.display().to_string();
+        let torrents = self.torrents.lock().unwrap();
+        let torrents: Vec<Value> = torrents
+            .entries
+            .iter()
+            .filter(|entry| selects(ids, entry))
+            .map(|entry| {
+                let progress = entry.torrent.progress();
+                let fields: Map<String, Value> = fields
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .filter_map(|field| {
+                        Some((
+                            field.to_string(),
+                            entry.field(field, &progress, &download_dir)?,
+                        ))
+                    })
+                    .collect();
+                Value::Object(fields)
+            })
+            .collect();
+        Ok(json!({ "torrents": torrents }))
+    }
+
+    fn torrent_start(&self, arguments: &Map<String, Value>) -> Value {
+        let ids = arguments.get("ids");
+        for entry in &self.torrents.lock().unwrap().entries {
+            if selects(ids, entry) {
+                entry.torrent.resume();
+            }
+        }
+        json!({})
+    }
+
+    // Pauses the selected torrents, they keep what was downloaded and can be started again.
+    async fn torrent_stop(&self, arguments: &Map<String, Value>) -> Value {
+        let ids = arguments.get("ids");
+        let torrents: Vec<_> = self
+            .torrents
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .filter(|entry| selects(ids, entry))
+            .map(|entry| entry.torrent.clone())
+            .collect();
+        for torrent in torrents {
+            torrent.pause().await;
+        }
+        json!({})
+    }
+
+    fn torrent(&self, id: u64) -> Option<Arc<TorrentHandle>> {
+        let torrents = self.torrents.lock().unwrap();
+        let entry = torrents.entries.iter().find(|entry| entry.id == id)?;
+        Some(entry.torrent.clone())
+    }
+
+    // The id, name and hash of a torrent, as torrent-add answers.
+    fn brief(&self, matches: impl Fn(&Entry) -> bool) -> Option<Value> {
+        let torrents = self.torrents.lock().unwrap();
+        let entry = torrents.entries.iter().find(|entry| matches(entry))?;
+        Some(json!({
+            "id": entry.id,
+            "name": entry.torrent.name(),
+            "hashString": entry.hash_string,
+        }))
+    }
+}
+
+impl Entry {
+    fn field(&self, field: &str, progress: &Progress, download_dir: &str) -> Option<Value> {
+        let total_length = self.torrent.total_length();
+        let piece_length = progress.piece_length as u64;
+        let wanted_pieces = progress.pieces_done + progress.pieces_missing;
+        // the last piece is usually shorter, hence the caps
+        let wanted = (wanted_pieces as u64 * piece_length).min(total_length);
+        let left = (progress.pieces_missing as u64 * piece_length).min(wanted);
+        let failure = match &progress.state {
+            TorrentState::Failed(reason) => Some(reason),
+            _ => None,
+        };
+        Some(match field {
+            "id" => json!(self.id),
+            "name" => json!(self.torrent.name()),
+            "hashString" => json!(self.hash_string),
+            "status" => json!(status_code(&progress.state)),
+            "percentDone" => json!(if wanted_pieces == 0 {
+                1.0
+            } else {
+                progress.pieces_done as f64 / wanted_pieces as f64
+            }),
+            "recheckProgress" => json!(match progress.state {
+                TorrentState::Starting if progress.total_pieces > 0 => {
+                    progress.pieces_checked as f64 / progress.total_pieces as f64
+                }
+                _ => 0.0,
+            }),
+            "rateDownload" => json!(self.speeds.download as u64),
+            "rateUpload" => json!(self.speeds.upload as u64),
+            // -1 when there is no estimate
+            "eta" => json!(self
+                .speeds
+                .eta
+                .map_or(-1, |eta| eta.as_secs().min(i64::MAX as u64) as i64)),
+            "totalSize" => json!(total_length),
+            "sizeWhenDone" => json!(wanted),
+            "leftUntilDone" => json!(left),
+            "haveValid" => json!(wanted - left),
+            "pieceCount" => json!(progress.total_pieces),
+            "pieceSize" => json!(piece_length),
+            "peersConnected" => json!(progress.peers),
+            // since the torrent was last started
+            "downloadedEver" => json!(progress.downloaded),
+            "uploadedEver" => json!(progress.uploaded),
+            "isFinished" => json!(progress.state == TorrentState::Finished),
+            "error" => json!(if failure.is_some() { ERROR_LOCAL } else { 0 }),
+            "errorString" => json!(failure.map_or("", String::as_str)),
+            "downloadDir" => json!(download_dir),
+            "addedDate" => json!(self.added),
+            _ => return None,
+        })
+    }
+}
+
+// Runs the daemon until Ctrl-C, then pauses the torrents and closes the session. Torrents
+// dropped into the watch directory of the config are added meanwhile.
+pub async fn run(daemon: Daemon, addr: SocketAddr) -> anyhow::Result<()> {
+    let daemon = Arc::new(daemon);
+    let (addr, server) = daemon.serve(addr, async {
+        let _ = tokio::signal::ctrl_c().await;
+    })?;
+    info!("Answering RPC requests on http://{addr}{RPC_PATH}");
+    let watcher = daemon.session.config().watch_dir.clone().map(|dir| {
+        let daemon = daemon.clone();
+        tokio::spawn(async move {
+            Watcher::new(dir)
+                .run(|source| daemon.add_torrent(source).map(drop))
+                .await
+        })
+    });
+    let _ = server.await;
+    if let Some(watcher) = watcher {
+        watcher.abort();
+        let _ = watcher.await;
+    }
+    info!("Shutting down, the torrents are paused");
+    daemon.pause_all().await;
+    // the server and its connections are gone, so the daemon is not shared anymore
+    if let Result::Ok(daemon) = Arc::try_unwrap(daemon) {
+        daemon.session.close().await;
+    }
+    Ok(())
+}
+
+// Whether ids selects the torrent: an id, a hash string, a list of those, or every torrent when
+// ids is missing. "recently-active" selects every torrent as well.
+fn selects(ids: Option<&Value>, entry: &Entry) -> bool {
+    let names = |id: &Value| match id {
+        Value::Number(id) => id.as_u64() == Some(entry.id),
+        Value::String(hash) => hash.eq_ignore_ascii_case(&entry.hash_string),
+        _ => false,
+    };
+    match ids {
+        None => true,
+        Some(Value::String(ids)) if ids == "recently-active" => true,
+        Some(Value::Array(ids)) => ids.iter().any(names),
+        Some(id) => names(id),
+    }
+}
+
+fn status_code(state: &TorrentState) -> u64 {
+    match state {
+        TorrentState::Starting => STATUS_CHECKING,
+        TorrentState::Downloading => STATUS_DOWNLOADING,
+        TorrentState::Seeding => STATUS_SEEDING,
+        TorrentState::Finished
+        | TorrentState::Paused
+        | TorrentState::Stopped
+        | TorrentState::Failed(_) => STATUS_STOPPED,
+    }
+}
+
+// Sends every event as JSON until the client closes the socket or goes away.
+async fn send_events(mut socket: WebSocketStream<Upgraded>, events: impl Stream<Item = Event>) {
+    let mut events = pin!(events);
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                let event = serde_json::to_string(&event).expect("events serialize to JSON");
+                if socket.send(Message::Text(event)).await.is_err() {
+                    return;
+                }
+            }
+            // messages of the client are not for us, reading answers its pings
+            message = socket.next() => match message {
+                Some(Result::Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Result::Ok(_)) => {}
+            },
+        }
+    }
+    let _ = socket.close(None).await;
+}
+
+// The session id of the header, or of the query on the event stream.
+fn session_id(request: &Request<Body>) -> Option<&str> {
+    header_value(request, SESSION_ID_HEADER).or_else(|| {
+        if request.uri().path() != EVENTS_PATH {
+            return None;
+        }
+        request
+            .uri()
+            .query()?
+            .split('&')
+            .find_map(|parameter| parameter.strip_prefix("session-id="))
+    })
+}
+
+fn header_value<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    request.headers().get(name)?.to_str().ok()
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if bytes.len() + chunk.len() > MAX_REQUEST_LENGTH {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, IpFamily};
+    use crate::download::{create_torrent_file, CreateOptions};
+    use hyper::Client;
+
+    struct Rpc {
+        addr: SocketAddr,
+        client: Client<hyper::client::HttpConnector>,
+    }
+
+    impl Rpc {
+        async fn post(&self, session_id: Option<&str>, body: Value) -> Response<Body> {
+            let mut request = Request::post(format!("http://{}{RPC_PATH}", self.addr));
+            if let Some(session_id) = session_id {
+                request = request.header(SESSION_ID_HEADER, session_id);
+            }
+            self.client
+                .request(request.body(Body::from(body.to_string())).unwrap())
+                .await
+                .unwrap()
+        }
+
+        async fn session_id(&self) -> String {
+            let response = self.post(None, json!({})).await;
+            assert_eq!(response.status(), StatusCode::CONFLICT);
+            response.headers()[SESSION_ID_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+
+        // Gets the session id first, like the clients of Transmission do.
+        async fn call(&self, body: Value) -> Value {
+            let session_id = self.session_id().await;
+            let response = self.post(Some(&session_id), body).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+    }
+
+    fn daemon(download_dir: &Path, credentials: Option<&str>) -> Rpc {
+        let session = Session::new(Config {
+            download_dir: download_dir.to_path_buf(),
+            listen_port: Some(0),
+            ip_family: Some(IpFamily::V4),
+            dht: false,
+            port_mapping: false,
+            ..Default::default()
+        });
+        let daemon = Arc::new(Daemon::new(session, credentials));
+        let (addr, _) = daemon
+            .serve("127.0.0.1:0".parse().unwrap(), std::future::pending())
+            .unwrap();
+        Rpc {
+            addr,
+            client: Client::new(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn torrents_are_added_and_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("payload.bin");
+        std::fs::write(&source, vec![7; 100_000]).unwrap();
+        let torrent_file = dir.path().join("payload.torrent");
+        create_torrent_file(&source, &CreateOptions::default(), &torrent_file).unwrap();
+        let metainfo = base64_encode(&std::fs::read(&torrent_file).unwrap());
+        let rpc = daemon(dir.path(), None);
+
+        let answer = rpc
+            .call(json!({"method": "torrent-add", "arguments": {"metainfo": metainfo}, "tag": 3}))
+            .await;
+        assert_eq!(answer["result"], "success");
+        assert_eq!(answer["tag"], 3);
+        let added = &answer["arguments"]["torrent-added"];
+        assert_eq!(added["id"], 1);
+        assert_eq!(added["name"], "payload.bin");
+        assert_eq!(added["hashString"].as_str().unwrap().len(), 40);
+
+        let answer = rpc
+            .call(json!({"method": "torrent-add", "arguments": {"filename": torrent_file}}))
+            .await;
+        assert_eq!(answer["arguments"]["torrent-duplicate"], *added);
+
+        let answer = rpc
+            .call(json!({
+                "method": "torrent-get",
+                "arguments": {"ids": [added["hashString"]], "fields": ["id", "totalSize", "peersConnected", "unknownField"]},
+            }))
+            .await;
+        assert_eq!(
+            answer["arguments"]["torrents"],
+            json!([{"id": 1, "totalSize": 100_000, "peersConnected": 0}])
+        );
+
+        let answer = rpc
+            .call(json!({"method": "torrent-stop", "arguments": {"ids": 1}}))
+            .await;
+        assert_eq!(answer["result"], "success");
+        let answer = rpc
+            .call(json!({"method": "torrent-get", "arguments": {"fields": ["status"]}}))
+            .await;
+        assert_eq!(
+            answer["arguments"]["torrents"],
+            json!([{ "status": STATUS_STOPPED }])
+        );
+
+        let answer = rpc.call(json!({"method": "session-stats"})).await;
+        assert_eq!(answer["arguments"]["torrentCount"], 1);
+        assert_eq!(answer["arguments"]["activeTorrentCount"], 0);
+
+        let answer = rpc.call(json!({"method": "torrent-verify"})).await;
+        assert_eq!(answer["result"], "method name not recognized");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn events_are_streamed_over_a_websocket() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("payload.bin");
+        std::fs::write(&source, vec![7; 1000]).unwrap();
+        let torrent_file = dir.path().join("payload.torrent");
+        // nothing listens on the tracker's port
+        let tracker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+        drop(tracker);
+        let options = CreateOptions {
+            trackers: vec![vec![announce]],
+            ..Default::default()
+        };
+        create_torrent_file(&source, &options, &torrent_file).unwrap();
+        let rpc = daemon(dir.path(), None);
+
+        let url = format!("ws://{}{EVENTS_PATH}", rpc.addr);
+        assert!(tokio_tungstenite::connect_async(&url).await.is_err());
+        let session_id = rpc.session_id().await;
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("{url}?session-id={session_id}"))
+                .await
+                .unwrap();
+
+        let answer = rpc
+            .call(json!({"method": "torrent-add", "arguments": {"filename": torrent_file}}))
+            .await;
+        let hash_string = &answer["arguments"]["torrent-added"]["hashString"];
+        let message = tokio::time::timeout(Duration::from_secs(30), socket.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let event: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(event["event"], "TrackerError");
+        assert_eq!(event["info_hash"], *hash_string);
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let rpc = daemon(dir.path(), Some("admin:secret"));
+        let response = rpc.post(None, json!({"method": "session-get"})).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = |authorization: &str| {
+            Request::post(format!("http://{}{RPC_PATH}", rpc.addr))
+                .header(header::AUTHORIZATION, authorization)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = rpc
+            .client
+            .request(request("Basic YWRtaW46c2VjcmV0"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = rpc
+            .client
+            .request(request("Basic YWRtaW46d3Jvbmc="))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
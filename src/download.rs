@@ -1,63 +1,205 @@
-use crate::helper::{print_single_ln, read_string};
-use anyhow::{bail, Context};
-use std::{fs, io::ErrorKind};
-mod peers;
+use crate::{config::Config, error::RustyBitError};
+use anyhow::Context;
+use std::{fs, path::Path};
+mod bandwidth;
+mod choker;
+mod control;
+mod dht;
+mod disk_io;
+mod disk_space;
+mod disk_writer;
+mod dns;
+pub mod events;
+mod file_paths;
+mod have;
+mod hooks;
+mod http_connect;
+pub mod ip_filter;
+mod listener;
+mod magnet;
+mod merkle;
+mod metadata;
+pub mod metrics;
+#[cfg(test)]
+mod mock_tracker;
+mod net;
+mod partial_pieces;
+mod peer_id;
+mod peer_manager;
+pub mod peers;
+mod piece_map;
+mod piece_queue;
+mod pipeline;
+#[cfg(feature = "upnp")]
+mod port_mapping;
+pub mod progress;
+mod read_cache;
+mod resume;
+mod schedule;
+mod seed_limit;
+pub mod session;
+mod shared;
+mod socks5;
+mod staging;
+mod storage;
+mod streaming;
+#[cfg(test)]
+mod test_peer;
 mod torrent;
 mod tracker;
+mod upload;
+mod verify;
+pub mod watch;
+mod web_seed;
 use serde_bencode;
 use torrent::Torrent;
+pub use torrent::{piece_length_for, CreateOptions};
 
 /*
  * This function is responsible for converting the data in bencoded file into rust datatype.
 */
-fn decode_bencoded_file(file_path: String) -> anyhow::Result<Torrent> {
-    println!("Trying to read file {file_path}\n");
-    let file_data = fs::read(&file_path);
-    match file_data {
-        Ok(file_data_vec) => {
-            println!("Decoding bencoded file {file_path}\n");
-            let decoder_result = serde_bencode::from_bytes::<Torrent>(&file_data_vec);
-            match decoder_result {
-                Ok(torrent_data) => Ok(torrent_data),
-                Err(_) => {
-                    println!("File could not be decoded!");
-                    bail!("File could not be decoded!")
-                }
-            }
-        }
-        Err(e) => {
-            if e.kind() == ErrorKind::NotFound {
-                println!("File path does not exist, check the file path again!!");
-            } else {
-                println!("Could not read the file!!");
-            }
-            Err(e.into())
-        }
-    }
+fn read_torrent_file(file_path: &Path) -> anyhow::Result<Torrent> {
+    let file_data = fs::read(file_path)
+        .map_err(RustyBitError::disk(&file_path.display().to_string()))
+        .with_context(|| format!("Reading {}", file_path.display()))?;
+    Ok(serde_bencode::from_bytes::<Torrent>(&file_data)
+        .map_err(RustyBitError::bencode(&file_path.display().to_string()))?)
+}
+
+/*
+ * This function describes the torrent in the .torrent file: its name, info hash, pieces, whether
+ * it is private, files and trackers. Nothing is downloaded.
+*/
+pub fn torrent_info(file_path: &Path) -> anyhow::Result<String> {
+    read_torrent_file(file_path)?.describe()
+}
+
+// Pieces of a torrent that are not on disk yet.
+pub struct Verification {
+    pub total_pieces: usize,
+    pub missing_pieces: Vec<usize>,
 }
 
 /*
- * This function downloads torrent resource using the .torrent file
+ * This function makes a magnet link for the torrent in the .torrent file, for sharing it without
+ * the file.
 */
-pub async fn download_using_file() -> anyhow::Result<()> {
-    print_single_ln("You chose to download using .torrent file, provide the file path: ");
-    let file_path = read_string();
-    println!();
-    let mut decoded_metainfo_file = decode_bencoded_file(file_path)?;
-    // Console output is handled by the decode_bencoded_file function so no need to take any action in case of faiure.
-
-    decoded_metainfo_file
-        .start_download()
-        .await
-        .context("Could not start download")?;
-    Ok(())
+pub fn magnet_link(file_path: &Path) -> anyhow::Result<String> {
+    read_torrent_file(file_path)?.magnet_link()
+}
+
+/*
+ * This function checks the download of the torrent in the .torrent file, in the directory the
+ * config downloads it to, against the piece hashes.
+*/
+pub fn verify_download(file_path: &Path, config: &Config) -> anyhow::Result<Verification> {
+    let torrent = read_torrent_file(file_path)?;
+    Ok(Verification {
+        total_pieces: torrent.info.total_pieces(),
+        missing_pieces: torrent.missing_pieces(config)?,
+    })
+}
+
+/*
+ * This function creates a .torrent file at output for the file or directory at source and returns
+ * what the file contains. A torrent without trackers gets its peers from the DHT or --peer.
+*/
+pub fn create_torrent_file(
+    source: &Path,
+    options: &CreateOptions,
+    output: &Path,
+) -> anyhow::Result<String> {
+    let torrent = Torrent::create(source, options)?;
+    let encoded = serde_bencode::to_bytes(&torrent).context("Encoding the torrent")?;
+    fs::write(output, encoded).with_context(|| format!("Writing {}", output.display()))?;
+    torrent.describe()
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs;
 
-    use super::Torrent;
+    use super::*;
+
+    #[test]
+    fn info_and_verification_of_a_torrent_file() {
+        let source = tempfile::tempdir().unwrap();
+        let payload = source.path().join("payload.bin");
+        fs::write(&payload, vec![3_u8; 40_000]).unwrap();
+        let torrent_file = source.path().join("payload.torrent");
+        create_torrent_file(
+            &payload,
+            &CreateOptions {
+                piece_length: Some(16 * 1024),
+                trackers: vec![vec!["http://tracker.example/announce".to_string()]],
+                ..Default::default()
+            },
+            &torrent_file,
+        )
+        .unwrap();
+
+        let info = torrent_info(&torrent_file).unwrap();
+        assert!(info.contains("Name: payload.bin"), "{info}");
+        assert!(info.contains("Pieces: 3"), "{info}");
+        assert!(info.contains("Total size: 40000 bytes"), "{info}");
+        assert!(info.contains("Private: no"), "{info}");
+        assert!(
+            info.contains("tier 1: http://tracker.example/announce"),
+            "{info}"
+        );
+
+        // nothing is downloaded yet
+        let config = Config {
+            download_dir: source.path().join("downloads"),
+            ..Default::default()
+        };
+        let verification = verify_download(&torrent_file, &config).unwrap();
+        assert_eq!(verification.total_pieces, 3);
+        assert_eq!(verification.missing_pieces, vec![0, 1, 2]);
+
+        // the download directory is named after the torrent
+        let directory = config.download_dir.join("payload");
+        fs::create_dir_all(&directory).unwrap();
+        fs::copy(&payload, directory.join("payload.bin")).unwrap();
+        let verification = verify_download(&torrent_file, &config).unwrap();
+        assert!(verification.missing_pieces.is_empty());
+    }
+
+    #[test]
+    fn magnet_link_of_a_torrent_file() {
+        let source = tempfile::tempdir().unwrap();
+        let payload = source.path().join("my payload.bin");
+        fs::write(&payload, vec![3_u8; 40_000]).unwrap();
+        let torrent_file = source.path().join("payload.torrent");
+        create_torrent_file(
+            &payload,
+            &CreateOptions {
+                trackers: vec![
+                    vec!["http://tracker.example/announce?key=1&x=2".to_string()],
+                    vec!["udp://backup.example:6969".to_string()],
+                ],
+                ..Default::default()
+            },
+            &torrent_file,
+        )
+        .unwrap();
+
+        let info_hash: String = read_torrent_file(&torrent_file)
+            .unwrap()
+            .calc_hash()
+            .unwrap()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        assert_eq!(
+            magnet_link(&torrent_file).unwrap(),
+            format!(
+                "magnet:?xt=urn:btih:{info_hash}&dn=my%20payload.bin\
+                 &tr=http%3A%2F%2Ftracker.example%2Fannounce%3Fkey%3D1%26x%3D2\
+                 &tr=udp%3A%2F%2Fbackup.example%3A6969"
+            )
+        );
+    }
 
     #[test]
     fn bendy_from_bytes_success() {
@@ -0,0 +1,223 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Token bucket limiting how many bytes per second pass through it. The bucket holds at most one
+// second worth of tokens, callers that take more than what is available go into debt and sleep
+// until the debt is paid back. Since every caller waits behind the debt of the callers before it,
+// concurrent users are served roughly in turn and share the rate evenly.
+//
+// A limiter can have a parent (e.g. the session wide limiter of a torrent's limiter), bytes then
+// have to pass through both buckets.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    parent: Option<Box<RateLimiter>>,
+    // bytes that went through the limiter, limited or not
+    transferred: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // bytes per second, None means unlimited
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last_refill = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or_default() as f64,
+                last_refill: Instant::now(),
+            })),
+            parent: None,
+            transferred: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // A limiter with its own rate whose bytes also count against this one.
+    pub fn child(&self, rate: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            parent: Some(Box::new(self.clone())),
+            ..RateLimiter::new(rate)
+        }
+    }
+
+    pub fn transferred(&self) -> u64 {
+        self.transferred.load(Ordering::Relaxed)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    // Changes the refill rate without disturbing transfers waiting on the bucket.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.rate = rate;
+        if let Some(rate) = rate {
+            bucket.tokens = bucket.tokens.min(rate as f64);
+        }
+    }
+
+    pub async fn acquire(&self, bytes: usize) {
+        self.transferred.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.acquire_own(bytes).await;
+        if let Some(parent) = &self.parent {
+            Box::pin(parent.acquire(bytes)).await;
+        }
+    }
+
+    async fn acquire_own(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.refill();
+            let Some(rate) = bucket.rate else {
+                return;
+            };
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / rate.max(1) as f64)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// Download and upload limiters of a torrent.
+#[derive(Clone, Debug)]
+pub struct Bandwidth {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl Bandwidth {
+    pub fn new(download_limit: Option<u64>, upload_limit: Option<u64>) -> Bandwidth {
+        Bandwidth {
+            download: RateLimiter::new(download_limit),
+            upload: RateLimiter::new(upload_limit),
+        }
+    }
+}
+
+// Owns the session wide limiters every torrent's limiters draw from, so that the global cap holds
+// no matter how many torrents are running.
+#[derive(Clone, Debug)]
+pub struct BandwidthManager {
+    global: Bandwidth,
+}
+
+impl BandwidthManager {
+    pub fn new(download_limit: Option<u64>, upload_limit: Option<u64>) -> BandwidthManager {
+        BandwidthManager {
+            global: Bandwidth::new(download_limit, upload_limit),
+        }
+    }
+
+    // The session wide limiters, e.g. for the alternative speed scheduler to reconfigure.
+    pub fn global(&self) -> Bandwidth {
+        self.global.clone()
+    }
+
+    // Limiters for one torrent with optional per-torrent caps below the global ones.
+    pub fn torrent(&self, download_limit: Option<u64>, upload_limit: Option<u64>) -> Bandwidth {
+        Bandwidth {
+            download: self.global.download.child(download_limit),
+            upload: self.global.upload.child(upload_limit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_throughput() {
+        let limiter = RateLimiter::new(Some(100_000));
+        let started = Instant::now();
+        // the first 100KB are the burst, the next 50KB need half a second
+        for _ in 0..15 {
+            limiter.acquire(10_000).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(900), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn unlimited_never_waits() {
+        let limiter = RateLimiter::new(None);
+        let started = Instant::now();
+        limiter.acquire(usize::MAX / 2).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert_eq!(limiter.transferred(), (usize::MAX / 2) as u64);
+
+        limiter.set_rate(Some(1000));
+        assert_eq!(limiter.rate(), Some(1000));
+    }
+
+    // Pulls 16KiB blocks through the limiter until the deadline, returns the bytes transferred.
+    async fn simulated_torrent(bandwidth: Bandwidth, until: Instant) -> usize {
+        let mut transferred = 0;
+        while Instant::now() < until {
+            bandwidth.download.acquire(16 * 1024).await;
+            transferred += 16 * 1024;
+            // stands in for the socket read a real peer would wait on
+            tokio::task::yield_now().await;
+        }
+        transferred
+    }
+
+    #[tokio::test]
+    async fn torrents_share_the_global_cap() {
+        let global_limit = 400 * 1024;
+        let manager = BandwidthManager::new(Some(global_limit), None);
+        let until = Instant::now() + Duration::from_secs(2);
+
+        let first = tokio::spawn(simulated_torrent(manager.torrent(None, None), until));
+        let second = tokio::spawn(simulated_torrent(manager.torrent(None, None), until));
+        let (first, second) = (first.await.unwrap(), second.await.unwrap());
+
+        // two seconds at the cap plus the one second burst, plus one block of slack each
+        let allowed = 3 * global_limit as usize + 2 * 16 * 1024;
+        assert!(first + second <= allowed, "{first} + {second} > {allowed}");
+        assert!(first + second >= 2 * global_limit as usize);
+        let ratio = first as f64 / second as f64;
+        assert!(
+            (0.75..1.33).contains(&ratio),
+            "uneven split {first} / {second}"
+        );
+    }
+
+    #[tokio::test]
+    async fn per_torrent_cap_applies_below_the_global_cap() {
+        let manager = BandwidthManager::new(Some(10_000_000), None);
+        let capped = manager.torrent(Some(100_000), None);
+        let started = Instant::now();
+        for _ in 0..15 {
+            capped.download.acquire(10_000).await;
+        }
+        assert!(started.elapsed() >= Duration::from_millis(450));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::download::bandwidth::Bandwidth;
+use rand::seq::IteratorRandom;
+use std::{
+    collections::HashSet,
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::watch;
+
+/*
+ * Which of the peers of a torrent we upload to, with the choking algorithm of BitTorrent. Every
+ * RECHOKE_INTERVAL the upload_slots interested peers that sent us the most since the last round
+ * are unchoked, while seeding nobody sends us anything and the peers we sent the most to win,
+ * which favours peers that can take a lot. Every OPTIMISTIC_ROUNDS rounds one more interested peer
+ * is picked at random and stays unchoked until the next pick, so that peers that have not been
+ * uploaded to get a chance to show how fast they are. Everybody else is choked.
+ *
+ * A peer that becomes interested while a slot is free is unchoked right away instead of waiting
+ * for the next round.
+ */
+
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+// The optimistic unchoke moves on every 30 seconds.
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+pub struct Choker {
+    slots: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    peers: Vec<ChokedPeer>,
+    optimistic: Option<u64>,
+    rounds: u32,
+}
+
+struct ChokedPeer {
+    id: u64,
+    bandwidth: Bandwidth,
+    interested: bool,
+    unchoked: watch::Sender<bool>,
+    // bytes transferred with the peer by the last round
+    downloaded: u64,
+    uploaded: u64,
+}
+
+// The place of one connection in the choker, it leaves when dropped.
+pub struct Slot {
+    choker: Arc<Choker>,
+    id: u64,
+    pub unchoked: watch::Receiver<bool>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Choker {
+        Choker {
+            slots: slots.max(1),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // Adds a connection, choked and not interested. The bandwidth is the one of the peer, the
+    // bytes that go through it rank the peer.
+    pub fn join(self: &Arc<Self>, bandwidth: Bandwidth) -> Slot {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let (unchoked, unchoked_receiver) = watch::channel(false);
+        state.peers.push(ChokedPeer {
+            id,
+            downloaded: bandwidth.download.transferred(),
+            uploaded: bandwidth.upload.transferred(),
+            bandwidth,
+            interested: false,
+            unchoked,
+        });
+        Slot {
+            choker: self.clone(),
+            id,
+            unchoked: unchoked_receiver,
+        }
+    }
+
+    // Chooses the peers to unchoke every RECHOKE_INTERVAL, never returns.
+    pub async fn run(self: Arc<Self>) {
+        let mut rounds = tokio::time::interval(RECHOKE_INTERVAL);
+        loop {
+            rounds.tick().await;
+            self.rechoke();
+        }
+    }
+
+    // One round of the algorithm.
+    pub fn rechoke(&self) {
+        let mut state = self.state.lock().unwrap();
+        // bytes sent by the peer and to the peer since the last round
+        let mut ranked: Vec<(u64, u64, u64)> = Vec::new();
+        for peer in &mut state.peers {
+            let downloaded = peer.bandwidth.download.transferred();
+            let uploaded = peer.bandwidth.upload.transferred();
+            let rates = (
+                downloaded - mem::replace(&mut peer.downloaded, downloaded),
+                uploaded - mem::replace(&mut peer.uploaded, uploaded),
+            );
+            if peer.interested {
+                ranked.push((rates.0, rates.1, peer.id));
+            }
+        }
+        ranked.sort_unstable_by(|a, b| b.cmp(a));
+        let mut unchoked: HashSet<u64> = ranked
+            .iter()
+            .take(self.slots)
+            .map(|&(_, _, id)| id)
+            .collect();
+
+        let optimistic_left = state.optimistic.is_some_and(|optimistic| {
+            !state
+                .peers
+                .iter()
+                .any(|peer| peer.id == optimistic && peer.interested)
+        });
+        if state.rounds.is_multiple_of(OPTIMISTIC_ROUNDS) || optimistic_left {
+            state.optimistic = ranked
+                .iter()
+                .map(|&(_, _, id)| id)
+                .filter(|id| !unchoked.contains(id))
+                .choose(&mut rand::thread_rng());
+        }
+        state.rounds += 1;
+        unchoked.extend(state.optimistic);
+        for peer in &state.peers {
+            set_unchoked(peer, unchoked.contains(&peer.id));
+        }
+    }
+
+    fn set_interested(&self, id: u64, interested: bool) {
+        let mut state = self.state.lock().unwrap();
+        let free_slot = state
+            .peers
+            .iter()
+            .filter(|peer| *peer.unchoked.borrow())
+            .count()
+            < self.slots;
+        let Some(peer) = state.peers.iter_mut().find(|peer| peer.id == id) else {
+            return;
+        };
+        peer.interested = interested;
+        if !interested {
+            set_unchoked(peer, false);
+        } else if free_slot {
+            set_unchoked(peer, true);
+        }
+    }
+
+    fn leave(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.retain(|peer| peer.id != id);
+    }
+}
+
+// Only wakes the connection if that changes anything.
+fn set_unchoked(peer: &ChokedPeer, unchoked: bool) {
+    peer.unchoked
+        .send_if_modified(|current| mem::replace(current, unchoked) != unchoked);
+}
+
+impl Slot {
+    pub fn set_interested(&self, interested: bool) {
+        self.choker.set_interested(self.id, interested);
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.choker.leave(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(choker: &Arc<Choker>) -> (Slot, Bandwidth) {
+        let bandwidth = Bandwidth::new(None, None);
+        let slot = choker.join(bandwidth.clone());
+        (slot, bandwidth)
+    }
+
+    fn unchoked(slots: &[&Slot]) -> Vec<bool> {
+        slots.iter().map(|slot| *slot.unchoked.borrow()).collect()
+    }
+
+    #[tokio::test]
+    async fn fastest_peers_get_the_slots() {
+        let choker = Arc::new(Choker::new(2));
+        let (a, a_bandwidth) = peer(&choker);
+        let (b, b_bandwidth) = peer(&choker);
+        let (c, c_bandwidth) = peer(&choker);
+        let (d, _) = peer(&choker);
+
+        // free slots are handed out right away
+        a.set_interested(true);
+        b.set_interested(true);
+        c.set_interested(true);
+        assert_eq!(unchoked(&[&a, &b, &c, &d]), [true, true, false, false]);
+
+        // c and b sent us the most, a is the only one left for the optimistic unchoke
+        c_bandwidth.download.acquire(3000).await;
+        b_bandwidth.download.acquire(2000).await;
+        a_bandwidth.upload.acquire(5000).await;
+        choker.rechoke();
+        assert_eq!(unchoked(&[&a, &b, &c, &d]), [true, true, true, false]);
+
+        // seeding, what we sent them counts, a stays the optimistic unchoke
+        b_bandwidth.upload.acquire(100).await;
+        a_bandwidth.upload.acquire(50).await;
+        choker.rechoke();
+        assert_eq!(unchoked(&[&a, &b, &c, &d]), [true, true, false, false]);
+
+        // a peer that lost interest is choked and its slot goes to the next interested one
+        b.set_interested(false);
+        d.set_interested(true);
+        assert_eq!(unchoked(&[&a, &b, &c, &d]), [true, false, false, true]);
+        choker.rechoke();
+        assert_eq!(unchoked(&[&a, &b, &c, &d]), [true, false, true, true]);
+
+        // the third round picks a new optimistic unchoke, there is nobody left for it
+        drop(a);
+        drop(d);
+        choker.rechoke();
+        assert_eq!(unchoked(&[&b, &c]), [false, true]);
+    }
+}
@@ -0,0 +1,486 @@
+use crate::download::{
+    bandwidth::{Bandwidth, BandwidthManager},
+    events::{Event, Events},
+    have::Have,
+};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+// The rates of a peer are averaged over about this long.
+const PEER_RATE_WINDOW: Duration = Duration::from_secs(5);
+
+// What a torrent of a session is doing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TorrentState {
+    // checking the pieces already on disk and looking for peers
+    Starting,
+    Downloading,
+    // every piece is on disk, peers are uploaded to until the torrent is paused or stopped
+    Seeding,
+    // every piece is on disk
+    Finished,
+    Paused,
+    Stopped,
+    Failed(String),
+}
+
+impl TorrentState {
+    // The download is over for now, the torrent is not started again unless it is resumed.
+    pub fn is_done(&self) -> bool {
+        !matches!(
+            self,
+            TorrentState::Starting | TorrentState::Downloading | TorrentState::Seeding
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub state: TorrentState,
+    // pieces the check of the data on disk got through while starting
+    pub pieces_checked: usize,
+    // verified pieces on disk
+    pub pieces_done: usize,
+    pub total_pieces: usize,
+    // wanted pieces that are not on disk yet, skipped files are not wanted
+    pub pieces_missing: usize,
+    pub piece_length: usize,
+    // peers we exchange pieces with right now
+    pub peers: usize,
+    // bytes received and sent through the rate limiters since the torrent was last started
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+// Shared by a running download and the handle of the torrent. The download reports its state and
+// which pieces it has, the handle asks it to stop or to hold.
+pub struct Control {
+    state: watch::Sender<TorrentState>,
+    stop: watch::Sender<bool>,
+    // a held download stays connected to its peers but requests nothing
+    hold: watch::Sender<bool>,
+    // pieces are downloaded in order
+    sequential: watch::Sender<bool>,
+    have: Mutex<Option<Arc<Have>>>,
+    // pieces checked so far, counted from the hashing thread
+    checked: Arc<AtomicUsize>,
+    limits: Mutex<Limits>,
+    peers: PeerList,
+}
+
+// The peers a download exchanges pieces with, cloned into the connection tasks.
+#[derive(Clone, Default)]
+pub struct PeerList(Arc<Mutex<Vec<Weak<ConnectedPeer>>>>);
+
+// A connection of the download, listed while the tasks serving it hold on to it. Its limiters
+// count what goes through the connection and draw from those of the torrent. The peer is
+// reported as disconnected once the last task lets go of it.
+#[derive(Debug)]
+pub struct ConnectedPeer {
+    pub addr: SocketAddr,
+    // the client the peer id names
+    pub client: String,
+    pub bandwidth: Bandwidth,
+    // set by the tasks serving the connection
+    pub status: Arc<PeerStatus>,
+    connected_at: Instant,
+    // when the peer was listed and its bytes by then, oldest first, for its rates
+    samples: Mutex<VecDeque<(Instant, u64, u64)>>,
+    events: Events,
+}
+
+// Where the exchange with a peer stands. The download of a connection keeps the side of it that
+// asks for pieces up to date, the upload the side that serves them.
+#[derive(Debug)]
+pub struct PeerStatus {
+    pub choking_us: AtomicBool,
+    pub interested: AtomicBool,
+    pub choking_peer: AtomicBool,
+    pub peer_interested: AtomicBool,
+    // blocks we requested that the peer did not send yet
+    pub requests_in_flight: AtomicUsize,
+    // the peer took our requests and sent nothing for a while
+    pub snubbed: AtomicBool,
+}
+
+impl Default for PeerStatus {
+    // Both sides start out choked and not interested.
+    fn default() -> PeerStatus {
+        PeerStatus {
+            choking_us: AtomicBool::new(true),
+            interested: AtomicBool::new(false),
+            choking_peer: AtomicBool::new(true),
+            peer_interested: AtomicBool::new(false),
+            requests_in_flight: AtomicUsize::new(0),
+            snubbed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Drop for ConnectedPeer {
+    fn drop(&mut self) {
+        let addr = self.addr;
+        self.events
+            .send(|info_hash| Event::PeerDisconnected { info_hash, addr });
+    }
+}
+
+// A connected peer for the progress: what it transferred so far, its rates in bytes per second
+// over the last few seconds, or since it connected when it is listed for the first time, and
+// where the exchange with it stands.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub client: String,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub requests_in_flight: usize,
+    pub choking_us: bool,
+    pub interested: bool,
+    pub choking_peer: bool,
+    pub peer_interested: bool,
+    pub snubbed: bool,
+    pub connected_for: Duration,
+}
+
+impl PeerList {
+    pub fn connected(
+        &self,
+        addr: SocketAddr,
+        client: String,
+        torrent: &Bandwidth,
+        events: &Events,
+    ) -> Arc<ConnectedPeer> {
+        events.send(|info_hash| Event::PeerConnected {
+            info_hash,
+            addr,
+            client: client.clone(),
+        });
+        let connected_at = Instant::now();
+        let peer = Arc::new(ConnectedPeer {
+            addr,
+            client,
+            bandwidth: Bandwidth {
+                download: torrent.download.child(None),
+                upload: torrent.upload.child(None),
+            },
+            status: Arc::default(),
+            connected_at,
+            samples: Mutex::new(VecDeque::from([(connected_at, 0, 0)])),
+            events: events.clone(),
+        });
+        let mut peers = self.0.lock().unwrap();
+        peers.retain(|peer| peer.strong_count() > 0);
+        peers.push(Arc::downgrade(&peer));
+        peer
+    }
+
+    pub fn count(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|peer| peer.strong_count() > 0)
+            .count()
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|peer| peer.info(Instant::now()))
+            .collect()
+    }
+}
+
+impl ConnectedPeer {
+    fn info(&self, now: Instant) -> PeerInfo {
+        let downloaded = self.bandwidth.download.transferred();
+        let uploaded = self.bandwidth.upload.transferred();
+        let mut samples = self.samples.lock().unwrap();
+        // the oldest sample kept is the last one from before the window
+        while samples.len() > 1 && now.duration_since(samples[1].0) >= PEER_RATE_WINDOW {
+            samples.pop_front();
+        }
+        let (since, downloaded_then, uploaded_then) = samples[0];
+        samples.push_back((now, downloaded, uploaded));
+        let seconds = now.duration_since(since).as_secs_f64().max(f64::EPSILON);
+        let status = &self.status;
+        PeerInfo {
+            addr: self.addr,
+            client: self.client.clone(),
+            downloaded,
+            uploaded,
+            download_rate: (downloaded - downloaded_then) as f64 / seconds,
+            upload_rate: (uploaded - uploaded_then) as f64 / seconds,
+            requests_in_flight: status.requests_in_flight.load(Ordering::Relaxed),
+            choking_us: status.choking_us.load(Ordering::Relaxed),
+            interested: status.interested.load(Ordering::Relaxed),
+            choking_peer: status.choking_peer.load(Ordering::Relaxed),
+            peer_interested: status.peer_interested.load(Ordering::Relaxed),
+            snubbed: status.snubbed.load(Ordering::Relaxed),
+            connected_for: now.duration_since(self.connected_at),
+        }
+    }
+}
+
+// Download and upload limits of the torrent in bytes per second, None is unlimited, and the
+// limiters of the running download they apply to.
+#[derive(Default)]
+struct Limits {
+    download: Option<u64>,
+    upload: Option<u64>,
+    running: Option<Bandwidth>,
+}
+
+impl Control {
+    pub fn new() -> Control {
+        Control {
+            state: watch::channel(TorrentState::Starting).0,
+            stop: watch::channel(false).0,
+            hold: watch::channel(false).0,
+            sequential: watch::channel(false).0,
+            have: Mutex::new(None),
+            checked: Arc::default(),
+            limits: Mutex::default(),
+            peers: PeerList::default(),
+        }
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.state.borrow().clone()
+    }
+
+    pub fn set_state(&self, state: TorrentState) {
+        self.state.send_replace(state);
+    }
+
+    // Waits until the state is one of the done states and returns it.
+    pub async fn done(&self) -> TorrentState {
+        let mut state = self.state.subscribe();
+        // the sender lives as long as self
+        let done = state
+            .wait_for(TorrentState::is_done)
+            .await
+            .expect("Control keeps the state sender")
+            .clone();
+        done
+    }
+
+    // The pieces of the running download, kept after it ends for the progress.
+    pub fn track(&self, have: Arc<Have>) {
+        *self.have.lock().unwrap() = Some(have);
+    }
+
+    // Wanted pieces that are not on disk yet, None before the download knows.
+    pub fn pieces_missing(&self) -> Option<usize> {
+        self.have
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|have| have.missing())
+    }
+
+    pub fn pieces_done(&self) -> usize {
+        self.have
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |have| have.count())
+    }
+
+    // The counter the check of the data on disk moves forward, reset for a new check.
+    pub fn start_check(&self) -> Arc<AtomicUsize> {
+        self.checked.store(0, Ordering::Relaxed);
+        self.checked.clone()
+    }
+
+    pub fn pieces_checked(&self) -> usize {
+        self.checked.load(Ordering::Relaxed)
+    }
+
+    pub fn peers(&self) -> PeerList {
+        self.peers.clone()
+    }
+
+    // Whether each piece is on disk, all false before the download knows.
+    pub fn pieces(&self, total_pieces: usize) -> Vec<bool> {
+        let have = self.have.lock().unwrap();
+        (0..total_pieces)
+            .map(|piece_index| have.as_ref().is_some_and(|have| have.has(piece_index)))
+            .collect()
+    }
+
+    // Bytes downloaded and uploaded by the last run of the download.
+    pub fn transferred(&self) -> (u64, u64) {
+        self.limits
+            .lock()
+            .unwrap()
+            .running
+            .as_ref()
+            .map_or((0, 0), |bandwidth| {
+                (
+                    bandwidth.download.transferred(),
+                    bandwidth.upload.transferred(),
+                )
+            })
+    }
+
+    // Asks the running download to end, or clears the request before it starts again.
+    pub fn request_stop(&self, stop: bool) {
+        self.stop.send_replace(stop);
+    }
+
+    pub fn stop_requested(&self) -> bool {
+        *self.stop.borrow()
+    }
+
+    // Resolves once the download is asked to end.
+    pub async fn stopped(&self) {
+        let mut stop = self.stop.subscribe();
+        let _ = stop.wait_for(|&stop| stop).await;
+    }
+
+    // Asks the running download to stop requesting pieces, or to carry on again.
+    pub fn request_hold(&self, hold: bool) {
+        self.hold.send_replace(hold);
+    }
+
+    pub fn hold_requested(&self) -> bool {
+        *self.hold.borrow()
+    }
+
+    // For the peer tasks, they check it before every piece.
+    pub fn hold_receiver(&self) -> watch::Receiver<bool> {
+        self.hold.subscribe()
+    }
+
+    // Switches between downloading the pieces in order and in the usual order, also while the
+    // download runs.
+    pub fn set_sequential(&self, sequential: bool) {
+        self.sequential.send_replace(sequential);
+    }
+
+    pub fn sequential_receiver(&self) -> watch::Receiver<bool> {
+        self.sequential.subscribe()
+    }
+
+    // Changes the limits of the torrent, also while the download runs.
+    pub fn set_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        let mut limits = self.limits.lock().unwrap();
+        limits.download = download;
+        limits.upload = upload;
+        if let Some(running) = &limits.running {
+            running.download.set_rate(download);
+            running.upload.set_rate(upload);
+        }
+    }
+
+    // Limiters for a run of the download below the session wide ones, they follow set_limits
+    // until the next run.
+    pub fn bandwidth(&self, manager: &BandwidthManager) -> Bandwidth {
+        let mut limits = self.limits.lock().unwrap();
+        let bandwidth = manager.torrent(limits.download, limits.upload);
+        limits.running = Some(bandwidth.clone());
+        bandwidth
+    }
+
+    // Resolves once the hold request is the given one.
+    pub async fn held(&self, hold: bool) {
+        let mut requested = self.hold.subscribe();
+        let _ = requested.wait_for(|&requested| requested == hold).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_apply_to_the_running_download() {
+        let manager = BandwidthManager::new(Some(1000), None);
+        let control = Control::new();
+        control.set_limits(Some(500), None);
+        let bandwidth = control.bandwidth(&manager);
+        assert_eq!(bandwidth.download.rate(), Some(500));
+        assert_eq!(bandwidth.upload.rate(), None);
+
+        control.set_limits(None, Some(200));
+        assert_eq!(bandwidth.download.rate(), None);
+        assert_eq!(bandwidth.upload.rate(), Some(200));
+        // the session wide limits are left alone
+        assert_eq!(manager.global().download.rate(), Some(1000));
+    }
+
+    #[tokio::test]
+    async fn peers_are_listed_while_their_connection_is_held() {
+        let control = Control::new();
+        let torrent = Bandwidth::new(None, None);
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let peer = control.peers().connected(
+            addr,
+            "Rusty-Bit 0.1.0".to_string(),
+            &torrent,
+            &Events::default(),
+        );
+        peer.bandwidth.download.acquire(100).await;
+        peer.status.interested.store(true, Ordering::Relaxed);
+        peer.status.requests_in_flight.store(3, Ordering::Relaxed);
+        let listed = control.peers().peers();
+        assert_eq!(listed.len(), 1);
+        let info = &listed[0];
+        assert_eq!(
+            (
+                info.addr,
+                info.client.as_str(),
+                info.downloaded,
+                info.uploaded
+            ),
+            (addr, "Rusty-Bit 0.1.0", 100, 0)
+        );
+        assert!(info.interested && info.choking_us && info.choking_peer);
+        assert!(!info.peer_interested && !info.snubbed);
+        assert_eq!(info.requests_in_flight, 3);
+        // listed for the first time the rate is the one since connecting
+        assert_eq!(
+            info.download_rate,
+            100.0 / info.connected_for.as_secs_f64().max(f64::EPSILON)
+        );
+        // the bytes count for the torrent too
+        assert_eq!(torrent.download.transferred(), 100);
+
+        drop(peer);
+        assert_eq!(control.peers().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn peer_rates_cover_the_last_few_seconds() {
+        let peer = PeerList::default().connected(
+            "127.0.0.1:6881".parse().unwrap(),
+            String::new(),
+            &Bandwidth::new(None, None),
+            &Events::default(),
+        );
+        let start = peer.connected_at;
+        let second = Duration::from_secs(1);
+        peer.bandwidth.upload.acquire(1000).await;
+        assert_eq!(peer.info(start + 2 * second).upload_rate, 500.0);
+        peer.bandwidth.upload.acquire(3000).await;
+        assert_eq!(peer.info(start + 4 * second).upload_rate, 1000.0);
+        // the first sample left the window, the one at 2s is the oldest kept
+        assert_eq!(peer.info(start + 8 * second).upload_rate, 500.0);
+        assert_eq!(peer.info(start + 20 * second).upload_rate, 0.0);
+    }
+}
@@ -0,0 +1,523 @@
+use anyhow::{bail, Context};
+use futures_util::future::join_all;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, sync::oneshot, task::JoinHandle};
+use tracing::debug;
+
+mod krpc;
+mod routing;
+
+use krpc::{as_v4, Message, NodeId, NodeInfo, Query, Response};
+use routing::{distance, RoutingTable, K};
+
+// Mainline DHT node (BEP 5). Peers of a torrent are found without a tracker by asking the nodes
+// closest to its info_hash, every node answers with the peers it knows or with nodes closer to
+// the info_hash than itself. Only IPv4 is supported.
+
+// Queries sent in parallel during a lookup
+const ALPHA: usize = 3;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Tokens handed out in get_peers responses are accepted for up to twice this long.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+// Announced peers are forgotten after this long unless they announce again.
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+// Most peers returned for one get_peers query, so the response fits in a UDP packet.
+const MAX_VALUES: usize = 50;
+
+// Most info_hashes, and peers of each, kept from announces. Anybody can announce, the oldest
+// announces make room for new ones.
+const MAX_ANNOUNCED_TORRENTS: usize = 1000;
+const MAX_ANNOUNCED_PEERS: usize = 500;
+
+struct TokenSecrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+// queries waiting for an answer by transaction id, with the node that was asked
+type Pending = HashMap<Vec<u8>, (SocketAddrV4, oneshot::Sender<Message>)>;
+
+// peers announced to us and when, by info_hash
+type Announced = HashMap<[u8; 20], HashMap<SocketAddrV4, Instant>>;
+
+struct Node {
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<Pending>,
+    next_transaction: AtomicU16,
+    peers: Mutex<Announced>,
+    secrets: Mutex<TokenSecrets>,
+}
+
+pub struct Dht {
+    node: Arc<Node>,
+    receiver: JoinHandle<()>,
+}
+
+impl Dht {
+    // Binds the UDP socket and starts answering queries of other nodes.
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Dht> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("Binding DHT socket to {addr}"))?;
+        let mut rng = rand::thread_rng();
+        let node = Arc::new(Node {
+            socket,
+            table: Mutex::new(RoutingTable::new(rng.gen())),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rng.gen()),
+            peers: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(TokenSecrets {
+                current: rng.gen(),
+                previous: rng.gen(),
+                rotated: Instant::now(),
+            }),
+        });
+        let receiver = tokio::spawn(node.clone().receive());
+        Ok(Dht { node, receiver })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.node.socket.local_addr()?)
+    }
+
+    // Nodes in the routing table
+    pub fn nodes(&self) -> usize {
+        self.node.table.lock().unwrap().len()
+    }
+
+    /*
+     * Join the DHT through the given nodes: ask them for nodes close to our own id, which fills
+     * the routing table with our neighbourhood. Returns the number of nodes known afterwards.
+     */
+    pub async fn bootstrap(&self, nodes: &[SocketAddr]) -> usize {
+        let id = self.node.table.lock().unwrap().id();
+        join_all(nodes.iter().filter_map(|addr| as_v4(*addr)).map(|addr| {
+            let node = self.node.clone();
+            async move {
+                if let Ok(response) = node.query(addr, Query::FindNode { target: id }).await {
+                    node.learn(addr, &response);
+                }
+            }
+        }))
+        .await;
+        self.node.lookup(id, false).await;
+        self.nodes()
+    }
+
+    /*
+     * Find peers of the torrent. With announce_port the nodes closest to the info_hash are told
+     * that we are a peer too, listening on that port.
+     */
+    pub async fn get_peers(
+        &self,
+        info_hash: [u8; 20],
+        announce_port: Option<u16>,
+    ) -> Vec<SocketAddr> {
+        let (peers, closest) = self.node.lookup(info_hash, true).await;
+        if let Some(port) = announce_port {
+            join_all(closest.into_iter().filter_map(|(node, token)| {
+                let token = token?;
+                let dht_node = self.node.clone();
+                Some(async move {
+                    let announce = Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port: false,
+                    };
+                    if let Err(e) = dht_node.query(node.addr, announce).await {
+                        debug!("Could not announce to DHT node {}: {e:#}", node.addr);
+                    }
+                })
+            }))
+            .await;
+        }
+        peers.into_iter().map(SocketAddr::V4).collect()
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl Node {
+    async fn receive(self: Arc<Node>) {
+        let mut buffer = [0; 2048];
+        loop {
+            let (length, from) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                // ICMP errors of earlier sends show up here on some platforms
+                Err(_) => continue,
+            };
+            let (Some(from), Ok(message)) = (as_v4(from), Message::decode(&buffer[..length]))
+            else {
+                continue;
+            };
+            match message {
+                Message::Query {
+                    transaction,
+                    id,
+                    query,
+                } => {
+                    let reply = self.answer(transaction, id, query, from);
+                    let _ = self.socket.send_to(&reply.encode(), from).await;
+                }
+                message => {
+                    let mut pending = self.pending.lock().unwrap();
+                    // answers have to come from the node that was asked
+                    if pending
+                        .get(message.transaction())
+                        .is_some_and(|(addr, _)| *addr == from)
+                    {
+                        let (_, sender) = pending.remove(message.transaction()).unwrap();
+                        let _ = sender.send(message);
+                    }
+                }
+            }
+        }
+    }
+
+    fn answer(
+        &self,
+        transaction: Vec<u8>,
+        id: NodeId,
+        query: Query,
+        from: SocketAddrV4,
+    ) -> Message {
+        let mut table = self.table.lock().unwrap();
+        table.insert(NodeInfo { id, addr: from });
+        let mut response = Response {
+            id: table.id(),
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => response.nodes = table.closest(&target, K),
+            Query::GetPeers { info_hash } => {
+                let mut peers = self.peers.lock().unwrap();
+                let known: Vec<SocketAddrV4> = peers
+                    .get_mut(&info_hash)
+                    .map(|peers| {
+                        peers.retain(|_, announced| announced.elapsed() < PEER_LIFETIME);
+                        peers.keys().take(MAX_VALUES).copied().collect()
+                    })
+                    .unwrap_or_default();
+                if known.is_empty() {
+                    response.nodes = table.closest(&info_hash, K);
+                } else {
+                    response.values = known;
+                }
+                response.token = Some(self.token(&from, false));
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port,
+            } => {
+                if token != self.token(&from, false) && token != self.token(&from, true) {
+                    return Message::Error {
+                        transaction,
+                        code: 203,
+                        message: "Bad token".to_string(),
+                    };
+                }
+                let port = if implied_port { from.port() } else { port };
+                store_announce(
+                    &mut self.peers.lock().unwrap(),
+                    info_hash,
+                    SocketAddrV4::new(*from.ip(), port),
+                    Instant::now(),
+                );
+            }
+        }
+        Message::Response {
+            transaction,
+            response,
+        }
+    }
+
+    // Token that proves the node asked get_peers from its address before announcing.
+    fn token(&self, addr: &SocketAddrV4, previous: bool) -> Vec<u8> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated.elapsed() >= TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = rand::thread_rng().gen();
+            secrets.rotated = Instant::now();
+        }
+        let secret = if previous {
+            secrets.previous
+        } else {
+            secrets.current
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(addr.ip().octets());
+        hasher.finalize().to_vec()
+    }
+
+    async fn query(&self, addr: SocketAddrV4, query: Query) -> anyhow::Result<Response> {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction.clone(), (addr, sender));
+        let message = Message::Query {
+            transaction: transaction.clone(),
+            id: self.table.lock().unwrap().id(),
+            query,
+        };
+        let answer = async {
+            self.socket.send_to(&message.encode(), addr).await?;
+            anyhow::Ok(receiver.await?)
+        };
+        let answer = tokio::time::timeout(QUERY_TIMEOUT, answer).await;
+        self.pending.lock().unwrap().remove(&transaction);
+        match answer.context("DHT node did not answer")?? {
+            Message::Response { response, .. } => Ok(response),
+            Message::Error { code, message, .. } => bail!("DHT node answered {code}: {message}"),
+            Message::Query { .. } => bail!("DHT node answered with a query"),
+        }
+    }
+
+    // Add a node that answered us, and the nodes it told us about, to the routing table.
+    fn learn(&self, addr: SocketAddrV4, response: &Response) -> Vec<NodeInfo> {
+        let nodes: Vec<NodeInfo> = response
+            .nodes
+            .iter()
+            .filter(|node| node.addr.port() != 0 && !node.addr.ip().is_unspecified())
+            .copied()
+            .collect();
+        let mut table = self.table.lock().unwrap();
+        table.insert(NodeInfo {
+            id: response.id,
+            addr,
+        });
+        for node in &nodes {
+            table.insert(*node);
+        }
+        nodes
+    }
+
+    /*
+     * Iterative lookup: query the ALPHA closest nodes not asked yet, move towards the target with
+     * the nodes they return, and stop once the K closest known nodes have all answered. Returns
+     * the peers found with get_peers, and the K closest nodes that answered with their tokens.
+     */
+    async fn lookup(
+        &self,
+        target: NodeId,
+        get_peers: bool,
+    ) -> (Vec<SocketAddrV4>, Vec<(NodeInfo, Option<Vec<u8>>)>) {
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut answered = BTreeMap::new();
+        let mut peers = Vec::new();
+        loop {
+            let batch: Vec<NodeInfo> = candidates
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let answers = join_all(batch.iter().map(|node| {
+                queried.insert(node.id);
+                let query = if get_peers {
+                    Query::GetPeers { info_hash: target }
+                } else {
+                    Query::FindNode { target }
+                };
+                self.query(node.addr, query)
+            }))
+            .await;
+            for (node, answer) in batch.into_iter().zip(answers) {
+                let key = distance(&node.id, &target);
+                match answer {
+                    Ok(response) => {
+                        for found in self.learn(node.addr, &response) {
+                            if !queried.contains(&found.id) {
+                                candidates.insert(distance(&found.id, &target), found);
+                            }
+                        }
+                        for peer in &response.values {
+                            if !peers.contains(peer) {
+                                peers.push(*peer);
+                            }
+                        }
+                        let node = NodeInfo {
+                            id: response.id,
+                            addr: node.addr,
+                        };
+                        answered.insert(key, (node, response.token));
+                    }
+                    Err(_) => {
+                        self.table.lock().unwrap().failed(&node.id);
+                        candidates.remove(&key);
+                    }
+                }
+            }
+        }
+        (peers, answered.into_values().take(K).collect())
+    }
+}
+
+// Keeps the peer announced at now. Expired peers go first, then the peer announced longest ago,
+// and for a new info_hash the one announced to longest ago.
+fn store_announce(
+    announced: &mut Announced,
+    info_hash: [u8; 20],
+    peer: SocketAddrV4,
+    now: Instant,
+) {
+    let alive = |at: &Instant| now.duration_since(*at) < PEER_LIFETIME;
+    if !announced.contains_key(&info_hash) && announced.len() >= MAX_ANNOUNCED_TORRENTS {
+        announced.retain(|_, peers| peers.values().any(alive));
+        let oldest = announced
+            .iter()
+            .min_by_key(|(_, peers)| peers.values().max().copied())
+            .map(|(info_hash, _)| *info_hash);
+        if let (Some(oldest), true) = (oldest, announced.len() >= MAX_ANNOUNCED_TORRENTS) {
+            announced.remove(&oldest);
+        }
+    }
+    let peers = announced.entry(info_hash).or_default();
+    if !peers.contains_key(&peer) && peers.len() >= MAX_ANNOUNCED_PEERS {
+        peers.retain(|_, at| alive(at));
+        let oldest = peers
+            .iter()
+            .min_by_key(|(_, at)| **at)
+            .map(|(peer, _)| *peer);
+        if let (Some(oldest), true) = (oldest, peers.len() >= MAX_ANNOUNCED_PEERS) {
+            peers.remove(&oldest);
+        }
+    }
+    peers.insert(peer, now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn local_node() -> Dht {
+        Dht::bind("127.0.0.1:0".parse().unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn announced_peer_is_found_by_another_node() {
+        let router = local_node().await;
+        let router_addr = router.local_addr().unwrap();
+        let mut nodes = Vec::new();
+        for _ in 0..5 {
+            let node = local_node().await;
+            assert!(node.bootstrap(&[router_addr]).await >= 1);
+            nodes.push(node);
+        }
+
+        let info_hash = [7; 20];
+        assert!(nodes[0].get_peers(info_hash, Some(51413)).await.is_empty());
+
+        let peers = nodes[4].get_peers(info_hash, None).await;
+        assert_eq!(
+            peers,
+            vec!["127.0.0.1:51413".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn announce_with_a_forged_token_is_refused() {
+        let node = local_node().await;
+        let other = local_node().await;
+        let addr = as_v4(node.local_addr().unwrap()).unwrap();
+        let announce = Query::AnnouncePeer {
+            info_hash: [1; 20],
+            port: 6881,
+            token: b"forged".to_vec(),
+            implied_port: false,
+        };
+        assert!(other.node.query(addr, announce).await.is_err());
+        assert!(node.node.peers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn announces_are_capped() {
+        let start = Instant::now();
+        let peer = |i: usize| SocketAddrV4::new([10, 0, (i >> 8) as u8, i as u8].into(), 6881);
+        let mut announced = Announced::new();
+        for i in 0..=MAX_ANNOUNCED_PEERS {
+            store_announce(
+                &mut announced,
+                [0; 20],
+                peer(i),
+                start + Duration::from_secs(i as u64),
+            );
+        }
+        // the first peer made room for the last one
+        assert_eq!(announced[&[0; 20]].len(), MAX_ANNOUNCED_PEERS);
+        assert!(!announced[&[0; 20]].contains_key(&peer(0)));
+
+        let later = start + Duration::from_secs(MAX_ANNOUNCED_PEERS as u64);
+        for i in 1..=MAX_ANNOUNCED_TORRENTS {
+            let info_hash = [(i >> 8) as u8, i as u8].repeat(10).try_into().unwrap();
+            store_announce(
+                &mut announced,
+                info_hash,
+                peer(0),
+                later + Duration::from_secs(1),
+            );
+        }
+        // torrent [0; 20] was announced to longest ago
+        assert_eq!(announced.len(), MAX_ANNOUNCED_TORRENTS);
+        assert!(!announced.contains_key(&[0; 20]));
+
+        // expired torrents go before the others
+        let expired = later + PEER_LIFETIME + Duration::from_secs(1);
+        store_announce(&mut announced, [0xff; 20], peer(1), expired);
+        assert_eq!(announced.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unreachable_node_times_out() {
+        let node = local_node().await;
+        // bound but never answering
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = as_v4(silent.local_addr().unwrap()).unwrap();
+        let started = Instant::now();
+        assert!(node.node.query(addr, Query::Ping).await.is_err());
+        assert!(started.elapsed() >= QUERY_TIMEOUT);
+        assert!(node.node.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,360 @@
+use crate::error::RustyBitError;
+use anyhow::{bail, Context};
+use serde_bencode::value::Value;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+
+// KRPC, the bencoded messages DHT nodes exchange over UDP (BEP 5). Every message is a dictionary
+// with a transaction id "t" and a type "y": "q" for a query, "r" for a response and "e" for an
+// error.
+
+pub type NodeId = [u8; 20];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+        // use the port the query came from instead of port
+        implied_port: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    // peers of the torrent, only in get_peers responses
+    pub values: Vec<SocketAddrV4>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Query {
+        transaction: Vec<u8>,
+        id: NodeId,
+        query: Query,
+    },
+    Response {
+        transaction: Vec<u8>,
+        response: Response,
+    },
+    Error {
+        transaction: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+
+impl Message {
+    pub fn transaction(&self) -> &[u8] {
+        match self {
+            Message::Query { transaction, .. }
+            | Message::Response { transaction, .. }
+            | Message::Error { transaction, .. } => transaction,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = HashMap::new();
+        message.insert(b"t".to_vec(), bytes(self.transaction()));
+        match self {
+            Message::Query { id, query, .. } => {
+                let mut arguments = HashMap::new();
+                arguments.insert(b"id".to_vec(), bytes(id));
+                let name: &[u8] = match query {
+                    Query::Ping => b"ping",
+                    Query::FindNode { target } => {
+                        arguments.insert(b"target".to_vec(), bytes(target));
+                        b"find_node"
+                    }
+                    Query::GetPeers { info_hash } => {
+                        arguments.insert(b"info_hash".to_vec(), bytes(info_hash));
+                        b"get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port,
+                    } => {
+                        arguments.insert(b"info_hash".to_vec(), bytes(info_hash));
+                        arguments.insert(b"port".to_vec(), Value::Int(*port as i64));
+                        arguments.insert(b"token".to_vec(), bytes(token));
+                        arguments
+                            .insert(b"implied_port".to_vec(), Value::Int(*implied_port as i64));
+                        b"announce_peer"
+                    }
+                };
+                message.insert(b"y".to_vec(), bytes(b"q"));
+                message.insert(b"q".to_vec(), bytes(name));
+                message.insert(b"a".to_vec(), Value::Dict(arguments));
+            }
+            Message::Response { response, .. } => {
+                let mut values = HashMap::new();
+                values.insert(b"id".to_vec(), bytes(&response.id));
+                if !response.nodes.is_empty() {
+                    let nodes: Vec<u8> = response
+                        .nodes
+                        .iter()
+                        .flat_map(|node| {
+                            node.id
+                                .into_iter()
+                                .chain(compact_addr(&node.addr))
+                                .collect::<Vec<u8>>()
+                        })
+                        .collect();
+                    values.insert(b"nodes".to_vec(), Value::Bytes(nodes));
+                }
+                if !response.values.is_empty() {
+                    let peers = response
+                        .values
+                        .iter()
+                        .map(|peer| Value::Bytes(compact_addr(peer).to_vec()))
+                        .collect();
+                    values.insert(b"values".to_vec(), Value::List(peers));
+                }
+                if let Some(token) = &response.token {
+                    values.insert(b"token".to_vec(), bytes(token));
+                }
+                message.insert(b"y".to_vec(), bytes(b"r"));
+                message.insert(b"r".to_vec(), Value::Dict(values));
+            }
+            Message::Error {
+                code,
+                message: text,
+                ..
+            } => {
+                message.insert(b"y".to_vec(), bytes(b"e"));
+                message.insert(
+                    b"e".to_vec(),
+                    Value::List(vec![Value::Int(*code), bytes(text.as_bytes())]),
+                );
+            }
+        }
+        serde_bencode::to_bytes(&Value::Dict(message)).expect("values always encode")
+    }
+
+    pub fn decode(data: &[u8]) -> anyhow::Result<Message> {
+        let Value::Dict(message) = serde_bencode::from_bytes::<Value>(data)
+            .map_err(RustyBitError::bencode("KRPC message"))?
+        else {
+            bail!("KRPC message is not a dictionary");
+        };
+        let transaction = get_bytes(&message, "t")?.to_vec();
+        match get_bytes(&message, "y")? {
+            b"q" => {
+                let arguments = get_dict(&message, "a")?;
+                let id = get_id(arguments, "id")?;
+                let query = match get_bytes(&message, "q")? {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode {
+                        target: get_id(arguments, "target")?,
+                    },
+                    b"get_peers" => Query::GetPeers {
+                        info_hash: get_id(arguments, "info_hash")?,
+                    },
+                    b"announce_peer" => Query::AnnouncePeer {
+                        info_hash: get_id(arguments, "info_hash")?,
+                        port: u16::try_from(get_int(arguments, "port")?)
+                            .context("Port out of range")?,
+                        token: get_bytes(arguments, "token")?.to_vec(),
+                        implied_port: get_int(arguments, "implied_port").unwrap_or(0) == 1,
+                    },
+                    other => bail!("Unknown query {}", String::from_utf8_lossy(other)),
+                };
+                Ok(Message::Query {
+                    transaction,
+                    id,
+                    query,
+                })
+            }
+            b"r" => {
+                let values = get_dict(&message, "r")?;
+                let nodes = match values.get(b"nodes".as_slice()) {
+                    Some(Value::Bytes(nodes)) => nodes
+                        .chunks_exact(26)
+                        .map(|node| NodeInfo {
+                            id: node[..20].try_into().expect("chunk is 26 bytes"),
+                            addr: parse_compact_addr(&node[20..]),
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                let peers = match values.get(b"values".as_slice()) {
+                    Some(Value::List(peers)) => peers
+                        .iter()
+                        .filter_map(|peer| match peer {
+                            Value::Bytes(peer) if peer.len() == 6 => Some(parse_compact_addr(peer)),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                Ok(Message::Response {
+                    transaction,
+                    response: Response {
+                        id: get_id(values, "id")?,
+                        nodes,
+                        values: peers,
+                        token: get_bytes(values, "token").ok().map(<[u8]>::to_vec),
+                    },
+                })
+            }
+            b"e" => {
+                let (code, message) = match message.get(b"e".as_slice()) {
+                    Some(Value::List(error)) => match error.as_slice() {
+                        [Value::Int(code), Value::Bytes(message)] => {
+                            (*code, String::from_utf8_lossy(message).into_owned())
+                        }
+                        _ => bail!("Malformed KRPC error"),
+                    },
+                    _ => bail!("Malformed KRPC error"),
+                };
+                Ok(Message::Error {
+                    transaction,
+                    code,
+                    message,
+                })
+            }
+            other => bail!("Unknown message type {}", String::from_utf8_lossy(other)),
+        }
+    }
+}
+
+// IPv4 address and port in network byte order, as used in nodes and values.
+pub fn compact_addr(addr: &SocketAddrV4) -> [u8; 6] {
+    let mut compact = [0; 6];
+    compact[..4].copy_from_slice(&addr.ip().octets());
+    compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+fn parse_compact_addr(compact: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]),
+        u16::from_be_bytes([compact[4], compact[5]]),
+    )
+}
+
+pub fn as_v4(addr: SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) => Some(addr),
+        SocketAddr::V6(addr) => addr
+            .ip()
+            .to_ipv4_mapped()
+            .map(|ip| SocketAddrV4::new(ip, addr.port())),
+    }
+}
+
+fn bytes(value: &[u8]) -> Value {
+    Value::Bytes(value.to_vec())
+}
+
+fn get_bytes<'a>(dict: &'a HashMap<Vec<u8>, Value>, key: &str) -> anyhow::Result<&'a [u8]> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Bytes(value)) => Ok(value),
+        _ => bail!("Missing {key} in KRPC message"),
+    }
+}
+
+fn get_int(dict: &HashMap<Vec<u8>, Value>, key: &str) -> anyhow::Result<i64> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Int(value)) => Ok(*value),
+        _ => bail!("Missing {key} in KRPC message"),
+    }
+}
+
+fn get_dict<'a>(
+    dict: &'a HashMap<Vec<u8>, Value>,
+    key: &str,
+) -> anyhow::Result<&'a HashMap<Vec<u8>, Value>> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Dict(value)) => Ok(value),
+        _ => bail!("Missing {key} in KRPC message"),
+    }
+}
+
+fn get_id(dict: &HashMap<Vec<u8>, Value>, key: &str) -> anyhow::Result<[u8; 20]> {
+    get_bytes(dict, key)?
+        .try_into()
+        .with_context(|| format!("{key} is not 20 bytes long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: Message) {
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let transaction = b"aa".to_vec();
+        round_trip(Message::Query {
+            transaction: transaction.clone(),
+            id: [1; 20],
+            query: Query::AnnouncePeer {
+                info_hash: [2; 20],
+                port: 6881,
+                token: b"token".to_vec(),
+                implied_port: false,
+            },
+        });
+        round_trip(Message::Response {
+            transaction: transaction.clone(),
+            response: Response {
+                id: [3; 20],
+                nodes: vec![NodeInfo {
+                    id: [4; 20],
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                }],
+                values: vec!["192.168.1.2:51413".parse().unwrap()],
+                token: Some(b"x".to_vec()),
+            },
+        });
+        round_trip(Message::Error {
+            transaction,
+            code: 203,
+            message: "Bad token".to_string(),
+        });
+    }
+
+    // the ping query from BEP 5
+    #[test]
+    fn ping_matches_the_specification() {
+        let ping = Message::Query {
+            transaction: b"aa".to_vec(),
+            id: *b"abcdefghij0123456789",
+            query: Query::Ping,
+        };
+        assert_eq!(
+            ping.encode(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        assert!(Message::decode(b"not bencode").is_err());
+        assert!(Message::decode(b"d1:t2:aa1:y1:qe").is_err());
+    }
+}
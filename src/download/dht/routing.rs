@@ -0,0 +1,163 @@
+use super::krpc::{NodeId, NodeInfo};
+// Nodes kept per bucket
+pub const K: usize = 8;
+
+// A node stays in the table until it fails to answer this many queries in a row.
+const MAX_FAILURES: u32 = 2;
+
+struct Entry {
+    node: NodeInfo,
+    failures: u32,
+}
+
+/*
+ * Kademlia routing table. Bucket i holds nodes whose distance to us has i leading zero bits, so
+ * the table knows many nodes close to our id and only a few far away.
+ */
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0; 20];
+    for i in 0..20 {
+        distance[i] = a[i] ^ b[i];
+    }
+    distance
+}
+
+fn bucket_index(distance: &NodeId) -> usize {
+    let zeros = distance
+        .iter()
+        .position(|byte| *byte != 0)
+        .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+        .unwrap_or(160);
+    zeros.min(159)
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> RoutingTable {
+        RoutingTable {
+            id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    // Record that the node answered or queried us. Returns false when its bucket is full of good
+    // nodes and it was dropped.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        if node.id == self.id {
+            return false;
+        }
+        let bucket = &mut self.buckets[bucket_index(&distance(&self.id, &node.id))];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.failures = 0;
+            return true;
+        }
+        let entry = Entry { node, failures: 0 };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+        // replace the worst of the nodes that stopped answering
+        match bucket
+            .iter_mut()
+            .filter(|entry| entry.failures >= MAX_FAILURES)
+            .max_by_key(|entry| entry.failures)
+        {
+            Some(bad) => {
+                *bad = entry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The node did not answer a query.
+    pub fn failed(&mut self, id: &NodeId) {
+        let bucket = &mut self.buckets[bucket_index(&distance(&self.id, id))];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == *id) {
+            entry.failures += 1;
+        }
+    }
+
+    // Up to count good nodes, closest to target first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.failures < MAX_FAILURES)
+            .map(|entry| entry.node)
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: NodeId) -> NodeInfo {
+        NodeInfo {
+            id,
+            addr: "127.0.0.1:6881".parse().unwrap(),
+        }
+    }
+
+    // ids differing from ours only in the last byte all land in the closest buckets
+    fn far_id(i: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[0] = 0x80;
+        id[19] = i;
+        id
+    }
+
+    #[test]
+    fn full_buckets_keep_good_nodes_and_replace_failed_ones() {
+        let mut table = RoutingTable::new([0; 20]);
+        for i in 0..K as u8 {
+            assert!(table.insert(node(far_id(i))));
+        }
+        assert!(!table.insert(node(far_id(100))));
+        assert_eq!(table.len(), K);
+
+        for _ in 0..MAX_FAILURES {
+            table.failed(&far_id(3));
+        }
+        assert!(table.insert(node(far_id(100))));
+        assert_eq!(table.len(), K);
+        assert!(table
+            .closest(&far_id(0), K)
+            .iter()
+            .all(|node| node.id != far_id(3)));
+    }
+
+    #[test]
+    fn closest_orders_by_distance() {
+        let mut table = RoutingTable::new([0; 20]);
+        let mut ids = vec![];
+        for first in [0x01, 0x02, 0x04, 0x40, 0x80] {
+            let mut id = [0; 20];
+            id[0] = first;
+            ids.push(id);
+            table.insert(node(id));
+        }
+        let mut target = [0; 20];
+        target[0] = 0x41;
+        let closest: Vec<NodeId> = table.closest(&target, 3).iter().map(|n| n.id).collect();
+        assert_eq!(closest, vec![ids[3], ids[0], ids[1]]);
+    }
+}
@@ -0,0 +1,356 @@
+use crate::config::Config;
+use crate::download::{
+    disk_writer::DiskWriter, metrics::Metrics, piece_map::PieceMap, read_cache::ReadCache,
+    storage::Storage,
+};
+use crate::error::RustyBitError;
+use anyhow::{bail, Context};
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+};
+use tracing::warn;
+
+// All disk access of a running download goes through one task on a blocking thread, so peer
+// tasks never block the executor on the file system. Jobs arrive over a bounded channel: when
+// the disk falls behind, peers wait to hand over their pieces instead of piling them up in memory.
+// The pieces waiting in the queue by the time the disk is free are written together, so that the
+// ones that follow each other in a file go out with one write, and with a write cache they stay
+// buffered until it is full or old (see DiskWriter), the task wakes up for the old ones by itself.
+// Uploads read whole pieces into a read cache and get their blocks from there.
+
+// Jobs queued for the disk before senders have to wait
+const QUEUE_LENGTH: usize = 16;
+
+enum Job {
+    WritePiece {
+        index: usize,
+        data: Vec<u8>,
+        done: oneshot::Sender<anyhow::Result<()>>,
+    },
+    ReadBlock {
+        index: usize,
+        begin: usize,
+        length: usize,
+        done: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    },
+    SyncAll {
+        done: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+
+// Handle to the disk task, it stops once every handle is dropped.
+#[derive(Clone)]
+pub struct DiskIo {
+    jobs: mpsc::Sender<Job>,
+    // where the time pieces take to be written is recorded
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl DiskIo {
+    // Writes, reads and cache use are counted in metrics if given.
+    pub fn spawn(
+        storage: Arc<dyn Storage>,
+        piece_map: Arc<PieceMap>,
+        config: &Config,
+        metrics: Option<Arc<Metrics>>,
+    ) -> DiskIo {
+        let (jobs, mut receiver) = mpsc::channel(QUEUE_LENGTH);
+        let writer = DiskWriter::new(storage.clone(), piece_map.clone(), config);
+        let mut cache = ReadCache::new(config.read_cache);
+        let runtime = Handle::current();
+        let task_metrics = metrics.clone();
+        tokio::task::spawn_blocking(move || {
+            let metrics = task_metrics;
+            // writes and flushes of the writer the metrics were given
+            let mut counted = (0, 0);
+            let mut count = |writer: &DiskWriter| {
+                if let Some(metrics) = &metrics {
+                    let now = (writer.writes(), writer.flushes());
+                    metrics.record_disk_writes(now.0 - counted.0);
+                    metrics.record_disk_flushes(now.1 - counted.1);
+                    counted = now;
+                }
+            };
+            // a job taken off the queue while collecting pieces, it comes after them
+            let mut next = None;
+            loop {
+                let job = match (next.take(), writer.flush_due()) {
+                    (Some(job), _) => job,
+                    (None, None) => match receiver.blocking_recv() {
+                        Some(job) => job,
+                        None => break,
+                    },
+                    (None, Some(due)) => {
+                        let waited =
+                            runtime.block_on(tokio::time::timeout_at(due.into(), receiver.recv()));
+                        match waited {
+                            Ok(Some(job)) => job,
+                            Ok(None) => break,
+                            Err(_) => {
+                                if let Err(e) = writer.flush_expired() {
+                                    warn!("buffered pieces were not written: {e:#}");
+                                }
+                                count(&writer);
+                                continue;
+                            }
+                        }
+                    }
+                };
+                match job {
+                    Job::WritePiece { index, data, done } => {
+                        let mut pieces = vec![(index, data)];
+                        let mut waiting = vec![done];
+                        while pieces.len() < QUEUE_LENGTH {
+                            match receiver.try_recv() {
+                                Ok(Job::WritePiece { index, data, done }) => {
+                                    pieces.push((index, data));
+                                    waiting.push(done);
+                                }
+                                Ok(job) => {
+                                    next = Some(job);
+                                    break;
+                                }
+                                Err(_) => break,
+                            }
+                        }
+                        for &(index, _) in &pieces {
+                            cache.remove(index);
+                        }
+                        let results = writer.write_pieces(&pieces);
+                        for (done, written) in waiting.into_iter().zip(results) {
+                            let _ = done.send(written);
+                        }
+                    }
+                    Job::ReadBlock {
+                        index,
+                        begin,
+                        length,
+                        done,
+                    } => {
+                        // what is still buffered of the piece is read from disk
+                        let read = writer.flush_piece(index).and_then(|()| {
+                            if cache.is_enabled() {
+                                read_cached(
+                                    &mut cache,
+                                    &piece_map,
+                                    storage.as_ref(),
+                                    metrics.as_deref(),
+                                    index,
+                                    begin,
+                                    length,
+                                )
+                            } else {
+                                read_block(&piece_map, storage.as_ref(), index, begin, length)
+                            }
+                        });
+                        let _ = done.send(read);
+                    }
+                    Job::SyncAll { done } => {
+                        let _ = done.send(writer.sync_all());
+                    }
+                }
+                count(&writer);
+            }
+            // nothing buffered is left behind once the download is dropped
+            if let Err(e) = writer.flush_all() {
+                warn!("buffered pieces were not written: {e:#}");
+            }
+            count(&writer);
+        });
+        DiskIo { jobs, metrics }
+    }
+
+    // Stores a verified piece, done once it is written as the sync policy requires, or buffered in
+    // the write cache.
+    pub async fn write_piece(&self, index: usize, data: Vec<u8>) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let written = self
+            .run(|done| Job::WritePiece { index, data, done })
+            .await?;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_disk_write(started.elapsed());
+        }
+        written
+    }
+
+    pub async fn read_block(
+        &self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.run(|done| Job::ReadBlock {
+            index,
+            begin,
+            length,
+            done,
+        })
+        .await?
+    }
+
+    pub async fn sync_all(&self) -> anyhow::Result<()> {
+        self.run(|done| Job::SyncAll { done }).await?
+    }
+
+    async fn run<T>(&self, job: impl FnOnce(oneshot::Sender<T>) -> Job) -> anyhow::Result<T> {
+        let (done, result) = oneshot::channel();
+        if self.jobs.send(job(done)).await.is_err() {
+            bail!("the disk task stopped");
+        }
+        result.await.context("the disk task stopped")
+    }
+}
+
+// Reads the block from the cached piece, the whole piece is read and cached first if it is not
+// there.
+fn read_cached(
+    cache: &mut ReadCache,
+    piece_map: &PieceMap,
+    storage: &dyn Storage,
+    metrics: Option<&Metrics>,
+    index: usize,
+    begin: usize,
+    length: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let modified: Vec<Option<SystemTime>> = piece_map
+        .locations(index)
+        .iter()
+        .map(|location| {
+            let path = piece_map.path(location.file_index);
+            storage.modified(path).ok().flatten()
+        })
+        .collect();
+    let block = |piece: &[u8]| {
+        piece
+            .get(begin..begin + length)
+            .map(<[u8]>::to_vec)
+            .with_context(|| format!("Block at {begin} is beyond the end of piece {index}"))
+    };
+    if let Some(piece) = cache.get(index, &modified) {
+        if let Some(metrics) = metrics {
+            metrics.record_disk_cache_hit();
+        }
+        return block(piece);
+    }
+    let piece = read_block(
+        piece_map,
+        storage,
+        index,
+        0,
+        piece_map.piece_range(index).len(),
+    )?;
+    let read = block(&piece);
+    cache.insert(index, piece, modified);
+    read
+}
+
+// Reads length bytes at begin of the piece, across the files it spans.
+fn read_block(
+    piece_map: &PieceMap,
+    storage: &dyn Storage,
+    index: usize,
+    begin: usize,
+    length: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut block = vec![0_u8; length];
+    let mut filled = 0;
+    for location in piece_map.block_locations(index, begin, length) {
+        let path = piece_map.path(location.file_index);
+        let mut buf = &mut block[filled..filled + location.length as usize];
+        let mut offset = location.offset;
+        while !buf.is_empty() {
+            let read = storage
+                .read_at(path, offset, buf)
+                .map_err(RustyBitError::disk(path))
+                .with_context(|| format!("Reading {path}"))?;
+            if read == 0 {
+                let eof = io::Error::from(ErrorKind::UnexpectedEof);
+                return Err(RustyBitError::disk(path)(eof))
+                    .with_context(|| format!("{path} ended before offset {offset}"));
+            }
+            buf = &mut buf[read..];
+            offset += read as u64;
+        }
+        filled += location.length as usize;
+    }
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::storage::test_backend::MemoryStorage;
+
+    fn disk_io() -> (DiskIo, Arc<MemoryStorage>) {
+        let storage = Arc::new(MemoryStorage::default());
+        {
+            let mut files = storage.files.lock().unwrap();
+            files.insert("a".to_string(), vec![0; 25]);
+            files.insert("b".to_string(), vec![0; 15]);
+        }
+        let piece_map = Arc::new(PieceMap::new(
+            10,
+            vec![("a".to_string(), 25), ("b".to_string(), 15)],
+        ));
+        let disk_io = DiskIo::spawn(storage.clone(), piece_map, &Config::default(), None);
+        (disk_io, storage)
+    }
+
+    #[tokio::test]
+    async fn pieces_are_written_and_read_back() {
+        let (disk_io, storage) = disk_io();
+        for index in 0..4 {
+            let data: Vec<u8> = (index as u8 * 10..(index as u8 * 10 + 10).min(40)).collect();
+            disk_io.write_piece(index, data).await.unwrap();
+        }
+        disk_io.sync_all().await.unwrap();
+        assert_eq!(
+            storage.files.lock().unwrap()["a"],
+            (0..25).collect::<Vec<u8>>()
+        );
+
+        // a block that spans both files
+        let block = disk_io.read_block(2, 3, 6).await.unwrap();
+        assert_eq!(block, (23..29).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test]
+    async fn read_pieces_are_cached_until_written() {
+        let (disk_io, storage) = disk_io();
+        disk_io.write_piece(1, vec![1; 10]).await.unwrap();
+        assert_eq!(disk_io.read_block(1, 0, 4).await.unwrap(), [1; 4]);
+
+        // the memory backend has no modification times, what is changed behind its back is not
+        // noticed
+        storage.files.lock().unwrap().get_mut("a").unwrap()[10..20].fill(7);
+        assert_eq!(disk_io.read_block(1, 6, 4).await.unwrap(), [1; 4]);
+        assert!(disk_io.read_block(1, 8, 4).await.is_err());
+
+        disk_io.write_piece(1, vec![2; 10]).await.unwrap();
+        assert_eq!(disk_io.read_block(1, 6, 4).await.unwrap(), [2; 4]);
+    }
+
+    #[tokio::test]
+    async fn writers_wait_when_the_queue_is_full() {
+        let (disk_io, _) = disk_io();
+        // hold every slot of the queue
+        let mut permits = Vec::new();
+        for _ in 0..QUEUE_LENGTH {
+            permits.push(disk_io.jobs.clone().reserve_owned().await.unwrap());
+        }
+        let write = disk_io.write_piece(0, vec![1; 10]);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(50), write)
+                .await
+                .is_err()
+        );
+        drop(permits);
+        disk_io.write_piece(0, vec![1; 10]).await.unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{bail, Context};
+use std::{io::ErrorKind, path::Path};
+use tracing::warn;
+
+// Refuse to start a download the disk cannot hold, instead of failing halfway through
+// reserving the files.
+
+// Room left free on top of the download so the rest of the system keeps working.
+const SAFETY_MARGIN: u64 = 64 * 1024 * 1024;
+
+pub fn ensure_free_space(
+    download_directory: &Path,
+    required: u64,
+    allow_low_space: bool,
+) -> anyhow::Result<()> {
+    let available = fs4::available_space(download_directory).with_context(|| {
+        format!(
+            "Querying free space of {}",
+            download_directory.to_string_lossy()
+        )
+    })?;
+    check_free_space(required, available, allow_low_space)
+}
+
+// The directory downloads go into, it is created when missing. Anything else in its place is an
+// error rather than a reason to write somewhere else.
+pub fn check_download_dir(download_dir: &Path) -> anyhow::Result<()> {
+    let metadata = match std::fs::metadata(download_dir) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Checking download directory {}", download_dir.display()))
+        }
+    };
+    if !metadata.is_dir() {
+        bail!(
+            "Download directory {} is not a directory",
+            download_dir.display()
+        );
+    }
+    if metadata.permissions().readonly() {
+        bail!("Download directory {} is read-only", download_dir.display());
+    }
+    Ok(())
+}
+
+fn check_free_space(required: u64, available: u64, allow_low_space: bool) -> anyhow::Result<()> {
+    if required.saturating_add(SAFETY_MARGIN) <= available {
+        return Ok(());
+    }
+    if allow_low_space {
+        warn!(
+            "download needs {required} bytes but only {available} bytes are available, continuing because of --allow-low-space"
+        );
+        return Ok(());
+    }
+    bail!(
+        "Not enough disk space: download needs {required} bytes (plus {SAFETY_MARGIN} bytes margin) but only {available} bytes are available, pass --allow-low-space to start anyway"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enough_space() {
+        assert!(check_free_space(1_000, SAFETY_MARGIN + 1_000, false).is_ok());
+    }
+
+    #[test]
+    fn low_space_is_refused_with_sizes() {
+        let error = check_free_space(1_000, SAFETY_MARGIN + 999, false).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("1000 bytes"), "{message}");
+        assert!(message.contains(&format!("{} bytes", SAFETY_MARGIN + 999)));
+    }
+
+    #[test]
+    fn override_allows_low_space() {
+        assert!(check_free_space(u64::MAX, 0, true).is_ok());
+    }
+
+    #[test]
+    fn download_dir_has_to_be_a_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        assert!(check_download_dir(directory.path()).is_ok());
+        // created when the download starts
+        assert!(check_download_dir(&directory.path().join("missing")).is_ok());
+
+        let file = directory.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let message = check_download_dir(&file).unwrap_err().to_string();
+        assert!(message.contains("is not a directory"), "{message}");
+    }
+}
@@ -0,0 +1,657 @@
+use crate::config::{Config, SyncPolicy};
+use crate::download::{
+    piece_map::{PieceLocationMap, PieceMap},
+    storage::{Segment, Storage},
+};
+use crate::error::RustyBitError;
+use anyhow::{anyhow, Context};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Buffered runs are written once they grow past this size.
+const COALESCE_LIMIT: usize = 1024 * 1024;
+
+// Writes pieces to storage and takes care of making them durable according to the configured
+// sync policy. In paranoid mode (verify_writes) a piece is read back and compared before it
+// counts as written.
+//
+// Blocks are not written one by one: contiguous blocks of a piece within a file are collected
+// into one run and written with a single positioned write when a block does not continue the
+// run, the run gets large or old, or the piece is finished. Pieces written together that follow
+// each other share their runs, so the pieces that queued up while the disk was busy go out with a
+// write per file instead of one per piece.
+//
+// With a write cache, finished pieces stay buffered and are reported written right away: a piece
+// that follows one still buffered joins its runs, and everything goes out once the cache is full,
+// once its oldest run is old enough, before a read of the files and when syncing. Should such a
+// write fail, the torrent counts pieces as there that are not, so every later write and sync fails
+// with that error.
+pub struct DiskWriter {
+    storage: Arc<dyn Storage>,
+    piece_map: Arc<PieceMap>,
+    sync_policy: SyncPolicy,
+    verify_writes: bool,
+    // buffered run of contiguous bytes per file and piece
+    pending: Mutex<HashMap<RunKey, PendingWrite>>,
+    // files written to since they were last synced
+    dirty: Mutex<HashSet<u32>>,
+    last_sync: Mutex<Instant>,
+    // positioned writes that reached storage
+    writes: AtomicU64,
+    // bytes buffered before everything is written, nothing stays buffered with 0
+    write_cache: usize,
+    // how long a run stays buffered at most
+    write_cache_age: Duration,
+    // times the write cache was written out
+    flushes: AtomicU64,
+    // path, kind and message of the buffered write that failed
+    failure: Mutex<Option<(String, ErrorKind, String)>>,
+}
+
+// (file index, piece index), the first piece of the run
+type RunKey = (u32, usize);
+
+struct PendingWrite {
+    offset: u64,
+    data: Vec<u8>,
+    since: Instant,
+    // the highest piece with data in the run
+    last_piece: usize,
+}
+
+impl DiskWriter {
+    pub fn new(storage: Arc<dyn Storage>, piece_map: Arc<PieceMap>, config: &Config) -> DiskWriter {
+        DiskWriter {
+            storage,
+            piece_map,
+            sync_policy: config.sync_policy,
+            verify_writes: config.verify_writes,
+            pending: Mutex::new(HashMap::new()),
+            dirty: Mutex::new(HashSet::new()),
+            last_sync: Mutex::new(Instant::now()),
+            writes: AtomicU64::new(0),
+            write_cache: config.write_cache,
+            write_cache_age: config.write_cache_age,
+            flushes: AtomicU64::new(0),
+            failure: Mutex::new(None),
+        }
+    }
+
+    // Positioned writes so far.
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    // Times the write cache was written out so far.
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    // Pieces stay buffered after they are reported written. Not when each of them has to be
+    // synced or read back.
+    fn write_behind(&self) -> bool {
+        self.write_cache > 0 && self.sync_policy != SyncPolicy::EveryPiece && !self.verify_writes
+    }
+
+    /*
+     * Writes whole pieces, returns the result of each in the order given. Pieces that follow each
+     * other are buffered in the runs of the first of them and written out together, the pieces of
+     * a failed write all fail. With a write cache they are only buffered, unless it is full.
+     */
+    pub fn write_pieces(&self, pieces: &[(usize, Vec<u8>)]) -> Vec<anyhow::Result<()>> {
+        if let Err(e) = self.failed() {
+            let message = format!("{e:#}");
+            let mut error = Some(e);
+            return pieces
+                .iter()
+                .map(|_| Err(error.take().unwrap_or_else(|| anyhow!("{message}"))))
+                .collect();
+        }
+        let mut order: Vec<usize> = (0..pieces.len()).collect();
+        order.sort_unstable_by_key(|&position| pieces[position].0);
+        let mut results: Vec<anyhow::Result<()>> = pieces.iter().map(|_| Ok(())).collect();
+        for span in order.chunk_by(|&a, &b| pieces[a].0 + 1 == pieces[b].0) {
+            let first = pieces[span[0]].0;
+            // pieces that follow ones still buffered join their runs
+            let run_piece = self.run_before(first).unwrap_or(first);
+            let written = span
+                .iter()
+                .try_for_each(|&position| {
+                    let (piece_index, piece_data) = &pieces[position];
+                    self.write_block(run_piece, *piece_index, 0, piece_data)
+                })
+                .and_then(|()| {
+                    if self.write_behind() {
+                        Ok(())
+                    } else {
+                        self.flush_runs(run_piece)
+                    }
+                });
+            match written {
+                Ok(()) => {
+                    for &position in span {
+                        let (piece_index, piece_data) = &pieces[position];
+                        results[position] = self.settle_piece(*piece_index, piece_data);
+                    }
+                }
+                Err(e) => {
+                    self.discard_runs(run_piece);
+                    let message = format!("{e:#}");
+                    let mut error = Some(e);
+                    for &position in span {
+                        results[position] =
+                            Err(error.take().unwrap_or_else(|| anyhow!("{message}")));
+                    }
+                }
+            }
+        }
+        if self.write_behind() && self.buffered() >= self.write_cache {
+            if let Err(e) = self.flush_all() {
+                let message = format!("{e:#}");
+                for result in results.iter_mut().filter(|result| result.is_ok()) {
+                    *result = Err(anyhow!("{message}"));
+                }
+            }
+        }
+        results
+    }
+
+    // Queues the block starting at piece_offset within the piece for writing, in the runs of
+    // run_piece.
+    pub fn write_block(
+        &self,
+        run_piece: usize,
+        piece_index: usize,
+        piece_offset: usize,
+        block: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut buffered = 0;
+        for location in self
+            .piece_map
+            .block_locations(piece_index, piece_offset, block.len())
+        {
+            let length = location.length as usize;
+            self.buffer(
+                (location.file_index, run_piece),
+                piece_index,
+                location.offset,
+                &block[buffered..buffered + length],
+            )?;
+            buffered += length;
+        }
+        self.flush_expired()
+    }
+
+    // Syncs and verifies a piece that was written out, as configured.
+    fn settle_piece(&self, piece_index: usize, piece_data: &[u8]) -> anyhow::Result<()> {
+        let piece_locations = self.piece_map.locations(piece_index);
+        match self.sync_policy {
+            SyncPolicy::Never => {}
+            SyncPolicy::EveryPiece => {
+                for location in &piece_locations {
+                    self.sync_file(location.file_index)?;
+                }
+            }
+            SyncPolicy::Periodic(interval) => {
+                if self.last_sync.lock().unwrap().elapsed() >= interval {
+                    self.sync_all()?;
+                }
+            }
+        }
+
+        if self.verify_writes {
+            self.verify_piece(&piece_locations, piece_data)?;
+        }
+        Ok(())
+    }
+
+    // Drops what is buffered in the runs of run_piece, so that pieces that failed to be written
+    // cannot overwrite them once they are downloaded again.
+    pub fn discard_runs(&self, run_piece: usize) {
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|&(_, piece_index), _| piece_index != run_piece);
+    }
+
+    fn buffer(
+        &self,
+        key: RunKey,
+        piece_index: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let full_run = {
+            let mut pending = self.pending.lock().unwrap();
+            let continues_run = pending
+                .get(&key)
+                .is_some_and(|run| run.offset + run.data.len() as u64 == offset);
+            let gap_run = if continues_run {
+                None
+            } else {
+                pending.remove(&key)
+            };
+            let run = pending.entry(key).or_insert_with(|| PendingWrite {
+                offset,
+                data: Vec::new(),
+                since: Instant::now(),
+                last_piece: piece_index,
+            });
+            run.data.extend_from_slice(data);
+            run.last_piece = run.last_piece.max(piece_index);
+            let full_run = if run.data.len() >= COALESCE_LIMIT {
+                pending.remove(&key)
+            } else {
+                None
+            };
+            gap_run.into_iter().chain(full_run)
+        };
+        self.write_runs(full_run.map(|run| (key.0, run)).collect())
+    }
+
+    // The run piece of the runs the piece before this one is buffered in.
+    fn run_before(&self, piece_index: usize) -> Option<usize> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, run)| run.last_piece + 1 == piece_index)
+            .map(|(&(_, run_piece), _)| run_piece)
+    }
+
+    // Bytes buffered.
+    fn buffered(&self) -> usize {
+        self.pending
+            .lock()
+            .unwrap()
+            .values()
+            .map(|run| run.data.len())
+            .sum()
+    }
+
+    // When the oldest run is due to be written, None when nothing is buffered.
+    pub fn flush_due(&self) -> Option<Instant> {
+        self.pending
+            .lock()
+            .unwrap()
+            .values()
+            .map(|run| run.since + self.write_cache_age)
+            .min()
+    }
+
+    // Writes the runs that waited long enough.
+    pub fn flush_expired(&self) -> anyhow::Result<()> {
+        let expired: Vec<RunKey> = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, run)| run.since.elapsed() >= self.write_cache_age)
+            .map(|(key, _)| *key)
+            .collect();
+        self.flush(expired)
+    }
+
+    // Writes the runs in the files the piece has data in, so that reading it finds it on disk.
+    pub fn flush_piece(&self, piece_index: usize) -> anyhow::Result<()> {
+        let files: Vec<u32> = self
+            .piece_map
+            .locations(piece_index)
+            .iter()
+            .map(|location| location.file_index)
+            .collect();
+        let keys: Vec<RunKey> = self
+            .pending
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(file_index, _)| files.contains(file_index))
+            .copied()
+            .collect();
+        self.flush(keys)
+    }
+
+    // Writes out everything buffered.
+    pub fn flush_all(&self) -> anyhow::Result<()> {
+        let keys: Vec<RunKey> = self.pending.lock().unwrap().keys().copied().collect();
+        self.flush(keys)
+    }
+
+    fn flush(&self, keys: Vec<RunKey>) -> anyhow::Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.write_runs(self.take_runs(&keys))
+    }
+
+    fn flush_runs(&self, run_piece: usize) -> anyhow::Result<()> {
+        let keys: Vec<RunKey> = self
+            .pending
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|&&(_, piece_index)| piece_index == run_piece)
+            .copied()
+            .collect();
+        self.write_runs(self.take_runs(&keys))
+    }
+
+    fn take_runs(&self, keys: &[RunKey]) -> Vec<(u32, PendingWrite)> {
+        let mut pending = self.pending.lock().unwrap();
+        keys.iter()
+            .filter_map(|key| pending.remove(key).map(|run| (key.0, run)))
+            .collect()
+    }
+
+    // Writes the runs, with their file index, in one go: storage may submit them together.
+    fn write_runs(&self, runs: Vec<(u32, PendingWrite)>) -> anyhow::Result<()> {
+        if runs.is_empty() {
+            return Ok(());
+        }
+        let segments: Vec<Segment> = runs
+            .iter()
+            .map(|(file_index, run)| Segment {
+                path: self.piece_map.path(*file_index),
+                offset: run.offset,
+                data: &run.data,
+            })
+            .collect();
+        if let Err((index, e)) = self.storage.write_segments(&segments) {
+            let path = segments[index].path;
+            if self.write_behind() {
+                self.failure
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(|| (path.to_string(), e.kind(), e.to_string()));
+            }
+            return Err(RustyBitError::disk(path)(e)).with_context(|| format!("Writing to {path}"));
+        }
+        self.writes.fetch_add(runs.len() as u64, Ordering::Relaxed);
+        self.dirty
+            .lock()
+            .unwrap()
+            .extend(runs.iter().map(|(file_index, _)| *file_index));
+        Ok(())
+    }
+
+    // Writes out everything buffered and syncs every file with unsynced writes, used at the end
+    // of a download.
+    pub fn sync_all(&self) -> anyhow::Result<()> {
+        self.failed()?;
+        self.flush_all()?;
+        if self.sync_policy == SyncPolicy::Never {
+            return Ok(());
+        }
+        let dirty: Vec<u32> = self.dirty.lock().unwrap().iter().copied().collect();
+        for file_index in dirty {
+            self.sync_file(file_index)?;
+        }
+        *self.last_sync.lock().unwrap() = Instant::now();
+        Ok(())
+    }
+
+    // The error of the buffered write that failed, if one did.
+    fn failed(&self) -> anyhow::Result<()> {
+        match &*self.failure.lock().unwrap() {
+            Some((path, kind, message)) => Err(RustyBitError::disk(path)(io::Error::new(
+                *kind,
+                message.clone(),
+            )))
+            .with_context(|| format!("Writing pieces reported written to {path} failed")),
+            None => Ok(()),
+        }
+    }
+
+    fn sync_file(&self, file_index: u32) -> anyhow::Result<()> {
+        let path = self.piece_map.path(file_index);
+        self.storage
+            .sync(path)
+            .map_err(RustyBitError::disk(path))
+            .with_context(|| format!("Syncing {path}"))?;
+        self.dirty.lock().unwrap().remove(&file_index);
+        Ok(())
+    }
+
+    fn verify_piece(
+        &self,
+        piece_locations: &[PieceLocationMap],
+        piece_data: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut piece_data_pointer = 0;
+        for location in piece_locations {
+            let path = self.piece_map.path(location.file_index);
+            let length = location.length as usize;
+            let expected = &piece_data[piece_data_pointer..piece_data_pointer + length];
+            let mut written = vec![0_u8; length];
+            let mut read = 0;
+            while read < written.len() {
+                let n = self
+                    .storage
+                    .read_at(path, location.offset + read as u64, &mut written[read..])
+                    .map_err(RustyBitError::disk(path))
+                    .with_context(|| format!("Reading back {path}"))?;
+                if n == 0 {
+                    break;
+                }
+                read += n;
+            }
+            if written != expected {
+                let changed = io::Error::new(ErrorKind::InvalidData, "data changed on disk");
+                return Err(RustyBitError::disk(path)(changed)).with_context(|| {
+                    format!(
+                        "Data read back from {path} at offset {} differs from what was written",
+                        location.offset
+                    )
+                });
+            }
+            piece_data_pointer += length;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::storage::test_backend::MemoryStorage;
+
+    // the second piece starts 4 bytes into file a and ends in file b
+    fn piece_map() -> PieceMap {
+        PieceMap::new(16, vec![("a".to_string(), 26), ("b".to_string(), 6)])
+    }
+
+    fn write_piece(writer: &DiskWriter, piece_data: &[u8]) -> anyhow::Result<()> {
+        writer.write_pieces(&[(1, piece_data.to_vec())]).remove(0)
+    }
+
+    // pieces are written as they come
+    fn write_through() -> Config {
+        Config {
+            write_cache: 0,
+            ..Default::default()
+        }
+    }
+
+    fn writer(
+        storage: MemoryStorage,
+        piece_map: PieceMap,
+        config: &Config,
+    ) -> (DiskWriter, Arc<MemoryStorage>) {
+        let storage = Arc::new(storage);
+        (
+            DiskWriter::new(storage.clone(), Arc::new(piece_map), config),
+            storage,
+        )
+    }
+
+    #[test]
+    fn piece_is_split_over_files() {
+        let (writer, storage) = writer(MemoryStorage::default(), piece_map(), &write_through());
+        let data: Vec<u8> = (0..16).collect();
+        write_piece(&writer, &data).unwrap();
+
+        let files = storage.files.lock().unwrap();
+        assert_eq!(files["a"][16..], data[..10]);
+        assert_eq!(files["b"], data[10..]);
+        assert_eq!(*storage.syncs.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn read_back_catches_short_write() {
+        let config = Config {
+            verify_writes: true,
+            ..Default::default()
+        };
+        let (writer, _) = writer(
+            MemoryStorage {
+                short_write: 1,
+                ..Default::default()
+            },
+            piece_map(),
+            &config,
+        );
+        let error = write_piece(&writer, &[7; 16]).unwrap_err();
+        assert!(error.to_string().contains("differs"), "{error}");
+    }
+
+    #[test]
+    fn every_piece_policy_syncs_written_files() {
+        let config = Config {
+            sync_policy: SyncPolicy::EveryPiece,
+            ..Default::default()
+        };
+        let (writer, storage) = writer(MemoryStorage::default(), piece_map(), &config);
+        write_piece(&writer, &[1; 16]).unwrap();
+        assert_eq!(*storage.syncs.lock().unwrap(), 2);
+        // nothing left to sync at the end
+        writer.sync_all().unwrap();
+        assert_eq!(*storage.syncs.lock().unwrap(), 2);
+    }
+
+    // Writes 16 blocks of 4 bytes covering two files in the given order, returns the number of
+    // write calls that reached storage.
+    fn write_blocks(order: &[usize]) -> usize {
+        let (writer, storage) = writer(
+            MemoryStorage::default(),
+            PieceMap::new(64, vec![("a".to_string(), 40), ("b".to_string(), 24)]),
+            &Config::default(),
+        );
+        let data: Vec<u8> = (0..64).collect();
+        for &block in order {
+            writer
+                .write_block(0, 0, block * 4, &data[block * 4..block * 4 + 4])
+                .unwrap();
+        }
+        writer.sync_all().unwrap();
+
+        let files = storage.files.lock().unwrap();
+        assert_eq!(files["a"], data[..40]);
+        assert_eq!(files["b"], data[40..]);
+        let writes = *storage.writes.lock().unwrap();
+        writes
+    }
+
+    #[test]
+    fn in_order_blocks_are_coalesced() {
+        let in_order: Vec<usize> = (0..16).collect();
+        // one run per file
+        assert_eq!(write_blocks(&in_order), 2);
+    }
+
+    #[test]
+    fn shuffled_blocks_are_written_correctly() {
+        let shuffled = [3, 4, 5, 0, 1, 2, 15, 9, 10, 11, 12, 13, 14, 6, 7, 8];
+        let writes = write_blocks(&shuffled);
+        assert!(writes > 2 && writes < shuffled.len(), "{writes} writes");
+    }
+
+    #[test]
+    fn pieces_that_follow_each_other_share_a_write() {
+        let (writer, storage) = writer(
+            MemoryStorage::default(),
+            PieceMap::new(8, vec![("a".to_string(), 20), ("b".to_string(), 12)]),
+            &write_through(),
+        );
+        let data: Vec<u8> = (0..32).collect();
+        let piece = |index: usize| (index, data[index * 8..index * 8 + 8].to_vec());
+        let results = writer.write_pieces(&[piece(3), piece(0), piece(1)]);
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        let files = storage.files.lock().unwrap();
+        assert_eq!(files["a"][..16], data[..16]);
+        assert_eq!(files["b"][4..], data[24..]);
+        // pieces 0 and 1 went out together
+        assert_eq!(*storage.writes.lock().unwrap(), 2);
+        assert_eq!(writer.writes(), 2);
+    }
+
+    #[test]
+    fn discarded_piece_is_not_written() {
+        let (writer, storage) = writer(MemoryStorage::default(), piece_map(), &Config::default());
+        writer.write_block(0, 0, 0, &[9; 4]).unwrap();
+        writer.discard_runs(0);
+        writer.sync_all().unwrap();
+        assert_eq!(*storage.writes.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn cached_pieces_are_written_together_later() {
+        let (writer, storage) = writer(
+            MemoryStorage::default(),
+            PieceMap::new(8, vec![("a".to_string(), 20), ("b".to_string(), 12)]),
+            &Config::default(),
+        );
+        let data: Vec<u8> = (0..32).collect();
+        for index in [0, 1, 2] {
+            let piece = (index, data[index * 8..index * 8 + 8].to_vec());
+            writer.write_pieces(&[piece]).remove(0).unwrap();
+        }
+        assert_eq!(*storage.writes.lock().unwrap(), 0);
+        assert!(writer.flush_due().is_some());
+
+        writer.sync_all().unwrap();
+        let files = storage.files.lock().unwrap();
+        assert_eq!(files["a"], data[..20]);
+        assert_eq!(files["b"][..4], data[20..24]);
+        // one write per file
+        assert_eq!(*storage.writes.lock().unwrap(), 2);
+        assert_eq!(writer.flushes(), 1);
+        assert!(writer.flush_due().is_none());
+    }
+
+    #[test]
+    fn write_cache_goes_out_once_full_or_old() {
+        let config = Config {
+            write_cache: 16,
+            write_cache_age: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (writer, storage) = writer(
+            MemoryStorage::default(),
+            PieceMap::new(8, vec![("a".to_string(), 32)]),
+            &config,
+        );
+        let piece = |index: usize| (index, vec![index as u8; 8]);
+        writer.write_pieces(&[piece(0)]).remove(0).unwrap();
+        assert_eq!(*storage.writes.lock().unwrap(), 0);
+        writer.write_pieces(&[piece(1)]).remove(0).unwrap();
+        assert_eq!(*storage.writes.lock().unwrap(), 1);
+
+        writer.write_pieces(&[piece(3)]).remove(0).unwrap();
+        writer.flush_expired().unwrap();
+        assert_eq!(*storage.writes.lock().unwrap(), 1);
+        std::thread::sleep(Duration::from_millis(60));
+        writer.flush_expired().unwrap();
+        assert_eq!(*storage.writes.lock().unwrap(), 2);
+        assert_eq!(writer.flushes(), 2);
+        assert_eq!(storage.files.lock().unwrap()["a"][24..], [3; 8]);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::config::{Config, IpFamily};
+use hyper::client::connect::dns::Name;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    future::Future,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Every hostname the crate needs to resolve (trackers, proxies, peers given by name) goes through
+// Resolver, which adds a timeout, caching and an address family preference on top of a Lookup backend.
+
+pub type LookupFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>>;
+
+// Source of DNS answers. Backends report a name that does not exist with ErrorKind::NotFound.
+pub trait Lookup: Send + Sync {
+    fn lookup<'a>(&'a self, host: &'a str) -> LookupFuture<'a>;
+}
+
+// Resolves through the operating system (getaddrinfo) on tokio's blocking pool.
+pub struct SystemLookup;
+
+impl Lookup for SystemLookup {
+    fn lookup<'a>(&'a self, host: &'a str) -> LookupFuture<'a> {
+        Box::pin(async move {
+            match tokio::net::lookup_host((host, 0)).await {
+                Result::Ok(addrs) => Ok(addrs.map(|addr| addr.ip()).collect()),
+                // getaddrinfo failures do not carry a usable ErrorKind, recognise the messages
+                // for "no such name" (EAI_NONAME / EAI_NODATA) on the platforms we care about.
+                Err(e) if is_name_not_found(&e) => Err(io::Error::new(ErrorKind::NotFound, e)),
+                Err(e) => Err(e),
+            }
+        })
+    }
+}
+
+fn is_name_not_found(e: &io::Error) -> bool {
+    let message = e.to_string();
+    [
+        "Name or service not known",
+        "No address associated with hostname",
+        "nodename nor servname provided",
+        "No such host is known",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+#[derive(Debug)]
+pub enum DnsError {
+    // The name does not exist (NXDOMAIN) or has no address records.
+    NotFound { host: String },
+    // The resolver did not answer in time.
+    Timeout { host: String, after: Duration },
+    Failed { host: String, source: io::Error },
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::NotFound { host } => write!(f, "{host} does not exist (NXDOMAIN)"),
+            DnsError::Timeout { host, after } => {
+                write!(f, "resolving {host} timed out after {after:?}")
+            }
+            DnsError::Failed { host, source } => write!(f, "resolving {host} failed: {source}"),
+        }
+    }
+}
+
+impl Error for DnsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DnsError::Failed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// hostname -> (addresses, expiry)
+type DnsCache = HashMap<String, (Vec<IpAddr>, Instant)>;
+
+#[derive(Clone)]
+pub struct Resolver {
+    backend: Arc<dyn Lookup>,
+    cache: Arc<Mutex<DnsCache>>,
+    timeout: Duration,
+    ttl: Duration,
+    prefer: IpFamily,
+}
+
+impl Resolver {
+    pub fn new(config: &Config) -> Resolver {
+        Resolver::with_backend(Arc::new(SystemLookup), config)
+    }
+
+    pub fn with_backend(backend: Arc<dyn Lookup>, config: &Config) -> Resolver {
+        Resolver {
+            backend,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            timeout: config.dns_timeout,
+            ttl: config.dns_cache_ttl,
+            prefer: config.dns_prefer,
+        }
+    }
+
+    // All addresses of host, the preferred family first.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, DnsError> {
+        // IP literals never hit the backend
+        if let Result::Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
+        if let Some((addrs, expires_at)) = self.cache.lock().unwrap().get(host) {
+            if *expires_at > Instant::now() {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let mut addrs = match tokio::time::timeout(self.timeout, self.backend.lookup(host)).await {
+            Err(_) => {
+                return Err(DnsError::Timeout {
+                    host: host.to_string(),
+                    after: self.timeout,
+                })
+            }
+            Result::Ok(Err(e)) if e.kind() == ErrorKind::NotFound => {
+                return Err(DnsError::NotFound {
+                    host: host.to_string(),
+                })
+            }
+            Result::Ok(Err(source)) => {
+                return Err(DnsError::Failed {
+                    host: host.to_string(),
+                    source,
+                })
+            }
+            Result::Ok(Result::Ok(addrs)) => addrs,
+        };
+        if addrs.is_empty() {
+            return Err(DnsError::NotFound {
+                host: host.to_string(),
+            });
+        }
+
+        // stable sort keeps the backend's order within a family
+        addrs.sort_by_key(|addr| !self.prefer.matches(addr));
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), (addrs.clone(), Instant::now() + self.ttl));
+        Ok(addrs)
+    }
+
+    // Resolves "host:port" (or "[v6]:port") to the first address of the preferred family.
+    pub async fn resolve_socket_addr(&self, host_port: &str) -> anyhow::Result<SocketAddr> {
+        if let Result::Ok(addr) = host_port.parse() {
+            return Ok(addr);
+        }
+        let (host, port) = host_port
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("{host_port} has no port"))?;
+        let port: u16 = port.parse()?;
+        let addrs = self.resolve(host).await?;
+        Ok(SocketAddr::new(addrs[0], port))
+    }
+}
+
+// Lets reqwest (tracker and web requests) use the same resolver.
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.resolve(name.as_str()).await?;
+            let addrs: reqwest::dns::Addrs =
+                Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct StubLookup {
+        lookups: AtomicUsize,
+    }
+
+    impl Lookup for StubLookup {
+        fn lookup<'a>(&'a self, host: &'a str) -> LookupFuture<'a> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                match host {
+                    "tracker.example" => Ok(vec![
+                        "2001:db8::1".parse().unwrap(),
+                        "192.0.2.1".parse().unwrap(),
+                    ]),
+                    "slow.example" => {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        Ok(Vec::new())
+                    }
+                    _ => Err(io::Error::new(ErrorKind::NotFound, "NXDOMAIN")),
+                }
+            })
+        }
+    }
+
+    fn resolver(config: &Config) -> (Resolver, Arc<StubLookup>) {
+        let stub = Arc::new(StubLookup {
+            lookups: AtomicUsize::new(0),
+        });
+        (Resolver::with_backend(stub.clone(), config), stub)
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached_and_ordered_by_preference() {
+        let (resolver, stub) = resolver(&Config::default());
+        let addrs = resolver.resolve("tracker.example").await.unwrap();
+        assert_eq!(addrs[0], "192.0.2.1".parse::<IpAddr>().unwrap());
+        resolver.resolve("tracker.example").await.unwrap();
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 1);
+
+        let config = Config {
+            dns_prefer: IpFamily::V6,
+            ..Default::default()
+        };
+        let (resolver, _) = self::resolver(&config);
+        let addr = resolver
+            .resolve_socket_addr("tracker.example:6969")
+            .await
+            .unwrap();
+        assert_eq!(addr, "[2001:db8::1]:6969".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_resolved_again() {
+        let config = Config {
+            dns_cache_ttl: Duration::ZERO,
+            ..Default::default()
+        };
+        let (resolver, stub) = resolver(&config);
+        resolver.resolve("tracker.example").await.unwrap();
+        resolver.resolve("tracker.example").await.unwrap();
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn nxdomain_and_timeout_are_distinct() {
+        let config = Config {
+            dns_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (resolver, _) = resolver(&config);
+        assert!(matches!(
+            resolver.resolve("missing.example").await,
+            Err(DnsError::NotFound { .. })
+        ));
+        assert!(matches!(
+            resolver.resolve("slow.example").await,
+            Err(DnsError::Timeout { .. })
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::download::metrics::Metrics;
+use futures_util::Stream;
+use serde::{Serialize, Serializer};
+use std::{fmt, net::SocketAddr, sync::Arc};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/*
+ * What happens in the torrents of a session, for frontends that show it as it happens instead of
+ * polling the progress. Every event names its torrent by the info hash, which serializes as hex:
+ *
+ *     {"event":"PieceVerified","info_hash":"c9e15763f722f23e98a29decdfae341b98d53056","piece":17}
+ *
+ * A subscriber that falls more than EVENT_CAPACITY events behind misses the oldest ones.
+ */
+
+pub const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    // a downloaded piece matched its hash and is on disk
+    PieceVerified {
+        #[serde(serialize_with = "hex")]
+        info_hash: [u8; 20],
+        piece: usize,
+    },
+    // a peer or a web seed sent a piece that does not match its hash
+    PieceFailed {
+        #[serde(serialize_with = "hex")]
+        info_hash: [u8; 20],
+        piece: usize,
+    },
+    // the handshake with a peer went through
+    PeerConnected {
+        #[serde(serialize_with = "hex")]
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+        client: String,
+    },
+    PeerDisconnected {
+        #[serde(serialize_with = "hex")]
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+    },
+    // every wanted piece is on disk
+    TorrentCompleted {
+        #[serde(serialize_with = "hex")]
+        info_hash: [u8; 20],
+    },
+    // an announce failed or the tracker refused it
+    TrackerError {
+        #[serde(serialize_with = "hex")]
+        info_hash: [u8; 20],
+        // the announce URL with any passkey redacted
+        tracker: String,
+        message: String,
+    },
+}
+
+// Sends the events of one torrent to the subscribers of the session and counts them in its
+// metrics, cloned into the tasks of the download.
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+    metrics: Arc<Metrics>,
+    info_hash: [u8; 20],
+}
+
+impl Events {
+    pub fn new(
+        sender: broadcast::Sender<Event>,
+        metrics: Arc<Metrics>,
+        info_hash: [u8; 20],
+    ) -> Events {
+        Events {
+            sender,
+            metrics,
+            info_hash,
+        }
+    }
+
+    // The event is made from the info hash of the torrent.
+    pub fn send(&self, event: impl FnOnce([u8; 20]) -> Event) {
+        let event = event(self.info_hash);
+        self.metrics.record(&event);
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(event);
+        }
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events")
+            .field("info_hash", &self.info_hash)
+            .finish_non_exhaustive()
+    }
+}
+
+// Events nobody can subscribe to.
+impl Default for Events {
+    fn default() -> Events {
+        Events::new(broadcast::channel(1).0, Arc::default(), [0; 20])
+    }
+}
+
+// The events sent to the receiver from now on. Events the subscriber fell too far behind on
+// are skipped.
+pub fn stream(receiver: broadcast::Receiver<Event>) -> impl Stream<Item = Event> + Send + 'static {
+    futures_util::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("an event subscriber fell behind and missed {missed} events");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+fn hex<S: Serializer>(info_hash: &[u8; 20], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = info_hash.iter().map(|byte| format!("{byte:02x}")).collect();
+    serializer.serialize_str(&hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn subscribers_get_the_events_of_every_torrent() {
+        let (sender, receiver) = broadcast::channel(EVENT_CAPACITY);
+        let mut events = Box::pin(stream(receiver));
+        Events::new(sender.clone(), Arc::default(), [0xab; 20]).send(|info_hash| {
+            Event::PieceVerified {
+                info_hash,
+                piece: 3,
+            }
+        });
+        Events::new(sender, Arc::default(), [1; 20])
+            .send(|info_hash| Event::TorrentCompleted { info_hash });
+
+        let event = events.next().await.unwrap();
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            format!(
+                r#"{{"event":"PieceVerified","info_hash":"{}","piece":3}}"#,
+                "ab".repeat(20)
+            )
+        );
+        assert_eq!(
+            events.next().await.unwrap(),
+            Event::TorrentCompleted { info_hash: [1; 20] }
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::{Path, PathBuf};
+
+/*
+ * Maps file paths from the torrent to paths on the local disk. Torrents made on other systems
+ * can contain names Windows cannot create (device names like "con.txt", names ending in dots or
+ * spaces, reserved characters) or trees deeper than MAX_PATH. Such names are rewritten in a
+ * deterministic way, so a restarted download finds the same files again.
+*/
+
+const RESERVED_DEVICE_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Paths this long need the \\?\ prefix to be usable with the Windows file APIs.
+const WINDOWS_MAX_PATH: usize = 260;
+
+// On-disk path of a file given as path components relative to the download directory.
+pub fn on_disk_path(download_directory: &Path, components: &[String]) -> PathBuf {
+    on_disk_path_for(download_directory, components, cfg!(windows))
+}
+
+// One path component that is safe to create on this platform.
+pub fn normalize_component(name: &str) -> String {
+    normalize_component_for(name, cfg!(windows))
+}
+
+fn on_disk_path_for(download_directory: &Path, components: &[String], windows: bool) -> PathBuf {
+    let mut path = download_directory.to_path_buf();
+    for component in components {
+        path.push(normalize_component_for(component, windows));
+    }
+    if windows {
+        long_path(path)
+    } else {
+        path
+    }
+}
+
+fn normalize_component_for(name: &str, windows: bool) -> String {
+    if !windows {
+        return name.to_string();
+    }
+
+    let mut name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows silently drops trailing dots and spaces, "a." and "a" would be the same file
+    let trimmed_len = name.trim_end_matches(['.', ' ']).len();
+    name.truncate(trimmed_len);
+    if name.is_empty() {
+        name.push('_');
+    }
+
+    // the device names are reserved with any extension too, "con.txt" opens the console
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_DEVICE_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        name.insert(stem.len(), '_');
+    }
+    name
+}
+
+fn long_path(path: PathBuf) -> PathBuf {
+    if path.as_os_str().len() < WINDOWS_MAX_PATH {
+        return path;
+    }
+    let absolute = std::path::absolute(&path).unwrap_or(path);
+    let mut prefixed = std::ffi::OsString::from(r"\\?\");
+    prefixed.push(absolute.as_os_str());
+    PathBuf::from(prefixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(name: &str) -> String {
+        normalize_component_for(name, true)
+    }
+
+    #[test]
+    fn reserved_device_names_are_suffixed() {
+        assert_eq!(windows("con.txt"), "con_.txt");
+        assert_eq!(windows("AUX"), "AUX_");
+        assert_eq!(windows("Lpt1.tar.gz"), "Lpt1_.tar.gz");
+        // only exact device names are reserved
+        assert_eq!(windows("console.txt"), "console.txt");
+        assert_eq!(windows("com10"), "com10");
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces_are_trimmed() {
+        assert_eq!(windows("notes. . "), "notes");
+        assert_eq!(windows("..."), "_");
+        assert_eq!(windows("nul. "), "nul_");
+    }
+
+    #[test]
+    fn reserved_characters_are_replaced() {
+        assert_eq!(windows("what?: <a|b>*.txt"), "what__ _a_b__.txt");
+        assert_eq!(windows("tab\there"), "tab_here");
+    }
+
+    #[test]
+    fn other_platforms_keep_names() {
+        assert_eq!(normalize_component_for("con.txt.", false), "con.txt.");
+    }
+
+    #[test]
+    fn deep_trees_get_the_long_path_prefix() {
+        let components = vec!["a".repeat(100), "b".repeat(100), "c".repeat(100)];
+        let path = on_disk_path_for(Path::new("Downloaded"), &components, true);
+        assert!(path.to_string_lossy().starts_with(r"\\?\"));
+
+        let short = on_disk_path_for(Path::new("Downloaded"), &["aux".to_string()], true);
+        assert_eq!(short, Path::new("Downloaded").join("aux_"));
+    }
+
+    #[test]
+    fn mapping_is_deterministic() {
+        let components = vec!["con".to_string(), "file. ".to_string()];
+        assert_eq!(
+            on_disk_path_for(Path::new("d"), &components, true),
+            on_disk_path_for(Path::new("d"), &components, true)
+        );
+    }
+}
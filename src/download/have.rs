@@ -0,0 +1,179 @@
+use crate::download::peers::Bitfield;
+use rand::seq::IteratorRandom;
+use std::{sync::Mutex, time::Duration};
+use tokio::sync::{futures::Notified, Notify};
+
+// Which pieces of a torrent are verified and on disk, with a way to wait for one to arrive. The
+// pieces of skipped files are not wanted, the download is complete without them.
+pub struct Have {
+    pieces: Mutex<Vec<bool>>,
+    wanted: Vec<bool>,
+    changed: Notify,
+}
+
+impl Have {
+    #[cfg(test)]
+    pub fn new(total_pieces: usize, missing: &[usize]) -> Have {
+        Have::skipping(total_pieces, missing, &[])
+    }
+
+    pub fn skipping(total_pieces: usize, missing: &[usize], skipped: &[usize]) -> Have {
+        let mut pieces = vec![true; total_pieces];
+        for &piece_index in missing {
+            pieces[piece_index] = false;
+        }
+        let mut wanted = vec![true; total_pieces];
+        for &piece_index in skipped {
+            wanted[piece_index] = false;
+        }
+        Have {
+            pieces: Mutex::new(pieces),
+            wanted,
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn has(&self, piece_index: usize) -> bool {
+        self.pieces.lock().unwrap()[piece_index]
+    }
+
+    // The piece is wanted and not available yet.
+    pub fn needs(&self, piece_index: usize) -> bool {
+        self.wanted[piece_index] && !self.has(piece_index)
+    }
+
+    // Number of wanted pieces that are not available yet.
+    pub fn missing(&self) -> usize {
+        let pieces = self.pieces.lock().unwrap();
+        pieces
+            .iter()
+            .zip(&self.wanted)
+            .filter(|(&has, &wanted)| wanted && !has)
+            .count()
+    }
+
+    // Number of pieces available.
+    pub fn count(&self) -> usize {
+        self.pieces
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&&has| has)
+            .count()
+    }
+
+    // Every wanted piece is available.
+    pub fn complete(&self) -> bool {
+        self.missing() == 0
+    }
+
+    // Waits until every wanted piece is available.
+    pub async fn wait_complete(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if self.complete() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    // Resolves once a piece arrives after it was made.
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+
+    pub fn set(&self, piece_index: usize) {
+        self.pieces.lock().unwrap()[piece_index] = true;
+        self.changed.notify_waiters();
+    }
+
+    // Waits until the piece is available, false if it did not arrive in time.
+    pub async fn wait_for(&self, piece_index: usize, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                // register before checking so that a set() in between is not missed
+                let changed = self.changed.notified();
+                if self.has(piece_index) {
+                    return;
+                }
+                changed.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+// Most pieces a lazy bitfield leaves out.
+const LAZY_PIECES: usize = 8;
+
+// The pieces a peer was told we have, so that every piece it was not told about yet is announced
+// once it is verified.
+pub struct Announced(Vec<bool>);
+
+impl Announced {
+    // Nothing was told yet.
+    pub fn new(total_pieces: usize) -> Announced {
+        Announced(vec![false; total_pieces])
+    }
+
+    // Our bitfield, what it holds counts as told. A lazy one leaves out a few pieces at random,
+    // they are announced along with the next verified pieces.
+    pub fn bitfield(&mut self, have: &Have, lazy: bool) -> Bitfield {
+        let pieces = have.pieces.lock().unwrap();
+        self.0.copy_from_slice(&pieces);
+        if lazy {
+            let left_out = (0..pieces.len())
+                .filter(|&piece_index| pieces[piece_index])
+                .choose_multiple(&mut rand::thread_rng(), LAZY_PIECES);
+            for piece_index in left_out {
+                self.0[piece_index] = false;
+            }
+        }
+        Bitfield::from_pieces(pieces.len(), |piece_index| self.0[piece_index])
+    }
+
+    // The pieces we have that the peer was not told about yet, they count as told from now on.
+    pub fn unannounced(&mut self, have: &Have) -> Vec<usize> {
+        let pieces = have.pieces.lock().unwrap();
+        self.0
+            .iter_mut()
+            .zip(pieces.iter())
+            .enumerate()
+            .filter(|(_, (told, &has))| has && !**told)
+            .map(|(piece_index, (told, _))| {
+                *told = true;
+                piece_index
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lazy_bitfields_leave_pieces_for_have_messages() {
+        let have = Have::new(20, &[3]);
+        let mut announced = Announced::new(20);
+        let bitfield = announced.bitfield(&have, false);
+        assert!((0..20).all(|piece_index| bitfield.has(piece_index) == (piece_index != 3)));
+        assert!(announced.unannounced(&have).is_empty());
+        have.set(3);
+        assert_eq!(announced.unannounced(&have), [3]);
+
+        let mut announced = Announced::new(20);
+        let bitfield = announced.bitfield(&have, true);
+        let left_out = announced.unannounced(&have);
+        assert_eq!(left_out.len(), LAZY_PIECES);
+        assert!(left_out
+            .iter()
+            .all(|&piece_index| !bitfield.has(piece_index)));
+        assert_eq!(
+            (0..20).filter(|&i| bitfield.has(i)).count(),
+            20 - LAZY_PIECES
+        );
+    }
+}
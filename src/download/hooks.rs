@@ -0,0 +1,106 @@
+use std::{path::Path, process::Stdio};
+use tokio::{process::Command, task::JoinHandle};
+use tracing::{debug, warn};
+
+/*
+ * Commands run when a torrent is added, completes or fails, e.g. to unpack a download or to have
+ * a media library scan it. A command is run by the shell and learns about the torrent from the
+ * environment:
+ *
+ *     RUSTY_BIT_NAME       name of the torrent
+ *     RUSTY_BIT_PATH       directory the torrent is downloaded into
+ *     RUSTY_BIT_INFO_HASH  info hash in hex
+ *     RUSTY_BIT_SIZE       bytes in the files of the torrent
+ *     RUSTY_BIT_ERROR      why the torrent failed, only for the error hook
+ *
+ * The download does not wait for the command, a command that fails is logged.
+ */
+
+// The torrent a hook is run for.
+pub struct HookTorrent<'a> {
+    pub name: &'a str,
+    pub path: &'a Path,
+    pub info_hash: [u8; 20],
+    pub size: u64,
+}
+
+// Starts the command, the handle ends once it exited. None if it could not be started.
+pub fn run(command: &str, torrent: &HookTorrent, error: Option<&str>) -> Option<JoinHandle<()>> {
+    let info_hash: String = torrent
+        .info_hash
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let mut process = shell(command);
+    process
+        .env("RUSTY_BIT_NAME", torrent.name)
+        .env("RUSTY_BIT_PATH", torrent.path)
+        .env("RUSTY_BIT_INFO_HASH", info_hash)
+        .env("RUSTY_BIT_SIZE", torrent.size.to_string())
+        .stdin(Stdio::null());
+    if let Some(error) = error {
+        process.env("RUSTY_BIT_ERROR", error);
+    }
+    let mut child = match process.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("could not run hook `{command}`: {e}");
+            return None;
+        }
+    };
+    debug!("Running hook `{command}` for {}", torrent.name);
+    let command = command.to_string();
+    Some(tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("hook `{command}` exited with {status}"),
+            Err(e) => warn!("waiting for hook `{command}` failed: {e}"),
+        }
+    }))
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hooks_get_the_torrent_in_their_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let command = format!(
+            "echo \"$RUSTY_BIT_NAME $RUSTY_BIT_PATH $RUSTY_BIT_INFO_HASH $RUSTY_BIT_SIZE $RUSTY_BIT_ERROR\" > {}",
+            out.display()
+        );
+        let torrent = HookTorrent {
+            name: "linux.iso",
+            path: Path::new("/downloads/linux"),
+            info_hash: [0xab; 20],
+            size: 1024,
+        };
+        run(&command, &torrent, Some("disk full"))
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            format!(
+                "linux.iso /downloads/linux {} 1024 disk full\n",
+                "ab".repeat(20)
+            )
+        );
+    }
+}
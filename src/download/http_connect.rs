@@ -0,0 +1,147 @@
+use crate::config::ProxyConfig;
+use crate::download::socks5::TargetAddr;
+use crate::helper::base64_encode;
+use anyhow::{bail, Context};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+// Minimal HTTP CONNECT client with basic authentication, for proxies that do not speak SOCKS5.
+// Like the SOCKS5 client it hands back the TcpStream to the proxy once the tunnel to the target
+// is open, so the handshake and PeerFrameCodec run on it unchanged.
+
+// A proxy that sends more than this before the end of its response header is not one.
+const MAX_RESPONSE_HEADER: usize = 8 * 1024;
+
+// Asks the proxy on an already connected stream to open a tunnel to the target.
+pub async fn connect_with(
+    mut stream: TcpStream,
+    proxy: &ProxyConfig,
+    target: &TargetAddr,
+) -> anyhow::Result<TcpStream> {
+    let authority = match target {
+        TargetAddr::Ip(addr) => addr.to_string(),
+        TargetAddr::Domain(host, port) => format!("{host}:{port}"),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(username) = &proxy.username {
+        let credentials = format!(
+            "{username}:{}",
+            proxy.password.as_deref().unwrap_or_default()
+        );
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64_encode(credentials.as_bytes())
+        );
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+
+    // read a byte at a time, whatever follows the header already comes from the target
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_RESPONSE_HEADER {
+            bail!("HTTP proxy {} sent an oversized response", proxy.addr);
+        }
+        header.push(
+            stream
+                .read_u8()
+                .await
+                .with_context(|| format!("Reading the response of HTTP proxy {}", proxy.addr))?,
+        );
+    }
+    let header = String::from_utf8_lossy(&header);
+    // e.g. HTTP/1.1 200 Connection established
+    let status_line = header.lines().next().unwrap_or_default();
+    let mut parts = status_line.split(' ');
+    if !parts
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/"))
+    {
+        bail!(
+            "HTTP proxy {} sent a malformed response: {status_line}",
+            proxy.addr
+        );
+    }
+    match parts.next().and_then(|status| status.parse::<u16>().ok()) {
+        Some(200..=299) => Ok(stream),
+        Some(407) => bail!("HTTP proxy {} rejected the credentials", proxy.addr),
+        _ => bail!(
+            "HTTP proxy {} could not connect to {authority}: {status_line}",
+            proxy.addr
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    // An HTTP proxy accepting one connection. Records the request header, answers with status
+    // and tunnels to the target if the status is 200.
+    async fn spawn_proxy(status: &'static str) -> (SocketAddr, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(client.read_u8().await.unwrap());
+            }
+            let request = String::from_utf8(request).unwrap();
+            client
+                .write_all(format!("HTTP/1.1 {status}\r\nVia: test\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            if status.starts_with("200") {
+                let target = request.split(' ').nth(1).unwrap();
+                let mut upstream = TcpStream::connect(target).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            }
+            request
+        });
+        (addr, handle)
+    }
+
+    #[tokio::test]
+    async fn connect_through_proxy_with_auth() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            // the peer speaks first, nothing of it may be lost with the proxy's response
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let (proxy_addr, server) = spawn_proxy("200 Connection established").await;
+        let proxy = ProxyConfig::parse(&format!("http://user:secret@{proxy_addr}")).unwrap();
+        let stream = TcpStream::connect(&proxy.addr).await.unwrap();
+        let mut stream = connect_with(stream, &proxy, &TargetAddr::Ip(upstream_addr))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(stream);
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with(&format!("CONNECT {upstream_addr} HTTP/1.1\r\n")));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+    }
+
+    #[tokio::test]
+    async fn refusals_are_reported() {
+        let (proxy_addr, _server) = spawn_proxy("407 Proxy Authentication Required").await;
+        let proxy = ProxyConfig::parse(&format!("http://{proxy_addr}")).unwrap();
+        let stream = TcpStream::connect(&proxy.addr).await.unwrap();
+        let target = TargetAddr::parse("peer.invalid:6881").unwrap();
+        let error = connect_with(stream, &proxy, &target).await.unwrap_err();
+        assert!(
+            error.to_string().contains("rejected the credentials"),
+            "{error}"
+        );
+    }
+}
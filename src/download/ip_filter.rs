@@ -0,0 +1,311 @@
+use anyhow::{bail, Context};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::warn;
+
+/*
+ * Addresses peers must not have, from blocklists and rules given on the command line. A blocklist
+ * has one range per line in the PeerGuardian p2p format
+ *
+ *     Some organization:1.2.3.0-1.2.3.255
+ *
+ * or the eMule ipfilter.dat format, where ranges with an access level of 128 and above are
+ * allowed
+ *
+ *     001.002.003.000 - 001.002.003.255 , 000 , Some organization
+ *
+ * and rules are CIDR blocks like 10.0.0.0/8 or 2001:db8::/32, or single addresses. IPv4
+ * addresses are kept as IPv4-mapped IPv6 ones so that both families are in one sorted list.
+ */
+
+// Entries of the eMule format with this access level or above are not blocked.
+const DAT_ALLOWED_LEVEL: u32 = 128;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpFilter {
+    // disjoint inclusive ranges sorted by their start
+    ranges: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    // Parses a CIDR block or a single address, as given with --block.
+    pub fn parse_rule(rule: &str) -> anyhow::Result<IpFilter> {
+        let mut filter = IpFilter::default();
+        let (first, last) = parse_cidr(rule.trim())
+            .with_context(|| format!("{rule} is not an address or a CIDR block"))?;
+        filter.add(first, last);
+        Ok(filter)
+    }
+
+    // Reads a blocklist in the p2p or the eMule format, lines of neither format are skipped.
+    pub fn load(path: &Path) -> anyhow::Result<IpFilter> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Reading blocklist {}", path.display()))?;
+        let mut filter = IpFilter::default();
+        let mut skipped = 0;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some((first, last))) => filter.add(first, last),
+                // an allowed range of the eMule format
+                Some(None) => {}
+                None => skipped += 1,
+            }
+        }
+        if filter.ranges.is_empty() && skipped > 0 {
+            bail!("{} is neither a p2p nor an eMule blocklist", path.display());
+        }
+        if skipped > 0 {
+            warn!(
+                "skipped {skipped} lines of {} that are not blocklist entries",
+                path.display()
+            );
+        }
+        Ok(filter)
+    }
+
+    // Adds the ranges of another filter to this one.
+    pub fn merge(&mut self, other: &IpFilter) {
+        for &(first, last) in &other.ranges {
+            self.add(first, last);
+        }
+    }
+
+    // Number of disjoint ranges that are blocked.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn blocks(&self, ip: &IpAddr) -> bool {
+        let ip = as_u128(ip);
+        // the last range starting at or before the address is the only one it can be in
+        let after = self.ranges.partition_point(|&(first, _)| first <= ip);
+        after > 0 && self.ranges[after - 1].1 >= ip
+    }
+
+    // Inserts the range, merging it with the ranges it overlaps or touches.
+    fn add(&mut self, first: u128, last: u128) {
+        let (first, last) = (first.min(last), first.max(last));
+        let start = self
+            .ranges
+            .partition_point(|&(_, end)| end.saturating_add(1) < first);
+        let end = self
+            .ranges
+            .partition_point(|&(begin, _)| begin <= last.saturating_add(1));
+        let merged = self.ranges[start..end]
+            .iter()
+            .fold((first, last), |(first, last), &(begin, end)| {
+                (first.min(begin), last.max(end))
+            });
+        self.ranges.splice(start..end, [merged]);
+    }
+}
+
+// The range of a line, None inside if the line allows the range, None if the line is not an
+// entry of either format.
+fn parse_line(line: &str) -> Option<Option<(u128, u128)>> {
+    if let Some((range, rest)) = line.split_once(',') {
+        // eMule: range , access level , description
+        let level: u32 = rest.split(',').next()?.trim().parse().ok()?;
+        let range = parse_range(range)?;
+        return Some((level < DAT_ALLOWED_LEVEL).then_some(range));
+    }
+    if let Some(range) = parse_cidr(line).or_else(|| parse_range(line)) {
+        return Some(Some(range));
+    }
+    // p2p: description:range, the description may contain colons itself
+    let (_, range) = line.rsplit_once(':')?;
+    parse_range(range).map(Some)
+}
+
+// first-last
+fn parse_range(range: &str) -> Option<(u128, u128)> {
+    let (first, last) = range.split_once('-')?;
+    Some((
+        as_u128(&parse_ip(first.trim())?),
+        as_u128(&parse_ip(last.trim())?),
+    ))
+}
+
+fn parse_cidr(rule: &str) -> Option<(u128, u128)> {
+    let (ip, prefix) = match rule.split_once('/') {
+        Some((ip, prefix)) => (parse_ip(ip)?, Some(prefix.parse::<u32>().ok()?)),
+        None => (parse_ip(rule)?, None),
+    };
+    // IPv4 prefixes count from the start of the mapped address
+    let prefix = match (ip, prefix) {
+        (_, None) => 128,
+        (IpAddr::V4(_), Some(prefix)) if prefix <= 32 => 96 + prefix,
+        (IpAddr::V6(_), Some(prefix)) if prefix <= 128 => prefix,
+        _ => return None,
+    };
+    let host_bits = u128::MAX.checked_shr(prefix).unwrap_or(0);
+    let ip = as_u128(&ip);
+    Some((ip & !host_bits, ip | host_bits))
+}
+
+// The eMule format pads the parts of IPv4 addresses with zeros, which the std parser rejects.
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    if let Result::Ok(ip) = ip.parse() {
+        return Some(ip);
+    }
+    let parts: Vec<u8> = ip
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    let parts: [u8; 4] = parts.try_into().ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(parts)))
+}
+
+fn as_u128(ip: &IpAddr) -> u128 {
+    let ip = match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    u128::from(ip)
+}
+
+// Connections the filter blocked in a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockedConnections {
+    // peers we did not connect to
+    pub outgoing: u64,
+    // peers that connected to us and were closed right away
+    pub incoming: u64,
+}
+
+// The filter of a session with the connections it blocked so far, clones share the counters.
+#[derive(Clone, Default)]
+pub struct PeerFilter {
+    filter: Arc<IpFilter>,
+    outgoing: Arc<AtomicU64>,
+    incoming: Arc<AtomicU64>,
+}
+
+impl PeerFilter {
+    pub fn new(filter: Arc<IpFilter>) -> PeerFilter {
+        PeerFilter {
+            filter,
+            ..Default::default()
+        }
+    }
+
+    // Whether we may connect to the address, a blocked address is counted.
+    pub fn allows_outgoing(&self, ip: &IpAddr) -> bool {
+        Self::allows(&self.filter, &self.outgoing, ip)
+    }
+
+    // Whether a peer that connected from the address may stay, a blocked address is counted.
+    pub fn allows_incoming(&self, ip: &IpAddr) -> bool {
+        Self::allows(&self.filter, &self.incoming, ip)
+    }
+
+    pub fn blocked(&self) -> BlockedConnections {
+        BlockedConnections {
+            outgoing: self.outgoing.load(Ordering::Relaxed),
+            incoming: self.incoming.load(Ordering::Relaxed),
+        }
+    }
+
+    fn allows(filter: &IpFilter, counter: &AtomicU64, ip: &IpAddr) -> bool {
+        let blocked = filter.blocks(ip);
+        if blocked {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        !blocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn both_blocklist_formats_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.txt");
+        fs::write(
+            &path,
+            "# a comment\n\
+             Some org: with colons:1.2.3.0-1.2.3.255\n\
+             010.000.000.000 - 010.000.000.255 , 000 , eMule range\n\
+             011.000.000.000 - 011.000.000.255 , 200 , allowed range\n\
+             not an entry\n\
+             \n",
+        )
+        .unwrap();
+        let filter = IpFilter::load(&path).unwrap();
+        assert!(filter.blocks(&ip("1.2.3.4")));
+        assert!(filter.blocks(&ip("10.0.0.255")));
+        assert!(!filter.blocks(&ip("1.2.4.0")));
+        assert!(!filter.blocks(&ip("11.0.0.1")));
+        // IPv4 peers accepted on the IPv6 socket
+        assert!(filter.blocks(&ip("::ffff:1.2.3.4")));
+
+        fs::write(&path, "<html>nothing here</html>\n").unwrap();
+        assert!(IpFilter::load(&path).is_err());
+    }
+
+    #[test]
+    fn rules_are_cidr_blocks() {
+        let mut filter = IpFilter::parse_rule("192.168.0.0/16").unwrap();
+        filter.merge(&IpFilter::parse_rule("2001:db8::/32").unwrap());
+        filter.merge(&IpFilter::parse_rule("8.8.8.8").unwrap());
+        assert!(filter.blocks(&ip("192.168.255.255")));
+        assert!(!filter.blocks(&ip("192.169.0.0")));
+        assert!(filter.blocks(&ip("2001:db8:1::1")));
+        assert!(!filter.blocks(&ip("2001:db9::1")));
+        assert!(filter.blocks(&ip("8.8.8.8")));
+        assert!(!filter.blocks(&ip("8.8.8.9")));
+        assert!(IpFilter::parse_rule("10.0.0.0/33").is_err());
+        assert!(IpFilter::parse_rule("example.com").is_err());
+        assert!(IpFilter::parse_rule("0.0.0.0/0")
+            .unwrap()
+            .blocks(&ip("255.1.2.3")));
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let mut filter = IpFilter::default();
+        filter.merge(&IpFilter::parse_rule("10.0.0.0/24").unwrap());
+        filter.merge(&IpFilter::parse_rule("10.0.2.0/24").unwrap());
+        filter.merge(&IpFilter::parse_rule("10.0.1.0/24").unwrap());
+        filter.merge(&IpFilter::parse_rule("10.0.0.128/25").unwrap());
+        assert_eq!(filter.ranges.len(), 1);
+        assert!(filter.blocks(&ip("10.0.1.17")));
+        assert!(!filter.blocks(&ip("10.0.3.0")));
+    }
+
+    #[test]
+    fn blocked_connections_are_counted() {
+        let filter = PeerFilter::new(Arc::new(IpFilter::parse_rule("10.0.0.0/8").unwrap()));
+        assert!(!filter.allows_outgoing(&ip("10.1.2.3")));
+        assert!(filter.allows_outgoing(&ip("11.1.2.3")));
+        assert!(!filter.clone().allows_incoming(&ip("10.3.2.1")));
+        assert_eq!(
+            filter.blocked(),
+            BlockedConnections {
+                outgoing: 1,
+                incoming: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,373 @@
+use crate::config::{Config, IpFamily};
+use crate::download::{ip_filter::PeerFilter, tracker::HandShake};
+use crate::error::RustyBitError;
+use anyhow::{bail, Context};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+// Accepts incoming peer connections. We listen on IPv4 and IPv6 with two sockets (the IPv6 one
+// is v6only) so that both families share the same port, and hand every connection whose
+// handshake carries the info_hash of one of our swarms to the torrent in that swarm.
+
+pub struct Listener {
+    sockets: Vec<TcpListener>,
+}
+
+// A connection that completed the handshake, with the peer id it sent.
+type Accepted = (TcpStream, SocketAddr, [u8; 20]);
+
+type Route = (Arc<Vec<u8>>, mpsc::Sender<Accepted>);
+
+// The swarms incoming connections can be for, with our handshake in each and the torrent that
+// takes the connections. Torrents join when they start and leave when they end.
+#[derive(Clone, Default)]
+pub struct Swarms {
+    routes: Arc<Mutex<HashMap<[u8; 20], Route>>>,
+}
+
+// Connections for the swarms of one torrent, it leaves the swarms when dropped.
+pub struct Incoming {
+    receiver: mpsc::Receiver<Accepted>,
+    // tells our routes from those of a later join of the same swarms
+    sender: mpsc::Sender<Accepted>,
+    info_hashes: Vec<[u8; 20]>,
+    swarms: Swarms,
+}
+
+impl Swarms {
+    // Joins the swarms of a torrent, with our handshake for every info hash.
+    pub fn join(&self, handshakes: &[([u8; 20], Arc<Vec<u8>>)]) -> Incoming {
+        let (sender, receiver) = mpsc::channel(16);
+        let mut routes = self.routes.lock().unwrap();
+        for (info_hash, handshake) in handshakes {
+            routes.insert(*info_hash, (handshake.clone(), sender.clone()));
+        }
+        Incoming {
+            receiver,
+            sender,
+            info_hashes: handshakes.iter().map(|(info_hash, _)| *info_hash).collect(),
+            swarms: self.clone(),
+        }
+    }
+
+    fn route(&self, info_hash: &[u8; 20]) -> Option<Route> {
+        self.routes.lock().unwrap().get(info_hash).cloned()
+    }
+
+    // Length of the handshakes, they all have the same.
+    fn handshake_len(&self) -> Option<usize> {
+        let routes = self.routes.lock().unwrap();
+        routes.values().next().map(|(handshake, _)| handshake.len())
+    }
+}
+
+impl Incoming {
+    pub async fn recv(&mut self) -> Option<Accepted> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        let mut routes = self.swarms.routes.lock().unwrap();
+        for info_hash in &self.info_hashes {
+            if routes
+                .get(info_hash)
+                .is_some_and(|(_, sender)| sender.same_channel(&self.sender))
+            {
+                routes.remove(info_hash);
+            }
+        }
+    }
+}
+
+impl Listener {
+    pub fn bind(config: &Config, port: u16) -> anyhow::Result<Listener> {
+        if let Some(bind_address) = config.bind_address {
+            let socket = bind_socket(SocketAddr::new(bind_address, port))?;
+            return Ok(Listener {
+                sockets: vec![socket],
+            });
+        }
+
+        let mut sockets = Vec::new();
+        let mut port = port;
+        if config.ip_family != Some(IpFamily::V6) {
+            let socket = bind_socket(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))?;
+            // with port 0 the IPv6 socket has to reuse the port the OS picked for IPv4
+            port = socket.local_addr()?.port();
+            sockets.push(socket);
+        }
+        if config.ip_family != Some(IpFamily::V4) {
+            match bind_socket(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)) {
+                Result::Ok(socket) => sockets.push(socket),
+                // Hosts without IPv6 support are still fine with the IPv4 socket
+                Err(e) if !sockets.is_empty() => {
+                    warn!("not listening on IPv6: {e:#}")
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Listener { sockets })
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets
+            .iter()
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect()
+    }
+
+    /*
+     * Start accepting connections. Every accepted connection has to send a handshake for one
+     * of the swarms that were joined, it is answered with our handshake for that swarm and then
+     * handed to the torrent in it. Peers the filter blocks are closed before the handshake.
+     * Accepting stops when the returned handles are aborted.
+     */
+    pub fn spawn(self, swarms: Swarms, peer_filter: PeerFilter) -> Vec<JoinHandle<()>> {
+        self.sockets
+            .into_iter()
+            .map(|socket| {
+                let swarms = swarms.clone();
+                let peer_filter = peer_filter.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let Result::Ok((stream, addr)) = socket.accept().await else {
+                            continue;
+                        };
+                        if !peer_filter.allows_incoming(&addr.ip()) {
+                            debug!("Closed connection from blocked peer {addr}");
+                            continue;
+                        }
+                        let swarms = swarms.clone();
+                        tokio::spawn(async move {
+                            match accept_handshake(stream, &swarms).await {
+                                Result::Ok((stream, sender, peer_id)) => {
+                                    let _ = sender.send((stream, addr, peer_id)).await;
+                                }
+                                Err(e) => debug!("Rejected connection from {addr}: {e:#}"),
+                            }
+                        });
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+fn bind_socket(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .context("Creating listen socket")?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Binding listen socket to {addr}"))?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+async fn accept_handshake(
+    mut stream: TcpStream,
+    swarms: &Swarms,
+) -> anyhow::Result<(TcpStream, mpsc::Sender<Accepted>, [u8; 20])> {
+    let Some(handshake_len) = swarms.handshake_len() else {
+        bail!(RustyBitError::PeerProtocol(
+            "no torrent is running".to_string()
+        ));
+    };
+    let mut request = vec![0_u8; handshake_len];
+    stream
+        .read_exact(&mut request)
+        .await
+        .context("Reading handshake")?;
+    let handshake: HandShake = bincode::deserialize(&request).context("Decoding handshake")?;
+    if &handshake.pstr != b"BitTorrent protocol" {
+        bail!(RustyBitError::PeerProtocol(
+            "not a BitTorrent handshake".to_string()
+        ));
+    }
+    let Some((encoded_handshake, sender)) = swarms.route(&handshake.info_hash) else {
+        bail!(RustyBitError::PeerProtocol(
+            "handshake is for a torrent we do not have".to_string()
+        ));
+    };
+    stream
+        .write_all(&encoded_handshake)
+        .await
+        .context("Sending handshake")?;
+    Ok((stream, sender, handshake.peer_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::ip_filter::IpFilter;
+
+    async fn connect_with_handshake(addr: SocketAddr, info_hash: [u8; 20]) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let handshake = bincode::serialize(&HandShake::new(info_hash, [1; 20])).unwrap();
+        stream.write_all(&handshake).await.unwrap();
+        let mut response = vec![0u8; handshake.len()];
+        stream.read_exact(&mut response).await.unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn accepts_ipv4_and_ipv6_into_the_same_torrent() {
+        let info_hash = [7; 20];
+        let listener = Listener::bind(&Config::default(), 0).unwrap();
+        let port = listener.local_addrs()[0].port();
+        assert!(listener
+            .local_addrs()
+            .iter()
+            .all(|addr| addr.port() == port));
+
+        let our_handshake =
+            Arc::new(bincode::serialize(&HandShake::new(info_hash, [2; 20])).unwrap());
+        let swarms = Swarms::default();
+        let mut incoming = swarms.join(&[(info_hash, our_handshake)]);
+        let handles = listener.spawn(swarms, PeerFilter::default());
+
+        let _v4 = connect_with_handshake(SocketAddr::from(([127, 0, 0, 1], port)), info_hash).await;
+        let _v6 =
+            connect_with_handshake(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port), info_hash)
+                .await;
+
+        let mut families = Vec::new();
+        for _ in 0..2 {
+            let (_, addr, _) = incoming.recv().await.unwrap();
+            families.push(addr.is_ipv6());
+        }
+        families.sort();
+        assert_eq!(families, vec![false, true]);
+
+        handles.iter().for_each(|handle| handle.abort());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_info_hashes() {
+        let config = Config {
+            ip_family: Some(IpFamily::V4),
+            ..Default::default()
+        };
+        let listener = Listener::bind(&config, 0).unwrap();
+        let addr = listener.local_addrs()[0];
+        let our_handshake =
+            Arc::new(bincode::serialize(&HandShake::new([7; 20], [2; 20])).unwrap());
+        let swarms = Swarms::default();
+        let mut incoming = swarms.join(&[([7; 20], our_handshake)]);
+        let handles = listener.spawn(swarms, PeerFilter::default());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let handshake = bincode::serialize(&HandShake::new([8; 20], [1; 20])).unwrap();
+        stream.write_all(&handshake).await.unwrap();
+        let mut buf = [0u8; 1];
+        // connection is closed without an answer
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        assert!(incoming.receiver.try_recv().is_err());
+
+        handles.iter().for_each(|handle| handle.abort());
+    }
+
+    #[tokio::test]
+    async fn answers_in_the_swarm_of_the_handshake() {
+        let config = Config {
+            ip_family: Some(IpFamily::V4),
+            ..Default::default()
+        };
+        let listener = Listener::bind(&config, 0).unwrap();
+        let addr = listener.local_addrs()[0];
+        // a hybrid torrent is in the swarms of its v1 and its v2 info hash
+        let handshakes = [[7; 20], [9; 20]]
+            .map(|info_hash| {
+                let handshake = HandShake::new(info_hash, [2; 20]);
+                (info_hash, Arc::new(bincode::serialize(&handshake).unwrap()))
+            })
+            .to_vec();
+        let swarms = Swarms::default();
+        let mut incoming = swarms.join(&handshakes);
+        let handles = listener.spawn(swarms, PeerFilter::default());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let handshake = bincode::serialize(&HandShake::new([9; 20], [1; 20])).unwrap();
+        stream.write_all(&handshake).await.unwrap();
+        let mut response = vec![0u8; handshake.len()];
+        stream.read_exact(&mut response).await.unwrap();
+        let response: HandShake = bincode::deserialize(&response).unwrap();
+        assert_eq!(response.info_hash, [9; 20]);
+        assert!(incoming.recv().await.is_some());
+
+        handles.iter().for_each(|handle| handle.abort());
+    }
+
+    #[tokio::test]
+    async fn connections_go_to_the_torrent_of_their_swarm() {
+        let config = Config {
+            ip_family: Some(IpFamily::V4),
+            ..Default::default()
+        };
+        let listener = Listener::bind(&config, 0).unwrap();
+        let addr = listener.local_addrs()[0];
+        let swarms = Swarms::default();
+        let handles = listener.spawn(swarms.clone(), PeerFilter::default());
+        let handshake =
+            |info_hash| Arc::new(bincode::serialize(&HandShake::new(info_hash, [2; 20])).unwrap());
+        let mut first = swarms.join(&[([7; 20], handshake([7; 20]))]);
+        let second = swarms.join(&[([8; 20], handshake([8; 20]))]);
+
+        let _stream = connect_with_handshake(addr, [7; 20]).await;
+        assert!(first.recv().await.is_some());
+
+        // a torrent that ended is no longer connected to
+        drop(second);
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = bincode::serialize(&HandShake::new([8; 20], [1; 20])).unwrap();
+        stream.write_all(&request).await.unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+        handles.iter().for_each(|handle| handle.abort());
+    }
+
+    #[tokio::test]
+    async fn blocked_peers_are_closed_before_the_handshake() {
+        let config = Config {
+            ip_family: Some(IpFamily::V4),
+            ..Default::default()
+        };
+        let listener = Listener::bind(&config, 0).unwrap();
+        let addr = listener.local_addrs()[0];
+        let our_handshake =
+            Arc::new(bincode::serialize(&HandShake::new([7; 20], [2; 20])).unwrap());
+        let swarms = Swarms::default();
+        let mut incoming = swarms.join(&[([7; 20], our_handshake)]);
+        let peer_filter = PeerFilter::new(Arc::new(IpFilter::parse_rule("127.0.0.0/8").unwrap()));
+        let handles = listener.spawn(swarms, peer_filter.clone());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let handshake = bincode::serialize(&HandShake::new([7; 20], [1; 20])).unwrap();
+        // the connection may be closed before the handshake is written
+        let _ = stream.write_all(&handshake).await;
+        let mut buf = [0u8; 1];
+        assert!(!matches!(stream.read(&mut buf).await, Result::Ok(1..)));
+        assert!(incoming.receiver.try_recv().is_err());
+        assert_eq!(peer_filter.blocked().incoming, 1);
+
+        handles.iter().for_each(|handle| handle.abort());
+    }
+}
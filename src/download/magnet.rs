@@ -0,0 +1,155 @@
+use anyhow::{bail, Context};
+use std::net::SocketAddr;
+
+/*
+ * A magnet link (BEP 9) names a torrent by its info hash, the metadata has to come from peers:
+ *
+ *     magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>&x.pe=<host:port>
+ *
+ * The info hash is 40 hex digits or 32 base32 characters. Every parameter but xt is optional and
+ * tr and x.pe may be given more than once. Parameters we do not use are ignored.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    // what the torrent is called until its metadata is there, the info hash in hex if the link
+    // has no dn
+    pub name: String,
+    pub trackers: Vec<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    pub fn parse(link: &str) -> anyhow::Result<Magnet> {
+        let query = link
+            .strip_prefix("magnet:?")
+            .with_context(|| format!("{link} is not a magnet link"))?;
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            // names may have their spaces as +, as in HTML forms
+            let value = urlencoding::decode(&value.replace('+', " "))
+                .with_context(|| format!("{key} of the magnet link is not valid UTF-8"))?
+                .into_owned();
+            match key {
+                "xt" => {
+                    // a v2 info hash (urn:btmh) alone is not enough to find the torrent
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" if !trackers.contains(&value) => trackers.push(value),
+                "x.pe" => match value.parse() {
+                    Ok(peer) => peers.push(peer),
+                    Err(_) => bail!("peer {value} of the magnet link is not an address"),
+                },
+                _ => {}
+            }
+        }
+        let info_hash: [u8; 20] = info_hash.context("The magnet link has no urn:btih info hash")?;
+        Ok(Magnet {
+            info_hash,
+            name: name
+                .unwrap_or_else(|| info_hash.iter().map(|byte| format!("{byte:02x}")).collect()),
+            trackers,
+            peers,
+        })
+    }
+
+    // Tiers of announce URLs, every tracker of the link and every extra one in a tier of its own.
+    pub fn tracker_tiers(&self, extra_trackers: &[String]) -> Vec<Vec<String>> {
+        let mut tiers: Vec<Vec<String>> = Vec::new();
+        for tracker in self.trackers.iter().chain(extra_trackers) {
+            if !tracker.is_empty() && !tiers.iter().flatten().any(|url| url == tracker) {
+                tiers.push(vec![tracker.clone()]);
+            }
+        }
+        tiers
+    }
+}
+
+// 40 hex digits or 32 base32 characters.
+fn parse_info_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => (0..40)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hash.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>(),
+        32 => base32(hash),
+        _ => None,
+    };
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .with_context(|| format!("{hash} is not an info hash"))
+}
+
+// RFC 4648 base32 without padding.
+fn base32(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer = 0_u64;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = buffer << 5 | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magnet_links_are_parsed() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:0123456789ABCDEF0123456789abcdef01234567&dn=my+payload%2Ebin\
+             &tr=http%3A%2F%2Ftracker.example%2Fannounce%3Fkey%3D1&tr=udp%3A%2F%2Fbackup.example%3A6969\
+             &x.pe=127.0.0.1%3A6881&xl=40000",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet,
+            Magnet {
+                info_hash: [
+                    0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89,
+                    0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67
+                ],
+                name: "my payload.bin".to_string(),
+                trackers: vec![
+                    "http://tracker.example/announce?key=1".to_string(),
+                    "udp://backup.example:6969".to_string()
+                ],
+                peers: vec!["127.0.0.1:6881".parse().unwrap()],
+            }
+        );
+        assert_eq!(
+            magnet.tracker_tiers(&["udp://backup.example:6969".to_string()]),
+            vec![
+                vec!["http://tracker.example/announce?key=1".to_string()],
+                vec!["udp://backup.example:6969".to_string()]
+            ]
+        );
+
+        // the same info hash in base32
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+        assert_eq!(base32.name, "0123456789abcdef0123456789abcdef01234567");
+
+        assert!(Magnet::parse("magnet:?dn=nothing").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:0123").is_err());
+        assert!(Magnet::parse("http://tracker.example/").is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::{bail, Context};
+use serde_bencode::value::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// Leaves of BitTorrent v2 hash trees (BEP 52) each cover this many bytes of a file.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/*
+ * The SHA-256 side of a hybrid torrent. Its v1 part pads every file to a piece boundary, so each
+ * v1 piece holds the data of at most one file and can be checked against the v2 hash trees too:
+ * against the file's piece layer when the file is larger than a piece, against the root of the
+ * file's tree otherwise.
+ */
+#[derive(Debug)]
+pub struct PieceHashesV2 {
+    // indexed by v1 piece, None for pieces that are only padding
+    pieces: Vec<Option<V2Piece>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct V2Piece {
+    // bytes of the piece that belong to the file, the padding after them is not hashed
+    length: usize,
+    // leaves of the tree the hash is the root of
+    leaves: usize,
+    hash: [u8; 32],
+}
+
+// A file as listed in the v1 part of a hybrid torrent, path is below the torrent's name.
+pub struct V1File<'a> {
+    pub path: &'a [String],
+    pub length: usize,
+    pub padding: bool,
+}
+
+// Root of the tree over data with the given number of leaves, one SHA-256 per block. Leaves past
+// the end of the data are zero.
+pub fn root(data: &[u8], leaves: usize) -> [u8; 32] {
+    let mut layer: Vec<[u8; 32]> = data
+        .chunks(BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect();
+    layer.resize(leaves.max(1), [0; 32]);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(pair[0]);
+                hasher.update(pair[1]);
+                hasher.finalize().into()
+            })
+            .collect();
+    }
+    layer[0]
+}
+
+fn dict(value: &Value) -> Option<&HashMap<Vec<u8>, Value>> {
+    match value {
+        Value::Dict(dict) => Some(dict),
+        _ => None,
+    }
+}
+
+// Length and pieces root of a file in the file tree, the root is None for an empty file.
+fn file_entry(file_tree: &Value, path: &[String]) -> Option<(usize, Option<[u8; 32]>)> {
+    let mut node = file_tree;
+    for component in path {
+        node = dict(node)?.get(component.as_bytes())?;
+    }
+    let entry = dict(dict(node)?.get(b"".as_slice())?)?;
+    let length = match entry.get(b"length".as_slice())? {
+        Value::Int(length) => usize::try_from(*length).ok()?,
+        _ => return None,
+    };
+    let pieces_root = match entry.get(b"pieces root".as_slice()) {
+        Some(Value::Bytes(root)) => Some(root.as_slice().try_into().ok()?),
+        Some(_) => return None,
+        None => None,
+    };
+    Some((length, pieces_root))
+}
+
+impl PieceHashesV2 {
+    pub fn new(
+        piece_length: usize,
+        files: &[V1File],
+        file_tree: &Value,
+        piece_layers: Option<&Value>,
+    ) -> anyhow::Result<PieceHashesV2> {
+        if piece_length < BLOCK_SIZE || !piece_length.is_power_of_two() {
+            bail!("Piece length {piece_length} is not allowed for a v2 torrent");
+        }
+        let piece_layers = piece_layers.and_then(dict);
+        let total_length: usize = files.iter().map(|file| file.length).sum();
+        let mut pieces = vec![None; total_length.div_ceil(piece_length)];
+        let mut offset = 0;
+        for file in files {
+            let start = offset;
+            offset += file.length;
+            if file.padding {
+                continue;
+            }
+            let path = file.path.join("/");
+            let (length, pieces_root) = file_entry(file_tree, file.path)
+                .with_context(|| format!("{path} is not in the file tree"))?;
+            if length != file.length {
+                bail!("{path} has different lengths in the v1 and the v2 file lists");
+            }
+            // empty files have no tree
+            let Some(pieces_root) = pieces_root else {
+                continue;
+            };
+            if !start.is_multiple_of(piece_length) {
+                bail!("{path} does not start at a piece boundary");
+            }
+            let first_piece = start / piece_length;
+            if length <= piece_length {
+                pieces[first_piece] = Some(V2Piece {
+                    length,
+                    leaves: length.div_ceil(BLOCK_SIZE).next_power_of_two(),
+                    hash: pieces_root,
+                });
+                continue;
+            }
+            let layer = match piece_layers.and_then(|layers| layers.get(&pieces_root[..])) {
+                Some(Value::Bytes(layer)) => layer,
+                _ => bail!("The piece layer of {path} is missing"),
+            };
+            if layer.len() != length.div_ceil(piece_length) * 32 {
+                bail!("The piece layer of {path} has the wrong length");
+            }
+            for (piece, hash) in layer.chunks_exact(32).enumerate() {
+                pieces[first_piece + piece] = Some(V2Piece {
+                    length: piece_length.min(length - piece * piece_length),
+                    leaves: piece_length / BLOCK_SIZE,
+                    hash: hash.try_into().unwrap(),
+                });
+            }
+        }
+        Ok(PieceHashesV2 { pieces })
+    }
+
+    // Whether a v1 piece matches the v2 hashes.
+    pub fn verify(&self, piece_index: usize, piece_data: &[u8]) -> bool {
+        match self.pieces.get(piece_index).copied().flatten() {
+            None => true,
+            Some(piece) => {
+                piece_data.len() >= piece.length
+                    && root(&piece_data[..piece.length], piece.leaves) == piece.hash
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_pair(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        Sha256::digest([left, right].concat()).into()
+    }
+
+    fn entry(length: usize, pieces_root: [u8; 32]) -> Value {
+        Value::Dict(HashMap::from([(
+            Vec::new(),
+            Value::Dict(HashMap::from([
+                (b"length".to_vec(), Value::Int(length as i64)),
+                (b"pieces root".to_vec(), Value::Bytes(pieces_root.to_vec())),
+            ])),
+        )]))
+    }
+
+    #[test]
+    fn roots_are_padded_with_zero_leaves() {
+        let block = vec![1; BLOCK_SIZE];
+        let leaf: [u8; 32] = Sha256::digest(&block).into();
+        assert_eq!(root(&block, 1), leaf);
+        assert_eq!(root(&block, 2), hash_pair(leaf, [0; 32]));
+        let short: [u8; 32] = Sha256::digest([2; 10]).into();
+        let data = [block.clone(), vec![2; 10]].concat();
+        assert_eq!(
+            root(&data, 4),
+            hash_pair(hash_pair(leaf, short), hash_pair([0; 32], [0; 32]))
+        );
+    }
+
+    #[test]
+    fn pieces_are_checked_against_layers_and_roots() {
+        let piece_length = 2 * BLOCK_SIZE;
+        // a file of one and a half pieces, its padding and a small file
+        let big: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        let small = vec![9; 100];
+        let layer = [root(&big[..piece_length], 2), root(&big[piece_length..], 2)];
+        let big_root = hash_pair(layer[0], layer[1]);
+        let small_root = root(&small, 1);
+        let file_tree = Value::Dict(HashMap::from([
+            (b"big".to_vec(), entry(big.len(), big_root)),
+            (b"small".to_vec(), entry(small.len(), small_root)),
+        ]));
+        let piece_layers = Value::Dict(HashMap::from([(
+            big_root.to_vec(),
+            Value::Bytes(layer.concat()),
+        )]));
+        let (big_path, padding_path, small_path) = (
+            ["big".to_string()],
+            [".pad".to_string(), "16384".to_string()],
+            ["small".to_string()],
+        );
+        let files = [
+            V1File {
+                path: &big_path,
+                length: big.len(),
+                padding: false,
+            },
+            V1File {
+                path: &padding_path,
+                length: BLOCK_SIZE,
+                padding: true,
+            },
+            V1File {
+                path: &small_path,
+                length: small.len(),
+                padding: false,
+            },
+        ];
+        let hashes =
+            PieceHashesV2::new(piece_length, &files, &file_tree, Some(&piece_layers)).unwrap();
+
+        assert!(hashes.verify(0, &big[..piece_length]));
+        let padded = [&big[piece_length..], &[0; BLOCK_SIZE]].concat();
+        assert!(hashes.verify(1, &padded));
+        assert!(hashes.verify(2, &small));
+        let mut corrupt = small.clone();
+        corrupt[50] ^= 1;
+        assert!(!hashes.verify(2, &corrupt));
+        assert!(!hashes.verify(0, &big[BLOCK_SIZE..BLOCK_SIZE + piece_length]));
+
+        // without its piece layer the big file can't be checked
+        assert!(PieceHashesV2::new(piece_length, &files, &file_tree, None).is_err());
+    }
+}
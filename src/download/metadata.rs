@@ -0,0 +1,479 @@
+use crate::config::{Config, SaveTorrent};
+use crate::download::{
+    dns::Resolver,
+    events::Events,
+    magnet::Magnet,
+    net,
+    peers::{self, PeerFrameCodec, PeerMessage, KEEP_ALIVE_INTERVAL},
+    shared::Shared,
+    torrent::{exchange_handshake, PeerLookup, Torrent},
+    tracker::{HandShake, TrackerRequest},
+};
+use crate::error::RustyBitError;
+use anyhow::{bail, Context};
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use tokio::task::JoinSet;
+use tokio_util::codec::Framed;
+use tracing::{debug, info, info_span, Instrument};
+
+/*
+ * All a magnet link gives is the info hash, the info dictionary of the torrent is fetched from
+ * its peers (BEP 9). Peers that set the extension bit in their handshake (BEP 10) send an
+ * extension handshake with the id they take ut_metadata messages under and the length of the
+ * info dictionary. It is requested in pieces of 16 KiB and kept once its SHA1 is the info hash.
+ * A few peers are asked at the same time, the first one to send all of it wins.
+ */
+
+// The bit of the reserved bytes of a handshake that says the extension protocol is spoken.
+pub const EXTENSION_BYTE: usize = 5;
+pub const EXTENSION_BIT: u8 = 0x10;
+// The id peers send us ut_metadata messages with.
+pub const UT_METADATA_ID: u8 = 1;
+// Every piece of the metadata but the last is this long.
+pub const METADATA_PIECE_LENGTH: usize = 16 * 1024;
+// Largest info dictionary accepted, that of a torrent of about 800000 pieces.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+// Peers asked for the metadata at the same time.
+const PEERS_AT_ONCE: usize = 8;
+// A peer that sends nothing for this long is given up on.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+// Lists and dictionaries nested deeper than this in a message are not looked into.
+const MAX_NESTING: usize = 32;
+
+// msg_type of the ut_metadata messages
+pub const REQUEST: i64 = 0;
+pub const DATA: i64 = 1;
+pub const REJECT: i64 = 2;
+
+// The extension handshake, the message of extended id 0.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    // the ids the sender takes the messages of each extension under, 0 turns one off
+    #[serde(default)]
+    pub m: HashMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+// The dictionary of a ut_metadata message, the data of a piece follows it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetadataMessage {
+    pub msg_type: i64,
+    pub piece: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<i64>,
+}
+
+impl MetadataMessage {
+    // The message and the bytes after its dictionary.
+    pub fn parse(payload: &[u8]) -> anyhow::Result<(MetadataMessage, &[u8])> {
+        let end = value_end(payload, 0, 0).ok_or_else(|| {
+            RustyBitError::PeerProtocol("ut_metadata message is not bencoded".to_string())
+        })?;
+        let message = serde_bencode::from_bytes(&payload[..end])
+            .map_err(RustyBitError::bencode("The ut_metadata message"))?;
+        Ok((message, &payload[end..]))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("a ut_metadata message is always encoded")
+    }
+}
+
+/*
+ * The torrent of a magnet link. Its metadata is fetched from the peers of the link, the trackers
+ * and the DHT, and saved as a .torrent file as soon as it is verified if the config asks for it.
+ * The torrent and the file get the trackers of the link, the extra ones of the config are only
+ * added when it runs.
+ */
+pub async fn resolve(shared: &Shared, config: &Config, magnet: &Magnet) -> anyhow::Result<Torrent> {
+    info!("Fetching the metadata of {}", magnet.name);
+    let info = fetch(shared, config, magnet).await?;
+    let trackers: Vec<String> = magnet.tracker_tiers(&[]).into_iter().flatten().collect();
+    let metainfo = metainfo(&info, &trackers);
+    let torrent = Torrent::from_metadata(&metainfo, info)?;
+    if let Some(save_torrent) = &config.save_torrent {
+        let path = match save_torrent {
+            SaveTorrent::NextToDownload => torrent.saved_torrent_path(config)?,
+            SaveTorrent::Path(path) => path.clone(),
+        };
+        std::fs::write(&path, &metainfo)
+            .map_err(RustyBitError::disk(&path.display().to_string()))
+            .with_context(|| format!("Writing {}", path.display()))?;
+        info!("Saved the metadata to {}", path.display());
+    }
+    Ok(torrent)
+}
+
+// A .torrent file of the info dictionary as it was fetched, the first tracker is the announce URL
+// and with more than one every tracker is a tier of its own.
+pub fn metainfo(info: &[u8], trackers: &[String]) -> Vec<u8> {
+    let string = |text: &str| format!("{}:{text}", text.len()).into_bytes();
+    let mut metainfo = b"d".to_vec();
+    if let Some(announce) = trackers.first() {
+        metainfo.extend(string("announce"));
+        metainfo.extend(string(announce));
+    }
+    if trackers.len() > 1 {
+        metainfo.extend(string("announce-list"));
+        metainfo.push(b'l');
+        for tracker in trackers {
+            metainfo.push(b'l');
+            metainfo.extend(string(tracker));
+            metainfo.push(b'e');
+        }
+        metainfo.push(b'e');
+    }
+    metainfo.extend(string("info"));
+    metainfo.extend_from_slice(info);
+    metainfo.push(b'e');
+    metainfo
+}
+
+// The info dictionary of the magnet link, from the first peer that has all of it.
+async fn fetch(shared: &Shared, config: &Config, magnet: &Magnet) -> anyhow::Result<Vec<u8>> {
+    let info_hash = magnet.info_hash;
+    // peers of the link come first, like the ones given on the command line
+    let mut config = config.clone();
+    config.peers.splice(0..0, magnet.peers.iter().copied());
+    let resolver = Resolver::new(&config);
+    let events = Events::new(shared.events.clone(), shared.metrics.clone(), info_hash);
+    let lookup = PeerLookup {
+        tracker_tiers: magnet.tracker_tiers(&config.trackers),
+        // whether the torrent is private is in the metadata
+        private: false,
+        swarms: vec![info_hash],
+    };
+    // how much is left is not known yet, anything but 0 keeps us from counting as a seeder
+    let tracker_request = TrackerRequest::new(info_hash, METADATA_PIECE_LENGTH, shared.peer_id);
+    let found = lookup
+        .find(shared, &config, &resolver, tracker_request, &events)
+        .await?;
+    let mut peers = found
+        .into_iter()
+        .flat_map(|found| found.peers)
+        .map(|(peer, _)| peer)
+        .filter(|peer| shared.peer_filter.allows_outgoing(&peer.ip()));
+
+    let mut fetches = JoinSet::new();
+    let mut failure = None;
+    loop {
+        while fetches.len() < PEERS_AT_ONCE {
+            let Some(peer) = peers.next() else {
+                break;
+            };
+            let config = config.clone();
+            let resolver = resolver.clone();
+            let peer_id = shared.peer_id;
+            fetches.spawn(
+                async move { fetch_from(peer, info_hash, peer_id, &config, &resolver).await }
+                    .instrument(info_span!("peer", addr = %peer)),
+            );
+        }
+        let Some(fetched) = fetches.join_next().await else {
+            break;
+        };
+        match fetched {
+            Ok(Ok(info)) => return Ok(info),
+            Ok(Err(e)) => {
+                debug!("Could not fetch the metadata: {e:#}");
+                failure = Some(e);
+            }
+            Err(e) => debug!("Fetching the metadata panicked: {e}"),
+        }
+    }
+    match failure {
+        Some(e) => Err(e.context("No peer sent the metadata")),
+        None => bail!("No peers found to fetch the metadata from"),
+    }
+}
+
+// Fetches the info dictionary from the peer and checks it against the info hash.
+async fn fetch_from(
+    peer: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    config: &Config,
+    resolver: &Resolver,
+) -> anyhow::Result<Vec<u8>> {
+    let mut stream = net::connect_peer(&peer.to_string(), config, resolver)
+        .await
+        .context("Connecting")?;
+    let mut handshake = HandShake::new(info_hash, peer_id);
+    handshake.reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
+    let handshake = exchange_handshake(&mut stream, &bincode::serialize(&handshake)?)
+        .await
+        .context("Handshake")?;
+    if handshake.info_hash != info_hash {
+        bail!(RustyBitError::PeerProtocol(
+            "peer answered for another torrent".to_string()
+        ));
+    }
+    if handshake.reserved[EXTENSION_BYTE] & EXTENSION_BIT == 0 {
+        bail!("peer does not speak the extension protocol");
+    }
+
+    let mut framed = Framed::new(stream, PeerFrameCodec::default());
+    let ours = ExtendedHandshake {
+        m: HashMap::from([("ut_metadata".to_string(), UT_METADATA_ID.into())]),
+        metadata_size: None,
+    };
+    framed
+        .send(PeerMessage::Extended {
+            id: 0,
+            payload: serde_bencode::to_bytes(&ours)?,
+        })
+        .await?;
+
+    // the id of the peer for ut_metadata, the metadata and which of its pieces arrived
+    let mut fetching: Option<(u8, Vec<u8>, Vec<bool>)> = None;
+    loop {
+        let Some(message) =
+            peers::next_frame(&mut framed, KEEP_ALIVE_INTERVAL, PEER_TIMEOUT).await?
+        else {
+            bail!("peer closed the connection");
+        };
+        let PeerMessage::Extended { id, payload } = message else {
+            continue;
+        };
+        if id == 0 {
+            if fetching.is_some() {
+                continue;
+            }
+            let theirs: ExtendedHandshake = serde_bencode::from_bytes(&payload)
+                .map_err(RustyBitError::bencode("The extension handshake"))?;
+            let Some(ut_metadata) = theirs
+                .m
+                .get("ut_metadata")
+                .and_then(|&id| u8::try_from(id).ok())
+                .filter(|&id| id != 0)
+            else {
+                bail!("peer does not share metadata");
+            };
+            let size = theirs
+                .metadata_size
+                .and_then(|size| usize::try_from(size).ok())
+                .filter(|size| (1..=MAX_METADATA_SIZE).contains(size))
+                .with_context(|| {
+                    format!("peer has metadata of {:?} bytes", theirs.metadata_size)
+                })?;
+            let pieces = size.div_ceil(METADATA_PIECE_LENGTH);
+            for piece in 0..pieces {
+                let request = MetadataMessage {
+                    msg_type: REQUEST,
+                    piece: piece as i64,
+                    total_size: None,
+                };
+                framed
+                    .feed(PeerMessage::Extended {
+                        id: ut_metadata,
+                        payload: request.encode(),
+                    })
+                    .await?;
+            }
+            framed.flush().await?;
+            fetching = Some((ut_metadata, vec![0; size], vec![false; pieces]));
+            continue;
+        }
+        if id != UT_METADATA_ID {
+            continue;
+        }
+        let Some((ut_metadata, metadata, received)) = &mut fetching else {
+            continue;
+        };
+        let (message, data) = MetadataMessage::parse(&payload)?;
+        match message.msg_type {
+            // we have nothing to share yet
+            REQUEST => {
+                let reject = MetadataMessage {
+                    msg_type: REJECT,
+                    piece: message.piece,
+                    total_size: None,
+                };
+                framed
+                    .send(PeerMessage::Extended {
+                        id: *ut_metadata,
+                        payload: reject.encode(),
+                    })
+                    .await?;
+            }
+            DATA => {
+                let piece = usize::try_from(message.piece)
+                    .ok()
+                    .filter(|&piece| piece < received.len())
+                    .ok_or_else(|| {
+                        RustyBitError::PeerProtocol(format!(
+                            "peer sent metadata piece {}",
+                            message.piece
+                        ))
+                    })?;
+                let start = piece * METADATA_PIECE_LENGTH;
+                let end = metadata.len().min(start + METADATA_PIECE_LENGTH);
+                if data.len() != end - start {
+                    bail!(RustyBitError::PeerProtocol(format!(
+                        "peer sent {} bytes of metadata piece {piece}",
+                        data.len()
+                    )));
+                }
+                metadata[start..end].copy_from_slice(data);
+                received[piece] = true;
+                if received.iter().all(|&received| received) {
+                    let metadata = std::mem::take(metadata);
+                    if <[u8; 20]>::from(Sha1::digest(&metadata)) != info_hash {
+                        bail!(RustyBitError::PeerProtocol(
+                            "peer sent metadata of another torrent".to_string()
+                        ));
+                    }
+                    debug!("Fetched {} bytes of metadata", metadata.len());
+                    return Ok(metadata);
+                }
+            }
+            REJECT => bail!(
+                "peer rejected the request for metadata piece {}",
+                message.piece
+            ),
+            _ => {}
+        }
+    }
+}
+
+// Where the bencoded value that starts at start ends, None if it is cut short or malformed.
+fn value_end(bytes: &[u8], start: usize, nesting: usize) -> Option<usize> {
+    match *bytes.get(start)? {
+        b'i' => Some(start + bytes[start..].iter().position(|&byte| byte == b'e')? + 1),
+        b'l' | b'd' if nesting < MAX_NESTING => {
+            let mut position = start + 1;
+            while *bytes.get(position)? != b'e' {
+                position = value_end(bytes, position, nesting + 1)?;
+            }
+            Some(position + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = start + bytes[start..].iter().position(|&byte| byte == b':')?;
+            let length: usize = std::str::from_utf8(&bytes[start..colon])
+                .ok()?
+                .parse()
+                .ok()?;
+            let end = colon.checked_add(1 + length)?;
+            (end <= bytes.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::IpFamily;
+    use crate::download::{
+        read_torrent_file,
+        test_peer::{self, Misbehavior, Seeder},
+    };
+    use std::sync::Arc;
+
+    // An info dictionary of a single file of 1000 pieces of 1 KiB, it takes two metadata pieces.
+    fn info() -> Vec<u8> {
+        let mut info =
+            b"d6:lengthi1024000e4:name11:payload.bin12:piece lengthi1024e6:pieces20000:".to_vec();
+        info.extend((0..20_000).map(|i| (i % 251) as u8));
+        info.push(b'e');
+        info
+    }
+
+    #[tokio::test]
+    async fn fetched_metadata_is_saved_as_a_torrent_file() {
+        let info = info();
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        let seeder = test_peer::spawn_with_metadata(
+            Seeder {
+                info_hash,
+                payload: Arc::new(vec![0; 1_024_000]),
+                piece_length: 1024,
+                misbehavior: Misbehavior::None,
+            },
+            info.clone(),
+        )
+        .await;
+        let hex: String = info_hash.iter().map(|byte| format!("{byte:02x}")).collect();
+        // the trackers cannot be reached, the peer of the link has the metadata
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{hex}&dn=payload\
+             &tr=http%3A%2F%2F127.0.0.1%3A1%2Fannounce&tr=http%3A%2F%2F127.0.0.1%3A1%2Fbackup\
+             &x.pe={seeder}"
+        ))
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            download_dir: dir.path().to_path_buf(),
+            listen_port: Some(0),
+            ip_family: Some(IpFamily::V4),
+            dht: false,
+            trackers: vec!["http://127.0.0.1:1/extra".to_string()],
+            save_torrent: Some(SaveTorrent::NextToDownload),
+            ..Default::default()
+        };
+        let shared = Shared::new(&config);
+
+        let torrent = resolve(&shared, &config, &magnet).await.unwrap();
+        assert_eq!(torrent.calc_hash().unwrap(), info_hash);
+        assert_eq!(torrent.info.name(), "payload.bin");
+
+        // the file holds the info dictionary as it was fetched, and the trackers of the link
+        // without the extra one of the config
+        let path = dir.path().join("payload.torrent");
+        let saved = read_torrent_file(&path).unwrap();
+        assert_eq!(saved.calc_hash().unwrap(), magnet.info_hash);
+        assert_eq!(saved.announce, "http://127.0.0.1:1/announce");
+        assert_eq!(
+            saved.announce_list,
+            Some(vec![
+                vec!["http://127.0.0.1:1/announce".to_string()],
+                vec!["http://127.0.0.1:1/backup".to_string()],
+            ])
+        );
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.windows(info.len()).any(|window| window == info));
+
+        // a path of our own
+        let path = dir.path().join("saved.torrent");
+        let config = Config {
+            save_torrent: Some(SaveTorrent::Path(path.clone())),
+            ..config
+        };
+        resolve(&shared, &config, &magnet).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        shared.close().await;
+    }
+
+    #[test]
+    fn info_hash_is_that_of_the_fetched_bytes() {
+        // a key the info dictionary is not decoded with is gone once it is encoded again
+        let mut info = info();
+        info.pop();
+        info.extend(b"6:source4:teste");
+        let torrent = Torrent::from_metadata(&metainfo(&info, &[]), info.clone()).unwrap();
+        assert_eq!(
+            torrent.calc_hash().unwrap(),
+            <[u8; 20]>::from(Sha1::digest(&info))
+        );
+        assert_eq!(torrent.announce, "");
+        assert_eq!(torrent.announce_list, None);
+    }
+
+    #[test]
+    fn metadata_messages_are_followed_by_their_data() {
+        let (message, data) =
+            MetadataMessage::parse(b"d8:msg_typei1e5:piecei0e1:xli1eli2eee10:total_sizei3eeabc")
+                .unwrap();
+        assert_eq!((message.msg_type, message.piece), (DATA, 0));
+        assert_eq!(message.total_size, Some(3));
+        assert_eq!(data, b"abc");
+        // cut short, and nested too deep to look into
+        assert!(MetadataMessage::parse(b"d8:msg_typei1e5:piecei0e").is_err());
+        let nested = format!("d1:x{}i1e{}e", "l".repeat(100), "e".repeat(100));
+        assert!(MetadataMessage::parse(nested.as_bytes()).is_err());
+    }
+}
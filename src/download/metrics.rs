@@ -0,0 +1,325 @@
+use crate::download::{events::Event, ip_filter::BlockedConnections};
+use anyhow::Context;
+use hyper::{
+    header::{self, HeaderValue},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/*
+ * Counters and gauges of a session in the Prometheus text format, served on /metrics for
+ * monitoring long running seedboxes. Most of them are counted from the events of the torrents,
+ * the transferred bytes come from the session wide rate limiters and the disk write latency is
+ * a histogram of how long pieces took from being handed to the disk task until they were written.
+ * The disk tasks count their writes, the write cache flushes and the read cache hits.
+ */
+
+// Upper bounds in seconds of the buckets of the disk write latency.
+const DISK_WRITE_BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Default)]
+pub struct Metrics {
+    pieces_verified: AtomicU64,
+    piece_failures: AtomicU64,
+    tracker_errors: AtomicU64,
+    torrents_completed: AtomicU64,
+    connected_peers: AtomicU64,
+    disk_writes: Histogram,
+    // positioned writes of downloaded data, pieces next to each other in a file share one
+    disk_write_calls: AtomicU64,
+    disk_cache_flushes: AtomicU64,
+    disk_cache_hits: AtomicU64,
+}
+
+// What the metrics are rendered from besides what they counted themselves.
+pub struct Totals {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub blocked: BlockedConnections,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // observations up to the bound of each bucket, the last one has no bound
+    buckets: [AtomicU64; DISK_WRITE_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record(&self, event: &Event) {
+        let counter = match event {
+            Event::PieceVerified { .. } => &self.pieces_verified,
+            Event::PieceFailed { .. } => &self.piece_failures,
+            Event::TrackerError { .. } => &self.tracker_errors,
+            Event::TorrentCompleted { .. } => &self.torrents_completed,
+            Event::PeerConnected { .. } => &self.connected_peers,
+            Event::PeerDisconnected { .. } => {
+                self.connected_peers.fetch_sub(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_disk_write(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = DISK_WRITE_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DISK_WRITE_BUCKETS.len());
+        self.disk_writes.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.disk_writes
+            .sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_disk_writes(&self, writes: u64) {
+        self.disk_write_calls.fetch_add(writes, Ordering::Relaxed);
+    }
+
+    pub fn record_disk_flushes(&self, flushes: u64) {
+        self.disk_cache_flushes
+            .fetch_add(flushes, Ordering::Relaxed);
+    }
+
+    pub fn record_disk_cache_hit(&self) {
+        self.disk_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, totals: &Totals) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(text, "# HELP rusty_bit_{name} {help}");
+            let _ = writeln!(text, "# TYPE rusty_bit_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(text, "rusty_bit_{name}{labels} {value}");
+            }
+        };
+        metric(
+            "downloaded_bytes_total",
+            "counter",
+            "Bytes received from peers and web seeds.",
+            &[("", totals.downloaded)],
+        );
+        metric(
+            "uploaded_bytes_total",
+            "counter",
+            "Bytes sent to peers.",
+            &[("", totals.uploaded)],
+        );
+        metric(
+            "pieces_verified_total",
+            "counter",
+            "Downloaded pieces that matched their hash.",
+            &[("", load(&self.pieces_verified))],
+        );
+        metric(
+            "piece_failures_total",
+            "counter",
+            "Downloaded pieces that did not match their hash.",
+            &[("", load(&self.piece_failures))],
+        );
+        metric(
+            "tracker_errors_total",
+            "counter",
+            "Announces that failed or were refused.",
+            &[("", load(&self.tracker_errors))],
+        );
+        metric(
+            "torrents_completed_total",
+            "counter",
+            "Downloads that got every wanted piece.",
+            &[("", load(&self.torrents_completed))],
+        );
+        metric(
+            "connected_peers",
+            "gauge",
+            "Peers pieces are exchanged with.",
+            &[("", load(&self.connected_peers))],
+        );
+        metric(
+            "disk_write_calls_total",
+            "counter",
+            "Writes of downloaded data to the disk, pieces written together share them.",
+            &[("", load(&self.disk_write_calls))],
+        );
+        metric(
+            "disk_cache_flushes_total",
+            "counter",
+            "Times the write cache went to the disk, once full or old, or before a read or sync.",
+            &[("", load(&self.disk_cache_flushes))],
+        );
+        metric(
+            "disk_cache_hits_total",
+            "counter",
+            "Blocks uploaded from the read cache without reading the disk.",
+            &[("", load(&self.disk_cache_hits))],
+        );
+        metric(
+            "blocked_connections_total",
+            "counter",
+            "Connections the IP filter blocked.",
+            &[
+                ("{direction=\"outgoing\"}", totals.blocked.outgoing),
+                ("{direction=\"incoming\"}", totals.blocked.incoming),
+            ],
+        );
+
+        // buckets count every observation up to their bound, the smaller buckets included
+        let _ = writeln!(
+            text,
+            "# HELP rusty_bit_disk_write_seconds Time pieces took to be written, waiting for the disk task included."
+        );
+        let _ = writeln!(text, "# TYPE rusty_bit_disk_write_seconds histogram");
+        let mut count = 0;
+        for (i, bucket) in self.disk_writes.buckets.iter().enumerate() {
+            count += load(bucket);
+            let bound = DISK_WRITE_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), f64::to_string);
+            let _ = writeln!(
+                text,
+                "rusty_bit_disk_write_seconds_bucket{{le=\"{bound}\"}} {count}"
+            );
+        }
+        let sum = load(&self.disk_writes.sum_micros) as f64 / 1_000_000.0;
+        let _ = writeln!(text, "rusty_bit_disk_write_seconds_sum {sum}");
+        let _ = writeln!(text, "rusty_bit_disk_write_seconds_count {count}");
+        text
+    }
+}
+
+// Serves the rendered metrics on /metrics at the address.
+pub fn spawn(
+    addr: SocketAddr,
+    render: impl Fn() -> String + Send + Sync + 'static,
+) -> anyhow::Result<(SocketAddr, JoinHandle<()>)> {
+    let render = Arc::new(render);
+    let make_service = make_service_fn(move |_| {
+        let render = render.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let render = render.clone();
+                async move {
+                    if request.uri().path() != "/metrics" {
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        return Ok::<_, Infallible>(response);
+                    }
+                    let mut response = Response::new(Body::from(render()));
+                    response.headers_mut().insert(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("text/plain; version=0.0.4"),
+                    );
+                    Ok(response)
+                }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .with_context(|| format!("Binding the metrics server to {addr}"))?
+        .serve(make_service);
+    let local_addr = server.local_addr();
+    let handle = tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("metrics server stopped: {e}");
+        }
+    });
+    Ok((local_addr, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_in_the_text_format() {
+        let metrics = Metrics::default();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let info_hash = [0; 20];
+        metrics.record(&Event::PeerConnected {
+            info_hash,
+            addr,
+            client: "Rusty-Bit 0.1.0".to_string(),
+        });
+        metrics.record(&Event::PeerConnected {
+            info_hash,
+            addr,
+            client: "Rusty-Bit 0.1.0".to_string(),
+        });
+        metrics.record(&Event::PeerDisconnected { info_hash, addr });
+        metrics.record(&Event::PieceFailed {
+            info_hash,
+            piece: 2,
+        });
+        metrics.record_disk_write(Duration::from_millis(3));
+        metrics.record_disk_write(Duration::from_secs(10));
+        metrics.record_disk_writes(3);
+        metrics.record_disk_flushes(2);
+        metrics.record_disk_cache_hit();
+
+        let text = metrics.render(&Totals {
+            downloaded: 1000,
+            uploaded: 10,
+            blocked: BlockedConnections {
+                outgoing: 4,
+                incoming: 0,
+            },
+        });
+        for line in [
+            "# TYPE rusty_bit_downloaded_bytes_total counter",
+            "rusty_bit_downloaded_bytes_total 1000",
+            "rusty_bit_connected_peers 1",
+            "rusty_bit_piece_failures_total 1",
+            "rusty_bit_pieces_verified_total 0",
+            "rusty_bit_disk_write_calls_total 3",
+            "rusty_bit_disk_cache_flushes_total 2",
+            "rusty_bit_disk_cache_hits_total 1",
+            "rusty_bit_blocked_connections_total{direction=\"outgoing\"} 4",
+            "rusty_bit_disk_write_seconds_bucket{le=\"0.0025\"} 0",
+            "rusty_bit_disk_write_seconds_bucket{le=\"0.005\"} 1",
+            "rusty_bit_disk_write_seconds_bucket{le=\"2.5\"} 1",
+            "rusty_bit_disk_write_seconds_bucket{le=\"+Inf\"} 2",
+            "rusty_bit_disk_write_seconds_sum 10.003",
+            "rusty_bit_disk_write_seconds_count 2",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} missing in\n{text}");
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served() {
+        let (addr, _server) = spawn("127.0.0.1:0".parse().unwrap(), || {
+            "rusty_bit_connected_peers 3\n".to_string()
+        })
+        .unwrap();
+        let client = hyper::Client::new();
+        let response = client
+            .get(format!("http://{addr}/metrics").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"rusty_bit_connected_peers 3\n");
+        let response = client
+            .get(format!("http://{addr}/").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,249 @@
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{Arc, Mutex},
+};
+
+// A minimal HTTP tracker on a random local port for tests. It records every announce and answers
+// with a configurable reply, optionally failing a number of announces first.
+
+// What the tracker answers with.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub peers: Vec<SocketAddrV4>,
+    // peers as a compact string (BEP 23) or as a list of dictionaries
+    pub compact: bool,
+    // sent as a compact string under peers6 (BEP 7)
+    pub peers6: Vec<SocketAddrV6>,
+    pub interval: usize,
+    pub min_interval: Option<usize>,
+    pub complete: usize,
+    pub incomplete: usize,
+    pub tracker_id: Option<String>,
+    pub warning: Option<String>,
+    // when set only the failure reason is sent
+    pub failure: Option<String>,
+}
+
+impl Default for Reply {
+    fn default() -> Self {
+        Reply {
+            peers: Vec::new(),
+            compact: true,
+            peers6: Vec::new(),
+            interval: 1800,
+            min_interval: None,
+            complete: 0,
+            incomplete: 0,
+            tracker_id: None,
+            warning: None,
+            failure: None,
+        }
+    }
+}
+
+impl Reply {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = b"d".to_vec();
+        if let Some(failure) = &self.failure {
+            bytes(&mut out, b"failure reason");
+            bytes(&mut out, failure.as_bytes());
+            out.push(b'e');
+            return out;
+        }
+
+        // bencoded dictionaries are sorted by key
+        bytes(&mut out, b"complete");
+        int(&mut out, self.complete);
+        bytes(&mut out, b"incomplete");
+        int(&mut out, self.incomplete);
+        bytes(&mut out, b"interval");
+        int(&mut out, self.interval);
+        if let Some(min_interval) = self.min_interval {
+            bytes(&mut out, b"min interval");
+            int(&mut out, min_interval);
+        }
+        bytes(&mut out, b"peers");
+        if self.compact {
+            let compact: Vec<u8> = self
+                .peers
+                .iter()
+                .flat_map(|peer| {
+                    [peer.ip().octets().as_slice(), &peer.port().to_be_bytes()].concat()
+                })
+                .collect();
+            bytes(&mut out, &compact);
+        } else {
+            out.push(b'l');
+            for peer in &self.peers {
+                out.push(b'd');
+                bytes(&mut out, b"ip");
+                bytes(&mut out, peer.ip().to_string().as_bytes());
+                bytes(&mut out, b"peer id");
+                bytes(&mut out, &[b'x'; 20]);
+                bytes(&mut out, b"port");
+                int(&mut out, peer.port() as usize);
+                out.push(b'e');
+            }
+            out.push(b'e');
+        }
+        if !self.peers6.is_empty() {
+            let compact: Vec<u8> = self
+                .peers6
+                .iter()
+                .flat_map(|peer| {
+                    [peer.ip().octets().as_slice(), &peer.port().to_be_bytes()].concat()
+                })
+                .collect();
+            bytes(&mut out, b"peers6");
+            bytes(&mut out, &compact);
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            bytes(&mut out, b"tracker id");
+            bytes(&mut out, tracker_id.as_bytes());
+        }
+        if let Some(warning) = &self.warning {
+            bytes(&mut out, b"warning message");
+            bytes(&mut out, warning.as_bytes());
+        }
+        out.push(b'e');
+        out
+    }
+}
+
+fn bytes(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend_from_slice(format!("{}:", value.len()).as_bytes());
+    out.extend_from_slice(value);
+}
+
+fn int(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(format!("i{value}e").as_bytes());
+}
+
+// One announce as received.
+#[derive(Clone, Debug)]
+pub struct Announce {
+    // the query string exactly as sent
+    pub query: String,
+    // percent-decoded query parameters
+    pub params: HashMap<String, Vec<u8>>,
+    // request headers by their lowercase name
+    pub headers: HashMap<String, String>,
+    // address the announce came from, announces over the same connection share it
+    pub from: SocketAddr,
+}
+
+#[derive(Default)]
+struct State {
+    reply: Reply,
+    // replies for announces of particular info hashes
+    swarm_replies: HashMap<[u8; 20], Reply>,
+    failures_left: usize,
+    announces: Vec<Announce>,
+}
+
+pub struct MockTracker {
+    pub addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockTracker {
+    pub fn spawn(reply: Reply) -> MockTracker {
+        let state = Arc::new(Mutex::new(State {
+            reply,
+            ..Default::default()
+        }));
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |connection: &AddrStream| {
+            let state = service_state.clone();
+            let from = connection.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(announce(&state, request, from)) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        MockTracker { addr, state }
+    }
+
+    pub fn announce_url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+
+    pub fn set_reply(&self, reply: Reply) {
+        self.state.lock().unwrap().reply = reply;
+    }
+
+    // Announces for info_hash are answered with reply instead.
+    pub fn set_swarm_reply(&self, info_hash: [u8; 20], reply: Reply) {
+        self.state
+            .lock()
+            .unwrap()
+            .swarm_replies
+            .insert(info_hash, reply);
+    }
+
+    // The next n announces are answered with HTTP 500.
+    pub fn fail_next(&self, n: usize) {
+        self.state.lock().unwrap().failures_left = n;
+    }
+
+    pub fn announces(&self) -> Vec<Announce> {
+        self.state.lock().unwrap().announces.clone()
+    }
+}
+
+fn announce(state: &Mutex<State>, request: Request<Body>, from: SocketAddr) -> Response<Body> {
+    let query = request.uri().query().unwrap_or_default().to_string();
+    let params: HashMap<String, Vec<u8>> = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| {
+            (
+                key.to_string(),
+                urlencoding::decode_binary(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect();
+
+    let mut state = state.lock().unwrap();
+    let reply = params
+        .get("info_hash")
+        .and_then(|info_hash| <[u8; 20]>::try_from(info_hash.as_slice()).ok())
+        .and_then(|info_hash| state.swarm_replies.get(&info_hash))
+        .unwrap_or(&state.reply)
+        .encode();
+    let headers = request
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str().to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect();
+    state.announces.push(Announce {
+        query,
+        params,
+        headers,
+        from,
+    });
+    if state.failures_left > 0 {
+        state.failures_left -= 1;
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return response;
+    }
+    Response::new(Body::from(reply))
+}
@@ -0,0 +1,257 @@
+use crate::config::{Config, IpFamily, ProxyKind};
+use crate::download::{
+    dns::Resolver,
+    http_connect,
+    socks5::{self, TargetAddr},
+};
+use anyhow::Context;
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+// All outgoing connections are created here so that they respect the network options in Config.
+
+// How long an unused HTTP connection is kept open, and how often TCP keep-alives go over it.
+const HTTP_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/*
+ * Connect to a peer given as "ip:port" or "host:port". With a proxy configured the proxy
+ * resolves hostnames, otherwise they are resolved locally.
+*/
+pub async fn connect_peer(
+    peer: &str,
+    config: &Config,
+    resolver: &Resolver,
+) -> anyhow::Result<TcpStream> {
+    let target = TargetAddr::parse(peer)?;
+    match config.peer_proxy() {
+        Some(proxy) => {
+            let proxy_addr = resolver.resolve_socket_addr(&proxy.addr).await?;
+            let stream = connect_tcp(proxy_addr, config)
+                .await
+                .with_context(|| format!("Connecting with proxy {}", proxy.addr))?;
+            match proxy.kind {
+                ProxyKind::Socks5 => socks5::connect_with(stream, proxy, &target).await,
+                ProxyKind::Http => http_connect::connect_with(stream, proxy, &target).await,
+            }
+        }
+        None => {
+            let addr = match target {
+                TargetAddr::Ip(addr) => addr,
+                TargetAddr::Domain(..) => resolver.resolve_socket_addr(peer).await?,
+            };
+            connect_tcp(addr, config)
+                .await
+                .with_context(|| format!("Connecting with peer {peer}"))
+        }
+    }
+}
+
+async fn connect_tcp(addr: SocketAddr, config: &Config) -> anyhow::Result<TcpStream> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }
+    .context("Creating socket")?;
+
+    // Binding before connect pins the source address, if the address is gone the bind fails
+    // and we never fall back to the default interface.
+    if let Some(bind_address) = config.bind_address {
+        socket
+            .bind(SocketAddr::new(bind_address, 0))
+            .with_context(|| format!("Binding socket to {bind_address}"))?;
+    }
+
+    Ok(socket.connect(addr).await?)
+}
+
+/*
+ * The client for trackers and web seeds. It keeps connections to a server open between requests,
+ * so it is built once per session and cloned, clones share the connections. Requests have no
+ * overall time limit since a web seed may take long for a piece, announces set their own.
+*/
+pub fn http_client(config: &Config, resolver: &Resolver) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .local_address(config.bind_address)
+        .dns_resolver(Arc::new(resolver.clone()))
+        .user_agent(&config.user_agent)
+        .connect_timeout(config.connect_timeout)
+        .pool_idle_timeout(HTTP_IDLE_TIMEOUT)
+        .tcp_keepalive(HTTP_IDLE_TIMEOUT);
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(
+            reqwest::Proxy::all(proxy.reqwest_url())
+                .with_context(|| format!("Using proxy {}", proxy.addr))?,
+        );
+    }
+    builder.build().context("Building HTTP client")
+}
+
+// Average time it took to connect to peers of each address family in one torrent, so that the
+// family that historically connects faster is dialed first.
+#[derive(Debug, Default)]
+pub struct FamilyStats {
+    // (connections, total connect time)
+    v4: (u32, Duration),
+    v6: (u32, Duration),
+}
+
+impl FamilyStats {
+    pub fn record(&mut self, addr: &SocketAddr, took: Duration) {
+        let family = if addr.is_ipv4() {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        };
+        family.0 += 1;
+        family.1 += took;
+    }
+
+    pub fn preferred(&self) -> IpFamily {
+        let average = |(count, total): (u32, Duration)| (count > 0).then(|| total / count);
+        match (average(self.v4), average(self.v6)) {
+            (Some(v4), Some(v6)) if v6 < v4 => IpFamily::V6,
+            (None, Some(_)) => IpFamily::V6,
+            _ => IpFamily::V4,
+        }
+    }
+
+    // Drops peers of a disabled family and puts the preferred family first, what goes with a
+    // peer stays with it.
+    pub fn order<T>(&self, peers: &mut Vec<(SocketAddr, T)>, only: Option<IpFamily>) {
+        if let Some(only) = only {
+            peers.retain(|(peer, _)| only.matches(&peer.ip()));
+        }
+        let preferred = self.preferred();
+        peers.sort_by_key(|(peer, _)| !preferred.matches(&peer.ip()));
+    }
+}
+
+/*
+ * Our global IPv6 address, if we have one, found by asking the OS which source address it
+ * would use to reach a public IPv6 address (no packet is sent).
+*/
+pub async fn global_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind("[::]:0").await.ok()?;
+    socket.connect("[2001:4860:4860::8888]:80").await.ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    let first_segment = ip.segments()[0];
+    // global unicast is 2000::/3, everything else (loopback, link local fe80::/10,
+    // unique local fc00::/7, ...) is useless to other peers
+    (first_segment & 0xe000) == 0x2000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyConfig;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn connections_originate_from_bind_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            bind_address: Some("127.0.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let stream = connect_peer(
+            &listener.local_addr().unwrap().to_string(),
+            &config,
+            &Resolver::new(&config),
+        )
+        .await
+        .unwrap();
+        let (_, remote_addr) = listener.accept().await.unwrap();
+
+        assert_eq!(
+            stream.local_addr().unwrap().ip(),
+            config.bind_address.unwrap()
+        );
+        assert_eq!(remote_addr.ip(), config.bind_address.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unavailable_bind_address_fails_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // TEST-NET-3 address that is not assigned to any local interface
+        let config = Config {
+            bind_address: Some("203.0.113.1".parse().unwrap()),
+            ..Default::default()
+        };
+
+        assert!(connect_peer(
+            &listener.local_addr().unwrap().to_string(),
+            &config,
+            &Resolver::new(&config)
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn peer_connections_use_the_proxy() {
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = peer.accept().await.unwrap();
+            stream.write_all(b"peer").await.unwrap();
+        });
+
+        let (proxy_addr, server) = socks5::test_server::spawn(None).await;
+        let config = Config {
+            peer_proxy: Some(ProxyConfig::parse(&format!("socks5://{proxy_addr}")).unwrap()),
+            ..Default::default()
+        };
+
+        let mut stream = connect_peer(&peer_addr.to_string(), &config, &Resolver::new(&config))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"peer");
+        drop(stream);
+
+        assert_eq!(server.await.unwrap(), Some(TargetAddr::Ip(peer_addr)));
+    }
+
+    #[test]
+    fn faster_family_is_dialed_first() {
+        let v4: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let mut stats = FamilyStats::default();
+
+        let mut peers = vec![(v6, 1), (v4, 0)];
+        stats.order(&mut peers, None);
+        assert_eq!(peers, vec![(v4, 0), (v6, 1)]);
+
+        stats.record(&v4, Duration::from_millis(300));
+        stats.record(&v6, Duration::from_millis(40));
+        stats.order(&mut peers, None);
+        assert_eq!(peers, vec![(v6, 1), (v4, 0)]);
+
+        stats.order(&mut peers, Some(IpFamily::V4));
+        assert_eq!(peers, vec![(v4, 0)]);
+    }
+
+    #[test]
+    fn only_global_ipv6_is_announced() {
+        assert!(is_global_ipv6(&"2001:db8::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"fe80::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"fd00::2".parse().unwrap()));
+        assert!(!is_global_ipv6(&Ipv6Addr::LOCALHOST));
+    }
+}
@@ -0,0 +1,332 @@
+use crate::error::RustyBitError;
+use anyhow::bail;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    mem,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+/*
+ * The pieces peers are downloading, shared by the peer tasks so that several peers can work on
+ * the same piece. A peer takes a piece off the queue, or joins a piece other peers are on that
+ * still has blocks nobody asked for, and takes its blocks one at a time. A block is asked of one
+ * peer at a time, the blocks a peer did not get are released for the others when it leaves the
+ * piece. The blocks are put together here, the peer that puts in the last one gets the piece to
+ * verify and store. A piece the last of its peers left before it was complete goes back on the
+ * queue and keeps its blocks for whoever takes it next. The blocks are hashed in piece order as
+ * the ones before them arrive, so the hash is ready along with the last block.
+ */
+
+pub struct PartialPieces {
+    block_length: usize,
+    next_worker: AtomicU64,
+    pieces: Mutex<HashMap<usize, PartialPiece>>,
+}
+
+struct PartialPiece {
+    length: usize,
+    data: Vec<u8>,
+    // in piece order, a block a peer sent only part of is followed by one for the rest
+    blocks: Vec<Block>,
+    // peer tasks working on the piece
+    workers: usize,
+    // peers that sent blocks of the piece, they answer for it failing the hash check
+    sources: Vec<SocketAddr>,
+    // the bytes from the start of the piece that went into the hasher
+    hasher: Sha1,
+    hashed: usize,
+}
+
+struct Block {
+    begin: usize,
+    length: usize,
+    state: BlockState,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BlockState {
+    Free,
+    // asked of the peer task with this id
+    Taken(u64),
+    Done,
+}
+
+// What became of a block a peer sent.
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    // nobody waits for the block, e.g. it arrived twice
+    Ignored,
+    Block,
+    // it was the last block, the piece is to be verified by the caller
+    Complete {
+        data: Vec<u8>,
+        hash: [u8; 20],
+        sources: Vec<SocketAddr>,
+    },
+}
+
+impl PartialPiece {
+    fn new(length: usize, block_length: usize) -> PartialPiece {
+        PartialPiece {
+            length,
+            data: vec![0; length],
+            blocks: (0..length)
+                .step_by(block_length)
+                .map(|begin| Block {
+                    begin,
+                    length: block_length.min(length - begin),
+                    state: BlockState::Free,
+                })
+                .collect(),
+            workers: 0,
+            sources: Vec::new(),
+            hasher: Sha1::new(),
+            hashed: 0,
+        }
+    }
+
+    // Hashes the blocks that arrived right after the bytes hashed so far.
+    fn hash_ready(&mut self) {
+        for block in &self.blocks {
+            if block.begin + block.length <= self.hashed {
+                continue;
+            }
+            if block.state != BlockState::Done {
+                return;
+            }
+            self.hasher
+                .update(&self.data[block.begin..block.begin + block.length]);
+            self.hashed = block.begin + block.length;
+        }
+    }
+
+    fn has_free(&self) -> bool {
+        self.blocks
+            .iter()
+            .any(|block| block.state == BlockState::Free)
+    }
+
+    fn release(&mut self, worker: u64) {
+        for block in &mut self.blocks {
+            if block.state == BlockState::Taken(worker) {
+                block.state = BlockState::Free;
+            }
+        }
+    }
+}
+
+impl PartialPieces {
+    pub fn new(block_length: usize) -> PartialPieces {
+        PartialPieces {
+            block_length,
+            next_worker: AtomicU64::new(0),
+            pieces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The id a peer task takes blocks under.
+    pub fn worker(&self) -> u64 {
+        self.next_worker.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // The piece of length bytes was taken off the queue, it keeps the blocks of earlier peers.
+    pub fn start(&self, piece_index: usize, length: usize) {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces
+            .entry(piece_index)
+            .or_insert_with(|| PartialPiece::new(length, self.block_length))
+            .workers += 1;
+    }
+
+    // Joins the first piece that other peers are on, that still has blocks nobody asked for and
+    // that has accepts.
+    pub fn join(&self, has: impl Fn(usize) -> bool) -> Option<usize> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let (&piece_index, piece) = pieces
+            .iter_mut()
+            .filter(|(&piece_index, piece)| {
+                piece.workers > 0 && piece.has_free() && has(piece_index)
+            })
+            .min_by_key(|(&piece_index, _)| piece_index)?;
+        piece.workers += 1;
+        Some(piece_index)
+    }
+
+    // Takes the next block of the piece nobody asked for yet, as (begin, length).
+    pub fn take(&self, piece_index: usize, worker: u64) -> Option<(usize, usize)> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let block = pieces
+            .get_mut(&piece_index)?
+            .blocks
+            .iter_mut()
+            .find(|block| block.state == BlockState::Free)?;
+        block.state = BlockState::Taken(worker);
+        Some((block.begin, block.length))
+    }
+
+    /*
+     * Puts in a block the worker received from source. A block the worker let go of is still
+     * taken as long as nobody else asked for it. A block shorter than asked for leaves the rest
+     * to be taken again, one longer is an error.
+     */
+    pub fn put(
+        &self,
+        piece_index: usize,
+        worker: u64,
+        source: Option<SocketAddr>,
+        begin: usize,
+        block: &[u8],
+    ) -> anyhow::Result<Received> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let Some(piece) = pieces.get_mut(&piece_index) else {
+            return Ok(Received::Ignored);
+        };
+        let Some(position) = piece.blocks.iter().position(|other| {
+            other.begin == begin
+                && (other.state == BlockState::Free || other.state == BlockState::Taken(worker))
+        }) else {
+            return Ok(Received::Ignored);
+        };
+        let requested_length = piece.blocks[position].length;
+        if block.len() > requested_length {
+            bail!(RustyBitError::PeerProtocol(format!(
+                "peer sent a block of {} bytes, requested {requested_length}",
+                block.len()
+            )));
+        }
+        if block.is_empty() {
+            return Ok(Received::Ignored);
+        }
+        piece.data[begin..begin + block.len()].copy_from_slice(block);
+        piece.blocks[position].state = BlockState::Done;
+        if block.len() < requested_length {
+            piece.blocks[position].length = block.len();
+            piece.blocks.insert(
+                position + 1,
+                Block {
+                    begin: begin + block.len(),
+                    length: requested_length - block.len(),
+                    state: BlockState::Free,
+                },
+            );
+        }
+        if let Some(source) = source.filter(|source| !piece.sources.contains(source)) {
+            piece.sources.push(source);
+        }
+        piece.hash_ready();
+        if piece.hashed < piece.length {
+            return Ok(Received::Block);
+        }
+        Ok(Received::Complete {
+            data: mem::take(&mut piece.data),
+            hash: mem::take(&mut piece.hasher).finalize().into(),
+            sources: mem::take(&mut piece.sources),
+        })
+    }
+
+    // The complete piece was stored, or failed and is downloaded again from scratch.
+    pub fn finish(&self, piece_index: usize, stored: bool) {
+        let mut pieces = self.pieces.lock().unwrap();
+        if stored {
+            pieces.remove(&piece_index);
+        } else if let Some(piece) = pieces.get_mut(&piece_index) {
+            *piece = PartialPiece {
+                workers: piece.workers,
+                ..PartialPiece::new(piece.length, self.block_length)
+            };
+        }
+    }
+
+    // The worker is done with the piece, its blocks are released. True if the piece has to go
+    // back on the queue, nobody works on it any more and it is not stored yet.
+    pub fn leave(&self, piece_index: usize, worker: u64) -> bool {
+        // the map may be poisoned if a task panicked while holding it
+        let mut pieces = self.pieces.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(piece) = pieces.get_mut(&piece_index) else {
+            return false;
+        };
+        piece.release(worker);
+        piece.workers -= 1;
+        piece.workers == 0
+    }
+
+    // The piece was stored by other means, e.g. from a web seed.
+    pub fn remove(&self, piece_index: usize) {
+        self.pieces.lock().unwrap().remove(&piece_index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_share_the_blocks_of_a_piece() {
+        let pieces = PartialPieces::new(4);
+        let (a, b) = (pieces.worker(), pieces.worker());
+        let source: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        pieces.start(0, 10);
+        assert_eq!(pieces.take(0, a), Some((0, 4)));
+        assert_eq!(pieces.join(|_| true), Some(0));
+        assert_eq!(pieces.take(0, b), Some((4, 4)));
+        assert_eq!(pieces.take(0, a), Some((8, 2)));
+        assert_eq!(pieces.take(0, b), None);
+
+        // b only sends half of its block, the rest is up for grabs
+        assert_eq!(pieces.put(0, b, None, 4, &[4, 5]).unwrap(), Received::Block);
+        assert!(!pieces.leave(0, b));
+        assert_eq!(pieces.take(0, a), Some((6, 2)));
+        // a block of somebody else, and one longer than asked for
+        assert_eq!(
+            pieces.put(0, b, None, 0, &[0, 1, 2, 3]).unwrap(),
+            Received::Ignored
+        );
+        assert!(pieces.put(0, a, None, 8, &[8, 9, 10]).is_err());
+        pieces.put(0, a, Some(source), 0, &[0, 1, 2, 3]).unwrap();
+        pieces.put(0, a, None, 8, &[8, 9]).unwrap();
+        assert_eq!(
+            pieces.put(0, a, None, 6, &[6, 7]).unwrap(),
+            Received::Complete {
+                data: (0..10).collect(),
+                hash: Sha1::digest((0..10).collect::<Vec<u8>>()).into(),
+                sources: vec![source],
+            }
+        );
+
+        // it failed the hash check and a leaves, the piece goes back on the queue
+        pieces.finish(0, false);
+        assert!(pieces.leave(0, a));
+        pieces.start(0, 10);
+        assert_eq!(pieces.take(0, b), Some((0, 4)));
+        pieces.finish(0, true);
+        assert!(!pieces.leave(0, b));
+    }
+
+    #[test]
+    fn a_piece_left_alone_keeps_its_blocks() {
+        let pieces = PartialPieces::new(4);
+        let a = pieces.worker();
+        pieces.start(3, 8);
+        assert_eq!(pieces.take(3, a), Some((0, 4)));
+        assert_eq!(pieces.take(3, a), Some((4, 4)));
+        pieces.put(3, a, None, 4, &[1; 4]).unwrap();
+        // nobody may join a piece that is back on the queue
+        assert!(pieces.leave(3, a));
+        assert_eq!(pieces.join(|_| true), None);
+
+        let b = pieces.worker();
+        pieces.start(3, 8);
+        assert_eq!(pieces.take(3, b), Some((0, 4)));
+        assert_eq!(pieces.take(3, b), None);
+        assert!(matches!(
+            pieces.put(3, b, None, 0, &[2; 4]).unwrap(),
+            Received::Complete { data, hash, .. }
+                if data == [2, 2, 2, 2, 1, 1, 1, 1] && hash == *Sha1::digest(&data)
+        ));
+    }
+}
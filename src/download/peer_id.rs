@@ -0,0 +1,148 @@
+use rand::Rng;
+
+// Our peer_id in the Azureus convention, "-RB" followed by four version characters and "-",
+// then 12 random bytes, so other clients and trackers can tell which client we are.
+
+const CLIENT_ID: &[u8; 2] = b"RB";
+
+pub fn generate() -> [u8; 20] {
+    let mut peer_id = [0_u8; 20];
+    peer_id[..8].copy_from_slice(&prefix());
+    rand::thread_rng().fill(&mut peer_id[8..]);
+    peer_id
+}
+
+// "-RB0100-" for version 0.1.0, every version component is one base 36 digit.
+fn prefix() -> [u8; 8] {
+    let mut version = env!("CARGO_PKG_VERSION")
+        .split(['.', '-', '+'])
+        .map(|component| component.parse::<u32>().unwrap_or(0).min(35))
+        .map(|component| {
+            char::from_digit(component, 36)
+                .unwrap()
+                .to_ascii_uppercase() as u8
+        });
+
+    let mut prefix = *b"-RB0000-";
+    prefix[1..3].copy_from_slice(CLIENT_ID);
+    for digit in &mut prefix[3..6] {
+        *digit = version.next().unwrap_or(b'0');
+    }
+    prefix
+}
+
+// Clients that use the Azureus convention, by their two character id.
+const KNOWN_CLIENTS: &[(&[u8; 2], &str)] = &[
+    (CLIENT_ID, "Rusty-Bit"),
+    (b"AZ", "Vuze"),
+    (b"BC", "BitComet"),
+    (b"BI", "BiglyBT"),
+    (b"BT", "BitTorrent"),
+    (b"DE", "Deluge"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent"),
+    (b"lt", "libTorrent"),
+    (b"qB", "qBittorrent"),
+    (b"TR", "Transmission"),
+    (b"UT", "\u{b5}Torrent"),
+];
+
+// Clients that use the Mainline convention, by their one letter id.
+const MAINLINE_CLIENTS: &[(u8, &str)] = &[(b'M', "BitTorrent"), (b'Q', "Queen Bee")];
+
+// Names the client of a peer id in the Azureus convention with its version, e.g. "qBittorrent
+// 4.6.2" for "-qB4620-", or in the Mainline convention, e.g. "BitTorrent 7.2.0" for "M7-2-0--".
+// Other peer ids are shown by their printable start, or as unknown.
+pub fn client(peer_id: &[u8; 20]) -> String {
+    if peer_id[0] == b'-'
+        && peer_id[7] == b'-'
+        && peer_id[1..7].iter().all(u8::is_ascii_alphanumeric)
+    {
+        let id: [u8; 2] = [peer_id[1], peer_id[2]];
+        let name = KNOWN_CLIENTS
+            .iter()
+            .find(|(known, _)| **known == id)
+            .map_or_else(
+                || String::from_utf8_lossy(&id).into_owned(),
+                |(_, name)| name.to_string(),
+            );
+        // trailing zeros of the version are left out, down to major.minor
+        let mut version: Vec<String> = peer_id[3..7]
+            .iter()
+            .map(|&digit| {
+                (digit as char)
+                    .to_digit(36)
+                    .map_or_else(|| (digit as char).to_string(), |digit| digit.to_string())
+            })
+            .collect();
+        while version.len() > 2 && version.last().is_some_and(|digit| digit == "0") {
+            version.pop();
+        }
+        return format!("{name} {}", version.join("."));
+    }
+    if let Some(client) = mainline_client(peer_id) {
+        return client;
+    }
+    let printable: String = peer_id
+        .iter()
+        .take_while(|byte| byte.is_ascii_graphic())
+        .map(|&byte| byte as char)
+        .collect();
+    if printable.len() >= 3 {
+        printable
+    } else {
+        "unknown".to_string()
+    }
+}
+
+// A letter, then the version numbers each followed by "-", padded with "-" to 8 bytes.
+fn mainline_client(peer_id: &[u8; 20]) -> Option<String> {
+    let (_, name) = MAINLINE_CLIENTS
+        .iter()
+        .find(|(letter, _)| *letter == peer_id[0])?;
+    let version = std::str::from_utf8(&peer_id[1..8]).ok()?;
+    let numbers: Vec<&str> = version.trim_end_matches('-').split('-').collect();
+    let valid = |number: &&str| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit());
+    numbers
+        .iter()
+        .all(valid)
+        .then(|| format!("{name} {}", numbers.join(".")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn azureus_style_prefix() {
+        let peer_id = generate();
+        assert_eq!(peer_id.len(), 20);
+        assert_eq!(&peer_id[..3], b"-RB");
+        assert_eq!(peer_id[7], b'-');
+        assert!(peer_id[3..7].iter().all(u8::is_ascii_alphanumeric));
+        assert_eq!(&peer_id[..8], &prefix());
+    }
+
+    #[test]
+    fn random_suffix_differs() {
+        let (first, second) = (generate(), generate());
+        assert_eq!(first[..8], second[..8]);
+        assert_ne!(first[8..], second[8..]);
+    }
+
+    #[test]
+    fn clients_are_named() {
+        let with_prefix = |prefix: &[u8]| {
+            let mut peer_id = [0xff; 20];
+            peer_id[..prefix.len()].copy_from_slice(prefix);
+            peer_id
+        };
+        assert_eq!(client(&with_prefix(b"-qB4620-")), "qBittorrent 4.6.2");
+        assert_eq!(client(&with_prefix(b"-TR3000-")), "Transmission 3.0");
+        assert_eq!(client(&with_prefix(b"-XX1A00-")), "XX 1.10");
+        assert_eq!(client(&with_prefix(b"M7-2-0--")), "BitTorrent 7.2.0");
+        assert_eq!(client(&with_prefix(b"M4-10-2-")), "BitTorrent 4.10.2");
+        assert_eq!(client(&with_prefix(b"Mx-2-0--")), "Mx-2-0--");
+        assert_eq!(client(&[0; 20]), "unknown");
+    }
+}
@@ -0,0 +1,276 @@
+use crate::config::Config;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+// Keeps track of the peers of a download and how connecting to them went. A failed connection
+// attempt is retried a few times with a growing delay, after that the peer counts as dead and is
+// not tried again. Addresses that keep sending pieces failing the hash check are banned.
+
+// Pieces of one IP address that may fail the hash check before it is banned
+const MAX_HASH_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerStatus {
+    // known but not connected to yet
+    Pending,
+    Connecting { attempt: u32 },
+    // a failed attempt, the next one starts at retry_at
+    Backoff { attempts: u32, retry_at: Instant },
+    Connected,
+    // the connection was used and then closed
+    Disconnected,
+    // every attempt failed, the last error is kept
+    Dead { reason: String },
+    // sent too many bad pieces
+    Banned,
+    // the IP filter blocks its address
+    Blocked,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // how long a single attempt, handshake included, may take
+    pub connect_timeout: Duration,
+    pub max_attempts: u32,
+    // delay before the second attempt, doubled for every one after it
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> RetryPolicy {
+        RetryPolicy {
+            connect_timeout: config.connect_timeout,
+            max_attempts: config.connect_attempts,
+            backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+pub struct PeerManager {
+    policy: RetryPolicy,
+    peers: Mutex<HashMap<SocketAddr, PeerStatus>>,
+    hash_failures: Mutex<HashMap<IpAddr, u32>>,
+    banned: Mutex<HashSet<IpAddr>>,
+}
+
+impl PeerManager {
+    pub fn new(policy: RetryPolicy) -> PeerManager {
+        PeerManager {
+            policy,
+            peers: Mutex::new(HashMap::new()),
+            hash_failures: Mutex::new(HashMap::new()),
+            banned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Adds a peer, false if it is known already so it is not connected to twice.
+    pub fn add(&self, addr: SocketAddr) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.contains_key(&addr) {
+            return false;
+        }
+        peers.insert(addr, PeerStatus::Pending);
+        true
+    }
+
+    pub fn status(&self, addr: &SocketAddr) -> Option<PeerStatus> {
+        self.peers.lock().unwrap().get(addr).cloned()
+    }
+
+    /*
+     * Runs connect until it succeeds, it fails max_attempts times, or the peer is dead already.
+     * Every attempt is limited to connect_timeout.
+     */
+    pub async fn connect<T, F, Fut>(&self, addr: SocketAddr, connect: F) -> Option<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        for attempt in 1..=self.policy.max_attempts {
+            if self.is_banned(&addr.ip())
+                || matches!(self.status(&addr), Some(PeerStatus::Dead { .. }))
+            {
+                return None;
+            }
+            self.set(addr, PeerStatus::Connecting { attempt });
+            let error = match tokio::time::timeout(self.policy.connect_timeout, connect()).await {
+                Result::Ok(Result::Ok(connection)) => {
+                    self.set(addr, PeerStatus::Connected);
+                    return Some(connection);
+                }
+                Result::Ok(Err(e)) => format!("{e:#}"),
+                Err(_) => format!("timed out after {:?}", self.policy.connect_timeout),
+            };
+            if attempt == self.policy.max_attempts {
+                debug!("Giving up on peer {addr} after {attempt} attempts: {error}");
+                self.set(addr, PeerStatus::Dead { reason: error });
+                return None;
+            }
+            let delay = self.policy.backoff * 2_u32.pow(attempt - 1);
+            debug!("Could not connect to peer {addr}, retrying in {delay:?}: {error}");
+            self.set(
+                addr,
+                PeerStatus::Backoff {
+                    attempts: attempt,
+                    retry_at: Instant::now() + delay,
+                },
+            );
+            tokio::time::sleep(delay).await;
+        }
+        None
+    }
+
+    // The peer is not connected to since the IP filter blocks it.
+    pub fn blocked(&self, addr: SocketAddr) {
+        self.set(addr, PeerStatus::Blocked);
+    }
+
+    pub fn disconnected(&self, addr: SocketAddr) {
+        if !self.is_banned(&addr.ip()) {
+            self.set(addr, PeerStatus::Disconnected);
+        }
+    }
+
+    // Records a piece from addr that failed the hash check, true once its IP address is banned.
+    pub fn hash_failed(&self, addr: SocketAddr) -> bool {
+        let failures = {
+            let mut hash_failures = self.hash_failures.lock().unwrap();
+            let failures = hash_failures.entry(addr.ip()).or_insert(0);
+            *failures += 1;
+            *failures
+        };
+        if failures < MAX_HASH_FAILURES {
+            return false;
+        }
+        if self.banned.lock().unwrap().insert(addr.ip()) {
+            warn!(
+                "Banned {} after {failures} pieces that failed the hash check",
+                addr.ip()
+            );
+        }
+        self.set(addr, PeerStatus::Banned);
+        true
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.lock().unwrap().contains(ip)
+    }
+
+    // "3 connected, 1 dead" style overview of the peers
+    pub fn summary(&self) -> String {
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        for status in self.peers.lock().unwrap().values() {
+            let name = match status {
+                PeerStatus::Pending => "pending",
+                PeerStatus::Connecting { .. } => "connecting",
+                PeerStatus::Backoff { .. } => "waiting to retry",
+                PeerStatus::Connected => "connected",
+                PeerStatus::Disconnected => "disconnected",
+                PeerStatus::Dead { .. } => "dead",
+                PeerStatus::Banned => "banned",
+                PeerStatus::Blocked => "blocked",
+            };
+            match counts.iter_mut().find(|(known, _)| *known == name) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((name, 1)),
+            }
+        }
+        counts.sort();
+        counts
+            .iter()
+            .map(|(name, count)| format!("{count} {name}"))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    fn set(&self, addr: SocketAddr, status: PeerStatus) {
+        self.peers.lock().unwrap().insert(addr, status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn manager() -> PeerManager {
+        PeerManager::new(RetryPolicy {
+            connect_timeout: Duration::from_millis(50),
+            max_attempts: 3,
+            backoff: Duration::from_millis(1),
+        })
+    }
+
+    fn addr() -> SocketAddr {
+        "10.0.0.1:6881".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_are_retried() {
+        let manager = manager();
+        assert!(manager.add(addr()));
+        assert!(!manager.add(addr()));
+
+        let attempts = AtomicU32::new(0);
+        let connection = manager
+            .connect(addr(), || async {
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    bail!("connection refused");
+                }
+                Ok(42)
+            })
+            .await;
+        assert_eq!(connection, Some(42));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(manager.status(&addr()), Some(PeerStatus::Connected));
+    }
+
+    #[tokio::test]
+    async fn peer_is_dead_after_the_last_attempt() {
+        let manager = manager();
+        manager.add(addr());
+        let attempts = AtomicU32::new(0);
+        // hangs until the connect timeout
+        let connection: Option<()> = manager
+            .connect(addr(), || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                std::future::pending().await
+            })
+            .await;
+        assert_eq!(connection, None);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(matches!(
+            manager.status(&addr()),
+            Some(PeerStatus::Dead { reason }) if reason.contains("timed out")
+        ));
+        assert_eq!(manager.summary(), "1 dead");
+
+        // dead peers are not tried again
+        let connection = manager.connect(addr(), || async { Ok(()) }).await;
+        assert_eq!(connection, None);
+    }
+
+    #[tokio::test]
+    async fn repeated_hash_failures_ban_the_address() {
+        let manager = manager();
+        manager.add(addr());
+        assert!(!manager.hash_failed(addr()));
+        assert!(!manager.hash_failed(addr()));
+        assert!(manager.hash_failed(addr()));
+        assert_eq!(manager.status(&addr()), Some(PeerStatus::Banned));
+
+        // the ban covers every port of the address
+        let other_port: SocketAddr = "10.0.0.1:51413".parse().unwrap();
+        assert!(manager.is_banned(&other_port.ip()));
+        manager.add(other_port);
+        let connection = manager.connect(other_port, || async { Ok(()) }).await;
+        assert_eq!(connection, None);
+    }
+}
@@ -1,22 +1,20 @@
+use crate::error::RustyBitError;
 use anyhow::bail;
+use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Instant,
+};
 use tokio_util::{
     bytes::{Buf, BytesMut},
-    codec::{Decoder, Encoder},
+    codec::{Decoder, Encoder, Framed},
 };
 
 #[repr(u8)]
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum PeerMsgTag {
-    // The keep-alive message is a message with zero bytes, specified with the length prefix set to zero.
-    // There is no message ID and no payload.
-    // Peers may close a connection if they receive no messages (keep-alive or any other message) for
-    // a certain period of time, so a keep-alive message must be sent to maintain the connection alive
-    // if no command have been sent for a given amount of time.
-    // This amount of time is generally two minutes.
-    // <len=0000>
-    // KeepAlive,
-
     // The choke message is fixed-length and has no payload.
     // <len=0001><id=0>
     Choke,
@@ -78,13 +76,19 @@ pub enum PeerMsgTag {
     // It is typically used during "End Game".
     // <len=0013><id=8><index><begin><length>
     Cancel,
+
+    // The extended message (BEP 10) carries the messages of extensions, e.g. the metadata
+    // exchange of magnet links. The first byte of the payload tells them apart: 0 is the
+    // extension handshake, every other id is one the handshake of the receiver handed out.
+    // It is only sent to peers that set the extension bit in their handshake.
+    // <len=0002+X><id=20><extended id><payload>
+    Extended = 20,
 }
 
 impl TryFrom<u8> for PeerMsgTag {
     type Error = &'static str;
     fn try_from(value: u8) -> Result<Self, &'static str> {
         match value {
-            // 0 => Ok(PeerMsgType::KeepAlive),
             0 => Ok(PeerMsgTag::Choke),
             1 => Ok(PeerMsgTag::Unchoke),
             2 => Ok(PeerMsgTag::Interested),
@@ -94,229 +98,695 @@ impl TryFrom<u8> for PeerMsgTag {
             6 => Ok(PeerMsgTag::Request),
             7 => Ok(PeerMsgTag::Piece),
             8 => Ok(PeerMsgTag::Cancel),
-            _ => Err("Conversion of u8 to PeerMsgType not possible"),
+            20 => Ok(PeerMsgTag::Extended),
+            _ => Err("Conversion of u8 to PeerMsgTag not possible"),
         }
     }
 }
-//
-// impl TryInto<u8> for PeerMsgTag {
-//     type Error = &'static str;
-//     fn try_into(self) -> Result<u8, &'static str> {
-//         match self {
-//             PeerMsgTag::Choke => Ok(0),
-//             PeerMsgTag::Unchoke => Ok(1),
-//             PeerMsgTag::Interested => Ok(2),
-//             PeerMsgTag::NotInterested => Ok(3),
-//             PeerMsgTag::Have => Ok(4),
-//             PeerMsgTag::Bitfield => Ok(5),
-//             PeerMsgTag::Request => Ok(6),
-//             PeerMsgTag::Piece => Ok(7),
-//             PeerMsgTag::Cancel => Ok(8),
-//             _ => Err("Conversion of PeerMsgType to u8 not possible"),
-//         }
-//     }
-// }
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct PeerMsgType {
-    msg_length: u32,
-    tag: PeerMsgTag,
-    data: Vec<u8>,
+
+// A message of the peer wire protocol, the payloads are laid out under PeerMsgTag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerMessage {
+    // The keep-alive message is a message with zero bytes, specified with the length prefix set to zero.
+    // There is no message ID and no payload.
+    // Peers may close a connection if they receive no messages (keep-alive or any other message) for
+    // a certain period of time, so a keep-alive message must be sent to maintain the connection alive
+    // if no command have been sent for a given amount of time.
+    // This amount of time is generally two minutes.
+    // <len=0000>
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    Bitfield(Bitfield),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
-impl PeerMsgType {
-    pub fn new(tag: PeerMsgTag, data: Vec<u8>) -> PeerMsgType {
-        PeerMsgType {
-            msg_length: (data.len() + 1) as u32,
-            tag,
-            data,
-        }
+impl PeerMessage {
+    // The id the message goes by on the wire, None for a keep-alive.
+    pub fn tag(&self) -> Option<PeerMsgTag> {
+        Some(match self {
+            PeerMessage::KeepAlive => return None,
+            PeerMessage::Choke => PeerMsgTag::Choke,
+            PeerMessage::Unchoke => PeerMsgTag::Unchoke,
+            PeerMessage::Interested => PeerMsgTag::Interested,
+            PeerMessage::NotInterested => PeerMsgTag::NotInterested,
+            PeerMessage::Have { .. } => PeerMsgTag::Have,
+            PeerMessage::Bitfield(_) => PeerMsgTag::Bitfield,
+            PeerMessage::Request { .. } => PeerMsgTag::Request,
+            PeerMessage::Piece { .. } => PeerMsgTag::Piece,
+            PeerMessage::Cancel { .. } => PeerMsgTag::Cancel,
+            PeerMessage::Extended { .. } => PeerMsgTag::Extended,
+        })
     }
 
-    pub fn tag(&self) -> &PeerMsgTag {
-        &self.tag
+    // The message of a frame, from its id and the bytes after it.
+    fn parse(tag: PeerMsgTag, payload: &[u8]) -> anyhow::Result<PeerMessage> {
+        let expected = match tag {
+            PeerMsgTag::Choke
+            | PeerMsgTag::Unchoke
+            | PeerMsgTag::Interested
+            | PeerMsgTag::NotInterested => Some(0),
+            PeerMsgTag::Have => Some(4),
+            PeerMsgTag::Request | PeerMsgTag::Cancel => Some(12),
+            PeerMsgTag::Bitfield => None,
+            PeerMsgTag::Piece if payload.len() < 8 => {
+                bail!(RustyBitError::PeerProtocol(format!(
+                    "piece message of {} bytes is too short",
+                    payload.len()
+                )))
+            }
+            PeerMsgTag::Piece => None,
+            PeerMsgTag::Extended if payload.is_empty() => {
+                bail!(RustyBitError::PeerProtocol(
+                    "extended message without an id".to_string()
+                ))
+            }
+            PeerMsgTag::Extended => None,
+        };
+        if expected.is_some_and(|expected| payload.len() != expected) {
+            bail!(RustyBitError::PeerProtocol(format!(
+                "{tag:?} message of {} bytes",
+                payload.len()
+            )));
+        }
+        let field = |i: usize| {
+            u32::from_be_bytes([
+                payload[4 * i],
+                payload[4 * i + 1],
+                payload[4 * i + 2],
+                payload[4 * i + 3],
+            ])
+        };
+        Ok(match tag {
+            PeerMsgTag::Choke => PeerMessage::Choke,
+            PeerMsgTag::Unchoke => PeerMessage::Unchoke,
+            PeerMsgTag::Interested => PeerMessage::Interested,
+            PeerMsgTag::NotInterested => PeerMessage::NotInterested,
+            PeerMsgTag::Have => PeerMessage::Have { index: field(0) },
+            PeerMsgTag::Bitfield => PeerMessage::Bitfield(Bitfield::from_bytes(payload.to_vec())),
+            PeerMsgTag::Request => PeerMessage::Request {
+                index: field(0),
+                begin: field(1),
+                length: field(2),
+            },
+            PeerMsgTag::Piece => PeerMessage::Piece {
+                index: field(0),
+                begin: field(1),
+                block: payload[8..].to_vec(),
+            },
+            PeerMsgTag::Cancel => PeerMessage::Cancel {
+                index: field(0),
+                begin: field(1),
+                length: field(2),
+            },
+            PeerMsgTag::Extended => PeerMessage::Extended {
+                id: payload[0],
+                payload: payload[1..].to_vec(),
+            },
+        })
     }
 
-    pub fn data(self) -> Vec<u8> {
-        self.data
+    // The bytes after the id.
+    fn write_payload(&self, dst: &mut BytesMut) {
+        match self {
+            PeerMessage::KeepAlive
+            | PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested => {}
+            PeerMessage::Have { index } => dst.extend(index.to_be_bytes()),
+            PeerMessage::Bitfield(bitfield) => dst.extend_from_slice(&bitfield.0),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                dst.extend(index.to_be_bytes());
+                dst.extend(begin.to_be_bytes());
+                dst.extend(length.to_be_bytes());
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.extend(index.to_be_bytes());
+                dst.extend(begin.to_be_bytes());
+                dst.extend_from_slice(block);
+            }
+            PeerMessage::Extended { id, payload } => {
+                dst.extend([*id]);
+                dst.extend_from_slice(payload);
+            }
+        }
     }
 }
 
-pub struct PeerFrameCodec;
+// The pieces a peer has, as it is sent: the high bit of the first byte is piece 0, spare bits at
+// the end are clear.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitfield(Vec<u8>);
 
-const MAX: usize = 1024 * 16; // 16KB for now is the max len that is allowed in the protocol
-
-impl Decoder for PeerFrameCodec {
-    type Item = PeerMsgType;
-    type Error = anyhow::Error;
+impl Bitfield {
+    pub fn from_bytes(bytes: Vec<u8>) -> Bitfield {
+        Bitfield(bytes)
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Self::Item>> {
-        if src.len() < 4 {
-            // Not enough data to read length marker.
-            return Ok(None);
+    pub fn from_pieces(total_pieces: usize, has: impl Fn(usize) -> bool) -> Bitfield {
+        let mut bytes = vec![0_u8; total_pieces.div_ceil(8)];
+        for piece_index in (0..total_pieces).filter(|&piece_index| has(piece_index)) {
+            bytes[piece_index / 8] |= 0x80 >> (piece_index % 8);
         }
+        Bitfield(bytes)
+    }
 
-        // Read length marker.
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
-
-        // Check that the length is not too large to avoid a denial of
-        // service attack where the server runs out of memory.
-        if length > MAX {
-            bail!("Frame of length {} is too large.", length);
+    // Whether the bitfield is one of a torrent of total_pieces pieces.
+    pub fn check(&self, total_pieces: usize) -> anyhow::Result<()> {
+        if self.0.len() != total_pieces.div_ceil(8) {
+            bail!(RustyBitError::PeerProtocol(format!(
+                "peer sent a bitfield of {} bytes for {total_pieces} pieces",
+                self.0.len()
+            )));
         }
+        let spare_bits = self.0.len() * 8 - total_pieces;
+        if self
+            .0
+            .last()
+            .is_some_and(|last| last & ((1 << spare_bits) - 1) != 0)
+        {
+            bail!(RustyBitError::PeerProtocol(
+                "peer sent a bitfield with spare bits set".to_string()
+            ));
+        }
+        Ok(())
+    }
 
-        if src.len() < 4 + length {
-            // The full data has not yet arrived.
+    pub fn has(&self, piece_index: usize) -> bool {
+        self.0
+            .get(piece_index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (piece_index % 8)) != 0)
+    }
+}
+
+/*
+ * Frames are limited by what their message can hold, so that a peer cannot make us buffer
+ * megabytes for a message that is never that long: a piece message holds a block after its tag,
+ * index and begin, and a bitfield one bit per piece of the torrent. When the number of pieces is
+ * not known yet, e.g. while a magnet link is resolved, bitfields of up to MAX_PIECES are accepted.
+ * Messages with an id we do not know are dropped without a word, the peer may use extensions
+ * we do not speak. Nothing a peer sends makes the decoder panic.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct PeerFrameCodec {
+    max_bitfield_frame: usize,
+}
 
-            // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            src.reserve(4 + length - src.len());
+// Largest block requested or served, the size every client uses.
+pub const MAX_BLOCK_LENGTH: usize = 16 * 1024;
+// Lengths of the frames of each message, including their tag.
+const MAX_PIECE_FRAME: usize = 1 + 8 + MAX_BLOCK_LENGTH;
+const REQUEST_FRAME: usize = 1 + 12;
+const HAVE_FRAME: usize = 1 + 4;
+// A piece of the metadata of a torrent is a 16 KiB block after a short dictionary.
+const MAX_EXTENDED_FRAME: usize = 2 + MAX_BLOCK_LENGTH + 1024;
+// Messages of unknown extensions are skipped, but not buffered beyond the longest known one.
+const MAX_UNKNOWN_FRAME: usize = MAX_PIECE_FRAME;
+// Most pieces a torrent is expected to have, a terabyte in 16 KiB pieces.
+const MAX_PIECES: usize = 64 * 1024 * 1024;
+
+impl Default for PeerFrameCodec {
+    fn default() -> PeerFrameCodec {
+        PeerFrameCodec::for_pieces(MAX_PIECES)
+    }
+}
 
-            // We inform the Framed that we need more bytes to form the next
-            // frame.
-            return Ok(None);
+impl PeerFrameCodec {
+    // The codec of a connection for a torrent of total_pieces pieces.
+    pub fn for_pieces(total_pieces: usize) -> PeerFrameCodec {
+        PeerFrameCodec {
+            max_bitfield_frame: 1 + total_pieces.div_ceil(8),
         }
+    }
 
-        if length == 0 {
-            src.advance(4);
-            println!("Keep Alive received");
-            return self.decode(src);
-        };
+    // Longest frame a message with the tag may take.
+    fn max_length(&self, tag: PeerMsgTag) -> usize {
+        match tag {
+            PeerMsgTag::Choke
+            | PeerMsgTag::Unchoke
+            | PeerMsgTag::Interested
+            | PeerMsgTag::NotInterested => 1,
+            PeerMsgTag::Have => HAVE_FRAME,
+            PeerMsgTag::Bitfield => self.max_bitfield_frame,
+            PeerMsgTag::Request | PeerMsgTag::Cancel => REQUEST_FRAME,
+            PeerMsgTag::Piece => MAX_PIECE_FRAME,
+            PeerMsgTag::Extended => MAX_EXTENDED_FRAME,
+        }
+    }
+}
 
-        let msg_type: u8 = src[4];
+impl Decoder for PeerFrameCodec {
+    type Item = PeerMessage;
+    type Error = anyhow::Error;
 
-        if length == 1 {
+    // Never panics, whatever the bytes are: the peer on the other end is a stranger.
+    fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Self::Item>> {
+        loop {
+            let Some(length_bytes) = src.get(..4) else {
+                // Not enough data to read length marker.
+                return Ok(None);
+            };
+
+            // Read length marker.
+            let length = u32::from_be_bytes(length_bytes.try_into()?) as usize;
+
+            if length == 0 {
+                src.advance(4);
+                return Ok(Some(PeerMessage::KeepAlive));
+            };
+
+            // Check that the length is not too large to avoid a denial of
+            // service attack where the server runs out of memory. Until the id is in, only
+            // the longest frame of any message is ruled out.
+            let max_length = match src.get(4) {
+                Some(&id) => {
+                    PeerMsgTag::try_from(id).map_or(MAX_UNKNOWN_FRAME, |tag| self.max_length(tag))
+                }
+                None => MAX_PIECE_FRAME
+                    .max(MAX_EXTENDED_FRAME)
+                    .max(self.max_bitfield_frame),
+            };
+            if length > max_length {
+                bail!(RustyBitError::PeerProtocol(format!(
+                    "Frame of length {length} is too large."
+                )));
+            }
+
+            if src.len() < 4 + length {
+                // The full data has not yet arrived.
+
+                // We reserve more space in the buffer. This is not strictly
+                // necessary, but is a good idea performance-wise.
+                src.reserve(4 + length - src.len());
+
+                // We inform the Framed that we need more bytes to form the next
+                // frame.
+                return Ok(None);
+            }
+
+            let Ok(tag) = PeerMsgTag::try_from(src[4]) else {
+                // messages of extensions we do not speak, e.g. the DHT port, are skipped
+                src.advance(4 + length);
+                continue;
+            };
+            let message = PeerMessage::parse(tag, &src[5..4 + length]);
             src.advance(4 + length);
-            return Ok(Some(PeerMsgType::new(
-                PeerMsgTag::try_from(msg_type).unwrap(),
-                Vec::new(),
-            )));
-        };
+            return message.map(Some);
+        }
+    }
+}
 
-        let data = src[5..4 + length].to_vec();
-        src.advance(4 + length);
-        Ok(Some(PeerMsgType::new(
-            PeerMsgTag::try_from(msg_type).unwrap(),
-            data,
-        )))
-
-        //     match PeerMsgTag::try_from(msg_type).unwrap() {
-        //         PeerMsgTag::KeepAlive => bail!("Msg Type not possible"),
-        //         PeerMsgTag::Choke => return Ok(Some(PeerMsgTag::Choke)),
-        //         PeerMsgTag::Unchoke => return Ok(Some(PeerMsgTag::Unchoke)),
-        //         PeerMsgTag::Interested => return Ok(Some(PeerMsgTag::Interested)),
-        //         PeerMsgTag::NotInterested => return Ok(Some(PeerMsgTag::NotInterested)),
-        //         PeerMsgTag::Have => bail!("Msg Type not possible"),
-        //         PeerMsgTag::Bitfield => bail!("Msg Type not possible"),
-        //         PeerMsgTag::Request => bail!("Msg Type not possible"),
-        //         PeerMsgTag::Piece => bail!("Msg Type not possible"),
-        //         PeerMsgTag::Cancel => bail!("Msg Type not possible"),
-        //     }
-        // }
-        //
-        // // Use advance to modify src such that it no longer contains
-        // // this frame.
-        // if length > 1 {
-        //     // let data = src[5..5 + length].to_vec();
-        //     src.advance(4 + length);
-        //     match PeerMsgTag::try_from(msg_type).unwrap() {
-        //         PeerMsgTag::KeepAlive => bail!("Msg Type not possible"),
-        //         PeerMsgTag::Choke => bail!("Msg Type not possible"),
-        //         PeerMsgTag::Unchoke => bail!("Msg Type not possible"),
-        //         PeerMsgTag::Interested => bail!("Msg Type not possible"),
-        //         PeerMsgTag::NotInterested => bail!("Msg Type not possible"),
-        //         PeerMsgTag::Have => return Ok(Some(PeerMsgTag::Have)),
-        //         PeerMsgTag::Bitfield => return Ok(Some(PeerMsgTag::Bitfield)),
-        //         PeerMsgTag::Request => return Ok(Some(PeerMsgTag::Request)),
-        //         PeerMsgTag::Piece => return Ok(Some(PeerMsgTag::Piece)),
-        //         PeerMsgTag::Cancel => return Ok(Some(PeerMsgTag::Cancel)),
-        //     }
-        // } else {
-        //     bail!("Not possible");
-        // }
+// Decodes a buffer that holds nothing but complete frames, a trailing partial frame is an error.
+pub fn decode_all(bytes: &[u8]) -> anyhow::Result<Vec<PeerMessage>> {
+    let mut src = BytesMut::from(bytes);
+    let mut frames = Vec::new();
+    let mut codec = PeerFrameCodec::default();
+    while let Some(frame) = codec.decode(&mut src)? {
+        frames.push(frame);
+    }
+    if !src.is_empty() {
+        bail!(RustyBitError::PeerProtocol(format!(
+            "{} bytes of an incomplete frame left",
+            src.len()
+        )));
     }
+    Ok(frames)
 }
 
-impl Encoder<PeerMsgType> for PeerFrameCodec {
+impl Encoder<PeerMessage> for PeerFrameCodec {
     type Error = anyhow::Error;
 
-    fn encode(&mut self, item: PeerMsgType, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        // Don't send a string if it is longer than the other end will
-        // accept.
-        // if item.len() > MAX {
-        //     return Err(std::io::Error::new(
-        //         std::io::ErrorKind::InvalidData,
-        //         format!("Frame of length {} is too large.", item.len()),
-        //     ));
-        // }
-
-        // Convert the length into a byte array.
-        // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_be_bytes(item.msg_length);
-        let msg_type_slice = u8::to_be_bytes(item.tag as u8);
-        let data = item.data.as_slice();
-
-        // Reserve space in the buffer.
-        dst.reserve(len_slice.len() + msg_type_slice.len() + data.len());
-
-        // Write the length and string to the buffer.
-        dst.extend(len_slice);
-        dst.extend(msg_type_slice);
-        dst.extend(data);
+    // A keep-alive is a frame of length zero, every other message a frame of its id and payload.
+    fn encode(&mut self, message: PeerMessage, dst: &mut BytesMut) -> anyhow::Result<()> {
+        let Some(tag) = message.tag() else {
+            dst.extend(0_u32.to_be_bytes());
+            return Ok(());
+        };
+        let start = dst.len();
+        dst.extend([0, 0, 0, 0, tag as u8]);
+        message.write_payload(dst);
+        let length = (dst.len() - start - 4) as u32;
+        dst[start..start + 4].copy_from_slice(&length.to_be_bytes());
         Ok(())
     }
 }
 
-pub struct PeerRequestMsgType {
-    // The request message is fixed length, and is used to request a block. The payload contains the following information:
-
-    // integer specifying the zero-based piece index
-    index: u32,
-    // integer specifying the zero-based byte offset within the piece
-    begin: u32,
-    //  integer specifying the requested length.
-    length: u32, // <len=0013><id=6><index><begin><length>
+// Peers usually drop connections that were quiet for two minutes.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/*
+ * Waits for the next frame of the peer, None once it closed the connection. Every keep_alive
+ * spent waiting a keep-alive is sent so the peer does not drop us. A peer that sent no frame for
+ * idle_timeout is an error, the keep-alives of the peer count as frames but are not returned.
+ */
+pub async fn next_frame<T: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<T, PeerFrameCodec>,
+    keep_alive: Duration,
+    idle_timeout: Duration,
+) -> anyhow::Result<Option<PeerMessage>> {
+    let mut deadline = Instant::now() + idle_timeout;
+    loop {
+        let wait = keep_alive.min(deadline.saturating_duration_since(Instant::now()));
+        match tokio::time::timeout(wait, framed.next()).await {
+            Ok(Some(Ok(PeerMessage::KeepAlive))) => deadline = Instant::now() + idle_timeout,
+            Ok(frame) => return frame.transpose(),
+            Err(_) if Instant::now() >= deadline => {
+                bail!(RustyBitError::PeerProtocol(format!(
+                    "peer sent nothing for {idle_timeout:?}"
+                )))
+            }
+            Err(_) => framed.send(PeerMessage::KeepAlive).await?,
+        }
+    }
 }
 
-impl PeerRequestMsgType {
-    pub fn new(index: u32, begin: u32, length: u32) -> PeerRequestMsgType {
-        PeerRequestMsgType {
-            index,
-            begin,
-            length,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn encode(frames: Vec<PeerMessage>) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        for frame in frames {
+            PeerFrameCodec::default().encode(frame, &mut dst).unwrap();
         }
+        dst.to_vec()
     }
-    pub fn to_bytes(&self) -> [u8; 12] {
-        let mut bytes = Vec::with_capacity(12);
-        bytes.extend(self.index.to_be_bytes());
-        bytes.extend(self.begin.to_be_bytes());
-        bytes.extend(self.length.to_be_bytes());
-        bytes.try_into().unwrap()
+
+    fn frame() -> impl Strategy<Value = PeerMessage> {
+        prop_oneof![
+            Just(PeerMessage::KeepAlive),
+            Just(PeerMessage::Choke),
+            Just(PeerMessage::Unchoke),
+            Just(PeerMessage::Interested),
+            Just(PeerMessage::NotInterested),
+            any::<u32>().prop_map(|index| PeerMessage::Have { index }),
+            prop::collection::vec(any::<u8>(), 0..64)
+                .prop_map(|bytes| PeerMessage::Bitfield(Bitfield::from_bytes(bytes))),
+            any::<(u32, u32, u32)>().prop_map(|(index, begin, length)| PeerMessage::Request {
+                index,
+                begin,
+                length
+            }),
+            (
+                any::<(u32, u32)>(),
+                prop::collection::vec(any::<u8>(), 0..=MAX_BLOCK_LENGTH)
+            )
+                .prop_map(|((index, begin), block)| PeerMessage::Piece {
+                    index,
+                    begin,
+                    block
+                }),
+            any::<(u32, u32, u32)>().prop_map(|(index, begin, length)| PeerMessage::Cancel {
+                index,
+                begin,
+                length
+            }),
+            (any::<u8>(), prop::collection::vec(any::<u8>(), 0..64))
+                .prop_map(|(id, payload)| PeerMessage::Extended { id, payload }),
+        ]
     }
-}
 
-pub struct PeerPieceMsgType {
-    _index: u32,
-    _begin: u32,
-    block: Vec<u8>,
-}
+    // the messages as the specification lays them out
+    #[test]
+    fn messages_match_the_spec() {
+        let messages = [
+            (PeerMessage::KeepAlive, vec![0, 0, 0, 0]),
+            (PeerMessage::Choke, vec![0, 0, 0, 1, 0]),
+            (PeerMessage::Unchoke, vec![0, 0, 0, 1, 1]),
+            (PeerMessage::Interested, vec![0, 0, 0, 1, 2]),
+            (PeerMessage::NotInterested, vec![0, 0, 0, 1, 3]),
+            (
+                PeerMessage::Have { index: 0x0102 },
+                vec![0, 0, 0, 5, 4, 0, 0, 1, 2],
+            ),
+            (
+                PeerMessage::Bitfield(Bitfield::from_pieces(10, |piece| piece != 1)),
+                vec![0, 0, 0, 3, 5, 0b1011_1111, 0b1100_0000],
+            ),
+            (
+                PeerMessage::Request {
+                    index: 1,
+                    begin: 0x4000,
+                    length: 0x4000,
+                },
+                vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (
+                PeerMessage::Piece {
+                    index: 1,
+                    begin: 2,
+                    block: vec![0xaa, 0xbb],
+                },
+                vec![0, 0, 0, 11, 7, 0, 0, 0, 1, 0, 0, 0, 2, 0xaa, 0xbb],
+            ),
+            (
+                PeerMessage::Cancel {
+                    index: 1,
+                    begin: 0x4000,
+                    length: 0x4000,
+                },
+                vec![0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (
+                PeerMessage::Extended {
+                    id: 0,
+                    payload: b"de".to_vec(),
+                },
+                vec![0, 0, 0, 4, 20, 0, b'd', b'e'],
+            ),
+        ];
+        for (message, bytes) in messages {
+            assert_eq!(encode(vec![message.clone()]), bytes);
+            assert_eq!(decode_all(&bytes).unwrap(), vec![message]);
+        }
+    }
 
-impl PeerPieceMsgType {
-    pub fn from_bytes(data: Vec<u8>) -> PeerPieceMsgType {
-        assert!(data.len() >= 8);
-        let index = u32::from_be_bytes(data[0..4].try_into().unwrap());
-        let begin = u32::from_be_bytes(data[4..8].try_into().unwrap());
-        let block = data[8..].to_vec();
-        PeerPieceMsgType {
-            _index: index,
-            _begin: begin,
-            block,
+    #[test]
+    fn bitfields_fit_their_torrent() {
+        let bitfield = Bitfield::from_pieces(10, |piece| piece % 3 == 0);
+        assert!(bitfield.check(10).is_ok());
+        assert!(bitfield.has(9) && !bitfield.has(8) && !bitfield.has(100));
+        assert!(bitfield.check(17).is_err());
+        // spare bits set
+        assert!(Bitfield::from_bytes(vec![0xff, 0xff]).check(10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff, 0xc0]).check(10).is_ok());
+    }
+
+    // regression inputs that used to panic the decoder
+    #[test]
+    fn malformed_frames_are_errors() {
+        // an unknown message id is skipped, a port message of the DHT extension too
+        assert_eq!(
+            decode_all(&[0, 0, 0, 1, 99, 0, 0, 0, 3, 9, 0x1a, 0xe1, 0, 0, 0, 1, 2]).unwrap(),
+            vec![PeerMessage::Interested]
+        );
+        // a frame cut short
+        let error = decode_all(&[0, 0, 0, 2, 0x7f]).unwrap_err();
+        assert!(matches!(
+            RustyBitError::find(&error),
+            Some(RustyBitError::PeerProtocol(_))
+        ));
+        // piece message without index and begin, and messages of the wrong length
+        assert!(decode_all(&[0, 0, 0, 3, 7, 0, 1]).is_err());
+        assert!(decode_all(&[0, 0, 0, 3, 4, 0, 1]).is_err());
+        assert!(decode_all(&[0, 0, 0, 2, 1, 0]).is_err());
+        assert!(decode_all(&[0, 0, 0, 1, 20]).is_err());
+        // length larger than allowed
+        assert!(decode_all(&[0, 1, 0, 0, 7]).is_err());
+    }
+
+    #[test]
+    fn frames_are_limited_by_their_message() {
+        let frame = |tag: PeerMsgTag, payload: usize| {
+            let mut bytes = ((payload + 1) as u32).to_be_bytes().to_vec();
+            bytes.push(tag as u8);
+            bytes.extend(vec![0; payload]);
+            BytesMut::from(&bytes[..])
+        };
+        let mut codec = PeerFrameCodec::for_pieces(100_000);
+        // a piece message with a whole 16 KiB block
+        let piece = codec
+            .decode(&mut frame(PeerMsgTag::Piece, 8 + MAX_BLOCK_LENGTH))
+            .unwrap();
+        assert!(
+            matches!(piece, Some(PeerMessage::Piece { block, .. }) if block.len() == MAX_BLOCK_LENGTH)
+        );
+        assert!(codec
+            .decode(&mut frame(PeerMsgTag::Piece, 9 + MAX_BLOCK_LENGTH))
+            .is_err());
+        // the bitfield of 100000 pieces is longer than any block
+        assert!(codec
+            .decode(&mut frame(PeerMsgTag::Bitfield, 12_500))
+            .unwrap()
+            .is_some());
+        assert!(codec
+            .decode(&mut frame(PeerMsgTag::Bitfield, 12_501))
+            .is_err());
+        assert!(PeerFrameCodec::for_pieces(8)
+            .decode(&mut frame(PeerMsgTag::Bitfield, 2))
+            .is_err());
+        // fixed length messages
+        assert!(codec.decode(&mut frame(PeerMsgTag::Have, 5)).is_err());
+        assert!(codec.decode(&mut frame(PeerMsgTag::Choke, 1)).is_err());
+        // an unknown message is only skipped while it is short
+        assert!(codec
+            .decode(&mut BytesMut::from(&[0x7f, 0xff, 0xff, 0xff, 99][..]))
+            .is_err());
+        // too long for any message before the tag is in
+        assert!(codec
+            .decode(&mut BytesMut::from(&20_000_u32.to_be_bytes()[..]))
+            .is_err());
+    }
+
+    #[test]
+    fn keep_alives_are_decoded_one_by_one() {
+        let mut bytes = vec![0_u8; 4 * 100_000];
+        bytes.extend([0, 0, 0, 1, 1]);
+        let frames = decode_all(&bytes).unwrap();
+        assert_eq!(frames.len(), 100_001);
+        assert_eq!(frames[0], PeerMessage::KeepAlive);
+        assert_eq!(frames[100_000], PeerMessage::Unchoke);
+    }
+
+    #[tokio::test]
+    async fn waiting_sends_keep_alives_until_idle_timeout() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let mut ours = Framed::new(ours, PeerFrameCodec::default());
+        let mut theirs = Framed::new(theirs, PeerFrameCodec::default());
+
+        theirs.send(PeerMessage::Unchoke).await.unwrap();
+        let frame = next_frame(
+            &mut ours,
+            Duration::from_millis(20),
+            Duration::from_millis(500),
+        )
+        .await
+        .unwrap();
+        assert_eq!(frame, Some(PeerMessage::Unchoke));
+
+        let idle = next_frame(
+            &mut ours,
+            Duration::from_millis(20),
+            Duration::from_millis(110),
+        );
+        assert!(idle.await.is_err());
+        // the peer received keep-alives while we waited
+        let mut sent = BytesMut::new();
+        let raw = theirs.get_mut();
+        while let Ok(Ok(read)) = tokio::time::timeout(
+            Duration::from_millis(10),
+            tokio::io::AsyncReadExt::read_buf(raw, &mut sent),
+        )
+        .await
+        {
+            if read == 0 {
+                break;
+            }
         }
+        assert!(sent.len() >= 4 * 3);
+        assert!(sent.iter().all(|byte| *byte == 0));
+
+        // keep-alives of the peer hold the idle timeout off
+        let waiting = tokio::spawn(async move {
+            let frame = next_frame(
+                &mut ours,
+                Duration::from_millis(20),
+                Duration::from_millis(110),
+            )
+            .await;
+            (ours, frame)
+        });
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            theirs.send(PeerMessage::KeepAlive).await.unwrap();
+        }
+        theirs.send(PeerMessage::Choke).await.unwrap();
+        let (mut ours, frame) = waiting.await.unwrap();
+        assert_eq!(frame.unwrap(), Some(PeerMessage::Choke));
+
+        drop(theirs);
+        let closed = next_frame(
+            &mut ours,
+            Duration::from_millis(20),
+            Duration::from_millis(500),
+        );
+        assert_eq!(closed.await.unwrap(), None);
     }
 
-    pub fn block(self) -> Vec<u8> {
-        self.block
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn decoding_arbitrary_bytes_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = decode_all(&bytes);
+        }
+
+        #[test]
+        fn encoded_frames_decode_back(frames in prop::collection::vec(frame(), 0..4)) {
+            let bytes = encode(frames.clone());
+            prop_assert_eq!(decode_all(&bytes).unwrap(), frames);
+        }
+
+        #[test]
+        fn segmentation_does_not_change_frames(
+            frames in prop::collection::vec(frame(), 1..4),
+            cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..8),
+        ) {
+            let bytes = encode(frames);
+            let mut cuts: Vec<usize> = cuts.iter().map(|cut| cut.index(bytes.len())).collect();
+            cuts.push(bytes.len());
+            cuts.sort_unstable();
+
+            // feed the bytes in segments like TCP would, decoding after each one
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            let mut start = 0;
+            for cut in cuts {
+                src.extend_from_slice(&bytes[start..cut]);
+                start = cut;
+                while let Some(frame) = PeerFrameCodec::default().decode(&mut src).unwrap() {
+                    decoded.push(frame);
+                }
+            }
+            prop_assert_eq!(decoded, decode_all(&bytes).unwrap());
+        }
     }
 }
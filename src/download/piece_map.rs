@@ -0,0 +1,268 @@
+use std::ops::Range;
+
+// Byte range math between the pieces and the files of a torrent. Offsets are positions in the
+// torrent's data, which is all files concatenated in torrent order.
+//
+// Only the files are stored, where a piece lies on disk is worked out when it is asked for. A
+// torrent with hundreds of thousands of pieces costs no more memory than its file list.
+#[derive(Debug, Clone)]
+pub struct PieceMap {
+    piece_length: usize,
+    // on-disk path and length of every file, in torrent order
+    files: Vec<(String, usize)>,
+    // torrent offset of the first byte of every file, followed by the total length
+    file_starts: Vec<usize>,
+}
+
+// A part of a piece that is stored in one file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PieceLocationMap {
+    pub file_index: u32,
+    // offset in the file
+    pub offset: u64,
+    pub length: u32,
+}
+
+impl PieceMap {
+    pub fn new(piece_length: usize, files: Vec<(String, usize)>) -> PieceMap {
+        let mut file_starts = Vec::with_capacity(files.len() + 1);
+        file_starts.push(0);
+        for (_, length) in &files {
+            file_starts.push(file_starts[file_starts.len() - 1] + length);
+        }
+        PieceMap {
+            piece_length,
+            files,
+            file_starts,
+        }
+    }
+
+    pub fn total_length(&self) -> usize {
+        self.file_starts[self.files.len()]
+    }
+
+    pub fn total_pieces(&self) -> usize {
+        self.total_length().div_ceil(self.piece_length)
+    }
+
+    pub fn piece_length(&self) -> usize {
+        self.piece_length
+    }
+
+    pub fn files(&self) -> &[(String, usize)] {
+        &self.files
+    }
+
+    pub fn file(&self, file_index: usize) -> Option<&(String, usize)> {
+        self.files.get(file_index)
+    }
+
+    pub fn path(&self, file_index: u32) -> &str {
+        &self.files[file_index as usize].0
+    }
+
+    // Torrent offset of the first byte of a file.
+    pub fn file_start(&self, file_index: usize) -> usize {
+        self.file_starts[file_index]
+    }
+
+    // The parts of the files that hold a piece, in piece order. Empty files are skipped.
+    pub fn locations(&self, piece_index: usize) -> Vec<PieceLocationMap> {
+        self.range_locations(self.piece_range(piece_index))
+    }
+
+    // The parts of the files that hold length bytes at begin within a piece, in piece order. What
+    // lies past the end of the piece is left out.
+    pub fn block_locations(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Vec<PieceLocationMap> {
+        let piece_range = self.piece_range(piece_index);
+        let start = (piece_range.start + begin).min(piece_range.end);
+        self.range_locations(start..(start + length).min(piece_range.end))
+    }
+
+    // The parts of the files that hold the torrent offsets in range, which has to be shorter
+    // than 4 GiB.
+    pub fn range_locations(&self, range: Range<usize>) -> Vec<PieceLocationMap> {
+        let mut locations = Vec::new();
+        if range.is_empty() {
+            return locations;
+        }
+        // the first file that ends after the start of the piece
+        let mut file_index = self.file_starts[1..].partition_point(|&start| start <= range.start);
+        let mut offset = range.start;
+        while offset < range.end {
+            let file_end = self.file_starts[file_index + 1];
+            if file_end > offset {
+                let end = file_end.min(range.end);
+                locations.push(PieceLocationMap {
+                    file_index: file_index as u32,
+                    offset: (offset - self.file_starts[file_index]) as u64,
+                    length: (end - offset) as u32,
+                });
+                offset = end;
+            }
+            file_index += 1;
+        }
+        locations
+    }
+
+    pub fn piece_of(&self, offset: usize) -> usize {
+        offset / self.piece_length
+    }
+
+    // Torrent offsets covered by a piece.
+    pub fn piece_range(&self, piece_index: usize) -> Range<usize> {
+        let start = piece_index * self.piece_length;
+        start..(start + self.piece_length).min(self.total_length())
+    }
+
+    // Pieces that hold any byte of the torrent offsets in range.
+    pub fn pieces_for_range(&self, range: Range<usize>) -> Range<usize> {
+        if range.is_empty() {
+            return 0..0;
+        }
+        self.piece_of(range.start)..self.piece_of(range.end - 1) + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn piece_map() -> PieceMap {
+        PieceMap::new(10, vec![("a".to_string(), 25), ("b".to_string(), 12)])
+    }
+
+    #[test]
+    fn ranges_map_to_pieces() {
+        let piece_map = piece_map();
+        assert_eq!(piece_map.total_length(), 37);
+        assert_eq!(piece_map.file_start(1), 25);
+        assert_eq!(piece_map.pieces_for_range(0..10), 0..1);
+        assert_eq!(piece_map.pieces_for_range(9..11), 0..2);
+        // file b starts in the middle of piece 2
+        assert_eq!(piece_map.pieces_for_range(25..37), 2..4);
+        assert_eq!(piece_map.piece_range(3), 30..37);
+        assert_eq!(piece_map.pieces_for_range(5..5), 0..0);
+    }
+
+    fn location(file_index: u32, offset: u64, length: u32) -> PieceLocationMap {
+        PieceLocationMap {
+            file_index,
+            offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn pieces_map_to_file_locations() {
+        let piece_map = PieceMap::new(
+            10,
+            vec![
+                ("a".to_string(), 25),
+                ("empty".to_string(), 0),
+                ("b".to_string(), 3),
+                ("c".to_string(), 9),
+                ("empty too".to_string(), 0),
+            ],
+        );
+        assert_eq!(piece_map.total_pieces(), 4);
+        assert_eq!(piece_map.locations(0), vec![location(0, 0, 10)]);
+        assert_eq!(piece_map.locations(1), vec![location(0, 10, 10)]);
+        // a piece spanning three files, with an empty one in between
+        assert_eq!(
+            piece_map.locations(2),
+            vec![location(0, 20, 5), location(2, 0, 3), location(3, 0, 2)]
+        );
+        // the last piece is short
+        assert_eq!(piece_map.locations(3), vec![location(3, 2, 7)]);
+        assert_eq!(piece_map.locations(4), vec![]);
+        // a block within a piece, and one running past its end
+        assert_eq!(
+            piece_map.block_locations(2, 4, 3),
+            vec![location(0, 24, 1), location(2, 0, 2)]
+        );
+        assert_eq!(piece_map.block_locations(3, 5, 8), vec![location(3, 7, 2)]);
+        assert_eq!(piece_map.path(3), "c");
+    }
+
+    // A location of the old mapping, which was made for every piece up front and kept in a
+    // HashMap<usize, Vec<OldLocation>> for the whole download.
+    #[allow(dead_code)]
+    struct OldLocation {
+        path: String,
+        offset: usize,
+        length: usize,
+    }
+
+    // Heap and inline bytes of the old mapping of the pieces.
+    fn old_mapping_size(piece_map: &PieceMap) -> usize {
+        (0..piece_map.total_pieces())
+            .map(|piece_index| {
+                let locations = piece_map.locations(piece_index);
+                let paths: usize = locations
+                    .iter()
+                    .map(|location| piece_map.path(location.file_index).len())
+                    .sum();
+                size_of::<(usize, Vec<OldLocation>)>()
+                    + locations.len() * size_of::<OldLocation>()
+                    + paths
+            })
+            .sum()
+    }
+
+    // Bytes of the file list the pieces are mapped from.
+    fn compact_size(piece_map: &PieceMap) -> usize {
+        let paths: usize = piece_map.files.iter().map(|(path, _)| path.len()).sum();
+        size_of::<PieceMap>()
+            + piece_map.files.len() * size_of::<(String, usize)>()
+            + paths
+            + piece_map.file_starts.len() * size_of::<usize>()
+    }
+
+    // Times mapping every piece of a synthetic torrent with 200k pieces over 20k files, and
+    // compares the memory it takes with the old mapping.
+    // cargo test --release large_torrent_is_mapped_quickly -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn large_torrent_is_mapped_quickly() {
+        let piece_length = 16 * 1024;
+        let files: Vec<(String, usize)> = (0..20_000)
+            .map(|i| {
+                (
+                    format!("Downloaded/pack/image{i:05}.png"),
+                    10 * piece_length + i % 7,
+                )
+            })
+            .collect();
+        let total_length: usize = files.iter().map(|(_, length)| length).sum();
+
+        let started = std::time::Instant::now();
+        let piece_map = PieceMap::new(piece_length, files);
+        let built = started.elapsed();
+        let mapped: usize = (0..piece_map.total_pieces())
+            .map(|piece_index| {
+                piece_map
+                    .locations(piece_index)
+                    .iter()
+                    .map(|location| location.length as usize)
+                    .sum::<usize>()
+            })
+            .sum();
+        let located = started.elapsed() - built;
+        let (compact, old) = (compact_size(&piece_map), old_mapping_size(&piece_map));
+        println!(
+            "{} pieces: built in {built:?}, all located in {located:?}, {compact} bytes against {old} bytes for the old mapping",
+            piece_map.total_pieces(),
+        );
+
+        assert!(piece_map.total_pieces() > 200_000);
+        assert_eq!(mapped, total_length);
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+        assert!(compact * 10 < old);
+    }
+}
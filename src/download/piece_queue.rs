@@ -0,0 +1,265 @@
+use crate::config::FilePriority;
+use crate::download::have::Have;
+use std::{ops::Range, sync::Arc};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/*
+ * The pieces nobody downloads yet, in the order they are handed out. One task owns the queue, the
+ * peer tasks, the web seeds and the streaming server ask it for pieces and give back the ones they
+ * did not finish over a channel, so no task waits on a lock another one holds. Peers take pieces
+ * from the back: in piece order in sequential mode, by file priority otherwise. In sequential mode
+ * a peer gets no piece more than lookahead pieces past the first missing one, unless the pieces
+ * there that are still queued are ones it does not have. Web seeds take pieces from the front.
+ */
+
+#[derive(Clone)]
+pub struct PieceQueue {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+enum Request {
+    // the pieces the peer has, by index
+    Claim {
+        has: Vec<bool>,
+        reply: oneshot::Sender<Option<usize>>,
+    },
+    ClaimFront {
+        reply: oneshot::Sender<Option<usize>>,
+    },
+    Requeue(usize),
+    Prioritize(Range<usize>),
+    #[cfg(test)]
+    Queued {
+        reply: oneshot::Sender<Vec<usize>>,
+    },
+}
+
+struct Owner {
+    queue: Vec<usize>,
+    have: Arc<Have>,
+    total_pieces: usize,
+    piece_priorities: Vec<FilePriority>,
+    sequential: bool,
+    lookahead: usize,
+}
+
+impl PieceQueue {
+    // Starts the task owning the queue, it ends once every handle is dropped. The queue follows
+    // the sequential mode as it is switched.
+    pub fn spawn(
+        queue: Vec<usize>,
+        have: Arc<Have>,
+        piece_priorities: Vec<FilePriority>,
+        sequential: watch::Receiver<bool>,
+        lookahead: usize,
+    ) -> PieceQueue {
+        let mut owner = Owner {
+            queue,
+            total_pieces: piece_priorities.len(),
+            have,
+            piece_priorities,
+            sequential: *sequential.borrow(),
+            lookahead: lookahead.max(1),
+        };
+        owner.order();
+        let (requests, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(owner.run(receiver, sequential));
+        PieceQueue { requests }
+    }
+
+    // Takes the next piece for a peer with these pieces, None if there is none it can have.
+    pub async fn claim(&self, has: &[bool]) -> Option<usize> {
+        let (reply, answer) = oneshot::channel();
+        let has = has.to_vec();
+        self.requests.send(Request::Claim { has, reply }).ok()?;
+        answer.await.ok().flatten()
+    }
+
+    // Takes the piece at the front of the queue.
+    pub async fn claim_front(&self) -> Option<usize> {
+        let (reply, answer) = oneshot::channel();
+        self.requests.send(Request::ClaimFront { reply }).ok()?;
+        answer.await.ok().flatten()
+    }
+
+    // Puts back a piece that was claimed but not stored, it is handed out next.
+    pub fn requeue(&self, piece_index: usize) {
+        // nothing is handed out any more once the queue is gone
+        let _ = self.requests.send(Request::Requeue(piece_index));
+    }
+
+    // Moves those of the pieces that are queued to the back, the lowest one last.
+    pub fn prioritize(&self, pieces: Range<usize>) {
+        let _ = self.requests.send(Request::Prioritize(pieces));
+    }
+
+    // The pieces in the queue, the next one last.
+    #[cfg(test)]
+    pub async fn queued(&self) -> Vec<usize> {
+        let (reply, answer) = oneshot::channel();
+        if self.requests.send(Request::Queued { reply }).is_err() {
+            return Vec::new();
+        }
+        answer.await.unwrap_or_default()
+    }
+}
+
+impl Owner {
+    async fn run(
+        mut self,
+        mut requests: mpsc::UnboundedReceiver<Request>,
+        mut sequential: watch::Receiver<bool>,
+    ) {
+        let mut switchable = true;
+        loop {
+            // a switch is taken into account before the requests that came after it
+            tokio::select! {
+                biased;
+                changed = sequential.changed(), if switchable => match changed {
+                    Ok(()) => {
+                        self.sequential = *sequential.borrow();
+                        self.order();
+                    }
+                    Err(_) => switchable = false,
+                },
+                request = requests.recv() => match request {
+                    Some(request) => self.answer(request),
+                    None => return,
+                },
+            }
+        }
+    }
+
+    fn answer(&mut self, request: Request) {
+        // a requester that is gone does not get its piece, it stays queued
+        match request {
+            Request::Claim { has, reply } => {
+                if let Some(position) = self.claimable(&has) {
+                    let piece_index = self.queue.remove(position);
+                    if let Err(Some(piece_index)) = reply.send(Some(piece_index)) {
+                        self.queue.insert(position, piece_index);
+                    }
+                } else {
+                    let _ = reply.send(None);
+                }
+            }
+            Request::ClaimFront { reply } => {
+                if self.queue.is_empty() {
+                    let _ = reply.send(None);
+                } else {
+                    let piece_index = self.queue.remove(0);
+                    if let Err(Some(piece_index)) = reply.send(Some(piece_index)) {
+                        self.queue.insert(0, piece_index);
+                    }
+                }
+            }
+            Request::Requeue(piece_index) => self.queue.push(piece_index),
+            Request::Prioritize(pieces) => {
+                let (mut bumped, rest): (Vec<usize>, Vec<usize>) = self
+                    .queue
+                    .iter()
+                    .partition(|piece_index| pieces.contains(piece_index));
+                // the lowest piece is needed first, so it goes last
+                bumped.sort_unstable_by(|a, b| b.cmp(a));
+                self.queue = rest;
+                self.queue.extend(bumped);
+            }
+            #[cfg(test)]
+            Request::Queued { reply } => {
+                let _ = reply.send(self.queue.clone());
+            }
+        }
+    }
+
+    // Where in the queue the piece for a peer with these pieces is.
+    fn claimable(&self, has: &[bool]) -> Option<usize> {
+        let has = |piece_index: usize| has.get(piece_index).copied().unwrap_or(false);
+        let position = self.queue.iter().rposition(|&piece_index| has(piece_index));
+        let first_missing =
+            (0..self.total_pieces).find(|&piece_index| self.have.needs(piece_index));
+        let (true, Some(first_missing)) = (self.sequential, first_missing) else {
+            return position;
+        };
+        let window_end = first_missing + self.lookahead;
+        let in_window = self
+            .queue
+            .iter()
+            .rposition(|&piece_index| piece_index < window_end && has(piece_index));
+        if in_window.is_some()
+            || !self
+                .queue
+                .iter()
+                .any(|&piece_index| piece_index < window_end)
+        {
+            return in_window;
+        }
+        position
+    }
+
+    fn order(&mut self) {
+        order_queue(&mut self.queue, self.sequential, &self.piece_priorities);
+    }
+}
+
+// Orders the queue so that peers, which take pieces from its back, get the most wanted ones first:
+// in piece order in sequential mode, by file priority otherwise.
+fn order_queue(queue: &mut [usize], sequential: bool, piece_priorities: &[FilePriority]) {
+    if sequential {
+        queue.sort_unstable_by(|a, b| b.cmp(a));
+    } else {
+        queue.sort_unstable_by_key(|&piece_index| (piece_priorities[piece_index], piece_index));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sequential_mode_stays_close_to_the_first_missing_piece() {
+        let all_pieces: Vec<usize> = (0..6).collect();
+        let have = Arc::new(Have::new(6, &all_pieces));
+        let (sequential, sequential_receiver) = watch::channel(true);
+        let queue = PieceQueue::spawn(
+            all_pieces.clone(),
+            have.clone(),
+            vec![FilePriority::Normal; 6],
+            sequential_receiver,
+            2,
+        );
+        let every_piece = [true; 6];
+
+        assert_eq!(queue.claim(&every_piece).await, Some(0));
+        assert_eq!(queue.claim(&every_piece).await, Some(1));
+        // both pieces of the window are being downloaded
+        assert_eq!(queue.claim(&every_piece).await, None);
+        have.set(0);
+        assert_eq!(queue.claim(&every_piece).await, Some(2));
+
+        // piece 1 failed, a peer that does not have it goes on beyond the window
+        queue.requeue(1);
+        let later_pieces = [false, false, false, false, true, true];
+        assert_eq!(queue.claim(&later_pieces).await, Some(4));
+
+        // out of sequential mode the queue is ordered by piece again, from the back
+        sequential.send_replace(false);
+        assert_eq!(queue.queued().await, vec![1, 3, 5]);
+        assert_eq!(queue.claim(&every_piece).await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn web_seeds_and_streams_reorder_the_queue() {
+        let have = Arc::new(Have::new(5, &[0, 1, 2, 3, 4]));
+        let queue = PieceQueue::spawn(
+            vec![0, 1, 2, 3, 4],
+            have,
+            vec![FilePriority::Normal; 5],
+            watch::channel(false).1,
+            1,
+        );
+        assert_eq!(queue.claim_front().await, Some(0));
+        queue.prioritize(3..7);
+        assert_eq!(queue.queued().await, vec![1, 2, 4, 3]);
+        assert_eq!(queue.claim(&[true; 5]).await, Some(3));
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+/*
+ * How many block requests a connection keeps outstanding. A peer only sends back to back if our
+ * next request reaches it before it is through with the ones it has, so the requests in flight
+ * have to cover what the peer sends within a round trip: its rate times the round trip time. The
+ * round trip is the shortest one seen between a request and its block, the others also waited in
+ * the queue of the peer. The depth is twice what covers it, so a peer that could go faster gets
+ * more requests and keeps growing its queue until its rate stops growing with it, while a slow
+ * peer, or one held back by the rate limits, only gets as many as it answers soon and leaves the
+ * rest of the piece to other peers.
+ */
+
+// Requests a new connection starts with, and the fewest a connection is left with.
+const INITIAL_DEPTH: usize = 4;
+const MIN_DEPTH: usize = 2;
+
+// The rate of the peer is measured over about this long.
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Pipeline {
+    block_length: usize,
+    max_depth: usize,
+    depth: usize,
+    // shortest time between a request and its block so far
+    min_rtt: Option<Duration>,
+    // bytes received since the measurement of the rate started
+    window_start: Instant,
+    window_bytes: usize,
+    // bytes per second, averaged over the measurements
+    rate: Option<f64>,
+}
+
+impl Pipeline {
+    pub fn new(block_length: usize, max_depth: usize, now: Instant) -> Pipeline {
+        let max_depth = max_depth.max(1);
+        Pipeline {
+            block_length,
+            max_depth,
+            depth: INITIAL_DEPTH.min(max_depth),
+            min_rtt: None,
+            window_start: now,
+            window_bytes: 0,
+            rate: None,
+        }
+    }
+
+    // Requests to keep outstanding.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // A block of length bytes that was requested at requested_at arrived.
+    pub fn received(&mut self, length: usize, requested_at: Instant, now: Instant) {
+        let rtt = now.duration_since(requested_at);
+        let min_rtt = self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt));
+        self.min_rtt = Some(min_rtt);
+        self.window_bytes += length;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < RATE_INTERVAL {
+            return;
+        }
+        let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        let rate = self.rate.map_or(rate, |average| (average + rate) / 2.0);
+        self.rate = Some(rate);
+        self.window_start = now;
+        self.window_bytes = 0;
+
+        let covered = rate * min_rtt.as_secs_f64() / self.block_length as f64;
+        self.depth =
+            ((2.0 * covered).ceil() as usize).clamp(MIN_DEPTH.min(self.max_depth), self.max_depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth_follows_rate_and_round_trip() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut pipeline = Pipeline::new(1000, 100, start);
+        assert_eq!(pipeline.depth(), 4);
+
+        // 40 blocks a second with a round trip of 100ms, 4 blocks cover it
+        for block in 1..=40 {
+            let now = start + ms(25 * block);
+            pipeline.received(1000, now - ms(100), now);
+        }
+        assert_eq!(pipeline.depth(), 8);
+
+        // twice the rate, the longer round trips waited in the queue of the peer
+        for block in 1..=80 {
+            let now = start + ms(1000) + ms(12 * block + block / 2);
+            pipeline.received(1000, now - ms(400), now);
+        }
+        // the rate is averaged with the one before
+        assert_eq!(pipeline.depth(), 12);
+
+        // a slow peer is left with few requests, and the limit holds
+        for block in 1..=4 {
+            let now = start + ms(2000) + ms(1000 * block);
+            pipeline.received(1000, now - ms(100), now);
+        }
+        assert_eq!(pipeline.depth(), 2);
+        let mut capped = Pipeline::new(1000, 3, start);
+        capped.received(1_000_000, start, start + ms(1000));
+        assert_eq!(capped.depth(), 3);
+    }
+}
@@ -0,0 +1,646 @@
+use anyhow::{bail, Context};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, task::JoinHandle, time::timeout};
+use tracing::{info, warn};
+
+// Port mapping makes us reachable from outside a home router so that other peers can connect
+// to our listener. We first try NAT-PMP (RFC 6886, also answered by most PCP capable routers)
+// against the default gateway and fall back to UPnP IGD (SSDP discovery + SOAP calls).
+// Every failure here is only a warning, the download must never depend on it. The sockets and
+// HTTP requests towards the gateway go out from the bind address, and every request gives up
+// after the timeout of the caller.
+
+const NAT_PMP_PORT: u16 = 5351;
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const LEASE_DURATION: u32 = 3600;
+const MAPPING_DESCRIPTION: &str = "Rusty-Bit";
+const UPNP_SERVICE_TYPES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Tcp,
+}
+
+impl Protocol {
+    fn nat_pmp_opcode(&self) -> u8 {
+        match self {
+            Protocol::Tcp => 2,
+        }
+    }
+
+    fn as_upnp_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Mapper {
+    NatPmp {
+        gateway: Ipv4Addr,
+        local: SocketAddr,
+    },
+    Upnp {
+        client: reqwest::Client,
+        control_url: String,
+        service_type: String,
+        local_ip: Ipv4Addr,
+    },
+}
+
+impl Mapper {
+    async fn add(&self, protocol: Protocol, port: u16) -> anyhow::Result<Duration> {
+        match self {
+            Mapper::NatPmp { gateway, local } => {
+                nat_pmp_map(*gateway, *local, protocol, port, LEASE_DURATION).await
+            }
+            Mapper::Upnp {
+                client,
+                control_url,
+                service_type,
+                local_ip,
+            } => {
+                upnp_add_mapping(client, control_url, service_type, *local_ip, protocol, port)
+                    .await?;
+                Ok(Duration::from_secs(LEASE_DURATION as u64))
+            }
+        }
+    }
+
+    async fn remove(&self, protocol: Protocol, port: u16) -> anyhow::Result<()> {
+        match self {
+            // A lifetime of zero deletes the mapping
+            Mapper::NatPmp { gateway, local } => nat_pmp_map(*gateway, *local, protocol, port, 0)
+                .await
+                .map(|_| ()),
+            Mapper::Upnp {
+                client,
+                control_url,
+                service_type,
+                ..
+            } => upnp_delete_mapping(client, control_url, service_type, protocol, port).await,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Mapper::NatPmp { .. } => "NAT-PMP",
+            Mapper::Upnp { .. } => "UPnP",
+        }
+    }
+}
+
+// An active mapping, kept alive by a background task until remove is called.
+pub struct PortMapping {
+    mapper: Mapper,
+    protocol: Protocol,
+    port: u16,
+    external_ip: Option<IpAddr>,
+    renew_task: JoinHandle<()>,
+}
+
+impl PortMapping {
+    // The external address the router reported, announces can pass it to the tracker.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    pub async fn remove(self) {
+        self.renew_task.abort();
+        match self.mapper.remove(self.protocol, self.port).await {
+            Ok(()) => info!(
+                "Removed {} port mapping for port {}",
+                self.mapper.name(),
+                self.port
+            ),
+            Err(e) => warn!(
+                "could not remove {} port mapping for port {}: {e:#}",
+                self.mapper.name(),
+                self.port
+            ),
+        }
+    }
+}
+
+/*
+ * Try to forward the given local port on the router, talking to it from bind_address. Returns
+ * None (after logging a warning) when neither NAT-PMP nor UPnP worked.
+*/
+pub async fn map_port(
+    protocol: Protocol,
+    port: u16,
+    bind_address: Option<IpAddr>,
+    http_timeout: Duration,
+) -> Option<PortMapping> {
+    let local = match local_addr(bind_address) {
+        Ok(local) => local,
+        Err(e) => {
+            warn!("{e:#}");
+            return None;
+        }
+    };
+    match default_gateway() {
+        Some(gateway) => {
+            let mapper = Mapper::NatPmp { gateway, local };
+            if let Some(mapping) = try_mapper(mapper, protocol, port).await {
+                return Some(mapping);
+            }
+        }
+        None => warn!("could not find the default gateway, skipping NAT-PMP"),
+    }
+
+    match discover_upnp(local, http_timeout).await {
+        Ok(mapper) => try_mapper(mapper, protocol, port).await,
+        Err(e) => {
+            warn!("UPnP discovery failed: {e:#}");
+            None
+        }
+    }
+}
+
+async fn try_mapper(mapper: Mapper, protocol: Protocol, port: u16) -> Option<PortMapping> {
+    let lease = match mapper.add(protocol, port).await {
+        Ok(lease) => lease,
+        Err(e) => {
+            warn!("{} port mapping failed: {e:#}", mapper.name());
+            return None;
+        }
+    };
+
+    let external_ip = match &mapper {
+        Mapper::NatPmp { gateway, local } => nat_pmp_external_ip(*gateway, *local)
+            .await
+            .ok()
+            .map(IpAddr::V4),
+        Mapper::Upnp {
+            client,
+            control_url,
+            service_type,
+            ..
+        } => upnp_external_ip(client, control_url, service_type)
+            .await
+            .ok()
+            .map(IpAddr::V4),
+    };
+
+    info!(
+        "Mapped {} port {port} using {}, external address: {}",
+        protocol.as_upnp_str(),
+        mapper.name(),
+        external_ip.map_or("unknown".to_string(), |ip| ip.to_string())
+    );
+
+    let renew_mapper = mapper.clone();
+    let renew_task = tokio::spawn(async move {
+        let mut lease = lease;
+        loop {
+            // renew halfway through the lease as recommended by RFC 6886
+            tokio::time::sleep(lease / 2).await;
+            match renew_mapper.add(protocol, port).await {
+                Ok(new_lease) => lease = new_lease,
+                Err(e) => {
+                    warn!(
+                        "renewing {} port mapping failed: {e:#}",
+                        renew_mapper.name()
+                    );
+                    lease = Duration::from_secs(60 * 2);
+                }
+            }
+        }
+    });
+
+    Some(PortMapping {
+        mapper,
+        protocol,
+        port,
+        external_ip,
+        renew_task,
+    })
+}
+
+// Where the sockets towards the gateway are bound, NAT-PMP and UPnP only speak IPv4.
+fn local_addr(bind_address: Option<IpAddr>) -> anyhow::Result<SocketAddr> {
+    match bind_address {
+        None => Ok((Ipv4Addr::UNSPECIFIED, 0).into()),
+        Some(IpAddr::V4(ip)) => Ok((ip, 0).into()),
+        Some(IpAddr::V6(ip)) => bail!("port mapping only works over IPv4, not from {ip}"),
+    }
+}
+
+// Reads the default route from the kernel routing table.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    let route_table = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_linux_route_table(&route_table)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+// Format of /proc/net/route:
+// Iface Destination Gateway Flags RefCnt Use Metric Mask MTU Window IRTT
+// eth0  00000000    0101A8C0 0003 ...
+// Addresses are hex encoded in host (little endian) byte order.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_linux_route_table(route_table: &str) -> Option<Ipv4Addr> {
+    route_table.lines().skip(1).find_map(|line| {
+        let mut columns = line.split_whitespace();
+        let destination = columns.nth(1)?;
+        let gateway = columns.next()?;
+        if destination != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        if gateway == 0 {
+            return None;
+        }
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+// Sends a request to the gateway, retrying with a doubling timeout (250ms, 500ms, ...) as RFC 6886 suggests.
+async fn nat_pmp_request(
+    gateway: Ipv4Addr,
+    local: SocketAddr,
+    request: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(local)
+        .await
+        .context("Binding NAT-PMP socket")?;
+    socket
+        .connect(SocketAddr::new(IpAddr::V4(gateway), NAT_PMP_PORT))
+        .await
+        .context("Connecting NAT-PMP socket")?;
+
+    let mut wait = Duration::from_millis(250);
+    let mut buf = [0u8; 16];
+    for _ in 0..4 {
+        socket
+            .send(request)
+            .await
+            .context("Sending NAT-PMP request")?;
+        if let Ok(received) = timeout(wait, socket.recv(&mut buf)).await {
+            let len = received.context("Receiving NAT-PMP response")?;
+            return Ok(buf[..len].to_vec());
+        }
+        wait *= 2;
+    }
+    bail!("gateway {gateway} did not answer NAT-PMP requests")
+}
+
+async fn nat_pmp_external_ip(gateway: Ipv4Addr, local: SocketAddr) -> anyhow::Result<Ipv4Addr> {
+    let response = nat_pmp_request(gateway, local, &[0, 0]).await?;
+    parse_nat_pmp_external_ip(&response)
+}
+
+// Response: <version=0><opcode=128><result code u16><epoch u32><external ip 4 bytes>
+fn parse_nat_pmp_external_ip(response: &[u8]) -> anyhow::Result<Ipv4Addr> {
+    if response.len() < 12 || response[1] != 128 {
+        bail!("malformed NAT-PMP external address response");
+    }
+    check_nat_pmp_result(u16::from_be_bytes([response[2], response[3]]))?;
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+async fn nat_pmp_map(
+    gateway: Ipv4Addr,
+    local: SocketAddr,
+    protocol: Protocol,
+    port: u16,
+    lifetime: u32,
+) -> anyhow::Result<Duration> {
+    // <version=0><opcode><reserved u16><internal port><suggested external port><lifetime u32>
+    let mut request = vec![0, protocol.nat_pmp_opcode(), 0, 0];
+    request.extend(port.to_be_bytes());
+    request.extend(port.to_be_bytes());
+    request.extend(lifetime.to_be_bytes());
+
+    let response = nat_pmp_request(gateway, local, &request).await?;
+    parse_nat_pmp_mapping(&response, protocol)
+}
+
+// Response: <version=0><opcode=128+op><result code u16><epoch u32><internal port><external port><lifetime u32>
+fn parse_nat_pmp_mapping(response: &[u8], protocol: Protocol) -> anyhow::Result<Duration> {
+    if response.len() < 16 || response[1] != 128 + protocol.nat_pmp_opcode() {
+        bail!("malformed NAT-PMP mapping response");
+    }
+    check_nat_pmp_result(u16::from_be_bytes([response[2], response[3]]))?;
+    let lifetime = u32::from_be_bytes(response[12..16].try_into().expect("will be len 4"));
+    Ok(Duration::from_secs(lifetime as u64))
+}
+
+fn check_nat_pmp_result(result_code: u16) -> anyhow::Result<()> {
+    match result_code {
+        0 => Ok(()),
+        1 => bail!("NAT-PMP version not supported by the gateway"),
+        2 => bail!("NAT-PMP disabled or not authorized on the gateway"),
+        3 => bail!("gateway network failure"),
+        4 => bail!("gateway is out of resources"),
+        5 => bail!("unsupported NAT-PMP opcode"),
+        code => bail!("NAT-PMP error code {code}"),
+    }
+}
+
+// Finds an internet gateway device on the LAN and the control URL of its WAN connection service.
+async fn discover_upnp(local: SocketAddr, http_timeout: Duration) -> anyhow::Result<Mapper> {
+    let socket = UdpSocket::bind(local)
+        .await
+        .context("Binding SSDP socket")?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+        HOST: {SSDP_ADDR}\r\n\
+        MAN: \"ssdp:discover\"\r\n\
+        MX: 2\r\n\
+        ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n"
+    );
+    socket
+        .send_to(search.as_bytes(), SSDP_ADDR)
+        .await
+        .context("Sending SSDP search")?;
+
+    let mut buf = [0u8; 2048];
+    let (len, gateway_addr) = timeout(Duration::from_secs(3), socket.recv_from(&mut buf))
+        .await
+        .context("No UPnP gateway answered the SSDP search")?
+        .context("Receiving SSDP response")?;
+    let location = parse_ssdp_location(&String::from_utf8_lossy(&buf[..len]))
+        .context("SSDP response without LOCATION header")?;
+
+    let client = upnp_client(local, http_timeout)?;
+    let description = client
+        .get(&location)
+        .send()
+        .await
+        .with_context(|| format!("Fetching gateway description {location}"))?
+        .text()
+        .await
+        .context("Reading gateway description")?;
+
+    let (service_type, control_path) = parse_upnp_control_url(&description)
+        .context("Gateway does not offer a WAN connection service")?;
+    let control_url = reqwest::Url::parse(&location)
+        .and_then(|base| base.join(&control_path))
+        .context("Building UPnP control URL")?
+        .to_string();
+
+    Ok(Mapper::Upnp {
+        client,
+        control_url,
+        service_type,
+        local_ip: local_ip_towards(local, gateway_addr).await?,
+    })
+}
+
+// The gateway is on the LAN, its requests never go through a proxy.
+fn upnp_client(local: SocketAddr, http_timeout: Duration) -> anyhow::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .local_address(local.ip())
+        .timeout(http_timeout)
+        .no_proxy()
+        .build()
+        .context("Building UPnP client")
+}
+
+// The address the gateway sees us as, which has to be given as NewInternalClient.
+async fn local_ip_towards(local: SocketAddr, gateway_addr: SocketAddr) -> anyhow::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(gateway_addr).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => bail!("UPnP port mapping is only supported over IPv4"),
+    }
+}
+
+fn parse_ssdp_location(response: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("location") {
+            Some(value.trim().to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn xml_tag_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some(xml[start..end].trim())
+}
+
+// Returns (service type, control URL) of the first WAN connection service in the device description.
+fn parse_upnp_control_url(description: &str) -> Option<(String, String)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = xml_tag_value(service, "serviceType")?;
+        if !UPNP_SERVICE_TYPES.contains(&service_type) {
+            return None;
+        }
+        let control_url = xml_tag_value(service, "controlURL")?;
+        Some((service_type.to_string(), control_url.to_string()))
+    })
+}
+
+async fn soap_call(
+    client: &reqwest::Client,
+    control_url: &str,
+    service_type: &str,
+    action: &str,
+    arguments: &str,
+) -> anyhow::Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body><u:{action} xmlns:u=\"{service_type}\">{arguments}</u:{action}></s:Body>\
+        </s:Envelope>"
+    );
+    let response = client
+        .post(control_url)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{service_type}#{action}\""))
+        .body(body)
+        .send()
+        .await
+        .with_context(|| format!("Calling UPnP action {action}"))?;
+
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        let reason = xml_tag_value(&text, "errorDescription").unwrap_or("unknown error");
+        bail!("UPnP action {action} failed with {status}: {reason}");
+    }
+    Ok(text)
+}
+
+async fn upnp_add_mapping(
+    client: &reqwest::Client,
+    control_url: &str,
+    service_type: &str,
+    local_ip: Ipv4Addr,
+    protocol: Protocol,
+    port: u16,
+) -> anyhow::Result<()> {
+    let arguments = format!(
+        "<NewRemoteHost></NewRemoteHost>\
+        <NewExternalPort>{port}</NewExternalPort>\
+        <NewProtocol>{}</NewProtocol>\
+        <NewInternalPort>{port}</NewInternalPort>\
+        <NewInternalClient>{local_ip}</NewInternalClient>\
+        <NewEnabled>1</NewEnabled>\
+        <NewPortMappingDescription>{MAPPING_DESCRIPTION}</NewPortMappingDescription>\
+        <NewLeaseDuration>{LEASE_DURATION}</NewLeaseDuration>",
+        protocol.as_upnp_str()
+    );
+    soap_call(
+        client,
+        control_url,
+        service_type,
+        "AddPortMapping",
+        &arguments,
+    )
+    .await
+    .map(|_| ())
+}
+
+async fn upnp_delete_mapping(
+    client: &reqwest::Client,
+    control_url: &str,
+    service_type: &str,
+    protocol: Protocol,
+    port: u16,
+) -> anyhow::Result<()> {
+    let arguments = format!(
+        "<NewRemoteHost></NewRemoteHost>\
+        <NewExternalPort>{port}</NewExternalPort>\
+        <NewProtocol>{}</NewProtocol>",
+        protocol.as_upnp_str()
+    );
+    soap_call(
+        client,
+        control_url,
+        service_type,
+        "DeletePortMapping",
+        &arguments,
+    )
+    .await
+    .map(|_| ())
+}
+
+async fn upnp_external_ip(
+    client: &reqwest::Client,
+    control_url: &str,
+    service_type: &str,
+) -> anyhow::Result<Ipv4Addr> {
+    let response = soap_call(
+        client,
+        control_url,
+        service_type,
+        "GetExternalIPAddress",
+        "",
+    )
+    .await?;
+    xml_tag_value(&response, "NewExternalIPAddress")
+        .context("GetExternalIPAddress response without address")?
+        .parse()
+        .context("Parsing external IP address")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gateway_from_route_table() {
+        let route_table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+            eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+            eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        assert_eq!(
+            parse_linux_route_table(route_table),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+    }
+
+    #[test]
+    fn nat_pmp_responses() {
+        let external = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+        assert_eq!(
+            parse_nat_pmp_external_ip(&external).unwrap(),
+            Ipv4Addr::new(203, 0, 113, 7)
+        );
+
+        let mapping = [
+            0, 130, 0, 0, 0, 0, 0, 1, 0x1b, 0x39, 0x1b, 0x39, 0, 0, 0x0e, 0x10,
+        ];
+        assert_eq!(
+            parse_nat_pmp_mapping(&mapping, Protocol::Tcp).unwrap(),
+            Duration::from_secs(3600)
+        );
+
+        let refused = [0, 130, 0, 2, 0, 0, 0, 1, 0x1b, 0x39, 0, 0, 0, 0, 0, 0];
+        assert!(parse_nat_pmp_mapping(&refused, Protocol::Tcp).is_err());
+    }
+
+    #[tokio::test]
+    async fn silent_gateway_times_out() {
+        // accepts the connection but never answers
+        let gateway = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let control_url = format!("http://{}/ctl/IPConn", gateway.local_addr().unwrap());
+        let _connection = tokio::spawn(async move {
+            let connection = gateway.accept().await;
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            drop(connection);
+        });
+
+        let local = local_addr(Some(Ipv4Addr::LOCALHOST.into())).unwrap();
+        let client = upnp_client(local, Duration::from_millis(200)).unwrap();
+        let started = std::time::Instant::now();
+        let service_type = UPNP_SERVICE_TYPES[0];
+        assert!(upnp_external_ip(&client, &control_url, service_type)
+            .await
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // the gateway is only talked to over IPv4
+        assert!(local_addr(Some("::1".parse().unwrap())).is_err());
+    }
+
+    #[test]
+    fn upnp_description_parsing() {
+        let ssdp = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+            Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            parse_ssdp_location(ssdp).as_deref(),
+            Some("http://192.168.1.1:5000/rootDesc.xml")
+        );
+
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        assert_eq!(
+            parse_upnp_control_url(description),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1".to_string(),
+                "/ctl/IPConn".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::download::control::{Progress, TorrentState};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/*
+ * Turns the progress of a torrent, sampled every second or so, into a status line: how much of
+ * the wanted pieces is on disk, the download and upload speed over the last few seconds and since
+ * the start, the connected peers and the time left at the current download speed. While the data
+ * on disk is checked the line shows how far the check is instead.
+ */
+
+// The current speed is averaged over this long, a single second jumps around too much.
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+
+// Width of the bar in characters.
+const BAR_WIDTH: usize = 30;
+
+// Bytes per second over the last few seconds and since the start, and the time left at the
+// current download speed if there is something left and it is coming in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Speeds {
+    pub download: f64,
+    pub upload: f64,
+    pub download_average: f64,
+    pub upload_average: f64,
+    pub eta: Option<Duration>,
+}
+
+pub struct ProgressMeter {
+    started: Instant,
+    // time and bytes downloaded and uploaded of the samples in the speed window, oldest first
+    samples: VecDeque<(Instant, u64, u64)>,
+}
+
+impl ProgressMeter {
+    pub fn new(now: Instant) -> ProgressMeter {
+        ProgressMeter {
+            started: now,
+            samples: VecDeque::new(),
+        }
+    }
+
+    // Records a sample and returns the speeds up to it.
+    pub fn sample(&mut self, progress: &Progress, now: Instant) -> Speeds {
+        // the transferred bytes start over when the torrent is started again
+        if self
+            .samples
+            .back()
+            .is_some_and(|&(_, downloaded, uploaded)| {
+                progress.downloaded < downloaded || progress.uploaded < uploaded
+            })
+        {
+            self.samples.clear();
+            self.started = now;
+        }
+        self.samples
+            .push_back((now, progress.downloaded, progress.uploaded));
+        while self
+            .samples
+            .front()
+            .is_some_and(|&(time, _, _)| now.duration_since(time) > SPEED_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+
+        let (download, upload) = match self.samples.front() {
+            Some(&(time, downloaded, uploaded)) => (
+                rate(progress.downloaded - downloaded, now.duration_since(time)),
+                rate(progress.uploaded - uploaded, now.duration_since(time)),
+            ),
+            None => (0.0, 0.0),
+        };
+        let elapsed = now.duration_since(self.started);
+        // the last piece is usually shorter, close enough
+        let left = (progress.pieces_missing * progress.piece_length) as f64;
+        Speeds {
+            download,
+            upload,
+            download_average: rate(progress.downloaded, elapsed),
+            upload_average: rate(progress.uploaded, elapsed),
+            eta: (progress.pieces_missing > 0 && download >= 1.0)
+                .then(|| Duration::from_secs_f64(left / download)),
+        }
+    }
+
+    // Records a sample and renders the status line for it.
+    pub fn update(&mut self, progress: &Progress, now: Instant) -> String {
+        let speeds = self.sample(progress, now);
+        let done = fraction_done(progress);
+        let filled = (done * BAR_WIDTH as f64) as usize;
+        let bar = format!("{}{}", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled));
+        // the torrent of a magnet link has no pieces until its metadata is fetched
+        if progress.state == TorrentState::Starting && progress.total_pieces == 0 {
+            return format!("{:<11} [{bar}] the metadata from peers", "Fetching");
+        }
+        if progress.state == TorrentState::Starting {
+            return format!(
+                "{:<11} [{bar}] {:5.1}% {}/{} pieces checked",
+                state_label(&progress.state),
+                done * 100.0,
+                progress.pieces_checked,
+                progress.total_pieces,
+            );
+        }
+        let mut line = format!(
+            "{:<11} [{bar}] {:5.1}% {}/{} pieces  down {} (avg {})  up {} (avg {})  {} peer{}",
+            state_label(&progress.state),
+            done * 100.0,
+            progress.pieces_done,
+            // pieces of skipped files that are not on disk are left out
+            progress.pieces_done + progress.pieces_missing,
+            format_rate(speeds.download),
+            format_rate(speeds.download_average),
+            format_rate(speeds.upload),
+            format_rate(speeds.upload_average),
+            progress.peers,
+            if progress.peers == 1 { "" } else { "s" },
+        );
+        if progress.pieces_missing > 0 {
+            line += "  ETA ";
+            line += &speeds.eta.map_or("-".to_string(), format_duration);
+        }
+        line
+    }
+}
+
+// How far the check of the data on disk is while starting, how much of the wanted pieces is on
+// disk afterwards, from 0 to 1.
+pub fn fraction_done(progress: &Progress) -> f64 {
+    let (done, total) = if progress.state == TorrentState::Starting {
+        (progress.pieces_checked, progress.total_pieces)
+    } else {
+        (
+            progress.pieces_done,
+            progress.pieces_done + progress.pieces_missing,
+        )
+    };
+    if total == 0 {
+        // nothing is checked before the metadata of a magnet link is there
+        if progress.state == TorrentState::Starting {
+            0.0
+        } else {
+            1.0
+        }
+    } else {
+        done as f64 / total as f64
+    }
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        bytes as f64 / elapsed.as_secs_f64()
+    }
+}
+
+pub fn state_label(state: &TorrentState) -> &'static str {
+    match state {
+        TorrentState::Starting => "Checking",
+        TorrentState::Downloading => "Downloading",
+        TorrentState::Seeding => "Seeding",
+        TorrentState::Finished => "Finished",
+        TorrentState::Paused => "Paused",
+        TorrentState::Stopped => "Stopped",
+        TorrentState::Failed(_) => "Failed",
+    }
+}
+
+pub fn format_rate(bytes_per_second: f64) -> String {
+    let units = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+    let mut value = bytes_per_second;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{value:.0} {}", units[unit])
+    } else {
+        format!("{value:.1} {}", units[unit])
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(pieces_done: usize, downloaded: u64) -> Progress {
+        Progress {
+            state: TorrentState::Downloading,
+            pieces_checked: 10,
+            pieces_done,
+            total_pieces: 10,
+            pieces_missing: 10 - pieces_done,
+            piece_length: 1024 * 1024,
+            peers: 3,
+            downloaded,
+            uploaded: 0,
+        }
+    }
+
+    #[test]
+    fn speeds_and_eta_follow_the_recent_samples() {
+        let start = Instant::now();
+        let mut meter = ProgressMeter::new(start);
+        meter.update(&progress(0, 0), start);
+        // 5 MiB in 10 seconds, the last 5 seconds at 1 MiB/s
+        let mut line = String::new();
+        for second in 1..=10_u64 {
+            let downloaded = second.saturating_sub(5) * 1024 * 1024;
+            line = meter.update(
+                &progress(downloaded as usize / (1024 * 1024), downloaded),
+                start + Duration::from_secs(second),
+            );
+        }
+        assert_eq!(
+            line,
+            format!(
+                "Downloading [{}{}]  50.0% 5/10 pieces  down 1.0 MiB/s (avg 512.0 KiB/s)  up 0 B/s (avg 0 B/s)  3 peers  ETA 5s",
+                "#".repeat(15),
+                "-".repeat(15)
+            )
+        );
+    }
+
+    #[test]
+    fn the_check_is_shown_while_starting() {
+        let start = Instant::now();
+        let mut meter = ProgressMeter::new(start);
+        let checking = Progress {
+            state: TorrentState::Starting,
+            pieces_checked: 4,
+            ..progress(0, 0)
+        };
+        assert_eq!(
+            meter.update(&checking, start),
+            format!(
+                "Checking    [{}{}]  40.0% 4/10 pieces checked",
+                "#".repeat(12),
+                "-".repeat(18)
+            )
+        );
+
+        let fetching = Progress {
+            state: TorrentState::Starting,
+            pieces_checked: 0,
+            total_pieces: 0,
+            pieces_missing: 0,
+            piece_length: 0,
+            ..progress(0, 0)
+        };
+        assert_eq!(
+            meter.update(&fetching, start),
+            format!("Fetching    [{}] the metadata from peers", "-".repeat(30))
+        );
+    }
+
+    #[test]
+    fn durations_and_rates_are_readable() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(192)), "3m 12s");
+        assert_eq!(format_duration(Duration::from_secs(7380)), "2h 03m");
+        assert_eq!(format_rate(500.0), "500 B/s");
+        assert_eq!(format_rate(1536.0), "1.5 KiB/s");
+        assert_eq!(format_rate(3.0 * 1024.0 * 1024.0 * 1024.0), "3.0 GiB/s");
+    }
+}
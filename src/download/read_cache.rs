@@ -0,0 +1,113 @@
+use std::{collections::HashMap, time::SystemTime};
+
+/*
+ * Whole pieces read for uploading, kept for the next requests of the same piece. Peers ask for a
+ * piece block by block, so it is read from disk once instead of once per block. The cache lives
+ * in the disk task and serves every peer of the torrent. Once the pieces take up more than the
+ * budget the least recently used ones are dropped. A piece is dropped when it is written, and
+ * when one of its files was modified since it was read.
+ */
+
+pub struct ReadCache {
+    // bytes the pieces may take up, nothing is cached with 0
+    budget: usize,
+    used: usize,
+    uses: u64,
+    pieces: HashMap<usize, CachedPiece>,
+}
+
+struct CachedPiece {
+    data: Vec<u8>,
+    // of the files of the piece when it was read
+    modified: Vec<Option<SystemTime>>,
+    last_used: u64,
+}
+
+impl ReadCache {
+    pub fn new(budget: usize) -> ReadCache {
+        ReadCache {
+            budget,
+            used: 0,
+            uses: 0,
+            pieces: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    // The piece, unless it is not cached or its files were modified since.
+    pub fn get(&mut self, piece_index: usize, modified: &[Option<SystemTime>]) -> Option<&[u8]> {
+        if self.pieces.get(&piece_index)?.modified != modified {
+            self.remove(piece_index);
+            return None;
+        }
+        self.uses += 1;
+        let piece = self.pieces.get_mut(&piece_index)?;
+        piece.last_used = self.uses;
+        Some(&piece.data)
+    }
+
+    // Keeps a piece read from files with these modification times, one larger than the budget
+    // is not kept.
+    pub fn insert(&mut self, piece_index: usize, data: Vec<u8>, modified: Vec<Option<SystemTime>>) {
+        self.remove(piece_index);
+        if data.len() > self.budget {
+            return;
+        }
+        while self.used + data.len() > self.budget {
+            let least_recently_used = self
+                .pieces
+                .iter()
+                .min_by_key(|(_, piece)| piece.last_used)
+                .map(|(&piece_index, _)| piece_index);
+            match least_recently_used {
+                Some(piece_index) => self.remove(piece_index),
+                None => break,
+            }
+        }
+        self.uses += 1;
+        self.used += data.len();
+        self.pieces.insert(
+            piece_index,
+            CachedPiece {
+                data,
+                modified,
+                last_used: self.uses,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, piece_index: usize) {
+        if let Some(piece) = self.pieces.remove(&piece_index) {
+            self.used -= piece.data.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn least_recently_used_pieces_make_room() {
+        let mut cache = ReadCache::new(10);
+        let read_at = Some(SystemTime::UNIX_EPOCH);
+        cache.insert(0, vec![0; 4], vec![read_at]);
+        cache.insert(1, vec![1; 4], vec![read_at]);
+        assert_eq!(cache.get(0, &[read_at]), Some(&[0; 4][..]));
+        // piece 1 was used last longest ago
+        cache.insert(2, vec![2; 4], vec![read_at]);
+        assert_eq!(cache.get(1, &[read_at]), None);
+        assert!(cache.get(0, &[read_at]).is_some());
+
+        // modified since, and too large to keep
+        let modified = read_at.map(|time| time + Duration::from_secs(1));
+        assert_eq!(cache.get(2, &[modified]), None);
+        cache.insert(3, vec![3; 11], vec![read_at]);
+        assert_eq!(cache.get(3, &[read_at]), None);
+        assert!(cache.get(0, &[read_at]).is_some());
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+// What a download knew when it ended, stored next to it so that the next start can skip hashing
+// every piece again. It is only trusted while the files are exactly as they were when it was
+// written: same lengths and modification times. Anything else falls back to a full check.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    // verified pieces
+    pub pieces: Vec<bool>,
+    pub files: Vec<FileStamp>,
+    // bytes transferred over all runs of the download
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub length: u64,
+    pub modified: SystemTime,
+}
+
+// The resume file of the download directory, e.g. Downloaded/ubuntu.resume for Downloaded/ubuntu
+pub fn path(download_directory: &str) -> PathBuf {
+    PathBuf::from(format!("{download_directory}.resume"))
+}
+
+// Length and modification time of every file, None when one of them can't be read. Files of
+// skipped files may not exist, they are stamped with length 0 and the epoch.
+pub fn stamp(files: &[(String, usize)]) -> Option<Vec<FileStamp>> {
+    files
+        .iter()
+        .map(|(path, _)| {
+            let metadata = match std::fs::metadata(path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Some(FileStamp {
+                        length: 0,
+                        modified: SystemTime::UNIX_EPOCH,
+                    })
+                }
+                Err(_) => return None,
+            };
+            Some(FileStamp {
+                length: metadata.len(),
+                modified: metadata.modified().ok()?,
+            })
+        })
+        .collect()
+}
+
+impl ResumeData {
+    // None when there is no resume file.
+    pub fn load(path: &Path) -> anyhow::Result<Option<ResumeData>> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+        let resume_data = bincode::deserialize(&bytes)
+            .with_context(|| format!("Decoding resume file {}", path.display()))?;
+        Ok(Some(resume_data))
+    }
+
+    // Written to a temporary file first so that a crash never leaves half a resume file behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let bytes = bincode::serialize(self).context("Encoding resume data")?;
+        let partial = path.with_extension("resume.part");
+        std::fs::write(&partial, bytes)
+            .with_context(|| format!("Writing {}", partial.display()))?;
+        std::fs::rename(&partial, path).with_context(|| format!("Writing {}", path.display()))
+    }
+
+    // The pieces still missing, if the resume data belongs to this torrent and its files did not
+    // change since it was saved.
+    pub fn missing_pieces(
+        &self,
+        info_hash: [u8; 20],
+        files: &[(String, usize)],
+    ) -> Option<Vec<usize>> {
+        if self.info_hash != info_hash || stamp(files).as_ref() != Some(&self.files) {
+            return None;
+        }
+        Some(
+            self.pieces
+                .iter()
+                .enumerate()
+                .filter(|(_, &has)| !has)
+                .map(|(piece_index, _)| piece_index)
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resume_data(files: &[(String, usize)]) -> ResumeData {
+        ResumeData {
+            info_hash: [4; 20],
+            pieces: vec![true, false, true],
+            files: stamp(files).unwrap(),
+            uploaded: 10,
+            downloaded: 20,
+        }
+    }
+
+    #[test]
+    fn saved_data_is_used_while_the_files_are_unchanged() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("a.bin");
+        std::fs::write(&file, [0; 30]).unwrap();
+        let files = vec![(file.to_str().unwrap().to_string(), 30)];
+
+        let resume_file = directory.path().join("download.resume");
+        assert_eq!(ResumeData::load(&resume_file).unwrap(), None);
+        resume_data(&files).save(&resume_file).unwrap();
+        let loaded = ResumeData::load(&resume_file).unwrap().unwrap();
+        assert_eq!(loaded, resume_data(&files));
+
+        assert_eq!(loaded.missing_pieces([4; 20], &files), Some(vec![1]));
+        // another torrent
+        assert_eq!(loaded.missing_pieces([5; 20], &files), None);
+    }
+
+    #[test]
+    fn changed_files_are_checked_again() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("a.bin");
+        std::fs::write(&file, [0; 30]).unwrap();
+        let files = vec![(file.to_str().unwrap().to_string(), 30)];
+        let resume_data = resume_data(&files);
+
+        std::fs::write(&file, [0; 31]).unwrap();
+        assert_eq!(resume_data.missing_pieces([4; 20], &files), None);
+
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(resume_data.missing_pieces([4; 20], &files), None);
+    }
+}
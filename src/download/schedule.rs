@@ -0,0 +1,172 @@
+use crate::config::{AltSpeedSchedule, Config};
+use crate::download::bandwidth::Bandwidth;
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDateTime};
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use tracing::info;
+
+// Switches the bandwidth limiters between the normal and the alternative limits according to
+// the time-of-day schedule in the config.
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> NaiveDateTime;
+}
+
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn now(&self) -> NaiveDateTime {
+        chrono::Local::now().naive_local()
+    }
+}
+
+/*
+ * Whether the alternative limits apply at the given local time. A window whose end is before
+ * its begin wraps past midnight and belongs to the day it begins on, so 22:00-06:00 on Friday
+ * covers Friday 22:00 until Saturday 06:00.
+*/
+pub fn alt_limits_active(schedule: &AltSpeedSchedule, now: NaiveDateTime) -> bool {
+    let time = now.time();
+    let today = now.weekday();
+    if schedule.begin <= schedule.end {
+        schedule.days.contains(&today) && schedule.begin <= time && time < schedule.end
+    } else {
+        let yesterday = (now - ChronoDuration::days(1)).weekday();
+        (schedule.days.contains(&today) && time >= schedule.begin)
+            || (schedule.days.contains(&yesterday) && time < schedule.end)
+    }
+}
+
+// Reconfigures the limiters whenever the schedule flips, transfers keep running.
+pub fn spawn_scheduler(
+    config: &Config,
+    bandwidth: Bandwidth,
+    clock: Arc<dyn Clock>,
+    tick: Duration,
+) -> Option<JoinHandle<()>> {
+    let schedule = config.alt_speed.clone()?;
+    let normal_limits = (config.download_limit, config.upload_limit);
+    Some(tokio::spawn(async move {
+        let mut alt_active = None;
+        loop {
+            let active = alt_limits_active(&schedule, clock.now());
+            if alt_active != Some(active) {
+                let (download_limit, upload_limit) = if active {
+                    (schedule.download_limit, schedule.upload_limit)
+                } else {
+                    normal_limits
+                };
+                bandwidth.download.set_rate(download_limit);
+                bandwidth.upload.set_rate(upload_limit);
+                if alt_active.is_some() {
+                    info!(
+                        "Switched to {} speed limits (download {}, upload {})",
+                        if active { "alternative" } else { "normal" },
+                        format_limit(bandwidth.download.rate()),
+                        format_limit(bandwidth.upload.rate())
+                    );
+                }
+                alt_active = Some(active);
+            }
+            tokio::time::sleep(tick).await;
+        }
+    }))
+}
+
+fn format_limit(limit: Option<u64>) -> String {
+    match limit {
+        Some(bytes_per_second) => format!("{} KiB/s", bytes_per_second / 1024),
+        None => "unlimited".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveTime, Weekday};
+    use std::sync::Mutex;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2024-01-01 is a Monday
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn schedule(begin: (u32, u32), end: (u32, u32), days: Vec<Weekday>) -> AltSpeedSchedule {
+        AltSpeedSchedule {
+            download_limit: Some(10_000),
+            upload_limit: Some(5_000),
+            begin: NaiveTime::from_hms_opt(begin.0, begin.1, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+            days,
+        }
+    }
+
+    #[test]
+    fn daytime_window() {
+        let schedule = schedule((8, 0), (18, 30), vec![Weekday::Mon, Weekday::Tue]);
+        assert!(!alt_limits_active(&schedule, at(1, 7, 59)));
+        assert!(alt_limits_active(&schedule, at(1, 8, 0)));
+        assert!(alt_limits_active(&schedule, at(2, 18, 29)));
+        assert!(!alt_limits_active(&schedule, at(2, 18, 30)));
+        // Wednesday is not scheduled
+        assert!(!alt_limits_active(&schedule, at(3, 12, 0)));
+    }
+
+    #[test]
+    fn window_wrapping_past_midnight() {
+        let schedule = schedule((22, 0), (6, 0), vec![Weekday::Fri]);
+        // Friday 2024-01-05 evening and the early Saturday morning after it
+        assert!(!alt_limits_active(&schedule, at(5, 21, 59)));
+        assert!(alt_limits_active(&schedule, at(5, 23, 0)));
+        assert!(alt_limits_active(&schedule, at(6, 5, 59)));
+        assert!(!alt_limits_active(&schedule, at(6, 6, 0)));
+        // Friday early morning belongs to Thursday's window, which is not scheduled
+        assert!(!alt_limits_active(&schedule, at(5, 3, 0)));
+    }
+
+    struct MockClock(Mutex<NaiveDateTime>);
+
+    impl Clock for MockClock {
+        fn now(&self) -> NaiveDateTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn scheduler_flips_limiter_rates() {
+        let config = Config {
+            download_limit: Some(1_000_000),
+            upload_limit: None,
+            alt_speed: Some(schedule((9, 0), (17, 0), vec![Weekday::Mon])),
+            ..Default::default()
+        };
+        let bandwidth = Bandwidth::new(config.download_limit, config.upload_limit);
+        let clock = Arc::new(MockClock(Mutex::new(at(1, 8, 0))));
+        let handle = spawn_scheduler(
+            &config,
+            bandwidth.clone(),
+            clock.clone(),
+            Duration::from_millis(5),
+        )
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(bandwidth.download.rate(), Some(1_000_000));
+        assert_eq!(bandwidth.upload.rate(), None);
+
+        *clock.0.lock().unwrap() = at(1, 10, 0);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(bandwidth.download.rate(), Some(10_000));
+        assert_eq!(bandwidth.upload.rate(), Some(5_000));
+
+        *clock.0.lock().unwrap() = at(1, 17, 0);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(bandwidth.download.rate(), Some(1_000_000));
+        assert_eq!(bandwidth.upload.rate(), None);
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,62 @@
+use crate::config::Config;
+use std::time::Duration;
+
+/*
+ * When a seeding torrent has given back enough: once it uploaded seed_ratio times what it
+ * downloaded over all its runs, or once it seeded for seed_time in this run. A torrent that was
+ * complete before it ever downloaded anything has its ratio counted against its size, like
+ * Transmission does.
+ */
+
+// How often the limits are checked while seeding.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Why seeding should end, None while it goes on.
+pub fn reached(
+    config: &Config,
+    uploaded: u64,
+    downloaded: u64,
+    size: u64,
+    seeded: Duration,
+) -> Option<String> {
+    if let Some(limit) = config.seed_ratio {
+        let ratio =
+            uploaded as f64 / (if downloaded > 0 { downloaded } else { size }).max(1) as f64;
+        if ratio >= limit {
+            return Some(format!("reached the ratio of {ratio:.2}"));
+        }
+    }
+    match config.seed_time {
+        Some(limit) if seeded >= limit => {
+            Some(format!("seeded for {} minutes", seeded.as_secs() / 60))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeding_ends_at_the_ratio_or_the_time() {
+        let config = Config {
+            seed_ratio: Some(2.0),
+            seed_time: Some(Duration::from_secs(60 * 60)),
+            ..Default::default()
+        };
+        let minute = Duration::from_secs(60);
+        assert_eq!(reached(&config, 150, 100, 100, minute), None);
+        assert_eq!(
+            reached(&config, 200, 100, 1000, minute),
+            Some("reached the ratio of 2.00".to_string())
+        );
+        // nothing downloaded, the size counts
+        assert_eq!(reached(&config, 200, 0, 1000, minute), None);
+        assert_eq!(
+            reached(&config, 0, 0, 1000, 90 * minute),
+            Some("seeded for 90 minutes".to_string())
+        );
+        assert_eq!(reached(&Config::default(), 5000, 1, 1, 900 * minute), None);
+    }
+}
@@ -0,0 +1,774 @@
+use crate::config::Config;
+use crate::download::{
+    control::Control,
+    events,
+    magnet::Magnet,
+    metadata,
+    metrics::{self, Totals},
+    shared::Shared,
+    torrent::Torrent,
+};
+use crate::error::RustyBitError;
+use anyhow::Context;
+use futures_util::Stream;
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+pub use crate::download::control::{PeerInfo, Progress, TorrentState};
+pub use crate::download::events::Event;
+pub use crate::download::ip_filter::BlockedConnections;
+
+/*
+ * The library surface of Rusty-Bit. A Session downloads torrents with one config, every torrent
+ * added to it runs in the background and is controlled through its TorrentHandle:
+ *
+ *     let session = Session::new(config);
+ *     let torrent = session.add_torrent(TorrentSource::File("linux.torrent".into()))?;
+ *     let state = torrent.wait().await;
+ *
+ * Torrents are started on the tokio runtime the methods are called from. They run side by side
+ * and share the listen port, the peer id, the rate limits and the open files.
+ */
+pub struct Session {
+    config: Config,
+    shared: Arc<Shared>,
+    // info hashes of the torrents that were added and not stopped yet
+    torrents: Arc<Mutex<HashSet<[u8; 20]>>>,
+    metrics_server: Mutex<Option<JoinHandle<()>>>,
+}
+
+// Where the metainfo of a torrent comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentSource {
+    // the contents of a .torrent file
+    Bytes(Vec<u8>),
+    // path of a .torrent file
+    File(PathBuf),
+    // a magnet link, its metadata has to come from peers
+    Magnet(String),
+}
+
+impl Session {
+    pub fn new(config: Config) -> Session {
+        Session {
+            shared: Arc::new(Shared::new(&config)),
+            config,
+            torrents: Arc::default(),
+            metrics_server: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Adds a torrent and starts downloading it right away. A torrent can only be added once. The
+    // torrent of a magnet link starts by fetching its metadata from peers.
+    pub fn add_torrent(&self, source: TorrentSource) -> anyhow::Result<TorrentHandle> {
+        let bytes = match source {
+            TorrentSource::Bytes(bytes) => bytes,
+            TorrentSource::File(path) => std::fs::read(&path)
+                .map_err(RustyBitError::disk(&path.display().to_string()))
+                .with_context(|| format!("Reading {}", path.display()))?,
+            TorrentSource::Magnet(link) => {
+                let magnet = Magnet::parse(&link)?;
+                return self.add(magnet.info_hash, OnceLock::new(), Some(magnet));
+            }
+        };
+        let torrent = serde_bencode::from_bytes::<Torrent>(&bytes)
+            .map_err(RustyBitError::bencode("The torrent"))?;
+        let info_hash = torrent.calc_hash().context("Calculate metainfo hash")?;
+        self.add(info_hash, OnceLock::from(torrent), None)
+    }
+
+    fn add(
+        &self,
+        info_hash: [u8; 20],
+        torrent: OnceLock<Torrent>,
+        magnet: Option<Magnet>,
+    ) -> anyhow::Result<TorrentHandle> {
+        let handle = TorrentHandle {
+            torrent: Arc::new(torrent),
+            magnet,
+            info_hash,
+            config: self.config.clone(),
+            shared: self.shared.clone(),
+            session_torrents: self.torrents.clone(),
+            control: Arc::new(Control::new()),
+            task: Mutex::new(None),
+        };
+        if !self.torrents.lock().unwrap().insert(info_hash) {
+            return Err(RustyBitError::Duplicate {
+                name: handle.name().to_string(),
+                info_hash,
+            }
+            .into());
+        }
+        handle.control.set_sequential(self.config.sequential);
+        handle.control.set_limits(
+            self.config.torrent_download_limit,
+            self.config.torrent_upload_limit,
+        );
+        // for a magnet link the hook runs once the metadata is there
+        if let (Some(command), Some(torrent)) = (&self.config.on_add, handle.torrent.get()) {
+            torrent.run_hook(command, &self.config, info_hash, None)?;
+        }
+        handle.resume();
+        Ok(handle)
+    }
+
+    // Changes the download and upload limits every torrent of the session shares, in bytes per
+    // second, None is unlimited. An alternative speed schedule sets them again when its window
+    // starts or ends.
+    pub fn set_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        let global = self.shared.bandwidth.global();
+        global.download.set_rate(download);
+        global.upload.set_rate(upload);
+    }
+
+    // What happens in the torrents of the session from now on: verified pieces, peers coming and
+    // going, completed downloads and tracker errors.
+    pub fn events(&self) -> impl Stream<Item = Event> + Send + 'static {
+        events::stream(self.shared.events.subscribe())
+    }
+
+    // Serves the metrics of the session in the Prometheus format on /metrics at the address of
+    // the config, until the session is closed. Returns the address, None without one in the
+    // config.
+    pub fn serve_metrics(&self) -> anyhow::Result<Option<SocketAddr>> {
+        let Some(addr) = self.config.metrics_address else {
+            return Ok(None);
+        };
+        let shared = self.shared.clone();
+        let (addr, server) = metrics::spawn(addr, move || {
+            let global = shared.bandwidth.global();
+            shared.metrics.render(&Totals {
+                downloaded: global.download.transferred(),
+                uploaded: global.upload.transferred(),
+                blocked: shared.peer_filter.blocked(),
+            })
+        })?;
+        if let Some(previous) = self.metrics_server.lock().unwrap().replace(server) {
+            previous.abort();
+        }
+        Ok(Some(addr))
+    }
+
+    // Connections to and from peers the IP filter blocked since the session was created.
+    pub fn blocked_connections(&self) -> BlockedConnections {
+        self.shared.peer_filter.blocked()
+    }
+
+    // Stops listening for peers, removes the port mapping and stops serving the metrics, after
+    // the torrents have ended.
+    pub async fn close(self) {
+        if let Some(server) = self.metrics_server.lock().unwrap().take() {
+            server.abort();
+        }
+        self.shared.close().await;
+    }
+}
+
+// A torrent of a session. Dropping the handle leaves the download running, stop ends it.
+pub struct TorrentHandle {
+    // not set until the metadata of a magnet link is fetched
+    torrent: Arc<OnceLock<Torrent>>,
+    magnet: Option<Magnet>,
+    info_hash: [u8; 20],
+    config: Config,
+    shared: Arc<Shared>,
+    session_torrents: Arc<Mutex<HashSet<[u8; 20]>>>,
+    control: Arc<Control>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl TorrentHandle {
+    // The name of the torrent, for a magnet link the one the link gives until the metadata is
+    // there.
+    pub fn name(&self) -> &str {
+        match (self.torrent.get(), &self.magnet) {
+            (Some(torrent), _) => torrent.info.name(),
+            (None, Some(magnet)) => &magnet.name,
+            (None, None) => unreachable!("a torrent without metadata has a magnet link"),
+        }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    // Bytes in the files of the torrent, 0 while the metadata is fetched.
+    pub fn total_length(&self) -> u64 {
+        self.torrent
+            .get()
+            .map_or(0, |torrent| torrent.info.total_length())
+    }
+
+    // Without the metadata the torrent has no pieces yet.
+    pub fn progress(&self) -> Progress {
+        let (total_pieces, piece_length) = self.torrent.get().map_or((0, 0), |torrent| {
+            (torrent.info.total_pieces(), torrent.info.piece_length())
+        });
+        let (downloaded, uploaded) = self.control.transferred();
+        Progress {
+            state: self.control.state(),
+            pieces_checked: self.control.pieces_checked(),
+            pieces_done: self.control.pieces_done(),
+            total_pieces,
+            pieces_missing: self.control.pieces_missing().unwrap_or(total_pieces),
+            piece_length,
+            peers: self.control.peers().count(),
+            downloaded,
+            uploaded,
+        }
+    }
+
+    // Whether each piece is on disk.
+    pub fn pieces(&self) -> Vec<bool> {
+        let total_pieces = self
+            .torrent
+            .get()
+            .map_or(0, |torrent| torrent.info.total_pieces());
+        self.control.pieces(total_pieces)
+    }
+
+    // The peers pieces are exchanged with right now.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.control.peers().peers()
+    }
+
+    // Waits until the download is finished, failed, paused or stopped, and returns that state.
+    // A seeding torrent only gets there once it is paused or stopped.
+    pub async fn wait(&self) -> TorrentState {
+        self.control.done().await
+    }
+
+    // Disconnects from every peer and keeps what was downloaded so far.
+    pub async fn pause(&self) {
+        self.end().await;
+    }
+
+    // Stops requesting pieces and tells the peers we are not interested, but stays connected to
+    // them so that resume carries on right away. What was downloaded so far is saved. A seeding
+    // torrent disconnects like with pause.
+    pub async fn pause_connected(&self) {
+        self.control.request_hold(true);
+        self.control.done().await;
+    }
+
+    // Carries on after pause_connected, or starts the torrent again after it ended, checking the
+    // pieces on disk first. Nothing happens while the download is running.
+    pub fn resume(&self) {
+        self.control.request_hold(false);
+        let mut task = self.task.lock().unwrap();
+        if task.as_ref().is_some_and(|task| !task.is_finished()) {
+            if self.control.state() == TorrentState::Paused {
+                self.control.set_state(TorrentState::Downloading);
+            }
+            return;
+        }
+        self.control.request_stop(false);
+        self.control.set_state(TorrentState::Starting);
+        let metadata = self.torrent.clone();
+        let magnet = self.magnet.clone();
+        let config = self.config.clone();
+        let shared = self.shared.clone();
+        let control = self.control.clone();
+        let info_hash = self.info_hash;
+        *task = Some(tokio::spawn(async move {
+            let (torrent, result) =
+                match torrent(&metadata, magnet.as_ref(), &shared, &config, &control).await {
+                    Ok(Some(mut torrent)) => {
+                        let result = torrent.run_in(&shared, &config, &control).await;
+                        (Some(torrent), result)
+                    }
+                    Ok(None) => (None, Ok(())),
+                    Err(e) => (None, Err(e)),
+                };
+            let state = match result {
+                Ok(()) if control.stop_requested() || control.hold_requested() => {
+                    TorrentState::Paused
+                }
+                Ok(()) => TorrentState::Finished,
+                Err(e) => TorrentState::Failed(format!("{e:#}")),
+            };
+            // without the metadata there is nothing to tell the hook
+            if let (TorrentState::Failed(error), Some(command), Some(torrent)) =
+                (&state, &config.on_error, &torrent)
+            {
+                if let Err(e) = torrent.run_hook(command, &config, info_hash, Some(error)) {
+                    warn!("could not run the error hook: {e:#}");
+                }
+            }
+            control.set_state(state);
+        }));
+    }
+
+    // Downloads the pieces in order from now on, or goes back to the usual order.
+    pub fn set_sequential(&self, sequential: bool) {
+        self.control.set_sequential(sequential);
+    }
+
+    // Limits the torrent below the session wide limits, in bytes per second, None is unlimited.
+    // Applies right away and after the torrent is resumed.
+    pub fn set_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.control.set_limits(download, upload);
+    }
+
+    // Ends the download for good, the torrent can be added to the session again.
+    pub async fn stop(self) {
+        self.end().await;
+        self.control.set_state(TorrentState::Stopped);
+        self.session_torrents
+            .lock()
+            .unwrap()
+            .remove(&self.info_hash);
+    }
+
+    async fn end(&self) {
+        self.control.request_stop(true);
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            // a download that panicked has nothing left to clean up
+            let _ = task.await;
+        }
+    }
+}
+
+// The torrent of a handle, for a magnet link its metadata is fetched first, unless an earlier run
+// fetched it already. None if the torrent was paused or stopped while fetching.
+async fn torrent(
+    metadata: &OnceLock<Torrent>,
+    magnet: Option<&Magnet>,
+    shared: &Shared,
+    config: &Config,
+    control: &Control,
+) -> anyhow::Result<Option<Torrent>> {
+    let (Some(magnet), None) = (magnet, metadata.get()) else {
+        return Ok(metadata.get().cloned());
+    };
+    let fetched = tokio::select! {
+        torrent = metadata::resolve(shared, config, magnet) => torrent?,
+        _ = control.stopped() => return Ok(None),
+    };
+    let torrent = metadata.get_or_init(|| fetched);
+    if let Some(command) = &config.on_add {
+        torrent.run_hook(command, config, magnet.info_hash, None)?;
+    }
+    Ok(Some(torrent.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::IpFamily;
+    use crate::download::{
+        resume::ResumeData,
+        test_peer::{self, Misbehavior, Seeder},
+        torrent::CreateOptions,
+    };
+    use futures_util::{FutureExt, StreamExt};
+    use std::{net::SocketAddr, time::Duration};
+
+    struct Swarm {
+        torrent: Vec<u8>,
+        magnet: String,
+        payload: Vec<u8>,
+        config: Config,
+        _directories: [tempfile::TempDir; 2],
+    }
+
+    // A torrent without a tracker and a seeder for it that also serves its metadata, the config
+    // downloads from the seeder.
+    async fn swarm(misbehavior: Misbehavior) -> Swarm {
+        let source = tempfile::tempdir().unwrap();
+        let payload: Vec<u8> = (0..100_000).map(|i| (i % 253) as u8).collect();
+        let path = source.path().join("payload.bin");
+        std::fs::write(&path, &payload).unwrap();
+        let torrent = Torrent::create(
+            &path,
+            &CreateOptions {
+                piece_length: Some(16 * 1024),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let seeder = test_peer::spawn_with_metadata(
+            Seeder {
+                info_hash: torrent.calc_hash().unwrap(),
+                payload: Arc::new(payload.clone()),
+                piece_length: 16 * 1024,
+                misbehavior,
+            },
+            serde_bencode::to_bytes(&torrent.info).unwrap(),
+        )
+        .await;
+
+        let target = tempfile::tempdir().unwrap();
+        let config = Config {
+            download_dir: target.path().to_path_buf(),
+            listen_port: Some(0),
+            ip_family: Some(IpFamily::V4),
+            peers: vec![seeder],
+            dht: false,
+            ..Default::default()
+        };
+        Swarm {
+            torrent: serde_bencode::to_bytes(&torrent).unwrap(),
+            magnet: torrent.magnet_link().unwrap(),
+            payload,
+            config,
+            _directories: [source, target],
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn seeding_ends_at_the_ratio_limit() {
+        let swarm = swarm(Misbehavior::None).await;
+        let session = Session::new(Config {
+            seed: true,
+            // the block requests count as uploaded
+            seed_ratio: Some(1e-9),
+            ..swarm.config.clone()
+        });
+        let torrent = session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .unwrap();
+        let state = tokio::time::timeout(Duration::from_secs(30), torrent.wait())
+            .await
+            .unwrap();
+        assert_eq!(state, TorrentState::Finished);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn download_without_every_piece_fails() {
+        // the only seeder is dropped for its corrupt pieces
+        let swarm = swarm(Misbehavior::Corrupt).await;
+        let session = Session::new(swarm.config.clone());
+        let torrent = session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .unwrap();
+        let state = tokio::time::timeout(Duration::from_secs(60), torrent.wait())
+            .await
+            .unwrap();
+        assert!(
+            matches!(&state, TorrentState::Failed(reason) if reason.contains("pieces missing")),
+            "{state:?}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn completed_files_are_moved_out_of_the_incomplete_directory() {
+        let swarm = swarm(Misbehavior::None).await;
+        let incomplete = tempfile::tempdir().unwrap();
+        let session = Session::new(Config {
+            incomplete_dir: Some(incomplete.path().to_path_buf()),
+            part_suffix: true,
+            ..swarm.config.clone()
+        });
+        let torrent = session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .unwrap();
+        let state = tokio::time::timeout(Duration::from_secs(30), torrent.wait())
+            .await
+            .unwrap();
+        assert_eq!(state, TorrentState::Finished);
+
+        let directory = swarm.config.download_dir.join("payload");
+        assert_eq!(
+            std::fs::read(directory.join("payload.bin")).unwrap(),
+            swarm.payload
+        );
+        assert!(!incomplete.path().join("payload").exists());
+
+        // a restart finds the file where it was moved to
+        torrent.resume();
+        let state = tokio::time::timeout(Duration::from_secs(30), torrent.wait())
+            .await
+            .unwrap();
+        assert_eq!(state, TorrentState::Finished);
+        assert_eq!(torrent.progress().pieces_done, 7);
+        assert!(!incomplete.path().join("payload").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn hooks_run_when_a_torrent_is_added_and_completes() {
+        let swarm = swarm(Misbehavior::None).await;
+        let log = swarm.config.download_dir.join("hooks.log");
+        let session = Session::new(Config {
+            on_add: Some(format!("echo added $RUSTY_BIT_NAME >> {}", log.display())),
+            on_complete: Some(format!(
+                "echo completed $RUSTY_BIT_SIZE >> {}",
+                log.display()
+            )),
+            ..swarm.config.clone()
+        });
+        let torrent = session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .unwrap();
+        let state = tokio::time::timeout(Duration::from_secs(30), torrent.wait())
+            .await
+            .unwrap();
+        assert_eq!(state, TorrentState::Finished);
+
+        // the hooks run in the background
+        let expected = "added payload.bin\ncompleted 100000\n";
+        for _ in 0..50 {
+            if std::fs::read_to_string(&log).unwrap_or_default() == expected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn added_torrent_is_downloaded() {
+        let swarm = swarm(Misbehavior::None).await;
+        let session = Session::new(swarm.config.clone());
+        let mut events = Box::pin(session.events());
+        let torrent = session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .unwrap();
+        assert_eq!(torrent.name(), "payload.bin");
+
+        let state = tokio::time::timeout(Duration::from_secs(30), torrent.wait())
+            .await
+            .unwrap();
+        assert_eq!(state, TorrentState::Finished);
+        let progress = torrent.progress();
+        assert_eq!(
+            progress,
+            Progress {
+                state: TorrentState::Finished,
+                pieces_checked: 7,
+                pieces_done: 7,
+                total_pieces: 7,
+                pieces_missing: 0,
+                piece_length: 16 * 1024,
+                peers: 0,
+                downloaded: 100_000,
+                // the block requests
+                uploaded: progress.uploaded,
+            }
+        );
+        let downloaded = std::fs::read(
+            swarm
+                .config
+                .download_dir
+                .join("payload")
+                .join("payload.bin"),
+        )
+        .unwrap();
+        assert!(downloaded == swarm.payload);
+
+        // the next start can skip checking the pieces
+        let resume_data = ResumeData::load(&swarm.config.download_dir.join("payload.resume"))
+            .unwrap()
+            .unwrap();
+        assert!(resume_data.pieces.iter().all(|&has| has));
+        assert_eq!(resume_data.downloaded, swarm.payload.len() as u64);
+
+        let mut verified = Vec::new();
+        let mut completed = false;
+        while let Some(Some(event)) = events.next().now_or_never() {
+            match event {
+                Event::PieceVerified { info_hash, piece } => {
+                    assert!(
+                        !completed,
+                        "piece {piece} verified after the download completed"
+                    );
+                    assert_eq!(info_hash, torrent.info_hash());
+                    verified.push(piece);
+                }
+                Event::TorrentCompleted { .. } => completed = true,
+                Event::PeerConnected { .. } | Event::PeerDisconnected { .. } => {}
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+        verified.sort();
+        assert_eq!(verified, (0..7).collect::<Vec<_>>());
+        assert!(completed);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn torrents_can_be_paused_resumed_and_stopped() {
+        let swarm = swarm(Misbehavior::Stall).await;
+        let session = Session::new(swarm.config.clone());
+        let torrent = session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .unwrap();
+        while torrent.progress().state != TorrentState::Downloading {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), torrent.pause())
+            .await
+            .unwrap();
+        assert_eq!(torrent.progress().state, TorrentState::Paused);
+        assert_eq!(torrent.progress().pieces_done, 0);
+
+        torrent.resume();
+        assert!(!torrent.progress().state.is_done());
+        tokio::time::timeout(Duration::from_secs(5), torrent.stop())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paused_torrents_can_stay_connected() {
+        let swarm = swarm(Misbehavior::None).await;
+        let session = Session::new(Config {
+            download_limit: Some(20 * 1024),
+            ..swarm.config.clone()
+        });
+        let torrent = session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .unwrap();
+        while torrent.progress().pieces_done == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), torrent.pause_connected())
+            .await
+            .unwrap();
+        assert_eq!(torrent.progress().state, TorrentState::Paused);
+        // the piece that was on its way is finished, nothing is requested after it
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let pieces_done = torrent.progress().pieces_done;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(torrent.progress().pieces_done, pieces_done);
+        assert!(pieces_done < 7);
+        // the progress is saved while paused
+        let resume_data = ResumeData::load(&swarm.config.download_dir.join("payload.resume"))
+            .unwrap()
+            .unwrap();
+        assert!(resume_data.pieces.iter().any(|&has| has));
+
+        torrent.resume();
+        let state = tokio::time::timeout(Duration::from_secs(30), torrent.wait())
+            .await
+            .unwrap();
+        assert_eq!(state, TorrentState::Finished);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn magnet_links_are_downloaded() {
+        let swarm = swarm(Misbehavior::None).await;
+        let session = Session::new(swarm.config.clone());
+        let torrent = session
+            .add_torrent(TorrentSource::Magnet(swarm.magnet.clone()))
+            .unwrap();
+        // the name of the link until the metadata is there
+        assert_eq!(torrent.name(), "payload.bin");
+
+        let state = tokio::time::timeout(Duration::from_secs(30), torrent.wait())
+            .await
+            .unwrap();
+        assert_eq!(state, TorrentState::Finished);
+        assert_eq!(torrent.total_length(), 100_000);
+        assert_eq!(torrent.progress().pieces_done, 7);
+        let downloaded = std::fs::read(
+            swarm
+                .config
+                .download_dir
+                .join("payload")
+                .join("payload.bin"),
+        )
+        .unwrap();
+        assert!(downloaded == swarm.payload);
+
+        // the torrent of the link is added already
+        assert!(session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .is_err());
+        assert!(session
+            .add_torrent(TorrentSource::Magnet("magnet:?dn=payload.bin".to_string()))
+            .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn torrents_share_the_listen_port() {
+        let swarm = swarm(Misbehavior::None).await;
+        // a second torrent that is complete in the download directory already
+        let seeded: Vec<u8> = (0..40_000).map(|i| (i % 251) as u8).collect();
+        let seeded_path = swarm.config.download_dir.join("seeded").join("seeded.bin");
+        std::fs::create_dir_all(seeded_path.parent().unwrap()).unwrap();
+        std::fs::write(&seeded_path, &seeded).unwrap();
+        let seeded_torrent = Torrent::create(
+            &seeded_path,
+            &CreateOptions {
+                piece_length: Some(16 * 1024),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let session = Session::new(Config {
+            listen_port: Some(port),
+            seed: true,
+            ..swarm.config.clone()
+        });
+        let downloading = session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .unwrap();
+        let seeding = session
+            .add_torrent(TorrentSource::Bytes(
+                serde_bencode::to_bytes(&seeded_torrent).unwrap(),
+            ))
+            .unwrap();
+        assert!(session
+            .add_torrent(TorrentSource::Bytes(swarm.torrent.clone()))
+            .is_err());
+        while downloading.progress().state != TorrentState::Seeding
+            || seeding.progress().state != TorrentState::Seeding
+        {
+            assert!(!downloading.progress().state.is_done());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // both torrents are uploaded to over the one port
+        for (torrent, payload) in [
+            (swarm.torrent.clone(), swarm.payload.clone()),
+            (serde_bencode::to_bytes(&seeded_torrent).unwrap(), seeded),
+        ] {
+            let mut torrent = serde_bencode::from_bytes::<Torrent>(&torrent).unwrap();
+            let target = tempfile::tempdir().unwrap();
+            let config = Config {
+                download_dir: target.path().to_path_buf(),
+                listen_port: Some(0),
+                ip_family: Some(IpFamily::V4),
+                peers: vec![SocketAddr::from(([127, 0, 0, 1], port))],
+                dht: false,
+                ..Default::default()
+            };
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                torrent.run(&config, &Control::new()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            let name = torrent.info.name();
+            let stem = name.split('.').next().unwrap();
+            let downloaded = std::fs::read(target.path().join(stem).join(name)).unwrap();
+            assert!(downloaded == payload);
+        }
+
+        downloading.stop().await;
+        seeding.stop().await;
+        session.close().await;
+    }
+}
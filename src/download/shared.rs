@@ -0,0 +1,261 @@
+use crate::config::{Config, IpFamily};
+#[cfg(feature = "upnp")]
+use crate::download::port_mapping::{self, PortMapping, Protocol};
+use crate::download::{
+    bandwidth::BandwidthManager,
+    dht::Dht,
+    dns::Resolver,
+    events::{Event, EVENT_CAPACITY},
+    ip_filter::PeerFilter,
+    listener::{Listener, Swarms},
+    metrics::Metrics,
+    net, peer_id,
+    schedule::{self, LocalClock},
+    storage::FileStorage,
+    tracker::LISTEN_PORT,
+};
+#[cfg(feature = "upnp")]
+use std::sync::Mutex;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, OnceCell},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+// Longest the first announce waits for the router to map the listen port.
+#[cfg(feature = "upnp")]
+const PORT_MAPPING_WAIT: Duration = Duration::from_secs(5);
+
+/*
+ * What the torrents of a session share: our peer id, the rate limiters, the open file handles, the
+ * IP filter, the event subscribers and metrics, the HTTP client with its open connections and the listen
+ * port. The listener and the DHT node on the port are started by the first torrent that runs and
+ * serve every torrent after it, an incoming connection goes to the torrent whose swarm its
+ * handshake names.
+ */
+pub struct Shared {
+    pub peer_id: [u8; 20],
+    // key parameter of our announces
+    pub tracker_key: String,
+    pub bandwidth: BandwidthManager,
+    pub storage: Arc<FileStorage>,
+    pub swarms: Swarms,
+    pub peer_filter: PeerFilter,
+    // the events of every torrent, for the subscribers of the session
+    pub events: broadcast::Sender<Event>,
+    pub metrics: Arc<Metrics>,
+    network: OnceCell<Network>,
+    http_client: OnceCell<reqwest::Client>,
+    // mapped once a torrent has a tracker or the DHT to tell other peers about the port
+    #[cfg(feature = "upnp")]
+    port_mapping: OnceCell<Mapping>,
+}
+
+// The port mapping once it is made, and the task making it.
+#[cfg(feature = "upnp")]
+struct Mapping {
+    mapping: Arc<Mutex<Option<PortMapping>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+pub struct Network {
+    pub listen_port: u16,
+    // whether incoming connections reach the torrents
+    pub listening: bool,
+    pub dht: Option<Arc<Dht>>,
+    // the listener and the alternative speed scheduler
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(|task| task.abort());
+    }
+}
+
+impl Shared {
+    pub fn new(config: &Config) -> Shared {
+        if !config.ip_filter.is_empty() {
+            info!("Blocking {} address ranges", config.ip_filter.len());
+        }
+        Shared {
+            peer_id: peer_id::generate(),
+            tracker_key: config
+                .tracker_key
+                .clone()
+                .unwrap_or_else(|| format!("{:08X}", rand::random::<u32>())),
+            bandwidth: BandwidthManager::new(config.download_limit, config.upload_limit),
+            storage: Arc::new(
+                FileStorage::new(config.max_open_files).with_backend(config.disk_backend),
+            ),
+            swarms: Swarms::default(),
+            peer_filter: PeerFilter::new(config.ip_filter.clone()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            metrics: Arc::default(),
+            network: OnceCell::new(),
+            http_client: OnceCell::new(),
+            #[cfg(feature = "upnp")]
+            port_mapping: OnceCell::new(),
+        }
+    }
+
+    // The listener and the DHT node, started on the first call.
+    pub async fn network(&self, config: &Config) -> &Network {
+        self.network
+            .get_or_init(|| async { self.start_network(config).await })
+            .await
+    }
+
+    // The client for tracker and web seed requests, built on the first call.
+    pub async fn http_client(&self, config: &Config) -> anyhow::Result<reqwest::Client> {
+        self.http_client
+            .get_or_try_init(|| async { net::http_client(config, &Resolver::new(config)) })
+            .await
+            .cloned()
+    }
+
+    async fn start_network(&self, config: &Config) -> Network {
+        let listen_port = config.listen_port.unwrap_or(LISTEN_PORT);
+        let mut tasks = Vec::new();
+
+        // The DHT answers other nodes on the listen port over UDP, and looks for peers while the
+        // trackers are contacted.
+        let dht = if !config.dht {
+            None
+        } else if config.proxy.is_some() || config.peer_proxy().is_some() {
+            info!("Not using the DHT since traffic goes through a proxy");
+            None
+        } else if config.ip_family == Some(IpFamily::V6)
+            || config.bind_address.is_some_and(|ip| ip.is_ipv6())
+        {
+            info!("Not using the DHT since it only supports IPv4");
+            None
+        } else {
+            let bind_address = config
+                .bind_address
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+            match Dht::bind(SocketAddr::new(bind_address, listen_port)).await {
+                Result::Ok(dht) => {
+                    if let Result::Ok(addr) = dht.local_addr() {
+                        info!("DHT node listening on {addr}");
+                    }
+                    Some(Arc::new(dht))
+                }
+                Err(e) => {
+                    warn!("not using the DHT: {e:#}");
+                    None
+                }
+            }
+        };
+
+        // Peers that connect to us are downloaded from until we have everything, after that
+        // they are uploaded to
+        let listener = if config.peer_proxy().is_some() {
+            info!("Not listening for incoming connections since peers are reached through a proxy");
+            None
+        } else {
+            match Listener::bind(config, listen_port) {
+                Result::Ok(listener) => Some(listener),
+                Err(e) => {
+                    warn!("not accepting incoming connections: {e:#}");
+                    None
+                }
+            }
+        };
+        let listening = listener.is_some();
+        if let Some(listener) = listener {
+            info!("Listening for peers on {:?}", listener.local_addrs());
+            tasks.extend(listener.spawn(self.swarms.clone(), self.peer_filter.clone()));
+        }
+
+        tasks.extend(schedule::spawn_scheduler(
+            config,
+            self.bandwidth.global(),
+            Arc::new(LocalClock),
+            Duration::from_secs(30),
+        ));
+
+        Network {
+            listen_port,
+            listening,
+            dht,
+            tasks,
+        }
+    }
+
+    // Forward our listen port on the router so that peers behind other NATs can reach us, the
+    // first call maps it. Returns the external address the router reported. The first call waits
+    // for the mapping up to PORT_MAPPING_WAIT, a slow router is left to finish in the background
+    // so it does not hold up the first announce.
+    #[cfg(feature = "upnp")]
+    pub async fn map_port(&self, config: &Config, listen_port: u16) -> Option<IpAddr> {
+        let Mapping { mapping, .. } = self
+            .port_mapping
+            .get_or_init(|| async {
+                let mapping = Arc::new(Mutex::new(None));
+                let mut task = if !config.port_mapping {
+                    None
+                } else if config.peer_proxy().is_some() {
+                    info!("Peer connections go through a proxy, incoming connections are unavailable so the listen port is not mapped");
+                    None
+                } else {
+                    let (mapped, bind_address) = (mapping.clone(), config.bind_address);
+                    let http_timeout = config.tracker_timeout;
+                    Some(tokio::spawn(async move {
+                        let mapping = port_mapping::map_port(
+                            Protocol::Tcp,
+                            listen_port,
+                            bind_address,
+                            http_timeout,
+                        )
+                        .await;
+                        *mapped.lock().unwrap() = mapping;
+                    }))
+                };
+                if let Some(task) = &mut task {
+                    let _ = tokio::time::timeout(PORT_MAPPING_WAIT, task).await;
+                }
+                Mapping { mapping, task }
+            })
+            .await;
+        let mapping = mapping.lock().unwrap();
+        mapping.as_ref().and_then(|mapping| mapping.external_ip())
+    }
+
+    // Built without UPnP and NAT-PMP, the listen port is left to be forwarded by hand.
+    #[cfg(not(feature = "upnp"))]
+    pub async fn map_port(&self, _config: &Config, _listen_port: u16) -> Option<IpAddr> {
+        None
+    }
+
+    // Stops listening and removes the port mapping, once no torrent runs any more.
+    pub async fn close(&self) {
+        if let Some(network) = self.network.get() {
+            network.tasks.iter().for_each(|task| task.abort());
+        }
+        #[cfg(feature = "upnp")]
+        self.remove_port_mapping().await;
+    }
+
+    #[cfg(feature = "upnp")]
+    async fn remove_port_mapping(&self) {
+        let mapping = self
+            .port_mapping
+            .get()
+            .and_then(|Mapping { mapping, task }| {
+                // a mapping still being made is given up on
+                if let Some(task) = task {
+                    task.abort();
+                }
+                mapping.lock().unwrap().take()
+            });
+        if let Some(mapping) = mapping {
+            mapping.remove().await;
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use crate::config::ProxyConfig;
+use anyhow::{bail, Context};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+// Minimal SOCKS5 (RFC 1928) CONNECT client with username/password authentication (RFC 1929).
+// The returned stream is a plain TcpStream already tunnelled to the target, so the handshake and
+// PeerFrameCodec run on it unchanged.
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+// Where the proxy should connect to. Hostnames are sent as they are so that DNS resolution
+// happens on the proxy and does not leak outside of it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    pub fn parse(addr: &str) -> anyhow::Result<TargetAddr> {
+        if let Result::Ok(socket_addr) = addr.parse::<SocketAddr>() {
+            return Ok(TargetAddr::Ip(socket_addr));
+        }
+        let (host, port) = addr
+            .rsplit_once(':')
+            .with_context(|| format!("{addr} has no port"))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("{addr} has an invalid port"))?;
+        Ok(TargetAddr::Domain(host.to_string(), port))
+    }
+
+    fn encode(&self, request: &mut Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                request.push(ATYP_IPV4);
+                request.extend(addr.ip().octets());
+                request.extend(addr.port().to_be_bytes());
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                request.push(ATYP_IPV6);
+                request.extend(addr.ip().octets());
+                request.extend(addr.port().to_be_bytes());
+            }
+            TargetAddr::Domain(host, port) => {
+                let host_len: u8 = host
+                    .len()
+                    .try_into()
+                    .with_context(|| format!("hostname {host} is too long for SOCKS5"))?;
+                request.push(ATYP_DOMAIN);
+                request.push(host_len);
+                request.extend(host.as_bytes());
+                request.extend(port.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runs the SOCKS5 negotiation on an already connected stream to the proxy.
+pub async fn connect_with(
+    mut stream: TcpStream,
+    proxy: &ProxyConfig,
+    target: &TargetAddr,
+) -> anyhow::Result<TcpStream> {
+    // greeting: <version><number of methods><methods...>
+    let greeting: &[u8] = if proxy.username.is_some() {
+        &[SOCKS_VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD]
+    } else {
+        &[SOCKS_VERSION, 1, NO_AUTHENTICATION]
+    };
+    stream.write_all(greeting).await?;
+
+    let mut method_selection = [0u8; 2];
+    stream.read_exact(&mut method_selection).await?;
+    if method_selection[0] != SOCKS_VERSION {
+        bail!("Proxy {} does not speak SOCKS5", proxy.addr);
+    }
+    match method_selection[1] {
+        NO_AUTHENTICATION => {}
+        USERNAME_PASSWORD => authenticate(&mut stream, proxy).await?,
+        NO_ACCEPTABLE_METHOD => bail!("Proxy {} refused our authentication methods", proxy.addr),
+        method => bail!("Proxy {} selected unsupported method {method}", proxy.addr),
+    }
+
+    // request: <version><command><reserved><address type><address><port>
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    target.encode(&mut request)?;
+    stream.write_all(&request).await?;
+
+    // reply: <version><reply code><reserved><address type><bound address><bound port>
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        bail!("Malformed SOCKS5 reply from {}", proxy.addr);
+    }
+    if reply[1] != 0 {
+        bail!(
+            "SOCKS5 proxy {} could not connect to {target:?}: {}",
+            proxy.addr,
+            reply_error(reply[1])
+        );
+    }
+    let bound_addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        address_type => bail!("Unknown SOCKS5 address type {address_type}"),
+    };
+    // The bound address is of no use to us, just consume it together with the port
+    let mut bound_addr = vec![0u8; bound_addr_len + 2];
+    stream.read_exact(&mut bound_addr).await?;
+
+    Ok(stream)
+}
+
+async fn authenticate(stream: &mut TcpStream, proxy: &ProxyConfig) -> anyhow::Result<()> {
+    let username = proxy.username.as_deref().unwrap_or_default();
+    let password = proxy.password.as_deref().unwrap_or_default();
+    if username.len() > 255 || password.len() > 255 {
+        bail!("SOCKS5 username and password must be at most 255 bytes");
+    }
+
+    // <version=1><username length><username><password length><password>
+    let mut request = vec![1, username.len() as u8];
+    request.extend(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend(password.as_bytes());
+    stream.write_all(&request).await?;
+
+    let mut response = [0u8; 2];
+    stream.read_exact(&mut response).await?;
+    if response[1] != 0 {
+        bail!("SOCKS5 proxy {} rejected the credentials", proxy.addr);
+    }
+    Ok(())
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+pub mod test_server {
+    use super::*;
+    use std::net::IpAddr;
+    use tokio::net::TcpListener;
+
+    // A tiny SOCKS5 server accepting one connection, used to test the client side.
+    // Records the requested target so tests can check that hostnames were not resolved locally.
+    pub async fn spawn(
+        credentials: Option<(&'static str, &'static str)>,
+    ) -> (SocketAddr, tokio::task::JoinHandle<Option<TargetAddr>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+
+            let mut header = [0u8; 2];
+            client.read_exact(&mut header).await.unwrap();
+            let mut methods = vec![0u8; header[1] as usize];
+            client.read_exact(&mut methods).await.unwrap();
+
+            match credentials {
+                Some((username, password)) => {
+                    assert!(methods.contains(&USERNAME_PASSWORD));
+                    client
+                        .write_all(&[SOCKS_VERSION, USERNAME_PASSWORD])
+                        .await
+                        .unwrap();
+                    let _version = client.read_u8().await.unwrap();
+                    let mut user = vec![0u8; client.read_u8().await.unwrap() as usize];
+                    client.read_exact(&mut user).await.unwrap();
+                    let mut pass = vec![0u8; client.read_u8().await.unwrap() as usize];
+                    client.read_exact(&mut pass).await.unwrap();
+                    let accepted = user == username.as_bytes() && pass == password.as_bytes();
+                    client.write_all(&[1, !accepted as u8]).await.unwrap();
+                    if !accepted {
+                        return None;
+                    }
+                }
+                None => client
+                    .write_all(&[SOCKS_VERSION, NO_AUTHENTICATION])
+                    .await
+                    .unwrap(),
+            }
+
+            let mut request = [0u8; 4];
+            client.read_exact(&mut request).await.unwrap();
+            let target = match request[3] {
+                ATYP_IPV4 => {
+                    let mut ip = [0u8; 4];
+                    client.read_exact(&mut ip).await.unwrap();
+                    let port = client.read_u16().await.unwrap();
+                    TargetAddr::Ip(SocketAddr::new(IpAddr::from(ip), port))
+                }
+                ATYP_DOMAIN => {
+                    let mut host = vec![0u8; client.read_u8().await.unwrap() as usize];
+                    client.read_exact(&mut host).await.unwrap();
+                    let port = client.read_u16().await.unwrap();
+                    TargetAddr::Domain(String::from_utf8(host).unwrap(), port)
+                }
+                _ => unimplemented!("test server only handles IPv4 and domains"),
+            };
+
+            // Hostnames always point to localhost in this test server
+            let upstream_addr = match &target {
+                TargetAddr::Ip(addr) => *addr,
+                TargetAddr::Domain(_, port) => SocketAddr::from(([127, 0, 0, 1], *port)),
+            };
+            let mut upstream = TcpStream::connect(upstream_addr).await.unwrap();
+            client
+                .write_all(&[SOCKS_VERSION, 0, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 0])
+                .await
+                .unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            Some(target)
+        });
+        (addr, handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn connect(proxy: &ProxyConfig, target: &TargetAddr) -> anyhow::Result<TcpStream> {
+        let stream = TcpStream::connect(&proxy.addr).await?;
+        connect_with(stream, proxy, target).await
+    }
+
+    async fn echo_once(listener: TcpListener) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_through_proxy_with_auth() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(echo_once(upstream));
+
+        let (proxy_addr, server) = test_server::spawn(Some(("user", "secret"))).await;
+        let proxy = ProxyConfig::parse(&format!("socks5://user:secret@{proxy_addr}")).unwrap();
+
+        let mut stream = connect(&proxy, &TargetAddr::Ip(upstream_addr))
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(stream);
+
+        assert_eq!(server.await.unwrap(), Some(TargetAddr::Ip(upstream_addr)));
+    }
+
+    #[tokio::test]
+    async fn hostnames_are_resolved_by_the_proxy() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        tokio::spawn(echo_once(upstream));
+
+        let (proxy_addr, server) = test_server::spawn(None).await;
+        let proxy = ProxyConfig::parse(&format!("socks5://{proxy_addr}")).unwrap();
+        let target = TargetAddr::parse(&format!("peer.invalid:{port}")).unwrap();
+
+        let mut stream = connect(&proxy, &target).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        drop(stream);
+
+        assert_eq!(
+            server.await.unwrap(),
+            Some(TargetAddr::Domain("peer.invalid".to_string(), port))
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_credentials_are_reported() {
+        let (proxy_addr, _server) = test_server::spawn(Some(("user", "secret"))).await;
+        let proxy = ProxyConfig::parse(&format!("socks5://user:wrong@{proxy_addr}")).unwrap();
+        let target = TargetAddr::parse("127.0.0.1:1").unwrap();
+
+        let error = connect(&proxy, &target).await.unwrap_err();
+        assert!(error.to_string().contains("rejected the credentials"));
+    }
+}
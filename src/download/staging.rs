@@ -0,0 +1,251 @@
+use crate::download::{have::Have, piece_map::PieceMap, storage::FileStorage};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+/*
+ * Downloads that are not complete yet can be kept apart from the finished ones: the files of a
+ * torrent are written into the incomplete directory, optionally with .part appended to their
+ * names, and each one is moved to the download directory once every piece it holds data of is
+ * verified. Within a file system that is a rename. To another one the file is copied next to its
+ * destination first and renamed there, either way a file shows up in the download directory
+ * whole. The file storage learns where a moved file went so that peers are still uploaded to
+ * from it. Directories of the torrent left empty by the moves are removed.
+ */
+
+pub const PART_SUFFIX: &str = ".part";
+
+pub struct Staging {
+    // directory of the torrent the incomplete files are in
+    directory: PathBuf,
+    files: Vec<StagedFile>,
+}
+
+struct StagedFile {
+    // where the file is downloaded to and where it goes once complete, the same for files that
+    // are not moved
+    incomplete: PathBuf,
+    complete: PathBuf,
+    length: usize,
+    // moved, or given up on moving
+    settled: bool,
+}
+
+impl Staging {
+    // Path and length of every file while incomplete and once complete, in torrent order. A file
+    // that is only in its complete place was moved by an earlier run.
+    pub fn new(
+        directory: PathBuf,
+        incomplete: Vec<(PathBuf, usize)>,
+        complete: Vec<(PathBuf, usize)>,
+    ) -> Staging {
+        let files = incomplete
+            .into_iter()
+            .zip(complete)
+            .map(|((incomplete, length), (complete, _))| StagedFile {
+                settled: incomplete == complete || (complete.exists() && !incomplete.exists()),
+                incomplete,
+                complete,
+                length,
+            })
+            .collect();
+        Staging { directory, files }
+    }
+
+    // Where every file is now, with its length.
+    pub fn files(&self) -> Vec<(PathBuf, usize)> {
+        self.files
+            .iter()
+            .map(|file| {
+                let path = if file.settled {
+                    &file.complete
+                } else {
+                    &file.incomplete
+                };
+                (path.clone(), file.length)
+            })
+            .collect()
+    }
+
+    // Nothing is left to move.
+    pub fn is_done(&self) -> bool {
+        self.files.iter().all(|file| file.settled)
+    }
+
+    // Moves the files as their last pieces arrive. Once finish is sent, or dropped, it moves what
+    // is complete by then and returns.
+    pub async fn run(
+        mut self,
+        piece_map: Arc<PieceMap>,
+        have: Arc<Have>,
+        storage: Arc<FileStorage>,
+        mut finish: oneshot::Receiver<()>,
+    ) {
+        let mut finishing = false;
+        loop {
+            let changed = have.changed();
+            let (piece_map, moved_have, storage) =
+                (piece_map.clone(), have.clone(), storage.clone());
+            // copies to another file system take a while
+            self = match tokio::task::spawn_blocking(move || {
+                self.move_complete(&piece_map, &moved_have, &storage);
+                self
+            })
+            .await
+            {
+                Ok(staging) => staging,
+                Err(e) => {
+                    warn!("moving completed files failed: {e}");
+                    return;
+                }
+            };
+            if finishing || self.is_done() {
+                return;
+            }
+            tokio::select! {
+                _ = changed => {}
+                _ = &mut finish => finishing = true,
+            }
+        }
+    }
+
+    // Moves the files whose pieces are all verified. The piece map names the files by their
+    // incomplete paths.
+    fn move_complete(&mut self, piece_map: &PieceMap, have: &Have, storage: &FileStorage) {
+        for (file_index, file) in self.files.iter_mut().enumerate() {
+            if file.settled {
+                continue;
+            }
+            let start = piece_map.file_start(file_index);
+            if !piece_map
+                .pieces_for_range(start..start + file.length)
+                .all(|piece_index| have.has(piece_index))
+            {
+                continue;
+            }
+            file.settled = true;
+            // a skipped file no wanted piece touches was never created
+            if !file.incomplete.exists() {
+                continue;
+            }
+            match move_file(storage, &file.incomplete, &file.complete) {
+                Ok(()) => {
+                    info!(
+                        "Moved {} to {}",
+                        file.incomplete.display(),
+                        file.complete.display()
+                    );
+                    for directory in file.incomplete.ancestors().skip(1) {
+                        if !directory.starts_with(&self.directory)
+                            || fs::remove_dir(directory).is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => warn!(
+                    "could not move {} to {}, leaving it: {e}",
+                    file.incomplete.display(),
+                    file.complete.display()
+                ),
+            }
+        }
+    }
+}
+
+// Renames the file, or copies it when from and to are on different file systems.
+fn move_file(storage: &FileStorage, from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // the paths came from the piece map, they are UTF-8
+    let (from_key, to_key) = (from.to_str().unwrap(), to.to_str().unwrap());
+    if storage
+        .relocate(from_key, to_key, || fs::rename(from, to))
+        .is_ok()
+    {
+        return Ok(());
+    }
+    let mut copy = to.as_os_str().to_os_string();
+    copy.push(".moving");
+    let copy = PathBuf::from(copy);
+    let copied = fs::copy(from, &copy)
+        .and_then(|_| storage.relocate(from_key, to_key, || fs::rename(&copy, to)));
+    if copied.is_err() {
+        let _ = fs::remove_file(&copy);
+    }
+    copied?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::storage::Storage;
+
+    #[tokio::test]
+    async fn complete_files_are_moved() {
+        let incomplete = tempfile::tempdir().unwrap();
+        let complete = tempfile::tempdir().unwrap();
+        let files = |dir: &Path, suffix: &str| {
+            vec![
+                (dir.join(format!("a{suffix}")), 10),
+                (dir.join("sub").join(format!("b{suffix}")), 10),
+                (dir.join(format!("empty{suffix}")), 0),
+            ]
+        };
+        let staging = Staging::new(
+            incomplete.path().join("torrent"),
+            files(&incomplete.path().join("torrent"), PART_SUFFIX),
+            files(complete.path(), ""),
+        );
+        let paths = staging.files();
+        fs::create_dir_all(incomplete.path().join("torrent/sub")).unwrap();
+        for (path, length) in &paths {
+            fs::write(path, vec![7; *length]).unwrap();
+        }
+        let piece_map = Arc::new(PieceMap::new(
+            8,
+            paths
+                .iter()
+                .map(|(path, length)| (path.to_str().unwrap().to_string(), *length))
+                .collect(),
+        ));
+        // piece 1 holds data of both files
+        let have = Arc::new(Have::new(3, &[1, 2]));
+        let storage = Arc::new(FileStorage::default());
+        let (finish, finished) = oneshot::channel();
+        let task =
+            tokio::spawn(staging.run(piece_map.clone(), have.clone(), storage.clone(), finished));
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(complete.path().join("empty").exists());
+        assert!(!complete.path().join("a").exists());
+        have.set(1);
+        have.set(2);
+        finish.send(()).unwrap();
+        task.await.unwrap();
+
+        assert_eq!(fs::read(complete.path().join("a")).unwrap(), [7; 10]);
+        assert_eq!(fs::read(complete.path().join("sub/b")).unwrap(), [7; 10]);
+        assert!(!paths[0].0.exists());
+        // the directory of the torrent is left empty
+        assert!(!incomplete.path().join("torrent").exists());
+        // still reachable under the old path
+        let mut buf = [0; 10];
+        assert_eq!(storage.read_at(piece_map.path(1), 0, &mut buf).unwrap(), 10);
+
+        // the next run finds them moved
+        let staging = Staging::new(
+            incomplete.path().join("torrent"),
+            files(&incomplete.path().join("torrent"), PART_SUFFIX),
+            files(complete.path(), ""),
+        );
+        assert!(staging.is_done());
+        assert_eq!(staging.files(), files(complete.path(), ""));
+    }
+}
@@ -0,0 +1,955 @@
+use crate::config::{DiskBackend, DEFAULT_MAX_OPEN_FILES};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tracing::warn;
+
+// Positioned access to the files of a torrent. The disk writer only talks to this trait so that
+// tests can swap the file system for an in-memory backend.
+pub trait Storage: Send + Sync {
+    // Writes data at offset, returns how many bytes were written which may be less than data.
+    fn write_at(&self, path: &str, offset: u64, data: &[u8]) -> io::Result<usize>;
+
+    // Reads into buf from offset, returns how many bytes were read.
+    fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    // Writes every segment whole, backends that can submit them together do. The index of a
+    // segment that could not be written comes with the error.
+    fn write_segments(&self, segments: &[Segment]) -> Result<(), (usize, io::Error)> {
+        write_each(self, segments)
+    }
+
+    // Makes everything written to path so far durable.
+    fn sync(&self, path: &str) -> io::Result<()>;
+
+    // When the file was last modified, None if the backend cannot tell.
+    fn modified(&self, _path: &str) -> io::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+}
+
+// Data to write to the file at path, at offset.
+pub struct Segment<'a> {
+    pub path: &'a str,
+    pub offset: u64,
+    pub data: &'a [u8],
+}
+
+// Storage on the local file system, the files are expected to exist (see reserve_space).
+//
+// Handles are kept open between calls but at most max_open of them: opening one more closes the
+// least recently used. Nothing is lost by closing a handle, written data is with the OS already
+// and a later sync through a new handle makes it durable all the same.
+//
+// A file can be moved while it is in use, see relocate. It is still named by its old path, the
+// new one is only used to open it.
+//
+// With the mmap backend every open file is also mapped into memory, reads and writes are copies
+// from and to the mapping instead of system calls. What lies past the length the file had when it
+// was opened, or past the length it has now, or a file that cannot be mapped, is accessed with
+// positioned reads and writes.
+//
+// The lock of the handles is only held to look a file up or open it, the reads and writes go
+// through a shared reference to it. A handle closed meanwhile stays open until they are done.
+//
+// With the io-uring backend the positioned reads and writes and the syncs go through the ring of
+// the thread, see uring, and segments written together are submitted together. Without a ring,
+// when the feature is not built in, off Linux or on a kernel that has none, the files are read and
+// written as usual.
+pub struct FileStorage {
+    max_open: usize,
+    mmap: bool,
+    uring: bool,
+    handles: Mutex<Handles>,
+}
+
+// An open file and its mapping.
+#[derive(Clone)]
+struct OpenFile {
+    file: Arc<File>,
+    mapping: Option<Arc<mapped::Mapping>>,
+}
+
+#[derive(Default)]
+struct Handles {
+    // handle and when it was last used
+    open: HashMap<String, (OpenFile, u64)>,
+    uses: u64,
+    // most handles that were open at the same time
+    peak: usize,
+    // old path of every moved file and where it is now
+    relocated: HashMap<String, String>,
+}
+
+impl Default for FileStorage {
+    fn default() -> Self {
+        FileStorage::new(DEFAULT_MAX_OPEN_FILES)
+    }
+}
+
+impl FileStorage {
+    pub fn new(max_open: usize) -> FileStorage {
+        FileStorage {
+            max_open: max_open.max(1),
+            mmap: false,
+            uring: false,
+            handles: Mutex::new(Handles::default()),
+        }
+    }
+
+    pub fn with_backend(self, backend: DiskBackend) -> FileStorage {
+        let mmap = backend == DiskBackend::Mmap;
+        if mmap && !mapped::SUPPORTED {
+            warn!("memory mapped files are only used on 64-bit Unix, reading and writing files");
+        }
+        let uring = backend == DiskBackend::IoUring
+            && uring::Ring::new()
+                .inspect_err(|e| {
+                    warn!("io_uring is not available ({e}), reading and writing files")
+                })
+                .is_ok();
+        FileStorage {
+            mmap: mmap && mapped::SUPPORTED,
+            uring,
+            ..self
+        }
+    }
+
+    // Runs f with the ring of this thread with the io-uring backend, None without one.
+    fn ring<T>(&self, f: impl FnOnce(&mut uring::Ring) -> T) -> Option<T> {
+        if self.uring {
+            uring::with_ring(f)
+        } else {
+            None
+        }
+    }
+
+    // Most files that were open at the same time.
+    pub fn peak_open_files(&self) -> usize {
+        self.handles.lock().unwrap().peak
+    }
+
+    // Moves the file at from to to with rename, no read or write of it starts meanwhile. Accesses
+    // to from go to the file at to afterwards.
+    pub fn relocate(
+        &self,
+        from: &str,
+        to: &str,
+        rename: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut handles = self.handles.lock().unwrap();
+        // an open file cannot be renamed on Windows
+        handles.open.remove(from);
+        rename()?;
+        handles.relocated.insert(from.to_string(), to.to_string());
+        Ok(())
+    }
+
+    // Where the file at path is now.
+    pub fn location(&self, path: &str) -> String {
+        let handles = self.handles.lock().unwrap();
+        handles
+            .relocated
+            .get(path)
+            .map_or(path, String::as_str)
+            .to_string()
+    }
+
+    // The paths are used as they are again, e.g. by a later run of the torrent that found its
+    // files in their new place.
+    pub fn forget_relocations(&self, paths: impl IntoIterator<Item = impl AsRef<str>>) {
+        let mut handles = self.handles.lock().unwrap();
+        for path in paths {
+            if handles.relocated.remove(path.as_ref()).is_some() {
+                handles.open.remove(path.as_ref());
+            }
+        }
+    }
+
+    fn handle(&self, path: &str) -> io::Result<OpenFile> {
+        let mut handles = self.handles.lock().unwrap();
+        handles.uses += 1;
+        let uses = handles.uses;
+        if let Some((file, last_used)) = handles.open.get_mut(path) {
+            *last_used = uses;
+            return Ok(file.clone());
+        }
+
+        if handles.open.len() >= self.max_open {
+            let least_recently_used = handles
+                .open
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(path) = least_recently_used {
+                handles.open.remove(&path);
+            }
+        }
+        let location = handles.relocated.get(path).map_or(path, String::as_str);
+        let file = OpenOptions::new().read(true).write(true).open(location)?;
+        // a file that cannot be mapped is read and written all the same
+        let mapping = if self.mmap {
+            mapped::Mapping::new(&file).ok().flatten().map(Arc::new)
+        } else {
+            None
+        };
+        let file = OpenFile {
+            file: Arc::new(file),
+            mapping,
+        };
+        handles.open.insert(path.to_string(), (file.clone(), uses));
+        handles.peak = handles.peak.max(handles.open.len());
+        Ok(file)
+    }
+}
+
+impl OpenFile {
+    // The mapping if it holds the byte at offset, with how many bytes from there on it holds. A
+    // file truncated by someone else since it was mapped would raise SIGBUS past its new end, so
+    // the mapping is only used up to the length the file has now.
+    fn mapped(&self, offset: u64) -> io::Result<Option<(&mapped::Mapping, usize)>> {
+        let Some(mapping) = &self.mapping else {
+            return Ok(None);
+        };
+        let end = (mapping.len() as u64).min(self.file.metadata()?.len());
+        Ok((offset < end).then(|| (mapping.as_ref(), (end - offset) as usize)))
+    }
+}
+
+impl Storage for FileStorage {
+    fn write_at(&self, path: &str, offset: u64, data: &[u8]) -> io::Result<usize> {
+        let file = self.handle(path)?;
+        match file.mapped(offset)? {
+            Some((mapping, len)) => {
+                Ok(mapping.write_at(offset as usize, &data[..data.len().min(len)]))
+            }
+            None => self
+                .ring(|ring| ring.write_at(&file.file, data, offset))
+                .unwrap_or_else(|| positioned::write_at(&file.file, data, offset)),
+        }
+    }
+
+    fn write_segments(&self, segments: &[Segment]) -> Result<(), (usize, io::Error)> {
+        if !self.uring {
+            return write_each(self, segments);
+        }
+        let files = segments
+            .iter()
+            .enumerate()
+            .map(|(index, segment)| self.handle(segment.path).map_err(|e| (index, e)))
+            .collect::<Result<Vec<OpenFile>, _>>()?;
+        let writes: Vec<(&File, u64, &[u8])> = files
+            .iter()
+            .zip(segments)
+            .map(|(file, segment)| (file.file.as_ref(), segment.offset, segment.data))
+            .collect();
+        uring::with_ring(|ring| ring.write_all_at(&writes))
+            .unwrap_or_else(|| write_each(self, segments))
+    }
+
+    fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let file = self.handle(path)?;
+        match file.mapped(offset)? {
+            Some((mapping, len)) => {
+                let len = buf.len().min(len);
+                Ok(mapping.read_at(offset as usize, &mut buf[..len]))
+            }
+            None => self
+                .ring(|ring| ring.read_at(&file.file, buf, offset))
+                .unwrap_or_else(|| positioned::read_at(&file.file, buf, offset)),
+        }
+    }
+
+    fn sync(&self, path: &str) -> io::Result<()> {
+        let file = self.handle(path)?;
+        if let Some(mapping) = &file.mapping {
+            mapping.sync()?;
+        }
+        self.ring(|ring| ring.sync(&file.file))
+            .unwrap_or_else(|| file.file.sync_data())
+    }
+
+    // of the file at the path, not of an open handle that may be of a file replaced since
+    fn modified(&self, path: &str) -> io::Result<Option<SystemTime>> {
+        fs::metadata(self.location(path))?.modified().map(Some)
+    }
+}
+
+// Reserves the first length bytes of the file on disk, through the ring of the thread with the
+// io-uring backend if there is one to be had.
+pub fn allocate(file: &File, length: u64, backend: DiskBackend) -> io::Result<()> {
+    if backend == DiskBackend::IoUring {
+        if let Some(allocated) = uring::with_ring(|ring| ring.allocate(file, length)) {
+            return allocated;
+        }
+    }
+    fs4::FileExt::allocate(file, length)
+}
+
+// Writes the segments one after the other, each of them whole.
+fn write_each<S: Storage + ?Sized>(
+    storage: &S,
+    segments: &[Segment],
+) -> Result<(), (usize, io::Error)> {
+    for (index, segment) in segments.iter().enumerate() {
+        let (mut offset, mut data) = (segment.offset, segment.data);
+        while !data.is_empty() {
+            match storage.write_at(segment.path, offset, data) {
+                Ok(0) => {
+                    let error = io::Error::new(io::ErrorKind::WriteZero, "disk accepted no data");
+                    return Err((index, error));
+                }
+                Ok(written) => {
+                    data = &data[written..];
+                    offset += written as u64;
+                }
+                Err(e) => return Err((index, e)),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Positioned reads and writes with what the platform offers: pread/pwrite on Unix, which leave
+// the file cursor alone, and seek_read/seek_write on Windows, which move it. Nothing relies on
+// the cursor since every access names its offset.
+#[cfg(unix)]
+mod positioned {
+    use std::{fs::File, io, os::unix::fs::FileExt};
+
+    pub fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+        file.write_at(data, offset)
+    }
+
+    pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        file.read_at(buf, offset)
+    }
+}
+
+#[cfg(windows)]
+mod positioned {
+    use std::{fs::File, io, os::windows::fs::FileExt};
+
+    pub fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+        file.seek_write(data, offset)
+    }
+
+    pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        file.seek_read(buf, offset)
+    }
+}
+
+// A file mapped into memory shared with the file, writes to it land in the file. Calls may read
+// and write it at the same time, the blocks of a piece are not written twice and a piece is only
+// read once it is written.
+#[cfg(all(unix, target_pointer_width = "64"))]
+mod mapped {
+    use memmap2::{MmapOptions, MmapRaw};
+    use std::{fs::File, io, ptr};
+
+    pub const SUPPORTED: bool = true;
+
+    pub struct Mapping(MmapRaw);
+
+    impl Mapping {
+        // None for an empty file, there is nothing to map.
+        pub fn new(file: &File) -> io::Result<Option<Mapping>> {
+            if file.metadata()?.len() == 0 {
+                return Ok(None);
+            }
+            Ok(Some(Mapping(MmapOptions::new().map_raw(file)?)))
+        }
+
+        pub fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        // Copies what fits before the end of the mapping, returns how much that was.
+        pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+            let len = buf.len().min(self.len().saturating_sub(offset));
+            // SAFETY: offset..offset + len is within the mapping
+            unsafe { ptr::copy_nonoverlapping(self.0.as_ptr().add(offset), buf.as_mut_ptr(), len) };
+            len
+        }
+
+        pub fn write_at(&self, offset: usize, data: &[u8]) -> usize {
+            let len = data.len().min(self.len().saturating_sub(offset));
+            // SAFETY: offset..offset + len is within the mapping
+            unsafe {
+                ptr::copy_nonoverlapping(data.as_ptr(), self.0.as_mut_ptr().add(offset), len)
+            };
+            len
+        }
+
+        pub fn sync(&self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+}
+
+// Nothing is mapped elsewhere.
+#[cfg(not(all(unix, target_pointer_width = "64")))]
+mod mapped {
+    use std::{fs::File, io};
+
+    pub const SUPPORTED: bool = false;
+
+    pub enum Mapping {}
+
+    impl Mapping {
+        pub fn new(_file: &File) -> io::Result<Option<Mapping>> {
+            Ok(None)
+        }
+
+        pub fn len(&self) -> usize {
+            match *self {}
+        }
+
+        pub fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> usize {
+            match *self {}
+        }
+
+        pub fn write_at(&self, _offset: usize, _data: &[u8]) -> usize {
+            match *self {}
+        }
+
+        pub fn sync(&self) -> io::Result<()> {
+            match *self {}
+        }
+    }
+}
+
+// The io_uring of the io-uring crate. Every thread that reads or writes files gets a ring of its
+// own, the disk task of a download keeps one for as long as it runs, so no lock is taken around
+// it. The operations of a call, e.g. the writes of a piece to each of its files, are submitted
+// together and all waited for before it returns: the kernel gets the buffers for the duration of
+// the call only. Kernels before 5.6 cannot read and write through a ring and are left to the usual
+// path, as is a thread whose ring broke.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring {
+    use io_uring::{
+        opcode, squeue,
+        types::{Fd, FsyncFlags},
+        IoUring,
+    };
+    use std::{cell::RefCell, fs::File, io, os::fd::AsRawFd};
+    use tracing::warn;
+
+    const ENTRIES: u32 = 32;
+    // IORING_ENTER_GETEVENTS of linux/io_uring.h
+    const ENTER_GETEVENTS: u32 = 1;
+
+    pub struct Ring {
+        ring: IoUring,
+        // operations that completed
+        completed: u64,
+        // the kernel did not take operations that cannot be taken back out of the ring, so it is
+        // not used again
+        broken: bool,
+    }
+
+    thread_local! {
+        // the ring of the thread, None once setting it up failed or it broke
+        static RING: RefCell<Option<Option<Ring>>> = const { RefCell::new(None) };
+    }
+
+    impl Ring {
+        pub fn new() -> io::Result<Ring> {
+            let ring = IoUring::new(ENTRIES)?;
+            if !ring.params().is_feature_rw_cur_pos() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the kernel cannot read and write files through io_uring",
+                ));
+            }
+            Ok(Ring {
+                ring,
+                completed: 0,
+                broken: false,
+            })
+        }
+
+        pub fn read_at(&mut self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let read = opcode::Read::new(Fd(file.as_raw_fd()), buf.as_mut_ptr(), len(buf))
+                .offset(offset)
+                .build();
+            // SAFETY: buf is borrowed until the read is done
+            unsafe { self.run_one(read) }.map(|read| read as usize)
+        }
+
+        pub fn write_at(&mut self, file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+            // SAFETY: data is borrowed until the write is done
+            unsafe { self.run_one(write(file, data, offset)) }.map(|written| written as usize)
+        }
+
+        // Writes every buffer whole at its offset, the writes are submitted together and what is
+        // left of short ones after them. The index of a write that failed comes with its error.
+        pub fn write_all_at(
+            &mut self,
+            writes: &[(&File, u64, &[u8])],
+        ) -> Result<(), (usize, io::Error)> {
+            let mut left: Vec<(u64, &[u8])> = writes
+                .iter()
+                .map(|&(_, offset, data)| (offset, data))
+                .collect();
+            loop {
+                let pending: Vec<usize> = (0..writes.len())
+                    .filter(|&index| !left[index].1.is_empty())
+                    .collect();
+                if pending.is_empty() {
+                    return Ok(());
+                }
+                let operations: Vec<squeue::Entry> = pending
+                    .iter()
+                    .map(|&index| write(writes[index].0, left[index].1, left[index].0))
+                    .collect();
+                // SAFETY: the buffers are borrowed from writes until every write is done
+                let results = unsafe { self.run(&operations) };
+                for (&index, result) in pending.iter().zip(results) {
+                    match result {
+                        Ok(0) => {
+                            let error =
+                                io::Error::new(io::ErrorKind::WriteZero, "disk accepted no data");
+                            return Err((index, error));
+                        }
+                        Ok(written) => {
+                            let (offset, data) = &mut left[index];
+                            *offset += u64::from(written);
+                            *data = &data[written as usize..];
+                        }
+                        Err(e) => return Err((index, e)),
+                    }
+                }
+            }
+        }
+
+        pub fn sync(&mut self, file: &File) -> io::Result<()> {
+            let sync = opcode::Fsync::new(Fd(file.as_raw_fd()))
+                .flags(FsyncFlags::DATASYNC)
+                .build();
+            // SAFETY: no memory is handed to the kernel
+            unsafe { self.run_one(sync) }.map(|_| ())
+        }
+
+        pub fn allocate(&mut self, file: &File, length: u64) -> io::Result<()> {
+            let allocate = opcode::Fallocate::new(Fd(file.as_raw_fd()), length).build();
+            // SAFETY: no memory is handed to the kernel
+            unsafe { self.run_one(allocate) }.map(|_| ())
+        }
+
+        // SAFETY: what the operation points to has to stay valid until it returns
+        unsafe fn run_one(&mut self, operation: squeue::Entry) -> io::Result<u32> {
+            let [result] = <[_; 1]>::try_from(self.run(&[operation]))
+                .unwrap_or_else(|_| unreachable!("one operation has one result"));
+            result
+        }
+
+        // Submits the operations, as many at a time as the ring holds, and waits for every one of
+        // them. The results are in the order of the operations.
+        //
+        // SAFETY: what the operations point to has to stay valid until it returns
+        unsafe fn run(&mut self, operations: &[squeue::Entry]) -> Vec<io::Result<u32>> {
+            let mut results: Vec<Option<io::Result<u32>>> =
+                operations.iter().map(|_| None).collect();
+            let entries = self.ring.params().sq_entries() as usize;
+            for (batch, chunk) in operations.chunks(entries).enumerate() {
+                self.run_batch(chunk, batch * entries, &mut results);
+            }
+            results
+                .into_iter()
+                .map(|result| result.unwrap_or_else(|| unreachable!("every operation completes")))
+                .collect()
+        }
+
+        // Submits no more operations than the ring holds, the first of them is number first, and
+        // waits for all of them.
+        unsafe fn run_batch(
+            &mut self,
+            batch: &[squeue::Entry],
+            first: usize,
+            results: &mut [Option<io::Result<u32>>],
+        ) {
+            let end = first + batch.len();
+            if self.broken {
+                for result in &mut results[first..end] {
+                    *result = Some(Err(io::Error::other("the io_uring of the thread broke")));
+                }
+                return;
+            }
+            {
+                let mut submission = self.ring.submission();
+                for (number, operation) in (first..).zip(batch) {
+                    // every earlier batch was waited for, the ring is empty
+                    submission
+                        .push(&operation.clone().user_data(number as u64))
+                        .unwrap_or_else(|_| unreachable!("the batch fits in the ring"));
+                }
+            }
+
+            let mut in_flight = batch.len();
+            while in_flight > 0 {
+                let entered = if self.broken {
+                    // waits without submitting what is left in the ring
+                    self.ring
+                        .submitter()
+                        .enter::<()>(0, 1, ENTER_GETEVENTS, None)
+                } else {
+                    self.ring.submit_and_wait(1)
+                };
+                if let Err(error) = entered {
+                    let unsubmitted = if self.broken {
+                        0
+                    } else {
+                        self.ring.submission().len()
+                    };
+                    // once the kernel has operations it may still use their buffers, so they are
+                    // waited for no matter what, and it may be short of resources until some of
+                    // them complete
+                    let busy = matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::ResourceBusy
+                    ) && in_flight > unsubmitted;
+                    if error.kind() != io::ErrorKind::Interrupted && !busy && unsubmitted > 0 {
+                        // the operations the kernel did not take fail with the error
+                        for result in &mut results[end - unsubmitted..end] {
+                            *result = Some(Err(io::Error::new(error.kind(), error.to_string())));
+                        }
+                        in_flight -= unsubmitted;
+                        self.broken = true;
+                    }
+                }
+                for completion in self.ring.completion() {
+                    results[completion.user_data() as usize] = Some(if completion.result() < 0 {
+                        Err(io::Error::from_raw_os_error(-completion.result()))
+                    } else {
+                        Ok(completion.result() as u32)
+                    });
+                    self.completed += 1;
+                    in_flight -= 1;
+                }
+            }
+        }
+    }
+
+    fn write(file: &File, data: &[u8], offset: u64) -> squeue::Entry {
+        opcode::Write::new(Fd(file.as_raw_fd()), data.as_ptr(), len(data))
+            .offset(offset)
+            .build()
+    }
+
+    // What one operation reads or writes of buf at most.
+    fn len(buf: &[u8]) -> u32 {
+        buf.len().min(u32::MAX as usize) as u32
+    }
+
+    // Runs f with the ring of this thread, which is set up the first time. None when there is no
+    // ring to be had.
+    pub fn with_ring<T>(f: impl FnOnce(&mut Ring) -> T) -> Option<T> {
+        RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+            let ring = ring.get_or_insert_with(|| Ring::new().ok());
+            let result = ring.as_mut().map(f);
+            if ring.as_ref().is_some_and(|ring| ring.broken) {
+                warn!("io_uring broke, reading and writing files on this thread");
+                *ring = None;
+            }
+            result
+        })
+    }
+
+    // Operations the ring of this thread completed.
+    #[cfg(test)]
+    pub fn completed() -> u64 {
+        RING.with(|ring| {
+            ring.borrow()
+                .as_ref()
+                .and_then(Option::as_ref)
+                .map_or(0, |ring| ring.completed)
+        })
+    }
+}
+
+// Without the feature, or off Linux, there is never a ring.
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+mod uring {
+    use std::{fs::File, io};
+
+    pub enum Ring {}
+
+    pub fn with_ring<T>(_f: impl FnOnce(&mut Ring) -> T) -> Option<T> {
+        None
+    }
+
+    #[cfg(test)]
+    pub fn completed() -> u64 {
+        0
+    }
+
+    impl Ring {
+        pub fn new() -> io::Result<Ring> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring is only built in on Linux with the io-uring feature",
+            ))
+        }
+
+        pub fn read_at(
+            &mut self,
+            _file: &File,
+            _buf: &mut [u8],
+            _offset: u64,
+        ) -> io::Result<usize> {
+            match *self {}
+        }
+
+        pub fn write_at(&mut self, _file: &File, _data: &[u8], _offset: u64) -> io::Result<usize> {
+            match *self {}
+        }
+
+        pub fn write_all_at(
+            &mut self,
+            _writes: &[(&File, u64, &[u8])],
+        ) -> Result<(), (usize, io::Error)> {
+            match *self {}
+        }
+
+        pub fn sync(&mut self, _file: &File) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub fn allocate(&mut self, _file: &File, _length: u64) -> io::Result<()> {
+            match *self {}
+        }
+    }
+}
+
+// Elsewhere seek and then read or write, under a lock of their own so no one moves the cursor
+// in between.
+#[cfg(not(any(unix, windows)))]
+mod positioned {
+    use std::{
+        fs::File,
+        io::{self, Read, Seek, SeekFrom, Write},
+        sync::Mutex,
+    };
+
+    static CURSOR: Mutex<()> = Mutex::new(());
+
+    pub fn write_at(mut file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+        let _cursor = CURSOR.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write(data)
+    }
+
+    pub fn read_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let _cursor = CURSOR.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read(buf)
+    }
+}
+
+#[cfg(test)]
+pub mod test_backend {
+    use super::*;
+
+    // Files kept in memory. With short_write set every write silently drops that many bytes
+    // at its end while still reporting success, like a misbehaving disk.
+    #[derive(Default)]
+    pub struct MemoryStorage {
+        pub files: Mutex<HashMap<String, Vec<u8>>>,
+        pub short_write: usize,
+        pub writes: Mutex<usize>,
+        pub syncs: Mutex<usize>,
+    }
+
+    impl Storage for MemoryStorage {
+        fn write_at(&self, path: &str, offset: u64, data: &[u8]) -> io::Result<usize> {
+            *self.writes.lock().unwrap() += 1;
+            let mut files = self.files.lock().unwrap();
+            let file = files.entry(path.to_string()).or_default();
+            let offset = offset as usize;
+            let stored = &data[..data.len().saturating_sub(self.short_write)];
+            if file.len() < offset + data.len() {
+                file.resize(offset + data.len(), 0);
+            }
+            file[offset..offset + stored.len()].copy_from_slice(stored);
+            Ok(data.len())
+        }
+
+        fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let files = self.files.lock().unwrap();
+            let file = files.get(path).map(Vec::as_slice).unwrap_or_default();
+            let start = (offset as usize).min(file.len());
+            let len = buf.len().min(file.len() - start);
+            buf[..len].copy_from_slice(&file[start..start + len]);
+            Ok(len)
+        }
+
+        fn sync(&self, _path: &str) -> io::Result<()> {
+            *self.syncs.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_files_are_capped() {
+        let directory = tempfile::tempdir().unwrap();
+        let paths: Vec<String> = (0..1000)
+            .map(|i| {
+                let path = directory.path().join(format!("image{i:04}.png"));
+                File::create(&path).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect();
+
+        let storage = FileStorage::new(8);
+        for (i, path) in paths.iter().enumerate() {
+            storage.write_at(path, 0, &i.to_be_bytes()).unwrap();
+            // the previous file is still open
+            if i > 0 {
+                storage.sync(&paths[i - 1]).unwrap();
+            }
+        }
+        for (i, path) in paths.iter().enumerate().rev() {
+            let mut buf = [0; 8];
+            assert_eq!(storage.read_at(path, 0, &mut buf).unwrap(), 8);
+            assert_eq!(usize::from_be_bytes(buf), i);
+        }
+        assert_eq!(storage.peak_open_files(), 8);
+    }
+
+    #[test]
+    fn writes_land_at_their_offsets() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file");
+        std::fs::write(&path, [0; 10]).unwrap();
+        let path = path.to_str().unwrap();
+
+        let storage = FileStorage::default();
+        assert_eq!(storage.write_at(path, 6, b"end").unwrap(), 3);
+        assert_eq!(storage.write_at(path, 1, b"mid").unwrap(), 3);
+        let mut buf = [0; 3];
+        assert_eq!(storage.read_at(path, 1, &mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"mid");
+        assert_eq!(std::fs::read(path).unwrap(), b"\0mid\0\0end\0");
+    }
+
+    #[test]
+    fn mapped_files_are_read_and_written() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file");
+        std::fs::write(&path, [0; 10]).unwrap();
+        let path = path.to_str().unwrap();
+
+        let storage = FileStorage::default().with_backend(DiskBackend::Mmap);
+        assert_eq!(storage.write_at(path, 6, b"end").unwrap(), 3);
+        assert_eq!(storage.write_at(path, 1, b"mid").unwrap(), 3);
+        // past the mapped length the file grows as usual
+        assert_eq!(storage.write_at(path, 10, b"!").unwrap(), 1);
+        storage.sync(path).unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(storage.read_at(path, 1, &mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"mid");
+        assert_eq!(std::fs::read(path).unwrap(), b"\0mid\0\0end\0!");
+    }
+
+    #[test]
+    fn truncated_mapped_files_are_read_up_to_their_end() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let path = path.to_str().unwrap();
+
+        let storage = FileStorage::default().with_backend(DiskBackend::Mmap);
+        let mut buf = [0; 4];
+        assert_eq!(storage.read_at(path, 0, &mut buf).unwrap(), 4);
+        // someone else cuts the file while it is mapped
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(5)
+            .unwrap();
+        assert_eq!(storage.read_at(path, 3, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"34");
+        assert_eq!(storage.read_at(path, 6, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(all(feature = "io-uring", target_os = "linux")),
+        ignore = "io_uring is not built in"
+    )]
+    fn files_are_read_and_written_through_io_uring() {
+        // where the kernel refuses a ring there is nothing to test
+        if uring::with_ring(|_| ()).is_none() {
+            return;
+        }
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file");
+        let other_path = directory.path().join("other");
+        let file = File::create(&path).unwrap();
+        File::create(&other_path).unwrap();
+        allocate(&file, 10, DiskBackend::IoUring).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 10);
+        let allocated = uring::completed();
+        assert!(allocated > 0);
+        let (path, other_path) = (path.to_str().unwrap(), other_path.to_str().unwrap());
+
+        let storage = FileStorage::default().with_backend(DiskBackend::IoUring);
+        assert_eq!(storage.write_at(path, 6, b"end").unwrap(), 3);
+        // a piece over both files goes out as one batch
+        let segments = [
+            Segment {
+                path,
+                offset: 1,
+                data: b"mid",
+            },
+            Segment {
+                path: other_path,
+                offset: 2,
+                data: b"start",
+            },
+        ];
+        storage.write_segments(&segments).unwrap();
+        storage.sync(path).unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(storage.read_at(path, 7, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"nd\0");
+        assert_eq!(uring::completed(), allocated + 5);
+        assert_eq!(std::fs::read(path).unwrap(), b"\0mid\0\0end\0");
+        assert_eq!(std::fs::read(other_path).unwrap(), b"\0\0start");
+    }
+
+    #[test]
+    fn relocated_files_are_used_under_their_old_path() {
+        let directory = tempfile::tempdir().unwrap();
+        let from = directory.path().join("file.part");
+        let to = directory.path().join("file");
+        std::fs::write(&from, b"data").unwrap();
+        let (from, to) = (from.to_str().unwrap(), to.to_str().unwrap());
+
+        let storage = FileStorage::default();
+        let mut buf = [0; 4];
+        storage.read_at(from, 0, &mut buf).unwrap();
+        storage
+            .relocate(from, to, || std::fs::rename(from, to))
+            .unwrap();
+        assert_eq!(storage.location(from), to);
+        storage.write_at(from, 0, b"DA").unwrap();
+        assert_eq!(std::fs::read(to).unwrap(), b"DAta");
+
+        storage.forget_relocations([from]);
+        assert_eq!(storage.location(from), from);
+        assert!(storage.read_at(from, 0, &mut buf).is_err());
+    }
+}
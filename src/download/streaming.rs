@@ -0,0 +1,425 @@
+use crate::download::{disk_io::DiskIo, have::Have, piece_map::PieceMap, piece_queue::PieceQueue};
+use anyhow::Context;
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use std::{convert::Infallible, net::SocketAddr, ops::Range, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/*
+ * Serves the files of a torrent over HTTP while it downloads, e.g. for a media player pointed at
+ * http://127.0.0.1:PORT/0 (the file index) or http://127.0.0.1:PORT/dir/movie.mkv (the path of
+ * the file in the torrent), / lists the files. Range requests are supported. Bytes that are not
+ * on disk yet are waited for. As a response is sent, the pieces just after the offset it reached
+ * are moved to the front of the download queue, so a player that seeks gets the pieces of its
+ * new position first.
+*/
+
+// Largest chunk read from disk and sent at once.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+// Bytes after the read offset whose pieces are moved to the front of the queue.
+pub const READAHEAD: usize = 16 * 1024 * 1024;
+
+pub struct StreamContext {
+    pub piece_map: Arc<PieceMap>,
+    // paths of the files within the torrent, indexed like the files of the piece map
+    pub file_names: Vec<String>,
+    // reads go through the disk task, off the runtime
+    pub disk_io: DiskIo,
+    pub have: Arc<Have>,
+    // the pieces nobody downloads yet
+    pub pieces: PieceQueue,
+    // how long a request waits for a missing piece before it is answered with 503
+    pub wait_timeout: Duration,
+    pub readahead: usize,
+}
+
+pub fn spawn(
+    addr: SocketAddr,
+    context: StreamContext,
+) -> anyhow::Result<(SocketAddr, JoinHandle<()>)> {
+    let context = Arc::new(context);
+    let make_service = make_service_fn(move |_| {
+        let context = context.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let context = context.clone();
+                async move { Ok::<_, Infallible>(respond(context, request).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .with_context(|| format!("Binding streaming server to {addr}"))?
+        .serve(make_service);
+    let local_addr = server.local_addr();
+    let handle = tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("streaming server stopped: {e}");
+        }
+    });
+    Ok((local_addr, handle))
+}
+
+async fn respond(context: Arc<StreamContext>, request: Request<Body>) -> Response<Body> {
+    let name = urlencoding::decode(request.uri().path().trim_start_matches('/'))
+        .map(|name| name.into_owned())
+        .unwrap_or_default();
+    if name.is_empty() {
+        return list_files(&context);
+    }
+    let Some((file_index, (_, file_length))) = name
+        .parse::<usize>()
+        .ok()
+        .or_else(|| context.file_names.iter().position(|file| *file == name))
+        .and_then(|file_index| Some((file_index, context.piece_map.file(file_index)?.clone())))
+    else {
+        return status(StatusCode::NOT_FOUND);
+    };
+
+    let range = match request.headers().get(header::RANGE) {
+        None => Some(0..file_length),
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| parse_range(value, file_length)),
+    };
+    let Some(range) = range else {
+        let mut response = status(StatusCode::RANGE_NOT_SATISFIABLE);
+        response.headers_mut().insert(
+            header::CONTENT_RANGE,
+            format!("bytes */{file_length}").parse().unwrap(),
+        );
+        return response;
+    };
+
+    let wait_timeout = context.wait_timeout;
+    let file_start = context.piece_map.file_start(file_index);
+    let torrent_range = file_start + range.start..file_start + range.end;
+    // an empty file has no piece to wait for
+    let body = if torrent_range.is_empty() {
+        Body::empty()
+    } else {
+        match stream(context, torrent_range).await {
+            Some(body) => body,
+            // answer with 503 right away instead of a response that stalls before its first byte
+            None => {
+                let mut response = status(StatusCode::SERVICE_UNAVAILABLE);
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, wait_timeout.as_secs().max(1).into());
+                return response;
+            }
+        }
+    };
+
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+    headers.insert(header::CONTENT_LENGTH, range.len().into());
+    headers.insert(
+        header::CONTENT_TYPE,
+        "application/octet-stream".parse().unwrap(),
+    );
+    if request.headers().contains_key(header::RANGE) {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response.headers_mut().insert(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{file_length}", range.start, range.end - 1)
+                .parse()
+                .unwrap(),
+        );
+    }
+    response
+}
+
+// The body of a non-empty range of the torrent, sent as its pieces arrive. None if the first
+// piece does not arrive in time.
+async fn stream(context: Arc<StreamContext>, torrent_range: Range<usize>) -> Option<Body> {
+    let first_piece = context.piece_map.piece_of(torrent_range.start);
+    prioritize(&context, first_piece, torrent_range.end);
+    if !context
+        .have
+        .wait_for(first_piece, context.wait_timeout)
+        .await
+    {
+        return None;
+    }
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut offset = torrent_range.start;
+        let mut current_piece = first_piece;
+        while offset < torrent_range.end {
+            let piece_index = context.piece_map.piece_of(offset);
+            if piece_index != current_piece {
+                prioritize(&context, piece_index, torrent_range.end);
+                current_piece = piece_index;
+            }
+            if !context
+                .have
+                .wait_for(piece_index, context.wait_timeout)
+                .await
+            {
+                sender.abort();
+                return;
+            }
+            let piece_range = context.piece_map.piece_range(piece_index);
+            let chunk_end = torrent_range
+                .end
+                .min(piece_range.end)
+                .min(offset + CHUNK_SIZE);
+            let read = context
+                .disk_io
+                .read_block(piece_index, offset - piece_range.start, chunk_end - offset)
+                .await;
+            let sent = match read {
+                Result::Ok(chunk) => sender.send_data(chunk.into()).await.is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                sender.abort();
+                return;
+            }
+            offset = chunk_end;
+        }
+    });
+    Some(body)
+}
+
+// One line per file: the number and the path it can be requested at, and its length.
+fn list_files(context: &StreamContext) -> Response<Body> {
+    let listing: String = context
+        .file_names
+        .iter()
+        .zip(context.piece_map.files())
+        .enumerate()
+        .map(|(file_index, (name, (_, length)))| {
+            format!(
+                "/{file_index} or /{}, {length} bytes\n",
+                urlencoding::encode(name).replace("%2F", "/")
+            )
+        })
+        .collect();
+    let mut response = Response::new(Body::from(listing));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        "text/plain; charset=utf-8".parse().unwrap(),
+    );
+    response
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+// Parses a single "bytes=start-end", "bytes=start-" or "bytes=-suffix" range into an exclusive
+// range within the file, None if it cannot be satisfied.
+fn parse_range(value: &str, file_length: usize) -> Option<Range<usize>> {
+    let (start, end) = value.strip_prefix("bytes=")?.trim().split_once('-')?;
+    let range = match (start.parse::<usize>().ok(), end.parse::<usize>().ok()) {
+        (Some(start), Some(end)) => start..end.saturating_add(1).min(file_length),
+        (Some(start), None) if end.is_empty() => start..file_length,
+        (None, Some(suffix)) if start.is_empty() => file_length.saturating_sub(suffix)..file_length,
+        _ => return None,
+    };
+    (range.start < range.end).then_some(range)
+}
+
+// Moves the pieces from the one being read up to the readahead that are still queued to the end
+// of the queue, peers pop from there. end is where the response stops.
+fn prioritize(context: &StreamContext, piece_index: usize, end: usize) {
+    let start = context.piece_map.piece_range(piece_index).start;
+    let wanted = context
+        .piece_map
+        .pieces_for_range(start..end.min(start + context.readahead.max(1)));
+    context.pieces.prioritize(wanted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, FilePriority};
+    use crate::download::storage::{test_backend::MemoryStorage, Storage};
+
+    struct TestServer {
+        addr: SocketAddr,
+        storage: Arc<MemoryStorage>,
+        have: Arc<Have>,
+        pieces: PieceQueue,
+    }
+
+    // Two files of 25 and 15 bytes in pieces of 10 bytes and an empty one, pieces 0 and 1 are on
+    // disk. Only the piece being read is moved up.
+    fn serve() -> TestServer {
+        let storage = Arc::new(MemoryStorage::default());
+        let data: Vec<u8> = (0..40).collect();
+        storage.write_at("a", 0, &data[..20]).unwrap();
+        let have = Arc::new(Have::new(4, &[2, 3]));
+        let piece_map = Arc::new(PieceMap::new(
+            10,
+            vec![
+                ("a".to_string(), 25),
+                ("b".to_string(), 15),
+                ("c".to_string(), 0),
+            ],
+        ));
+        let pieces = PieceQueue::spawn(
+            vec![2, 3],
+            have.clone(),
+            vec![FilePriority::Normal; 4],
+            tokio::sync::watch::channel(false).1,
+            1,
+        );
+        let context = StreamContext {
+            piece_map: piece_map.clone(),
+            file_names: vec!["a".to_string(), "dir/b c".to_string(), "c".to_string()],
+            disk_io: DiskIo::spawn(storage.clone(), piece_map.clone(), &Config::default(), None),
+            have: have.clone(),
+            pieces: pieces.clone(),
+            wait_timeout: Duration::from_secs(5),
+            readahead: 10,
+        };
+        let (addr, _) = spawn("127.0.0.1:0".parse().unwrap(), context).unwrap();
+        TestServer {
+            addr,
+            storage,
+            have,
+            pieces,
+        }
+    }
+
+    async fn get(addr: SocketAddr, file: &str, range: &str) -> reqwest::Response {
+        reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .get(format!("http://{addr}/{file}"))
+            .header("Range", range)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn available_range_is_served() {
+        let response = get(serve().addr, "0", "bytes=5-14").await;
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()["content-range"], "bytes 5-14/25");
+        let expected: Vec<u8> = (5..15).collect();
+        assert_eq!(response.bytes().await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn pending_range_waits_for_its_piece() {
+        let TestServer {
+            addr,
+            storage,
+            have,
+            pieces,
+        } = serve();
+        // bytes 2-7 of file b are torrent bytes 27-32, in piece 2 and 3
+        let request = tokio::spawn(async move { get(addr, "1", "bytes=2-7").await });
+
+        // the requested pieces are moved up once the request arrives, piece 2 is next
+        for _ in 0..100 {
+            if pieces.queued().await == vec![3, 2] {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(pieces.queued().await, vec![3, 2]);
+        let data: Vec<u8> = (0..40).collect();
+        storage.write_at("a", 20, &data[20..25]).unwrap();
+        storage.write_at("b", 0, &data[25..40]).unwrap();
+        have.set(2);
+        have.set(3);
+
+        let response = request.await.unwrap();
+        assert_eq!(response.status(), 206);
+        let expected: Vec<u8> = (27..33).collect();
+        assert_eq!(response.bytes().await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn pieces_are_moved_up_as_the_response_reaches_them() {
+        let TestServer {
+            addr,
+            storage,
+            have,
+            pieces,
+        } = serve();
+        pieces.prioritize(2..3);
+        let data: Vec<u8> = (0..40).collect();
+        storage.write_at("a", 20, &data[20..25]).unwrap();
+        storage.write_at("b", 0, &data[25..30]).unwrap();
+        have.set(2);
+        // file b is torrent bytes 25-39, its second half is in piece 3
+        let request = tokio::spawn(async move { get(addr, "dir/b%20c", "bytes=0-").await });
+
+        // piece 3 comes next once the response reaches it, piece 2 stays queued in this test
+        for _ in 0..100 {
+            if pieces.queued().await == vec![2, 3] {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(pieces.queued().await, vec![2, 3]);
+        storage.write_at("b", 5, &data[30..40]).unwrap();
+        have.set(3);
+
+        let response = request.await.unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.bytes().await.unwrap(), data[25..40]);
+    }
+
+    #[tokio::test]
+    async fn files_are_listed() {
+        let listing = get(serve().addr, "", "bytes=0-")
+            .await
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(
+            listing,
+            "/0 or /a, 25 bytes\n/1 or /dir/b%20c, 15 bytes\n/2 or /c, 0 bytes\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_file_is_answered_right_away() {
+        let addr = serve().addr;
+        let response = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .get(format!("http://{addr}/c"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-length"], "0");
+        assert!(response.bytes().await.unwrap().is_empty());
+        // no byte of it can be asked for
+        assert_eq!(get(addr, "2", "bytes=0-").await.status(), 416);
+    }
+
+    #[test]
+    fn ranges_are_parsed() {
+        assert_eq!(parse_range("bytes=0-9", 25), Some(0..10));
+        assert_eq!(parse_range("bytes=20-", 25), Some(20..25));
+        assert_eq!(parse_range("bytes=-5", 25), Some(20..25));
+        assert_eq!(parse_range("bytes=10-100", 25), Some(10..25));
+        assert_eq!(
+            parse_range(&format!("bytes=0-{}", usize::MAX), 25),
+            Some(0..25)
+        );
+        assert_eq!(parse_range("bytes=30-", 25), None);
+        assert_eq!(parse_range("items=0-1", 25), None);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::download::{
+    metadata::{
+        self, ExtendedHandshake, MetadataMessage, EXTENSION_BIT, EXTENSION_BYTE,
+        METADATA_PIECE_LENGTH,
+    },
+    peers::{Bitfield, PeerFrameCodec, PeerMessage},
+    tracker::HandShake,
+};
+use futures_util::{SinkExt, StreamExt};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::codec::Framed;
+
+// A seeder for tests that serves a torrent's payload from memory over the peer wire protocol,
+// built on PeerFrameCodec so it exercises the codec from the other side too.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Misbehavior {
+    None,
+    // choke us after serving this many blocks and never unchoke again
+    ChokeAfter(usize),
+    // choke us after serving this many blocks, dropping the request, and unchoke again right away
+    ChokeBriefly(usize),
+    // flip the bits of every block
+    Corrupt,
+    // accept requests but never answer them
+    Stall,
+    // like Stall, but send a have message every 50ms so the connection never goes quiet
+    Snub,
+    // close the connection after the first block of the second piece requested
+    DisconnectMidPiece,
+    // only have the even pieces, a request for any other one closes the connection
+    EvenPiecesOnly,
+    // send an empty bitfield and announce every piece with have messages once we are interested
+    HaveAfterInterested,
+    // answer requests in pairs, the second one first
+    AnswerInReverse,
+    // send only the first half of every block requested
+    ShortBlocks,
+    // send 1 KiB of every block requested, one every 100ms
+    Trickle,
+    // keep us choked and send nothing after the bitfield
+    SilentChoke,
+}
+
+pub struct Seeder {
+    pub info_hash: [u8; 20],
+    pub payload: Arc<Vec<u8>>,
+    pub piece_length: usize,
+    pub misbehavior: Misbehavior,
+}
+
+// Listens on a random local port, every connection is served by its own task.
+pub async fn spawn(seeder: Seeder) -> SocketAddr {
+    spawn_on("127.0.0.1:0", seeder).await
+}
+
+// Listens on addr, e.g. [::1]:0 for a seeder that is only reachable over IPv6.
+pub async fn spawn_on(addr: &str, seeder: Seeder) -> SocketAddr {
+    listen(addr, seeder, None).await
+}
+
+// Like spawn, and the info dictionary is served to peers that fetch it for a magnet link.
+pub async fn spawn_with_metadata(seeder: Seeder, info: Vec<u8>) -> SocketAddr {
+    listen("127.0.0.1:0", seeder, Some(Arc::new(info))).await
+}
+
+async fn listen(addr: &str, seeder: Seeder, info: Option<Arc<Vec<u8>>>) -> SocketAddr {
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seeder = Arc::new(seeder);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(seeder.clone(), info.clone(), stream));
+        }
+    });
+    addr
+}
+
+// Our id for ut_metadata messages, not the one of the client so that the ids are not mixed up.
+const UT_METADATA_ID: u8 = 3;
+
+async fn serve(seeder: Arc<Seeder>, info: Option<Arc<Vec<u8>>>, mut stream: TcpStream) {
+    let mut our_handshake = HandShake::new(seeder.info_hash, [b's'; 20]);
+    if info.is_some() {
+        our_handshake.reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
+    }
+    let our_handshake = bincode::serialize(&our_handshake).unwrap();
+    let mut handshake = vec![0_u8; our_handshake.len()];
+    if stream.read_exact(&mut handshake).await.is_err() {
+        return;
+    }
+    let handshake: HandShake = bincode::deserialize(&handshake).unwrap();
+    if handshake.info_hash != seeder.info_hash {
+        return;
+    }
+    stream.write_all(&our_handshake).await.unwrap();
+    let info = info.filter(|_| handshake.reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0);
+
+    let pieces = seeder.payload.len().div_ceil(seeder.piece_length);
+    let mut framed = Framed::new(stream, PeerFrameCodec::for_pieces(pieces));
+    let has = |piece: usize| match seeder.misbehavior {
+        Misbehavior::EvenPiecesOnly => piece.is_multiple_of(2),
+        Misbehavior::HaveAfterInterested => false,
+        _ => true,
+    };
+    let bitfield = Bitfield::from_pieces(pieces, has);
+    if framed.send(PeerMessage::Bitfield(bitfield)).await.is_err() {
+        return;
+    }
+    if let Some(info) = &info {
+        let handshake = ExtendedHandshake {
+            m: HashMap::from([("ut_metadata".to_string(), UT_METADATA_ID.into())]),
+            metadata_size: Some(info.len() as i64),
+        };
+        let handshake = PeerMessage::Extended {
+            id: 0,
+            payload: serde_bencode::to_bytes(&handshake).unwrap(),
+        };
+        if framed.send(handshake).await.is_err() {
+            return;
+        }
+    }
+    // the id the client takes ut_metadata messages under
+    let mut their_ut_metadata = None;
+
+    let mut blocks_served = 0;
+    let mut pieces_started = Vec::new();
+    let mut choked = true;
+    let mut choked_once = false;
+    // requests not answered yet
+    let mut held = Vec::new();
+    loop {
+        let frame = if seeder.misbehavior == Misbehavior::Snub {
+            match tokio::time::timeout(Duration::from_millis(50), framed.next()).await {
+                Ok(frame) => frame,
+                Err(_) => {
+                    if framed.send(PeerMessage::Have { index: 0 }).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+        } else {
+            framed.next().await
+        };
+        let Some(Ok(frame)) = frame else {
+            return;
+        };
+        match frame {
+            PeerMessage::Interested if choked && seeder.misbehavior != Misbehavior::SilentChoke => {
+                choked = false;
+                if seeder.misbehavior == Misbehavior::HaveAfterInterested {
+                    for piece in 0..pieces as u32 {
+                        let _ = framed.feed(PeerMessage::Have { index: piece }).await;
+                    }
+                }
+                let _ = framed.send(PeerMessage::Unchoke).await;
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } if !choked => {
+                let (index, begin, length) = (index as usize, begin as usize, length as usize);
+
+                match seeder.misbehavior {
+                    Misbehavior::Stall | Misbehavior::Snub => continue,
+                    Misbehavior::ChokeAfter(n) if blocks_served == n => {
+                        choked = true;
+                        let _ = framed.send(PeerMessage::Choke).await;
+                        continue;
+                    }
+                    Misbehavior::ChokeBriefly(n) if blocks_served == n && !choked_once => {
+                        choked_once = true;
+                        let _ = framed.send(PeerMessage::Choke).await;
+                        let _ = framed.send(PeerMessage::Unchoke).await;
+                        continue;
+                    }
+                    Misbehavior::DisconnectMidPiece if begin > 0 && pieces_started.len() > 1 => {
+                        return;
+                    }
+                    Misbehavior::EvenPiecesOnly if !has(index) => return,
+                    _ => {}
+                }
+                if begin == 0 {
+                    pieces_started.push(index);
+                }
+                let length = match seeder.misbehavior {
+                    Misbehavior::ShortBlocks => length.div_ceil(2),
+                    Misbehavior::Trickle => {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        length.min(1024)
+                    }
+                    _ => length,
+                };
+
+                held.push((index, begin, length));
+                let piece_end = seeder.payload.len().min((index + 1) * seeder.piece_length);
+                // held back until the next request, unless nothing else of the piece follows
+                if seeder.misbehavior == Misbehavior::AnswerInReverse
+                    && held.len() < 2
+                    && index * seeder.piece_length + begin + length < piece_end
+                {
+                    continue;
+                }
+                for (index, begin, length) in held.drain(..).rev() {
+                    if framed
+                        .send(piece_message(&seeder, index, begin, length))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    blocks_served += 1;
+                }
+            }
+            PeerMessage::Extended { id: 0, payload } => {
+                let handshake: ExtendedHandshake = serde_bencode::from_bytes(&payload).unwrap();
+                their_ut_metadata = handshake
+                    .m
+                    .get("ut_metadata")
+                    .map(|&id| u8::try_from(id).unwrap());
+            }
+            PeerMessage::Extended {
+                id: UT_METADATA_ID,
+                payload,
+            } => {
+                let (Some(info), Some(id)) = (&info, their_ut_metadata) else {
+                    continue;
+                };
+                let (request, _) = MetadataMessage::parse(&payload).unwrap();
+                assert_eq!(request.msg_type, metadata::REQUEST);
+                let start = request.piece as usize * METADATA_PIECE_LENGTH;
+                let end = info.len().min(start + METADATA_PIECE_LENGTH);
+                let mut payload = MetadataMessage {
+                    msg_type: metadata::DATA,
+                    piece: request.piece,
+                    total_size: Some(info.len() as i64),
+                }
+                .encode();
+                payload.extend_from_slice(&info[start..end]);
+                if framed
+                    .send(PeerMessage::Extended { id, payload })
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn piece_message(seeder: &Seeder, index: usize, begin: usize, length: usize) -> PeerMessage {
+    let start = index * seeder.piece_length + begin;
+    let mut block = seeder.payload[start..start + length].to_vec();
+    if seeder.misbehavior == Misbehavior::Corrupt {
+        block.iter_mut().for_each(|byte| *byte = !*byte);
+    }
+    PeerMessage::Piece {
+        index: index as u32,
+        begin: begin as u32,
+        block,
+    }
+}
@@ -1,44 +1,397 @@
 use super::tracker;
-use anyhow::{Context, Ok};
+use anyhow::{bail, Context, Ok};
 use futures_util::{future::join_all, SinkExt, StreamExt};
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize, Serializer,
 };
-use serde_bencode;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use serde_bencode::{self, value::Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, oneshot, watch, Semaphore},
+    task::{JoinHandle, JoinSet},
+};
 
-use rand::distributions::{Alphanumeric, DistString};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
+use crate::config::{Allocation, Config, DiskBackend, FilePriority, IpFamily};
 use crate::download::{
-    peers::{PeerFrameCodec, PeerPieceMsgType, PeerRequestMsgType},
+    bandwidth::Bandwidth,
+    choker::Choker,
+    control::{ConnectedPeer, Control, PeerList, PeerStatus, TorrentState},
+    disk_io::DiskIo,
+    disk_space,
+    dns::Resolver,
+    events::{Event as SessionEvent, Events},
+    file_paths,
+    have::{Announced, Have},
+    hooks::{self, HookTorrent},
+    ip_filter::PeerFilter,
+    listener::Incoming,
+    merkle::{PieceHashesV2, V1File},
+    net::{self, FamilyStats},
+    partial_pieces::{PartialPieces, Received},
+    peer_id,
+    peers::{self, PeerFrameCodec, PeerMessage, KEEP_ALIVE_INTERVAL},
+    piece_queue::PieceQueue,
+    pipeline::Pipeline,
+    resume::{self, ResumeData},
+    seed_limit,
+    shared::Shared,
+    staging::{self, Staging},
+    storage::{self, FileStorage},
+    streaming::{self, StreamContext},
     tracker::{HandShake, TrackerResponse},
+    upload::Uploader,
+    verify,
+    web_seed::{self, WebSeed},
 };
 use crate::download::{
-    peers::{PeerMsgTag, PeerMsgType},
-    tracker::TrackerRequest,
+    peer_manager::{PeerManager, RetryPolicy},
+    piece_map::PieceMap,
+    tracker::{Event, TrackerRequest},
 };
+use crate::error::RustyBitError;
 
+use std::fmt;
+use std::path::Path;
 use std::{
+    borrow::Cow,
     collections::HashMap,
-    fs::OpenOptions,
-    io::{Read, Seek, SeekFrom},
-    os::windows::prelude::FileExt,
+    io::Read,
+    net::SocketAddr,
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use std::{fmt, fs::File};
-use std::{path::Path, usize};
+use tracing::{debug, info, info_span, instrument, warn, Instrument};
+
+// How long the download waits for the DHT to come up with peers
+const DHT_LOOKUP_TIMEOUT: Duration = Duration::from_secs(20);
+// How long shutting down waits for the tracker to take note of the stopped event
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+// Failed requests in a row after which a web seed is given up on, and the delay before the first
+// retry, it grows with every failure
+const WEB_SEED_MAX_FAILURES: u32 = 5;
+const WEB_SEED_RETRY_DELAY: Duration = Duration::from_secs(2);
+// A peer that sent none of the blocks we asked for in this long is snubbing us, its piece goes to
+// other peers and it gets no requests for as long again
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// A peer has to finish a piece within the snub timeout plus the time the piece takes at this many
+// bytes a second, otherwise the piece goes to other peers and the peer sits out like a snubbing one
+const MIN_PIECE_RATE: usize = 4 * 1024;
+
+// Creates the file at path, length bytes long unless the allocation is none. Nothing is written,
+// the file reads as zeros until pieces land in it. A file that could not be allocated completely
+// is removed again, otherwise the next start would take it for an existing download.
+fn preallocate_file(
+    path: &Path,
+    length: usize,
+    allocation: Allocation,
+    backend: DiskBackend,
+) -> anyhow::Result<()> {
+    let allocate = || -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        match allocation {
+            Allocation::Sparse => file.set_len(length as u64),
+            Allocation::Full => storage::allocate(&file, length as u64, backend)
+                .and_then(|()| file.set_len(length as u64)),
+            Allocation::None => Result::Ok(()),
+        }
+    };
+    if let Err(e) = allocate() {
+        let _ = std::fs::remove_file(path);
+        return Err(RustyBitError::disk(&path.display().to_string())(e))
+            .with_context(|| format!("could not preallocate file {}", path.display()));
+    }
+    Ok(())
+}
 
-fn calc_sha1_hash(piece_data: Vec<u8>) -> [u8; 20] {
+fn calc_sha1_hash(piece_data: &[u8]) -> [u8; 20] {
     let mut piece_hasher = Sha1::new();
     piece_hasher.update(piece_data);
     let piece_hash = piece_hasher.finalize();
     Into::<[u8; 20]>::into(piece_hash)
 }
 
-#[derive(Debug)]
+async fn request_tracker(
+    client: &reqwest::Client,
+    url: String,
+    announce: &str,
+    config: &Config,
+) -> anyhow::Result<TrackerResponse> {
+    let mut request = client.get(url).timeout(config.tracker_timeout);
+    for (name, value) in &config.tracker_headers {
+        request = request.header(name, value);
+    }
+    let response = request
+        .send()
+        .await
+        // the error would show the whole URL, passkey included
+        .map_err(|e| e.without_url())
+        .map_err(RustyBitError::tracker(announce))?;
+
+    let response = response
+        .bytes()
+        .await
+        .map_err(|e| e.without_url())
+        .map_err(RustyBitError::tracker(announce))?;
+    let tracker_reponse: TrackerResponse = serde_bencode::from_bytes(&response).map_err(
+        RustyBitError::bencode(&format!("The response of tracker {announce}")),
+    )?;
+    // e.g. that the client or the account is about to be refused
+    if let tracker::TrackerResponseType::Success {
+        warning_message: Some(warning),
+        ..
+    } = &tracker_reponse.tracker_response_type
+    {
+        warn!("tracker {announce} warns: {warning}");
+    }
+    Ok(tracker_reponse)
+}
+
+fn tracker_error(events: &Events, tracker: &str, message: String) {
+    events.send(|info_hash| SessionEvent::TrackerError {
+        info_hash,
+        tracker: tracker.to_string(),
+        message,
+    });
+}
+
+// Fills in what was transferred since the started event and how much is still missing.
+fn report_progress(
+    request: &mut TrackerRequest,
+    bandwidth: &Bandwidth,
+    have: &Have,
+    piece_map: &PieceMap,
+) {
+    request.uploaded = bandwidth.upload.transferred() as usize;
+    request.downloaded = bandwidth.download.transferred() as usize;
+    request.left = (0..piece_map.total_pieces())
+        .filter(|&piece_index| have.needs(piece_index))
+        .map(|piece_index| piece_map.piece_range(piece_index).len())
+        .sum();
+}
+
+// What the first announce found: the peers with the swarm they are in, the announce URL of the
+// tracker that answered and when it wants to hear from us again, and the last error if none did.
+struct FirstAnnounce {
+    peers: Vec<(SocketAddr, usize)>,
+    announced_to: Option<(String, Duration)>,
+    failure: anyhow::Result<()>,
+}
+
+// Trackers are tried tier by tier until one answers with peers (BEP 12), the other swarms of a
+// hybrid torrent are then announced to the same tracker. The Announcer takes over from there.
+async fn first_announce(
+    client: &reqwest::Client,
+    tracker_tiers: &[Vec<String>],
+    swarms: &[[u8; 20]],
+    tracker_request: &mut TrackerRequest,
+    config: &Config,
+    events: &Events,
+) -> FirstAnnounce {
+    let mut first = FirstAnnounce {
+        peers: Vec::new(),
+        announced_to: None,
+        failure: Ok(()),
+    };
+    for announce in tracker_tiers.iter().flatten() {
+        // for messages, the announce URL may hold a passkey
+        let tracker_name = tracker::redacted(announce);
+        debug!("Trying to contact tracker at {}", tracker_name);
+        let url = tracker_request.url(announce);
+        match request_tracker(client, url, &tracker_name, config).await {
+            Result::Ok(tracker_reponse) => match tracker_reponse.tracker_response_type {
+                tracker::TrackerResponseType::Success {
+                    interval,
+                    min_interval,
+                    peers,
+                    peers6,
+                    tracker_id,
+                    ..
+                } => {
+                    info!("Connected to the tracker {tracker_name}");
+                    tracker_request.tracker_id = tracker_id;
+                    first
+                        .peers
+                        .extend(peers.with(peers6).0.into_iter().map(|peer| (peer, 0)));
+                    first.announced_to = Some((
+                        announce.clone(),
+                        tracker::reannounce_interval(interval, min_interval),
+                    ));
+                    break;
+                }
+                tracker::TrackerResponseType::Failure { failure_reason } => {
+                    warn!("Tracker {tracker_name} could not be connected due to: {failure_reason}");
+                    first.failure =
+                        Err(RustyBitError::tracker(&tracker_name)(failure_reason.clone()).into());
+                    tracker_error(events, &tracker_name, failure_reason);
+                }
+            },
+            Err(e) => {
+                warn!("{e:#}");
+                tracker_error(events, &tracker_name, format!("{e:#}"));
+                first.failure = Err(e);
+            }
+        }
+    }
+
+    let Some(announce) = first
+        .announced_to
+        .as_ref()
+        .map(|(announce, _)| announce.clone())
+    else {
+        return first;
+    };
+    let tracker_name = tracker::redacted(&announce);
+    for (swarm, swarm_hash) in swarms.iter().enumerate().skip(1) {
+        let mut request = tracker_request.clone();
+        request.info_hash = *swarm_hash;
+        let url = request.url(&announce);
+        match request_tracker(client, url, &tracker_name, config).await {
+            Result::Ok(tracker_reponse) => match tracker_reponse.tracker_response_type {
+                tracker::TrackerResponseType::Success { peers, peers6, .. } => {
+                    for peer in peers.with(peers6).0 {
+                        if !first.peers.iter().any(|&(known, _)| known == peer) {
+                            first.peers.push((peer, swarm));
+                        }
+                    }
+                }
+                tracker::TrackerResponseType::Failure { failure_reason } => {
+                    warn!("tracker {tracker_name} refused the v2 swarm: {failure_reason}");
+                    tracker_error(events, &tracker_name, failure_reason);
+                }
+            },
+            Err(e) => {
+                warn!("{e:#}");
+                tracker_error(events, &tracker_name, format!("{e:#}"));
+            }
+        }
+    }
+    first
+}
+
+// Announces to the tracker that gave us peers for as long as the download runs: regularly on its
+// interval, with the completed event once the last piece is verified and with the stopped event
+// when the download ends. Every swarm of the torrent is announced with its own request.
+struct Announcer {
+    announce: String,
+    requests: Vec<TrackerRequest>,
+    interval: Duration,
+    config: Config,
+    client: reqwest::Client,
+    bandwidth: Bandwidth,
+    have: Arc<Have>,
+    piece_map: Arc<PieceMap>,
+    events: Events,
+}
+
+// A running announcer.
+struct Announcing {
+    task: JoinHandle<()>,
+    shutdown: oneshot::Sender<()>,
+}
+
+impl Announcer {
+    fn spawn(self, new_peers: mpsc::UnboundedSender<Vec<(SocketAddr, usize)>>) -> Announcing {
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        Announcing {
+            task: tokio::spawn(self.run(new_peers, shutdown_receiver).in_current_span()),
+            shutdown,
+        }
+    }
+
+    // Sends the peers of every answer with the swarm they are in. A failed announce is retried
+    // after the same interval. Completed is not sent for a download that was complete when it
+    // started.
+    async fn run(
+        mut self,
+        new_peers: mpsc::UnboundedSender<Vec<(SocketAddr, usize)>>,
+        mut shutdown: oneshot::Receiver<()>,
+    ) {
+        let mut completion_pending = !self.have.complete();
+        loop {
+            let event = tokio::select! {
+                _ = tokio::time::sleep(self.interval) => None,
+                _ = self.have.wait_complete(), if completion_pending => {
+                    completion_pending = false;
+                    Some(Event::Completed)
+                }
+                _ = &mut shutdown => break,
+            };
+            let peers = self.announce(event).await;
+            if !peers.is_empty() {
+                // the download may not be looking for peers anymore
+                let _ = new_peers.send(peers);
+            }
+        }
+        // the last piece may have arrived just before the end
+        if completion_pending && self.have.complete() {
+            self.announce(Some(Event::Completed)).await;
+        }
+        self.announce(Some(Event::Stopped)).await;
+    }
+
+    // The peers the tracker returned and their swarm.
+    async fn announce(&mut self, event: Option<Event>) -> Vec<(SocketAddr, usize)> {
+        let tracker_name = tracker::redacted(&self.announce);
+        let mut found = Vec::new();
+        for swarm in 0..self.requests.len() {
+            let request = &mut self.requests[swarm];
+            request.event = event;
+            report_progress(request, &self.bandwidth, &self.have, &self.piece_map);
+            let url = request.url(&self.announce);
+            match request_tracker(&self.client, url, &tracker_name, &self.config).await {
+                Result::Ok(response) => match response.tracker_response_type {
+                    tracker::TrackerResponseType::Success {
+                        interval,
+                        min_interval,
+                        peers,
+                        peers6,
+                        tracker_id,
+                        ..
+                    } => {
+                        self.interval = tracker::reannounce_interval(interval, min_interval);
+                        // kept when a later answer has none
+                        if tracker_id.is_some() {
+                            request.tracker_id = tracker_id;
+                        }
+                        found.extend(peers.with(peers6).0.into_iter().map(|peer| (peer, swarm)));
+                    }
+                    tracker::TrackerResponseType::Failure { failure_reason } => {
+                        warn!("tracker {tracker_name} refused the announce: {failure_reason}");
+                        tracker_error(&self.events, &tracker_name, failure_reason);
+                    }
+                },
+                Err(e) => {
+                    warn!("{e:#}");
+                    tracker_error(&self.events, &tracker_name, format!("{e:#}"));
+                }
+            }
+        }
+        found
+    }
+}
+
+impl Announcing {
+    // Announces the stopped event, the tracker gets a few seconds to take note of it.
+    async fn stop(self) {
+        let _ = self.shutdown.send(());
+        let abort = self.task.abort_handle();
+        if tokio::time::timeout(STOPPED_ANNOUNCE_TIMEOUT, self.task)
+            .await
+            .is_err()
+        {
+            warn!("the tracker did not take note of the stopped event in time");
+            abort.abort();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 // using Vec beacuse we have no idea how large hash string can be
 pub struct Hashes(Vec<[u8; 20]>);
 struct HashesVisitor;
@@ -54,7 +407,7 @@ impl<'de> Visitor<'de> for HashesVisitor {
     where
         E: de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             return Err(E::custom(format!("length is {}", v.len())));
         }
         Result::Ok(Hashes(
@@ -85,16 +438,25 @@ impl Serialize for Hashes {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TorrentFile {
     pub length: usize,
     path: Vec<String>,
+    // BEP 47 attributes, "p" marks the padding files of hybrid torrents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attr: Option<String>,
+}
+
+impl TorrentFile {
+    fn is_padding(&self) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains('p'))
+    }
 }
 
 // There are two possible forms:
 //     one for the case of a 'single-file' torrent with no directory structure
 //     one for the case of a 'multi-file' torrent
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum FileType {
     SingleFile { length: usize },
@@ -102,7 +464,7 @@ pub enum FileType {
 }
 
 // Dictionary that describes the file(s) of the torrent.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Info {
     // suggested name for the file or the directory
     name: String,
@@ -121,406 +483,3725 @@ pub struct Info {
 
     #[serde(flatten)]
     pub file_type: FileType,
+
+    // 2 for torrents that also have the BitTorrent v2 keys (BEP 52), only hybrid torrents are
+    // supported since the pieces are still needed
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    meta_version: Option<u64>,
+
+    // the v2 file list with the root of every file's hash tree
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    file_tree: Option<Value>,
+
+    // 1 when the peers may only come from the trackers (BEP 27)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private: Option<u8>,
+}
+
+impl Info {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn total_pieces(&self) -> usize {
+        self.pieces.0.len()
+    }
+
+    pub fn piece_length(&self) -> usize {
+        self.piece_length
+    }
+
+    // Bytes in the files, padding files included.
+    pub fn total_length(&self) -> u64 {
+        match &self.file_type {
+            FileType::SingleFile { length } => *length as u64,
+            FileType::MultiFile { files } => files.iter().map(|file| file.length as u64).sum(),
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
 }
 
 // The content of a Torrent is a bencoded dictionary, containing the keys listed below. All character string values are UTF-8 encoded.
 // No optional field included for now.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
     pub info: Info,
 
     // The announce URL of the tracker (string)
+    #[serde(default)]
     pub announce: String,
+
+    // Tiers of announce URLs (BEP 12), when present announce is only there for old clients
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    // HTTP servers that have the files (BEP 19)
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "web_seed::deserialize_url_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
+
+    // v2 hashes of every piece of the files larger than a piece, by the root of their tree
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    piece_layers: Option<Value>,
+
+    // free-form text from the author of the torrent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    // the program that made the torrent
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+
+    // when the torrent was made, in seconds since the Unix epoch
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<u64>,
+
+    // the info dictionary as it was fetched from peers, the info hash is the hash of these bytes
+    // rather than of the dictionary encoded again
+    #[serde(skip)]
+    info_bytes: Option<Arc<Vec<u8>>>,
+}
+
+// Smallest and largest piece length picked for a new torrent.
+const MIN_CREATED_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_CREATED_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+
+// Number of pieces a new torrent is aimed at, fewer pieces make a smaller .torrent file and more
+// pieces make less data to throw away when one turns out bad.
+const CREATED_PIECES: usize = 1500;
+
+// What goes into a new torrent besides the files.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    // bytes in each piece, picked from the total size when not given
+    pub piece_length: Option<usize>,
+    // tiers of announce URLs, no tiers make a torrent without a tracker
+    pub trackers: Vec<Vec<String>>,
+    pub private: bool,
+    pub comment: Option<String>,
+}
+
+// A power of two piece length that gives the total size about CREATED_PIECES pieces.
+pub fn piece_length_for(total_length: usize) -> usize {
+    (total_length / CREATED_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_CREATED_PIECE_LENGTH, MAX_CREATED_PIECE_LENGTH)
+}
+
+// State shared by all the peer tasks of one download.
+#[derive(Clone)]
+struct PeerTask {
+    pieces: PieceQueue,
+    disk_io: DiskIo,
+    have: Arc<Have>,
+    // shared by every peer task, the list of a large torrent is big
+    pieces_hash: Arc<Vec<[u8; 20]>>,
+    // hybrid torrents have pieces checked against their v2 hashes too
+    pieces_hash_v2: Option<Arc<PieceHashesV2>>,
+    piece_length: usize,
+    total_pieces_to_download: usize,
+    torrent_data_len: usize,
+    bandwidth: Bandwidth,
+    // where the exchange with the peer stands, for its listing
+    status: Arc<PeerStatus>,
+    // a peer that sends nothing for this long is dropped
+    peer_timeout: Duration,
+    // a peer that answers none of our requests for this long is snubbing us
+    snub_timeout: Duration,
+    // a piece a peer has not finished after this long goes to other peers
+    piece_timeout: Duration,
+    // pieces are requested in blocks of this many bytes, the last block of a piece may be shorter
+    block_length: usize,
+    // a few of our pieces are left out of the bitfield and follow as have messages
+    lazy_bitfield: bool,
+    // most block requests kept outstanding per peer, the pipeline of each finds how many it takes
+    request_queue_depth: usize,
+    // the blocks of the pieces peers are on, several peers may share a piece
+    partial_pieces: Arc<PartialPieces>,
+    // when seeding, connections are uploaded to once the download is complete
+    seed: Option<Uploader>,
+    peer_manager: Arc<PeerManager>,
+    // while it is true no pieces are requested, connections stay open
+    hold: watch::Receiver<bool>,
+    events: Events,
 }
 
+// A downloaded piece did not match its hash.
 #[derive(Debug)]
-struct PieceLocationMap {
-    path: String,
-    offset: usize,
-    length: usize,
+struct HashMismatch;
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer sent data that does not match the piece hash")
+    }
 }
 
-impl Torrent {
-    pub fn calc_hash(&mut self) -> anyhow::Result<[u8; 20]> {
-        let mut hasher = Sha1::new();
-        hasher.update(
-            serde_bencode::to_bytes::<Info>(&self.info)
-                .context("Metainfo file's Info conversion to bytes")?,
-        );
-        let info_hash = hasher.finalize().into();
-        Ok(info_hash)
+impl std::error::Error for HashMismatch {}
+
+// A peer took our requests but sent no block for the snub timeout.
+#[derive(Debug)]
+struct Snubbed;
+
+impl fmt::Display for Snubbed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer sent none of the requested blocks")
     }
+}
 
-    // reserve space for files to be downloaded
-    fn reserve_space(&self, download_directory_path: &str) {
-        match &self.info.file_type {
-            FileType::SingleFile { length } => {
-                let file_path = Path::new(download_directory_path).join(&self.info.name);
-                if !file_path.exists() {
-                    std::fs::write(file_path, vec![0; *length]).unwrap();
+impl std::error::Error for Snubbed {}
+
+// A peer did not finish a piece within the piece timeout.
+#[derive(Debug)]
+struct PieceTimedOut;
+
+impl fmt::Display for PieceTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer did not finish the piece in time")
+    }
+}
+
+impl std::error::Error for PieceTimedOut {}
+
+type PeerFramed = tokio_util::codec::Framed<TcpStream, PeerFrameCodec>;
+
+// A piece taken off the queue, or joined, by a peer task. Unless it was stored, dropping it leaves
+// the piece to the other peers on it, or puts it back for another peer when there are none, also
+// when the task fails, panics or is aborted. Nothing of it is on disk yet, pieces are only written
+// once they are complete.
+struct ClaimedPiece<'a> {
+    task: &'a PeerTask,
+    index: usize,
+    stored: bool,
+    // the peer task the piece is shared under, None for a web seed that takes it whole
+    worker: Option<u64>,
+}
+
+impl ClaimedPiece<'_> {
+    fn stored(mut self) {
+        self.task.have.set(self.index);
+        self.stored = true;
+        let piece = self.index;
+        self.task
+            .events
+            .send(|info_hash| SessionEvent::PieceVerified { info_hash, piece });
+    }
+}
+
+impl Drop for ClaimedPiece<'_> {
+    fn drop(&mut self) {
+        // other peers on the piece finish it
+        let requeue = match self.worker {
+            Some(worker) => self.task.partial_pieces.leave(self.index, worker),
+            None => true,
+        };
+        if !self.stored && requeue {
+            self.task.pieces.requeue(self.index);
+        }
+    }
+}
+
+// What a peer told us about itself, updated with every frame it sends.
+struct PeerState {
+    // Peers start out choking us. Requests are only sent while unchoked, and a choke drops the
+    // ones the peer has not answered yet.
+    choking: bool,
+    // pieces the peer advertised in its bitfield or in have messages
+    pieces: Vec<bool>,
+    // choking and interest are reported here as well
+    status: Arc<PeerStatus>,
+}
+
+impl PeerState {
+    fn new(total_pieces: usize, status: Arc<PeerStatus>) -> PeerState {
+        PeerState {
+            choking: true,
+            pieces: vec![false; total_pieces],
+            status,
+        }
+    }
+
+    fn has(&self, piece_index: usize) -> bool {
+        self.pieces[piece_index]
+    }
+
+    fn update(&mut self, frame: &PeerMessage) -> anyhow::Result<()> {
+        match frame {
+            PeerMessage::Choke | PeerMessage::Unchoke => {
+                self.choking = *frame == PeerMessage::Choke;
+                self.status
+                    .choking_us
+                    .store(self.choking, Ordering::Relaxed);
+            }
+            PeerMessage::Interested | PeerMessage::NotInterested => self
+                .status
+                .peer_interested
+                .store(*frame == PeerMessage::Interested, Ordering::Relaxed),
+            PeerMessage::Bitfield(bitfield) => {
+                bitfield.check(self.pieces.len())?;
+                for (piece_index, has) in self.pieces.iter_mut().enumerate() {
+                    *has = bitfield.has(piece_index);
                 }
             }
-            FileType::MultiFile { files } => {
-                for file in files {
-                    let mut file_path = PathBuf::from(download_directory_path);
-                    for sub_directory in &file.path {
-                        file_path.push(sub_directory);
-                    }
+            &PeerMessage::Have { index } => {
+                let piece_index = index as usize;
+                if piece_index >= self.pieces.len() {
+                    bail!(RustyBitError::PeerProtocol(format!(
+                        "peer has piece {piece_index} which does not exist"
+                    )));
+                }
+                self.pieces[piece_index] = true;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
 
-                    if !file_path.exists() {
-                        let parent_path = file_path.parent().expect("There has to be a parent");
-                        std::fs::create_dir_all(parent_path).unwrap();
-                        std::fs::write(file_path, vec![0; file.length]).unwrap();
-                    }
+impl PeerTask {
+    // The task of one connection, its bytes count for the peer.
+    fn for_peer(&self, peer: &Arc<ConnectedPeer>) -> PeerTask {
+        PeerTask {
+            bandwidth: peer.bandwidth.clone(),
+            status: peer.status.clone(),
+            seed: self.seed.as_ref().map(|uploader| uploader.for_peer(peer)),
+            ..self.clone()
+        }
+    }
+
+    // Downloads pieces over a connection that already completed the handshake, until every
+    // piece is downloaded. If the peer fails the piece it was working on goes back to the queue.
+    async fn download(self, stream: TcpStream) {
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        let addr = stream.peer_addr().ok();
+        match self.exchange_pieces(stream, addr).await {
+            // the upload is not waited for, the download is done
+            Result::Ok((framed, announced)) => {
+                if let Some(uploader) = self.seed {
+                    tokio::spawn(
+                        uploader
+                            .upload_after_download(framed, announced)
+                            .in_current_span(),
+                    );
                 }
             }
+            Err(e) => debug!("Dropped peer {peer}: {e:#}"),
         }
     }
 
-    // generate a mapping of piece to its corresponding files
-    fn genereate_piece_mapping(
+    async fn exchange_pieces(
         &self,
-        total_pieces_to_download: usize,
-        torrent_data_len: usize,
-        download_directory_path: &str,
-    ) -> anyhow::Result<HashMap<usize, Vec<PieceLocationMap>>> {
-        let mut piece_mapping: HashMap<usize, Vec<PieceLocationMap>> = HashMap::new();
-        let mut file_vec = Vec::new();
-
-        // generate a vec of file details
-        match &self.info.file_type {
-            FileType::SingleFile { length } => {
-                let file_name = &self.info.name;
-                let mut path = PathBuf::from(download_directory_path);
-                path.push(file_name);
-                file_vec.push(PieceLocationMap {
-                    path: path.to_str().unwrap().to_string(),
-                    offset: 0,
-                    length: *length,
-                });
+        stream: TcpStream,
+        addr: Option<SocketAddr>,
+    ) -> anyhow::Result<(PeerFramed, Announced)> {
+        let mut framed = tokio_util::codec::Framed::new(
+            stream,
+            PeerFrameCodec::for_pieces(self.total_pieces_to_download),
+        );
+        let mut interested = false;
+
+        let mut peer = PeerState::new(self.total_pieces_to_download, self.status.clone());
+        // when we last had a missing piece the peer could give us
+        let mut useful_at = Instant::now();
+        // when we last sent the peer anything, while idle we keep the connection alive
+        let mut sent_at = Instant::now();
+        // when the peer last sent us anything, one that chokes us is dropped once it is quiet for
+        // peer_timeout
+        let mut heard_at = Instant::now();
+        // A snubbing or slow peer is not given pieces until this has passed, if it snubs us again
+        // right after it is dropped.
+        let mut snubbed_until: Option<Instant> = None;
+        let mut snubbed_again = false;
+        let mut pipeline =
+            Pipeline::new(self.block_length, self.request_queue_depth, Instant::now());
+        // the pieces we have so far, the ones verified later are announced as they come
+        let mut announced = Announced::new(self.total_pieces_to_download);
+        let worker = self.partial_pieces.worker();
+        framed
+            .send(PeerMessage::Bitfield(
+                announced.bitfield(&self.have, self.lazy_bitfield),
+            ))
+            .await?;
+        loop {
+            if self.announce(&mut framed, &peer, &mut announced).await? {
+                sent_at = Instant::now();
             }
-            FileType::MultiFile { files } => {
-                for file in files {
-                    let mut path = PathBuf::from(download_directory_path);
-                    for p in &file.path {
-                        path.push(p);
+            // A held download finishes the piece it is on, then tells the peer it wants nothing
+            // until the hold is over.
+            let hold = *self.hold.borrow();
+            if hold == interested {
+                let message = if hold {
+                    PeerMessage::NotInterested
+                } else {
+                    PeerMessage::Interested
+                };
+                framed.send(message).await?;
+                interested = !hold;
+                self.status.interested.store(interested, Ordering::Relaxed);
+                sent_at = Instant::now();
+            }
+            if hold {
+                useful_at = Instant::now();
+                if sent_at.elapsed() >= KEEP_ALIVE_INTERVAL {
+                    framed.send(PeerMessage::KeepAlive).await?;
+                    sent_at = Instant::now();
+                }
+                self.wait_for_frame(&mut framed, &mut peer, &mut heard_at)
+                    .await?;
+                continue;
+            }
+
+            let snubbed = snubbed_until.is_some_and(|until| Instant::now() < until);
+            let piece_index = if snubbed || peer.choking {
+                None
+            } else {
+                self.claim_next(&peer).await
+            };
+            let Some(piece_index) = piece_index else {
+                if self.have.complete() {
+                    return Ok((framed, announced));
+                }
+                // Other peers are working on the pieces left and one of them may fail, or this
+                // peer may unchoke us or announce new pieces. A peer without anything we miss is
+                // dropped.
+                if (0..self.total_pieces_to_download)
+                    .any(|piece_index| peer.has(piece_index) && self.have.needs(piece_index))
+                {
+                    useful_at = Instant::now();
+                } else if useful_at.elapsed() >= self.peer_timeout {
+                    bail!("peer has none of the pieces we are missing");
+                }
+                if sent_at.elapsed() >= KEEP_ALIVE_INTERVAL {
+                    framed.send(PeerMessage::KeepAlive).await?;
+                    sent_at = Instant::now();
+                }
+                self.wait_for_frame(&mut framed, &mut peer, &mut heard_at)
+                    .await?;
+                continue;
+            };
+
+            let claimed = ClaimedPiece {
+                task: self,
+                index: piece_index,
+                stored: false,
+                worker: Some(worker),
+            };
+            match self
+                .download_piece(
+                    &mut framed,
+                    &mut peer,
+                    &mut pipeline,
+                    piece_index,
+                    worker,
+                    addr,
+                )
+                .await
+            {
+                Result::Ok(stored) => {
+                    if stored {
+                        claimed.stored();
                     }
-                    file_vec.push(PieceLocationMap {
-                        path: path.to_str().unwrap().to_string(),
-                        offset: 0,
-                        length: file.length,
+                    snubbed_until = None;
+                    snubbed_again = false;
+                    self.status.snubbed.store(false, Ordering::Relaxed);
+                }
+                // the piece goes back to other peers while this one sits out
+                Err(e) if e.is::<Snubbed>() => {
+                    drop(claimed);
+                    if snubbed_again {
+                        bail!("peer snubbed us twice in a row");
+                    }
+                    debug!("Peer snubbed us on piece {piece_index}");
+                    snubbed_until = Some(Instant::now() + self.snub_timeout);
+                    snubbed_again = true;
+                    // its requests are given up on
+                    self.status.snubbed.store(true, Ordering::Relaxed);
+                    self.status.requests_in_flight.store(0, Ordering::Relaxed);
+                }
+                // a slow peer sits out as long as a snubbing one, but it is not dropped for it
+                Err(e) if e.is::<PieceTimedOut>() => {
+                    drop(claimed);
+                    debug!("Peer did not finish piece {piece_index} in time");
+                    snubbed_until = Some(Instant::now() + self.snub_timeout);
+                    self.status.requests_in_flight.store(0, Ordering::Relaxed);
+                }
+                // the piece goes back to the queue, the peer may go on unless it did this before
+                Err(e) if e.is::<HashMismatch>() => {
+                    drop(claimed);
+                    debug!("Piece {piece_index} failed the hash check");
+                    self.events.send(|info_hash| SessionEvent::PieceFailed {
+                        info_hash,
+                        piece: piece_index,
                     });
+                    if addr.is_some_and(|addr| self.peer_manager.hash_failed(addr)) {
+                        bail!("peer is banned for sending bad pieces");
+                    }
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Downloading piece {piece_index}"))
                 }
             }
+            sent_at = Instant::now();
+            // the frames of the piece were read with the idle timeout of next_frame
+            heard_at = Instant::now();
+        }
+    }
+
+    // Waits a little for a frame while the peer gives us nothing to download. A peer that chokes
+    // us and sent nothing for peer_timeout is dropped, as it would be in the middle of a piece.
+    // One that unchoked us may be quiet since we ask it for nothing.
+    async fn wait_for_frame(
+        &self,
+        framed: &mut PeerFramed,
+        peer: &mut PeerState,
+        heard_at: &mut Instant,
+    ) -> anyhow::Result<()> {
+        match tokio::time::timeout(Duration::from_millis(100), framed.next()).await {
+            Result::Ok(frame) => {
+                peer.update(&frame.context("peer closed the connection")??)?;
+                *heard_at = Instant::now();
+            }
+            Err(_) if peer.choking && heard_at.elapsed() >= self.peer_timeout => {
+                bail!(RustyBitError::PeerProtocol(format!(
+                    "peer sent nothing for {:?}",
+                    self.peer_timeout
+                )))
+            }
+            Err(_) => {}
         }
+        Ok(())
+    }
+
+    // Sends have messages for the pieces verified since the peer was last told, but not for
+    // those it has itself. True if anything was sent.
+    async fn announce(
+        &self,
+        framed: &mut PeerFramed,
+        peer: &PeerState,
+        announced: &mut Announced,
+    ) -> anyhow::Result<bool> {
+        let pieces = announced.unannounced(&self.have);
+        let mut sent = false;
+        for &piece_index in pieces.iter().filter(|&&i| !peer.has(i)) {
+            // 4 byte length prefix + 1 byte id + index
+            self.bandwidth.upload.acquire(5 + 4).await;
+            framed
+                .feed(PeerMessage::Have {
+                    index: piece_index as u32,
+                })
+                .await?;
+            sent = true;
+        }
+        if sent {
+            framed.flush().await?;
+        }
+        Ok(sent)
+    }
 
-        let mut file_vec_iter = file_vec.into_iter();
-        let piece_length = self.info.piece_length;
+    // The piece a peer downloads next: one other peers are on that has blocks left to ask for, so
+    // that started pieces are finished first, else the one the queue hands out for the peer.
+    // None when there is nothing to take.
+    async fn claim_next(&self, peer: &PeerState) -> Option<usize> {
+        if let Some(piece_index) = self
+            .partial_pieces
+            .join(|piece_index| peer.has(piece_index))
+        {
+            return Some(piece_index);
+        }
+        let piece_index = self.pieces.claim(&peer.pieces).await?;
+        self.partial_pieces
+            .start(piece_index, self.piece_len(piece_index));
+        Some(piece_index)
+    }
 
-        let mut current_file_details = file_vec_iter.next().unwrap();
-        let mut current_offset = 0;
-        let mut data_examined = 0;
+    // Bytes in the piece, the last one may be shorter.
+    fn piece_len(&self, piece_index: usize) -> usize {
+        if piece_index != self.total_pieces_to_download - 1 {
+            self.piece_length
+        } else {
+            self.torrent_data_len - (self.piece_length * (self.total_pieces_to_download - 1))
+        }
+    }
 
-        for piece_index in 0..total_pieces_to_download {
-            let mut piece_data_read = 0;
-            let piece_data_to_read = std::cmp::min(piece_length, torrent_data_len - data_examined);
-            let mut piece_location_vec: Vec<PieceLocationMap> = Vec::new();
+    /*
+     * Downloads blocks of the piece until none is left that nobody else asked for, true once
+     * this peer put in the last one and the piece is stored. A choke gives the blocks asked for
+     * to other peers, this one gets no piece until it is unchoked again.
+     */
+    async fn download_piece(
+        &self,
+        framed: &mut PeerFramed,
+        peer: &mut PeerState,
+        pipeline: &mut Pipeline,
+        piece_index: usize,
+        worker: u64,
+        addr: Option<SocketAddr>,
+    ) -> anyhow::Result<bool> {
+        // (begin, length, when it was requested) of the blocks asked for and not received yet
+        let mut in_flight: Vec<(usize, usize, Instant)> = Vec::new();
 
-            while piece_data_read != piece_data_to_read {
-                if current_file_details.length
-                    >= current_offset + (piece_data_to_read - piece_data_read)
-                {
-                    let data_to_read_in_this_iter = piece_data_to_read - piece_data_read;
-                    piece_location_vec.push(PieceLocationMap {
-                        path: current_file_details.path.clone(),
-                        offset: current_offset,
-                        length: data_to_read_in_this_iter,
-                    });
-                    current_offset += data_to_read_in_this_iter;
-                    piece_data_read += data_to_read_in_this_iter;
-                    data_examined += data_to_read_in_this_iter;
-                } else {
-                    let data_to_read_in_this_iter = current_file_details.length - current_offset;
-                    if data_to_read_in_this_iter != 0 {
-                        piece_location_vec.push(PieceLocationMap {
-                            path: current_file_details.path.clone(),
-                            offset: current_offset,
-                            length: data_to_read_in_this_iter,
-                        });
-                        piece_data_read += data_to_read_in_this_iter;
-                        data_examined += data_to_read_in_this_iter;
-                    }
+        // when the peer last sent a block, or the piece was started
+        let mut block_at = Instant::now();
+        let piece_deadline = block_at + self.piece_timeout;
+        loop {
+            // keep as many requests outstanding as the pipeline asks for
+            let mut requested = false;
+            while in_flight.len() < pipeline.depth() {
+                let Some((begin, length)) = self.partial_pieces.take(piece_index, worker) else {
+                    break;
+                };
 
-                    if torrent_data_len != data_examined {
-                        current_file_details = file_vec_iter.next().unwrap();
+                // 4 byte length prefix + 1 byte id + index, begin and length
+                self.bandwidth.upload.acquire(5 + 12).await;
+                framed
+                    .feed(PeerMessage::Request {
+                        index: piece_index as u32,
+                        begin: begin as u32,
+                        length: length as u32,
+                    })
+                    .await?;
+                in_flight.push((begin, length, Instant::now()));
+                requested = true;
+            }
+            if requested {
+                framed.flush().await?;
+            }
+            self.status
+                .requests_in_flight
+                .store(in_flight.len(), Ordering::Relaxed);
+            // the blocks left are with other peers
+            if in_flight.is_empty() {
+                return Ok(false);
+            }
+
+            // other frames do not count, a peer can chat along without ever sending a block
+            let snub_deadline = block_at + self.snub_timeout;
+            let frame = match tokio::time::timeout_at(
+                snub_deadline.min(piece_deadline).into(),
+                self.next_frame(framed),
+            )
+            .await
+            {
+                Result::Ok(frame) => frame?,
+                // the blocks still on their way are cancelled, the peer need not send them
+                Err(_) => {
+                    for &(begin, length, _) in &in_flight {
+                        self.bandwidth.upload.acquire(5 + 12).await;
+                        framed
+                            .feed(PeerMessage::Cancel {
+                                index: piece_index as u32,
+                                begin: begin as u32,
+                                length: length as u32,
+                            })
+                            .await?;
+                    }
+                    framed.flush().await?;
+                    if Instant::now() >= snub_deadline {
+                        return Err(Snubbed.into());
+                    }
+                    return Err(PieceTimedOut.into());
+                }
+            };
+            peer.update(&frame)?;
+            match frame {
+                // the peer drops our outstanding requests
+                PeerMessage::Choke => {
+                    self.status.requests_in_flight.store(0, Ordering::Relaxed);
+                    return Ok(false);
+                }
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                } => {
+                    let begin = begin as usize;
+                    if index as usize != piece_index {
+                        continue;
+                    }
+                    // a block may arrive twice, or after another peer sent it
+                    let received =
+                        self.partial_pieces
+                            .put(piece_index, worker, addr, begin, &block)?;
+                    if received == Received::Ignored {
+                        continue;
+                    }
+                    block_at = Instant::now();
+                    if let Some(position) = in_flight.iter().position(|&(other, ..)| other == begin)
+                    {
+                        let (_, _, requested_at) = in_flight.remove(position);
+                        pipeline.received(block.len(), requested_at, block_at);
+                    }
+                    self.bandwidth.download.acquire(block.len()).await;
+                    if let Received::Complete {
+                        data,
+                        hash,
+                        sources,
+                    } = received
+                    {
+                        self.status.requests_in_flight.store(0, Ordering::Relaxed);
+                        let stored = self.store_piece(piece_index, data, hash).await;
+                        self.partial_pieces.finish(piece_index, stored.is_ok());
+                        // this peer answers for the piece in exchange_pieces, the others here
+                        if stored.as_ref().is_err_and(|e| e.is::<HashMismatch>()) {
+                            for &source in sources.iter().filter(|&&source| Some(source) != addr) {
+                                self.peer_manager.hash_failed(source);
+                            }
+                        }
+                        return stored.map(|()| true);
                     }
-                    current_offset = 0;
                 }
+                _ => {}
             }
-
-            piece_mapping.insert(piece_index, piece_location_vec);
         }
-        Ok(piece_mapping)
     }
 
-    fn pieces_to_be_downloaded(
+    // Writes a downloaded piece once it matches its hash, piece_hash is the SHA-1 of the data.
+    async fn store_piece(
         &self,
-        total_pieces_to_download: usize,
-        // piece_mapping: HashMap<usize, Vec<PieceLocationMap>>,
-        piece_mapping: Arc<HashMap<usize, Vec<PieceLocationMap>>>,
-    ) -> anyhow::Result<Vec<usize>> {
-        let mut to_be_downloaded_pieces: Vec<usize> = Vec::new();
-        let mut current_path = &piece_mapping[&0][0].path;
-        let mut current_file_handler = File::open(current_path).unwrap();
-
-        for piece_index in 0..total_pieces_to_download {
-            let buffer_len = piece_mapping[&piece_index]
-                .iter()
-                .fold(0, |acc, x| acc + x.length);
+        piece_index: usize,
+        piece_data: Vec<u8>,
+        piece_hash: [u8; 20],
+    ) -> anyhow::Result<()> {
+        if self.pieces_hash[piece_index] != piece_hash
+            || self
+                .pieces_hash_v2
+                .as_ref()
+                .is_some_and(|hashes| !hashes.verify(piece_index, &piece_data))
+        {
+            return Err(HashMismatch.into());
+        }
 
-            let mut buf: Vec<u8> = Vec::with_capacity(buffer_len);
+        self.disk_io
+            .write_piece(piece_index, piece_data)
+            .await
+            .context("Piece was not stored")
+    }
 
-            for piece_location_map in piece_mapping[&piece_index].iter() {
-                let mut sub_buf: Vec<u8> = Vec::with_capacity(piece_location_map.length);
-                if &piece_location_map.path != current_path {
-                    current_path = &piece_location_map.path;
-                    current_file_handler = File::open(&piece_location_map.path).unwrap();
-                }
-                current_file_handler.seek(SeekFrom::Start(piece_location_map.offset as u64))?;
-                current_file_handler.read_exact(&mut sub_buf).unwrap();
-                buf.append(&mut sub_buf);
+    // Downloads pieces from a web seed next to the peers. It takes them from the front of the
+    // queue while peers take them from the back. A web seed that sends a bad piece, or fails too
+    // many times in a row, is given up on.
+    async fn download_from_web_seed(self, seed: WebSeed) {
+        let mut failures = 0;
+        loop {
+            if *self.hold.borrow() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
             }
-            if calc_sha1_hash(buf) != self.info.pieces.0[piece_index] {
-                to_be_downloaded_pieces.push(piece_index);
+            let Some(piece_index) = self.pieces.claim_front().await else {
+                if self.have.complete() {
+                    return;
+                }
+                // peers are working on the pieces left and one of them may fail
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            };
+            let claimed = ClaimedPiece {
+                task: &self,
+                index: piece_index,
+                stored: false,
+                worker: None,
+            };
+            let stored = match seed.fetch_piece(piece_index).await {
+                Result::Ok(piece_data) => {
+                    self.bandwidth.download.acquire(piece_data.len()).await;
+                    let piece_hash = calc_sha1_hash(&piece_data);
+                    self.store_piece(piece_index, piece_data, piece_hash).await
+                }
+                Err(e) => Err(e),
+            };
+            match stored {
+                Result::Ok(()) => {
+                    // blocks peers left of the piece are not needed any more
+                    self.partial_pieces.remove(piece_index);
+                    claimed.stored();
+                    failures = 0;
+                }
+                Err(e) if e.is::<HashMismatch>() => {
+                    drop(claimed);
+                    self.events.send(|info_hash| SessionEvent::PieceFailed {
+                        info_hash,
+                        piece: piece_index,
+                    });
+                    warn!(
+                        "Dropped web seed {}: piece {piece_index} failed the hash check",
+                        seed.url()
+                    );
+                    return;
+                }
+                Err(e) => {
+                    drop(claimed);
+                    failures += 1;
+                    warn!("web seed {}: {e:#}", seed.url());
+                    if failures == WEB_SEED_MAX_FAILURES {
+                        warn!("Dropped web seed {}: too many failures", seed.url());
+                        return;
+                    }
+                    tokio::time::sleep(WEB_SEED_RETRY_DELAY * failures).await;
+                }
             }
         }
+    }
 
-        Ok(to_be_downloaded_pieces)
+    async fn next_frame(&self, framed: &mut PeerFramed) -> anyhow::Result<PeerMessage> {
+        peers::next_frame(framed, KEEP_ALIVE_INTERVAL, self.peer_timeout)
+            .await?
+            .context("peer closed the connection")
     }
+}
 
-    pub async fn start_download(&mut self) -> anyhow::Result<()> {
-        // Create a directory if it does not already exist
-        let download_directory_path = format!(
-            "Downloaded/{}",
-            &self
-                .info
-                .name
-                .split('.')
-                .next()
-                .context("Removing extension from the torrent name")?
-        );
-        std::fs::create_dir_all(&download_directory_path)
-            .context("Creating directory to store the downloaded content")?;
+// Sends our handshake and reads the peer's answer.
+pub async fn exchange_handshake(
+    stream: &mut TcpStream,
+    encoded_handshake: &[u8],
+) -> anyhow::Result<HandShake> {
+    stream
+        .write_all(encoded_handshake)
+        .await
+        .context("Sending handshake")?;
+    let mut response = vec![0_u8; encoded_handshake.len()];
+    stream
+        .read_exact(&mut response)
+        .await
+        .context("Reading handshake")?;
+    bincode::deserialize(&response).context("Decoding handshake")
+}
 
-        // reserve space for files to be downloaded
-        self.reserve_space(&download_directory_path);
+// Adds the files below directory to files in sorted order, with their path components relative
+// to the directory the walk started in.
+fn collect_files(
+    directory: &Path,
+    components: &mut Vec<String>,
+    files: &mut Vec<(PathBuf, Vec<String>)>,
+) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(directory)
+        .with_context(|| format!("Reading directory {}", directory.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    entries.sort();
+    for path in entries {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} is not valid UTF-8", path.display()))?
+            .to_string();
+        components.push(name);
+        if path.is_dir() {
+            collect_files(&path, components, files)?;
+        } else {
+            files.push((path, components.clone()));
+        }
+        components.pop();
+    }
+    Ok(())
+}
 
-        let total_pieces_to_download = self.info.pieces.0.len();
+impl Torrent {
+    pub fn calc_hash(&self) -> anyhow::Result<[u8; 20]> {
+        let mut hasher = Sha1::new();
+        hasher.update(self.info_bytes()?);
+        let info_hash = hasher.finalize().into();
+        Ok(info_hash)
+    }
 
-        let torrent_data_len: usize = match self.info.file_type {
-            FileType::SingleFile { length } => length,
-            FileType::MultiFile { ref files } => files.iter().map(|file| file.length).sum(),
-        };
+    // The v2 info hash of a hybrid torrent (BEP 52), None for v1 torrents.
+    pub fn calc_hash_v2(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        if self.info.meta_version != Some(2) {
+            return Ok(None);
+        }
+        Ok(Some(Sha256::digest(self.info_bytes()?).into()))
+    }
 
-        println!(
-            "Total bytes to download: {}\nTotal pieces to download: {}\n",
-            torrent_data_len, total_pieces_to_download
-        );
+    // The bencoded info dictionary the info hashes are taken from.
+    fn info_bytes(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        match &self.info_bytes {
+            Some(info_bytes) => Ok(Cow::Borrowed(info_bytes)),
+            None => serde_bencode::to_bytes::<Info>(&self.info)
+                .map(Cow::Owned)
+                .context("Metainfo file's Info conversion to bytes"),
+        }
+    }
 
-        // generate a mapping of piece to its corresponding files
-        let piece_mapping = Arc::new(self.genereate_piece_mapping(
-            total_pieces_to_download,
-            torrent_data_len,
-            &download_directory_path,
-        )?);
+    // The torrent of the metainfo of a .torrent file made from an info dictionary fetched from
+    // peers, which keeps the info hash of the bytes as they were fetched.
+    pub fn from_metadata(metainfo: &[u8], info: Vec<u8>) -> anyhow::Result<Torrent> {
+        let mut torrent = serde_bencode::from_bytes::<Torrent>(metainfo)
+            .map_err(RustyBitError::bencode("The metadata"))?;
+        torrent.info_bytes = Some(Arc::new(info));
+        Ok(torrent)
+    }
 
-        // find out the completion status
-        let pieces_to_download = Arc::new(Mutex::new(
-            self.pieces_to_be_downloaded(total_pieces_to_download, piece_mapping.clone())?,
-        ));
+    // The info hashes peers of the torrent are found under: the v1 one, and for hybrid torrents
+    // the v2 one truncated to 20 bytes, as trackers, the DHT and handshakes use it.
+    fn swarms(&self) -> anyhow::Result<Vec<[u8; 20]>> {
+        let mut swarms = vec![self.calc_hash()?];
+        if let Some(info_hash_v2) = self.calc_hash_v2()? {
+            swarms.push(info_hash_v2[..20].try_into().unwrap());
+        }
+        Ok(swarms)
+    }
 
-        println!("pieces to download are {pieces_to_download:?}");
+    // What pieces are checked against besides their SHA1, for hybrid torrents.
+    fn piece_hashes_v2(&self) -> anyhow::Result<Option<PieceHashesV2>> {
+        if self.info.meta_version != Some(2) {
+            return Ok(None);
+        }
+        let file_tree = self
+            .info
+            .file_tree
+            .as_ref()
+            .context("The v2 torrent has no file tree")?;
+        let name = [self.info.name.clone()];
+        let files: Vec<V1File> = match &self.info.file_type {
+            FileType::SingleFile { length } => vec![V1File {
+                path: &name,
+                length: *length,
+                padding: false,
+            }],
+            FileType::MultiFile { files } => files
+                .iter()
+                .map(|file| V1File {
+                    path: &file.path,
+                    length: file.length,
+                    padding: file.is_padding(),
+                })
+                .collect(),
+        };
+        PieceHashesV2::new(
+            self.info.piece_length,
+            &files,
+            file_tree,
+            self.piece_layers.as_ref(),
+        )
+        .map(Some)
+        .context("Reading the v2 hashes of the hybrid torrent")
+    }
 
-        let info_hash = self.calc_hash().context("Calculate metainfo hash")?;
+    /*
+     * Creates a torrent for a file or a directory. The files of a directory are added in
+     * sorted path order. The first tracker is the announce URL for clients that don't know
+     * about tiers, the announce list is only added when there is more than one tracker.
+     */
+    pub fn create(source: &Path, options: &CreateOptions) -> anyhow::Result<Torrent> {
+        let name = source
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} has no usable name", source.display()))?
+            .to_string();
 
-        let announce = &self.announce;
-        println!(
-            "Starting download now, trying to contact tracker at {}\n",
-            announce
-        );
+        let mut files = Vec::new();
+        let file_type = if source.is_dir() {
+            collect_files(source, &mut Vec::new(), &mut files)?;
+            FileType::MultiFile {
+                files: files
+                    .iter()
+                    .map(|(path, components)| -> anyhow::Result<TorrentFile> {
+                        Ok(TorrentFile {
+                            length: std::fs::metadata(path)?.len() as usize,
+                            path: components.clone(),
+                            attr: None,
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            }
+        } else {
+            files.push((source.to_path_buf(), Vec::new()));
+            FileType::SingleFile {
+                length: std::fs::metadata(source)
+                    .with_context(|| format!("Reading {}", source.display()))?
+                    .len() as usize,
+            }
+        };
 
-        let peer_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 20);
-        let tracker_request = TrackerRequest::new(info_hash, torrent_data_len, &peer_id);
-        let url = tracker_request.url(announce);
+        let piece_length = match options.piece_length {
+            Some(0) => bail!("Piece length can't be 0"),
+            Some(piece_length) => piece_length,
+            None => piece_length_for(match &file_type {
+                FileType::SingleFile { length } => *length,
+                FileType::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+            }),
+        };
 
-        // let response = reqwest::Client::new()
-        //     .get(url)
-        //     .send()
-        //     .await
-        //     .with_context(|| format!("Requesting tracker {}", announce))?;
+        // pieces run on from one file into the next
+        let mut pieces = Vec::new();
+        let mut piece = Vec::with_capacity(piece_length);
+        for (path, _) in &files {
+            let mut file =
+                std::fs::File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+            loop {
+                let read = file
+                    .by_ref()
+                    .take((piece_length - piece.len()) as u64)
+                    .read_to_end(&mut piece)
+                    .with_context(|| format!("Reading {}", path.display()))?;
+                if piece.len() == piece_length {
+                    pieces.push(calc_sha1_hash(&piece));
+                    piece.clear();
+                } else if read == 0 {
+                    break;
+                }
+            }
+        }
+        if !piece.is_empty() {
+            pieces.push(calc_sha1_hash(&piece));
+        }
 
-        let response = reqwest::get(url)
-            .await
-            .with_context(|| format!("Requesting tracker {}", announce))?;
-
-        let tracker_reponse: TrackerResponse =
-            serde_bencode::from_bytes(&response.bytes().await.with_context(|| {
-                format!("Converting tracker's ({}) response to bytes", announce)
-            })?)
-            .with_context(|| {
-                format!(
-                    "Converting tracker's ({}) response bytes to TrackerResponse",
-                    announce
+        let tiers: Vec<Vec<String>> = options
+            .trackers
+            .iter()
+            .map(|tier| tier.iter().filter(|url| !url.is_empty()).cloned().collect())
+            .filter(|tier: &Vec<String>| !tier.is_empty())
+            .collect();
+        let trackers: Vec<String> = tiers.iter().flatten().cloned().collect();
+
+        Ok(Torrent {
+            info: Info {
+                name,
+                piece_length,
+                pieces: Hashes(pieces),
+                file_type,
+                meta_version: None,
+                file_tree: None,
+                private: options.private.then_some(1),
+            },
+            announce: trackers.first().cloned().unwrap_or_default(),
+            announce_list: (trackers.len() > 1).then_some(tiers),
+            url_list: Vec::new(),
+            piece_layers: None,
+            comment: options.comment.clone(),
+            created_by: Some(format!("Rusty-Bit {}", env!("CARGO_PKG_VERSION"))),
+            creation_date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|since| since.as_secs()),
+            info_bytes: None,
+        })
+    }
+
+    // A magnet link (BEP 9) with the info hash, the name and every tracker, hybrid torrents get
+    // their v2 info hash as well.
+    pub fn magnet_link(&self) -> anyhow::Result<String> {
+        let hex =
+            |bytes: &[u8]| -> String { bytes.iter().map(|byte| format!("{byte:02x}")).collect() };
+        let mut link = format!("magnet:?xt=urn:btih:{}", hex(&self.calc_hash()?));
+        if let Some(info_hash_v2) = self.calc_hash_v2()? {
+            // a multihash, SHA-256 with a length of 32 bytes
+            link += &format!("&xt=urn:btmh:1220{}", hex(&info_hash_v2));
+        }
+        link += &format!("&dn={}", urlencoding::encode(&self.info.name));
+        for tracker in self.tracker_tiers(&[]).iter().flatten() {
+            link += &format!("&tr={}", urlencoding::encode(tracker));
+        }
+        Ok(link)
+    }
+
+    // Announce URLs to try, tier by tier. Every extra tracker is added as a tier of its own
+    // unless the torrent already has it. The torrent itself is not changed.
+    fn tracker_tiers(&self, extra_trackers: &[String]) -> Vec<Vec<String>> {
+        let mut tiers = match &self.announce_list {
+            Some(announce_list) => announce_list.clone(),
+            None => vec![vec![self.announce.clone()]],
+        };
+        tiers
+            .iter_mut()
+            .for_each(|tier| tier.retain(|url| !url.is_empty()));
+        tiers.retain(|tier| !tier.is_empty());
+        for tracker in extra_trackers {
+            if !tiers.iter().flatten().any(|url| url == tracker) {
+                tiers.push(vec![tracker.clone()]);
+            }
+        }
+        tiers
+    }
+
+    // On-disk path and length of every file of the torrent, in torrent order.
+    fn file_paths(&self, download_directory_path: &str) -> Vec<(PathBuf, usize)> {
+        let download_directory = Path::new(download_directory_path);
+        match &self.info.file_type {
+            FileType::SingleFile { length } => vec![(
+                file_paths::on_disk_path(download_directory, std::slice::from_ref(&self.info.name)),
+                *length,
+            )],
+            FileType::MultiFile { files } => files
+                .iter()
+                .map(|file| {
+                    (
+                        file_paths::on_disk_path(download_directory, &file.path),
+                        file.length,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    // Paths of the files within the torrent, as they are named in the metainfo.
+    fn file_names(&self) -> Vec<String> {
+        match &self.info.file_type {
+            FileType::SingleFile { .. } => vec![self.info.name.clone()],
+            FileType::MultiFile { files } => files.iter().map(|file| file.path.join("/")).collect(),
+        }
+    }
+
+    // bytes the files reserve_space creates will hold, files that already exist are not touched
+    fn space_to_reserve(&self, files: &[(PathBuf, usize)], unwritten: &[usize]) -> u64 {
+        files
+            .iter()
+            .enumerate()
+            .filter(|(file_index, (file_path, _))| {
+                !unwritten.contains(file_index) && !file_path.exists()
+            })
+            .map(|(_, (_, length))| *length as u64)
+            .sum()
+    }
+
+    // reserve space for files to be downloaded, except the unwritten ones no wanted piece touches
+    fn reserve_space(
+        &self,
+        files: &[(PathBuf, usize)],
+        unwritten: &[usize],
+        allocation: Allocation,
+        backend: DiskBackend,
+    ) -> anyhow::Result<()> {
+        for (file_index, (file_path, length)) in files.iter().enumerate() {
+            if !unwritten.contains(&file_index) && !file_path.exists() {
+                let parent_path = file_path.parent().expect("There has to be a parent");
+                std::fs::create_dir_all(parent_path)
+                    .map_err(RustyBitError::disk(&parent_path.display().to_string()))
+                    .with_context(|| {
+                        format!("could not create directory {}", parent_path.display())
+                    })?;
+                preallocate_file(file_path, *length, allocation, backend)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Priority of every file as the config sets it. Padding files are never wanted.
+    fn file_priorities(
+        &self,
+        priorities: &HashMap<usize, FilePriority>,
+    ) -> anyhow::Result<Vec<FilePriority>> {
+        let padding: Vec<bool> = match &self.info.file_type {
+            FileType::SingleFile { .. } => vec![false],
+            FileType::MultiFile { files } => files.iter().map(TorrentFile::is_padding).collect(),
+        };
+        if let Some(file_index) = priorities
+            .keys()
+            .find(|&&file_index| file_index >= padding.len())
+        {
+            bail!(
+                "There is no file {file_index}, the torrent has {} files",
+                padding.len()
+            );
+        }
+        Ok(padding
+            .iter()
+            .enumerate()
+            .map(|(file_index, &padding)| match priorities.get(&file_index) {
+                _ if padding => FilePriority::Skip,
+                Some(&priority) => priority,
+                None => FilePriority::default(),
+            })
+            .collect())
+    }
+
+    // Directory the torrent is downloaded into, named after the torrent.
+    fn download_directory(&self, config: &Config) -> anyhow::Result<String> {
+        self.directory_in(&config.download_dir)
+    }
+
+    // Where a .torrent file of the torrent is saved by default, next to its download directory,
+    // e.g. Downloaded/ubuntu.torrent for Downloaded/ubuntu.
+    pub fn saved_torrent_path(&self, config: &Config) -> anyhow::Result<PathBuf> {
+        Ok(PathBuf::from(format!(
+            "{}.torrent",
+            self.download_directory(config)?
+        )))
+    }
+
+    // Directory the files of the torrent are in until they are complete.
+    fn incomplete_directory(&self, config: &Config) -> anyhow::Result<String> {
+        self.directory_in(
+            config
+                .incomplete_dir
+                .as_ref()
+                .unwrap_or(&config.download_dir),
+        )
+    }
+
+    fn directory_in(&self, parent: &Path) -> anyhow::Result<String> {
+        Ok(parent
+            .join(file_paths::normalize_component(
+                self.info
+                    .name
+                    .split('.')
+                    .next()
+                    .context("Removing extension from the torrent name")?,
+            ))
+            .to_str()
+            .context("Download directory is not valid UTF-8")?
+            .to_string())
+    }
+
+    // Runs the hook command for the torrent in the download directory of the config.
+    pub fn run_hook(
+        &self,
+        command: &str,
+        config: &Config,
+        info_hash: [u8; 20],
+        error: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let path = self.download_directory(config)?;
+        hooks::run(
+            command,
+            &HookTorrent {
+                name: &self.info.name,
+                path: Path::new(&path),
+                info_hash,
+                size: self.info.total_length(),
+            },
+            error,
+        );
+        Ok(())
+    }
+
+    // Where the files are downloaded to and where they are moved once complete.
+    fn staging(&self, config: &Config) -> anyhow::Result<Staging> {
+        let incomplete = self
+            .file_paths(&self.incomplete_directory(config)?)
+            .into_iter()
+            .map(|(path, length)| {
+                if !config.part_suffix {
+                    return (path, length);
+                }
+                let mut path = path.into_os_string();
+                path.push(staging::PART_SUFFIX);
+                (PathBuf::from(path), length)
+            })
+            .collect();
+        Ok(Staging::new(
+            self.incomplete_directory(config)?.into(),
+            incomplete,
+            self.file_paths(&self.download_directory(config)?),
+        ))
+    }
+
+    // Pieces that are not in the download directory yet, found by hashing what is there.
+    pub fn missing_pieces(&self, config: &Config) -> anyhow::Result<Vec<usize>> {
+        let piece_map = self.piece_map(&self.staging(config)?.files());
+        verify::missing_pieces(
+            &piece_map,
+            &FileStorage::new(config.max_open_files).with_backend(config.disk_backend),
+            &self.info.pieces.0,
+            self.piece_hashes_v2()?.as_ref(),
+            &|_| {},
+        )
+    }
+
+    // Human readable summary of the metainfo.
+    pub fn describe(&self) -> anyhow::Result<String> {
+        let info_hash: String = self
+            .calc_hash()?
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let mut description = format!(
+            "Name: {}\nInfo hash: {info_hash}\nPiece length: {} bytes\nPieces: {}\n",
+            self.info.name,
+            self.info.piece_length,
+            self.info.total_pieces()
+        );
+        if let Some(info_hash_v2) = self.calc_hash_v2()? {
+            let info_hash_v2: String = info_hash_v2
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            description += &format!("Info hash v2: {info_hash_v2}\n");
+        }
+        description += &format!(
+            "Private: {}\n",
+            if self.info.is_private() { "yes" } else { "no" }
+        );
+        if let Some(comment) = &self.comment {
+            description += &format!("Comment: {comment}\n");
+        }
+        // numbered as --file-priority expects, padding files keep their numbers
+        let files: Vec<(usize, String, usize)> = match &self.info.file_type {
+            FileType::SingleFile { length } => vec![(0, self.info.name.clone(), *length)],
+            FileType::MultiFile { files } => files
+                .iter()
+                .enumerate()
+                .filter(|(_, file)| !file.is_padding())
+                .map(|(file_index, file)| (file_index, file.path.join("/"), file.length))
+                .collect(),
+        };
+        let total_length: usize = files.iter().map(|(_, _, length)| length).sum();
+        description += &format!("Total size: {total_length} bytes\nFiles:\n");
+        for (file_index, path, length) in files {
+            description += &format!("  {file_index}: {path} ({length} bytes)\n");
+        }
+        let tiers = self.tracker_tiers(&[]);
+        if tiers.is_empty() {
+            description += "Trackers: none\n";
+        } else {
+            description += "Trackers:\n";
+            for (tier, trackers) in tiers.iter().enumerate() {
+                description += &format!("  tier {}: {}\n", tier + 1, trackers.join(", "));
+            }
+        }
+        if !self.url_list.is_empty() {
+            description += &format!("Web seeds: {}\n", self.url_list.join(", "));
+        }
+        Ok(description)
+    }
+
+    // Where the pieces of the torrent go on disk, for the paths and lengths of its files.
+    fn piece_map(&self, files: &[(PathBuf, usize)]) -> PieceMap {
+        PieceMap::new(
+            self.info.piece_length,
+            files
+                .iter()
+                .map(|(path, length)| (path.to_str().unwrap().to_string(), *length))
+                .collect(),
+        )
+    }
+
+    // Runs the torrent on its own, with nothing shared with other torrents.
+    #[cfg(test)]
+    pub async fn run(&mut self, config: &Config, control: &Control) -> anyhow::Result<()> {
+        control.set_sequential(config.sequential);
+        control.set_limits(config.torrent_download_limit, config.torrent_upload_limit);
+        let shared = Shared::new(config);
+        let result = self.run_in(&shared, config, control).await;
+        shared.close().await;
+        result
+    }
+
+    // Downloads the torrent, and seeds it afterwards if the config says so, until it is complete
+    // or the control asks it to stop. The state and the pieces are reported to the control. The
+    // listen port, the peer id, the rate limiters and the open files are shared with the other
+    // torrents of the session. What it logs is in a span named after the torrent.
+    //
+    // This is the coordinator of the torrent: it lays out the files, checks them and finds peers,
+    // then leaves the work to tasks of their own that it talks to over channels. The piece queue
+    // hands out pieces, the disk task writes them, the connector runs a task per peer and web seed,
+    // the announcer keeps the tracker up to date and sends the connector the peers it finds. What
+    // is left here is pausing, stopping and what happens once the download is over.
+    #[instrument(name = "torrent", skip_all, fields(name = %self.info.name))]
+    pub async fn run_in(
+        &mut self,
+        shared: &Shared,
+        config: &Config,
+        control: &Control,
+    ) -> anyhow::Result<()> {
+        control.set_state(TorrentState::Starting);
+        let layout = self.lay_out(config)?;
+        let piece_map = layout.piece_map.clone();
+        let total_pieces_to_download = piece_map.total_pieces();
+        // the same handles are used for checking, downloading and uploading
+        let storage = shared.storage.clone();
+        storage.forget_relocations(piece_map.files().iter().map(|(path, _)| path));
+
+        let swarms = self.swarms().context("Calculate metainfo hash")?;
+        let info_hash = swarms[0];
+        let events = Events::new(shared.events.clone(), shared.metrics.clone(), info_hash);
+        let pieces_hash = Arc::new(self.info.pieces.0.clone());
+        let pieces_hash_v2 = self.piece_hashes_v2()?.map(Arc::new);
+        let checked = self
+            .check(
+                &layout,
+                &storage,
+                info_hash,
+                &pieces_hash,
+                pieces_hash_v2.as_ref(),
+                control,
+            )
+            .await?;
+        let have = checked.have.clone();
+        // the completion hook is not run again for a torrent that was already complete
+        let completes_now = !checked.missing_pieces.is_empty();
+        let left: usize = checked
+            .missing_pieces
+            .iter()
+            .map(|&piece_index| piece_map.piece_range(piece_index).len())
+            .sum();
+        debug!("pieces to download are {:?}", checked.missing_pieces);
+        let staging = (!layout.staging.is_done()).then(|| {
+            let (finish, finished) = oneshot::channel();
+            let task =
+                layout
+                    .staging
+                    .run(piece_map.clone(), have.clone(), storage.clone(), finished);
+            (tokio::spawn(task.in_current_span()), finish)
+        });
+        let pieces = PieceQueue::spawn(
+            checked.missing_pieces,
+            have.clone(),
+            layout.piece_priorities,
+            control.sequential_receiver(),
+            config.sequential_lookahead,
+        );
+
+        let resolver = Resolver::new(config);
+        let peer_id = shared.peer_id;
+        let mut tracker_request = TrackerRequest::new(info_hash, layout.torrent_data_len, peer_id);
+        tracker_request.left = left;
+        let lookup = PeerLookup {
+            tracker_tiers: self.tracker_tiers(&config.trackers),
+            private: self.info.is_private(),
+            swarms: swarms.clone(),
+        };
+        let Some(found) = lookup
+            .find(shared, config, &resolver, tracker_request, &events)
+            .await?
+        else {
+            return Ok(());
+        };
+        if control.stop_requested() {
+            return Ok(());
+        }
+        debug!("All the available peers are: {:?}", found.peers);
+        debug!("Connecting to the peers");
+
+        // our handshake in every swarm
+        let handshakes: Vec<([u8; 20], Arc<Vec<u8>>)> = swarms
+            .iter()
+            .map(|&swarm_hash| {
+                let handshake = HandShake::new(swarm_hash, peer_id);
+                (
+                    swarm_hash,
+                    Arc::new(bincode::serialize(&handshake).unwrap()),
                 )
-            })?;
+            })
+            .collect();
 
-        match tracker_reponse.tracker_response_type {
-            tracker::TrackerResponseType::Success {
-                complete: _,
-                incomplete: _,
-                interval: _,
-                peers,
-                tracker_id: _,
-            } => {
-                println!("Connected to the tracker {announce}");
+        // files are moved once their pieces are verified, they have to be written by then
+        let write_through;
+        let disk_config = if staging.is_some() {
+            write_through = Config {
+                write_cache: 0,
+                ..config.clone()
+            };
+            &write_through
+        } else {
+            config
+        };
+        let disk_io = DiskIo::spawn(
+            storage.clone(),
+            piece_map.clone(),
+            disk_config,
+            Some(shared.metrics.clone()),
+        );
+        control.track(have.clone());
+        control.set_state(TorrentState::Downloading);
+
+        let bandwidth = control.bandwidth(&shared.bandwidth);
+        let progress = Progress {
+            path: layout.resume_path,
+            info_hash,
+            piece_map: piece_map.clone(),
+            storage: storage.clone(),
+            have: have.clone(),
+            bandwidth: bandwidth.clone(),
+            uploaded_before: checked.uploaded_before,
+            downloaded_before: checked.downloaded_before,
+        };
+        // uploads end with the torrent, freeing their connection slots
+        let (stop_uploads, uploads_stopped) = watch::channel(false);
+        let choker = Arc::new(Choker::new(config.upload_slots));
+        let rechoking = tokio::spawn(choker.clone().run());
+        let uploader = Uploader {
+            piece_map: piece_map.clone(),
+            disk_io: disk_io.clone(),
+            have: have.clone(),
+            bandwidth: bandwidth.clone(),
+            peer: None,
+            stop: uploads_stopped,
+            choker,
+            lazy_bitfield: config.lazy_bitfield,
+        };
+        let peer_manager = Arc::new(PeerManager::new(RetryPolicy::from_config(config)));
+        let peer_task = PeerTask {
+            pieces: pieces.clone(),
+            disk_io: disk_io.clone(),
+            have: have.clone(),
+            pieces_hash,
+            pieces_hash_v2,
+            piece_length: self.info.piece_length,
+            total_pieces_to_download,
+            torrent_data_len: layout.torrent_data_len,
+            bandwidth: bandwidth.clone(),
+            status: Arc::default(),
+            peer_timeout: Duration::from_secs(2 * 60),
+            snub_timeout: SNUB_TIMEOUT,
+            piece_timeout: SNUB_TIMEOUT
+                + Duration::from_secs((self.info.piece_length / MIN_PIECE_RATE) as u64),
+            block_length: config.block_size,
+            lazy_bitfield: config.lazy_bitfield,
+            request_queue_depth: config.request_queue_depth,
+            partial_pieces: Arc::new(PartialPieces::new(config.block_size)),
+            seed: config.seed.then(|| uploader.clone()),
+            peer_manager: peer_manager.clone(),
+            hold: control.hold_receiver(),
+            events: events.clone(),
+        };
 
-                let peer_list: Vec<String> = peers
-                    .0
+        // Every connection, outgoing or incoming, holds a permit while it is open. Peers we
+        // connect to wait for one in the order they were found, incoming connections beyond the
+        // limit are closed.
+        let connection_permits = Arc::new(Semaphore::new(config.max_connections));
+        // the torrent leaves its swarms when the task ends
+        let incoming = shared.network(config).await.listening.then(|| {
+            tokio::spawn(
+                accept_peers(
+                    shared.swarms.join(&handshakes),
+                    peer_task.clone(),
+                    uploader.clone(),
+                    connection_permits.clone(),
+                    control.peers(),
+                )
+                .in_current_span(),
+            )
+        });
+        let stream_server = config
+            .stream_port
+            .and_then(|port| self.stream(port, &piece_map, &disk_io, &have, &pieces));
+
+        // The tracker is announced to again on its interval, the peers it returns go to the
+        // connector. While it is announced to, running out of peers waits for the next announce.
+        let http_client = shared.http_client(config).await?;
+        let (found_sender, found_receiver) = mpsc::unbounded_channel();
+        let peers_from = found
+            .announced_to
+            .as_ref()
+            .map(|(announce, _)| tracker::redacted(announce));
+        let announcing = found.announced_to.map(|(announce, interval)| {
+            let announcer = Announcer {
+                announce,
+                requests: swarms
                     .iter()
-                    .map(|peer_info| format!("{}:{}", peer_info.ip_addr, peer_info.port))
-                    .collect();
-                println!("All the available peers are: {peer_list:?}");
-                println!("Connecting to the peers");
-
-                let mut handle_vec = Vec::new();
-
-                let handshake = HandShake::new(info_hash, peer_id.as_bytes().try_into().unwrap());
-                let encoded_handshake = Arc::new(bincode::serialize(&handshake).unwrap());
-
-                let file_handle_mapping: Arc<Mutex<HashMap<String, File>>> =
-                    Arc::new(Mutex::new(HashMap::new()));
-
-                let pieces_hash = &self.info.pieces.0;
-                for peer in peer_list {
-                    let encoded_handshake = encoded_handshake.clone();
-                    let pieces_to_download = pieces_to_download.clone();
-                    let file_handle_mapping = file_handle_mapping.clone();
-                    let piece_length = self.info.piece_length;
-                    let piece_mapping = piece_mapping.clone();
-                    let pieces_hash = pieces_hash.clone();
-                    handle_vec.push(tokio::spawn(async move {
-                        let mut stream = tokio::net::TcpStream::connect(peer)
-                            .await
-                            .context("Connecting with peer")
-                            .unwrap();
+                    .map(|&swarm_hash| {
+                        let mut request = found.tracker_request.clone();
+                        request.info_hash = swarm_hash;
+                        request
+                    })
+                    .collect(),
+                interval,
+                config: config.clone(),
+                client: http_client.clone(),
+                bandwidth: bandwidth.clone(),
+                have: have.clone(),
+                piece_map: piece_map.clone(),
+                events: events.clone(),
+            };
+            announcer.spawn(found_sender)
+        });
+        let web_seeds = if have.complete() {
+            Vec::new()
+        } else {
+            self.web_seeds(&piece_map, &http_client)
+        };
+        let connector = Connector {
+            peer_task,
+            handshakes: handshakes
+                .into_iter()
+                .map(|(_, handshake)| handshake)
+                .collect(),
+            config: Arc::new(config.clone()),
+            resolver,
+            peer_filter: shared.peer_filter.clone(),
+            connection_permits,
+            peers: control.peers(),
+            family_stats: FamilyStats::default(),
+        };
+        let mut connecting = tokio::spawn(
+            connector
+                .run(found.peers, web_seeds, found_receiver)
+                .in_current_span(),
+        );
+
+        // A stop request ends the peer tasks, their pieces stay in the queue. A hold keeps them
+        // connected, the progress so far is saved in case the process ends while held. The
+        // download is over once the connector ended and nothing holds it.
+        let mut held = false;
+        let mut downloading = true;
+        let stopped = loop {
+            if !downloading && !held {
+                break false;
+            }
+            tokio::select! {
+                _ = &mut connecting, if downloading => downloading = false,
+                _ = control.held(true), if !held => {
+                    held = true;
+                    match disk_io.sync_all().await {
+                        Result::Ok(()) => progress.save(),
+                        Err(e) => warn!("downloaded data may not be on disk yet: {e:#}"),
+                    }
+                    info!("Paused downloading {}", self.info.name);
+                    control.set_state(TorrentState::Paused);
+                }
+                _ = control.held(false), if held => {
+                    held = false;
+                    info!("Resumed downloading {}", self.info.name);
+                    control.set_state(TorrentState::Downloading);
+                }
+                _ = control.stopped() => break true,
+            }
+        };
+        connecting.abort();
+        let synced = disk_io.sync_all().await;
+        if let Err(e) = &synced {
+            warn!("downloaded data may not be on disk yet: {e:#}");
+        }
+        // the files that are complete are moved before the torrent counts as complete
+        if let Some((task, finish)) = staging {
+            let _ = finish.send(());
+            let _ = task.await;
+        }
+        let missing = have.missing();
+        if stopped {
+            info!("Stopped downloading {}", self.info.name);
+        } else if missing == 0 {
+            info!("Downloaded file {}", self.info.name);
+        } else {
+            warn!("No peers left to download {} from", self.info.name);
+        }
+        if !stopped && missing == 0 {
+            events.send(|info_hash| SessionEvent::TorrentCompleted { info_hash });
+            if let Some(command) = config.on_complete.as_deref().filter(|_| completes_now) {
+                self.run_hook(command, config, info_hash, None)?;
+            }
+        }
+        debug!(
+            "At most {} files were open at the same time",
+            storage.peak_open_files()
+        );
+        if let Some(tracker_name) = peers_from {
+            debug!("Peers came from the tracker {tracker_name}");
+        }
+        info!("Peers: {}", peer_manager.summary());
+        if config.seed && !stopped {
+            self.seed(config, control, &progress).await;
+        }
+        let _ = stop_uploads.send(true);
+        rechoking.abort();
+        if synced.is_ok() {
+            progress.save();
+        }
+        // the tracker stops handing us out to other peers
+        if let Some(announcing) = announcing {
+            announcing.stop().await;
+        }
+        if let Some(incoming) = incoming {
+            incoming.abort();
+        }
+        if let Some(stream_server) = stream_server {
+            stream_server.abort();
+        }
+        if !stopped && missing > 0 {
+            bail!(
+                "No peers left with {missing} of {} pieces missing",
+                self.info.total_pieces()
+            );
+        }
+        Ok(())
+    }
 
-                        // send handshake
-                        stream.write_all(&encoded_handshake.clone()).await.unwrap();
-                        let mut response = vec![0_u8; encoded_handshake.len()];
-                        stream.read_exact(&mut response).await.unwrap();
+    // Creates the download directory, works out where the pieces go on disk and reserves the
+    // space of the files to be downloaded.
+    fn lay_out(&self, config: &Config) -> anyhow::Result<Layout> {
+        disk_space::check_download_dir(&config.download_dir)?;
+        if let Some(incomplete_dir) = &config.incomplete_dir {
+            disk_space::check_download_dir(incomplete_dir)?;
+        }
+        // Create a directory if it does not already exist, the resume file is kept in it also
+        // while the files are in the incomplete directory
+        let download_directory_path = self.download_directory(config)?;
+        std::fs::create_dir_all(&download_directory_path)
+            .map_err(RustyBitError::disk(&download_directory_path))
+            .context("Creating directory to store the downloaded content")?;
+        // files already moved to the download directory are used from there
+        let staging = self.staging(config)?;
+        let files = staging.files();
 
-                        let _response_handshake: HandShake =
-                            bincode::deserialize(&response).unwrap();
+        let total_pieces_to_download = self.info.pieces.0.len();
 
-                        let mut framed = tokio_util::codec::Framed::new(stream, PeerFrameCodec);
+        let torrent_data_len: usize = match self.info.file_type {
+            FileType::SingleFile { length } => length,
+            FileType::MultiFile { ref files } => files.iter().map(|file| file.length).sum(),
+        };
 
-                        let _ = framed.next().await.unwrap().unwrap(); // bitfield msg
+        debug!("{torrent_data_len} bytes in {total_pieces_to_download} pieces to download");
 
-                        framed
-                            .send(PeerMsgType::new(PeerMsgTag::Interested, Vec::new()))
+        let piece_map = Arc::new(self.piece_map(&files));
+        if piece_map.total_pieces() != total_pieces_to_download {
+            bail!(
+                "The torrent has {total_pieces_to_download} piece hashes but its files make up {} pieces",
+                piece_map.total_pieces()
+            );
+        }
+
+        // A piece has the priority of the most wanted file it holds data of, so pieces of skipped
+        // files are left out unless they hold data of wanted files too. Skipped files no wanted
+        // piece touches are not created.
+        let file_priorities = self.file_priorities(&config.file_priorities)?;
+        let piece_priorities: Vec<FilePriority> = (0..total_pieces_to_download)
+            .map(|piece_index| {
+                piece_map
+                    .locations(piece_index)
+                    .iter()
+                    .map(|location| file_priorities[location.file_index as usize])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+        let mut written: Vec<bool> = file_priorities
+            .iter()
+            .map(|&priority| priority != FilePriority::Skip)
+            .collect();
+        for piece_index in (0..total_pieces_to_download)
+            .filter(|&piece_index| piece_priorities[piece_index] != FilePriority::Skip)
+        {
+            for location in piece_map.locations(piece_index) {
+                written[location.file_index as usize] = true;
+            }
+        }
+        let unwritten: Vec<usize> = (0..written.len())
+            .filter(|&file_index| !written[file_index])
+            .collect();
+
+        // reserve space for files to be downloaded
+        disk_space::ensure_free_space(
+            config
+                .incomplete_dir
+                .as_ref()
+                .unwrap_or(&config.download_dir),
+            self.space_to_reserve(&files, &unwritten),
+            config.allow_low_space,
+        )?;
+        self.reserve_space(&files, &unwritten, config.allocation, config.disk_backend)?;
+        Ok(Layout {
+            resume_path: resume::path(&download_directory_path),
+            staging,
+            piece_map,
+            piece_priorities,
+            torrent_data_len,
+        })
+    }
+
+    // Finds out the completion status, the resume file saves hashing every piece as long as the
+    // files did not change since it was written. The control follows the check.
+    async fn check(
+        &self,
+        layout: &Layout,
+        storage: &Arc<FileStorage>,
+        info_hash: [u8; 20],
+        pieces_hash: &Arc<Vec<[u8; 20]>>,
+        pieces_hash_v2: Option<&Arc<PieceHashesV2>>,
+        control: &Control,
+    ) -> anyhow::Result<Checked> {
+        let total_pieces = layout.piece_map.total_pieces();
+        let resume_data = ResumeData::load(&layout.resume_path).unwrap_or_else(|e| {
+            warn!("ignoring the resume file: {e:#}");
+            None
+        });
+        let checked_pieces = control.start_check();
+        let missing_pieces = match resume_data
+            .as_ref()
+            .and_then(|resume_data| resume_data.missing_pieces(info_hash, layout.piece_map.files()))
+        {
+            Some(missing_pieces) => {
+                info!("Resuming the download, its files did not change since it stopped");
+                checked_pieces.store(total_pieces, Ordering::Relaxed);
+                missing_pieces
+            }
+            None => {
+                // hashing the files takes a while for large torrents, it is kept off the runtime
+                let piece_map = layout.piece_map.clone();
+                let storage = storage.clone();
+                let pieces_hash = pieces_hash.clone();
+                let pieces_hash_v2 = pieces_hash_v2.cloned();
+                tokio::task::spawn_blocking(move || {
+                    verify::missing_pieces(
+                        &piece_map,
+                        storage.as_ref(),
+                        &pieces_hash,
+                        pieces_hash_v2.as_deref(),
+                        // the hashing threads can report out of order
+                        &|pieces| {
+                            checked_pieces.fetch_max(pieces, Ordering::Relaxed);
+                        },
+                    )
+                })
+                .await
+                .context("Checking the data on disk")??
+            }
+        };
+        // transfer counters carry on from the earlier runs
+        let (uploaded_before, downloaded_before) = resume_data
+            .filter(|resume_data| resume_data.info_hash == info_hash)
+            .map_or((0, 0), |resume_data| {
+                (resume_data.uploaded, resume_data.downloaded)
+            });
+        let skipped: Vec<usize> = (0..total_pieces)
+            .filter(|&piece_index| layout.piece_priorities[piece_index] == FilePriority::Skip)
+            .collect();
+        let have = Arc::new(Have::skipping(total_pieces, &missing_pieces, &skipped));
+        let missing_pieces = missing_pieces
+            .into_iter()
+            .filter(|&piece_index| layout.piece_priorities[piece_index] != FilePriority::Skip)
+            .collect();
+        Ok(Checked {
+            have,
+            missing_pieces,
+            uploaded_before,
+            downloaded_before,
+        })
+    }
+
+    // A web seed for every URL of the torrent.
+    fn web_seeds(&self, piece_map: &Arc<PieceMap>, http_client: &reqwest::Client) -> Vec<WebSeed> {
+        let files = match &self.info.file_type {
+            FileType::SingleFile { .. } => None,
+            FileType::MultiFile { files } => {
+                Some(files.iter().map(|file| file.path.clone()).collect())
+            }
+        };
+        self.url_list
+            .iter()
+            .map(|url| {
+                info!("Downloading from web seed {url}");
+                WebSeed::new(
+                    url,
+                    &self.info.name,
+                    files.clone(),
+                    piece_map.clone(),
+                    http_client.clone(),
+                )
+            })
+            .collect()
+    }
+
+    // Serves the files over HTTP on the port on localhost, None if it cannot be bound.
+    fn stream(
+        &self,
+        port: u16,
+        piece_map: &Arc<PieceMap>,
+        disk_io: &DiskIo,
+        have: &Arc<Have>,
+        pieces: &PieceQueue,
+    ) -> Option<JoinHandle<()>> {
+        let context = StreamContext {
+            piece_map: piece_map.clone(),
+            file_names: self.file_names(),
+            disk_io: disk_io.clone(),
+            have: have.clone(),
+            pieces: pieces.clone(),
+            wait_timeout: Duration::from_secs(30),
+            readahead: streaming::READAHEAD,
+        };
+        match streaming::spawn(SocketAddr::from(([127, 0, 0, 1], port)), context) {
+            Result::Ok((addr, handle)) => {
+                info!("Streaming the files at http://{addr}/<file number or path>, the list is at http://{addr}/");
+                Some(handle)
+            }
+            Err(e) => {
+                warn!("not streaming: {e:#}");
+                None
+            }
+        }
+    }
+
+    // Waits while the complete torrent is uploaded, until the control stops or holds it or a seed
+    // limit of the config is reached.
+    async fn seed(&self, config: &Config, control: &Control, progress: &Progress) {
+        control.set_state(TorrentState::Seeding);
+        info!("Seeding {}, press Ctrl-C to stop", self.info.name);
+        let seeding_since = Instant::now();
+        let limit_reached = async {
+            let mut checks = tokio::time::interval(seed_limit::CHECK_INTERVAL);
+            loop {
+                checks.tick().await;
+                let (uploaded, downloaded) = progress.transferred();
+                if let Some(reason) = seed_limit::reached(
+                    config,
+                    uploaded,
+                    downloaded,
+                    self.info.total_length(),
+                    seeding_since.elapsed(),
+                ) {
+                    return reason;
+                }
+            }
+        };
+        // there is nothing to hold, holding ends the seeding like stopping
+        tokio::select! {
+            _ = control.stopped() => {}
+            _ = control.held(true) => {}
+            reason = limit_reached => info!("Stopped seeding {}, it {reason}", self.info.name),
+        }
+    }
+}
+
+// Where the data of a torrent goes and which of it is wanted.
+struct Layout {
+    // the resume file is kept in the download directory also while the files are elsewhere
+    resume_path: PathBuf,
+    staging: Staging,
+    piece_map: Arc<PieceMap>,
+    piece_priorities: Vec<FilePriority>,
+    torrent_data_len: usize,
+}
+
+// What is on disk already, and what is still to download of the wanted files.
+struct Checked {
+    have: Arc<Have>,
+    missing_pieces: Vec<usize>,
+    // bytes transferred by the earlier runs of the download
+    uploaded_before: u64,
+    downloaded_before: u64,
+}
+
+// Where the peers of a torrent are looked up, also before its metadata is known: its trackers,
+// tier by tier, and the DHT unless the torrent is private, in every swarm of the torrent.
+pub struct PeerLookup {
+    pub tracker_tiers: Vec<Vec<String>>,
+    pub private: bool,
+    pub swarms: Vec<[u8; 20]>,
+}
+
+impl PeerLookup {
+    // Asks the trackers and, unless the torrent is private, the DHT for peers, and adds them to
+    // the peers given on the command line. The DHT is looked up while the trackers are contacted.
+    // None if no tracker answered and there are no peers, a tracker error is returned then.
+    pub async fn find(
+        &self,
+        shared: &Shared,
+        config: &Config,
+        resolver: &Resolver,
+        tracker_request: TrackerRequest,
+        events: &Events,
+    ) -> anyhow::Result<Option<FoundPeers>> {
+        let tracker_tiers = &self.tracker_tiers;
+        let swarms = &self.swarms;
+        let http_client = shared.http_client(config).await?;
+        let network = shared.network(config).await;
+        let listen_port = network.listen_port;
+        // private torrents get their peers from the trackers only
+        let dht = network.dht.clone().filter(|_| !self.private);
+        let dht_lookup = dht.clone().map(|dht| {
+            let resolver = resolver.clone();
+            let bootstrap = config.dht_bootstrap.clone();
+            let swarms = swarms.clone();
+            tokio::spawn(
+                async move {
+                    let nodes = join_all(
+                        bootstrap
+                            .iter()
+                            .map(|node| resolver.resolve_socket_addr(node)),
+                    )
+                    .await;
+                    let nodes: Vec<SocketAddr> =
+                        nodes.into_iter().filter_map(|node| node.ok()).collect();
+                    let known = dht.bootstrap(&nodes).await;
+                    info!("Joined the DHT, {known} nodes known");
+                    let mut peers = Vec::new();
+                    for (swarm, info_hash) in swarms.into_iter().enumerate() {
+                        let found = dht.get_peers(info_hash, Some(listen_port)).await;
+                        peers.extend(found.into_iter().map(|peer| (peer, swarm)));
+                    }
+                    peers
+                }
+                .in_current_span(),
+            )
+        });
+
+        // Without a tracker or the DHT no one learns about the listen port, so it is not mapped.
+        let external_ip = if tracker_tiers.is_empty() && dht.is_none() {
+            None
+        } else {
+            shared.map_port(config, listen_port).await
+        };
+
+        // peers given on the command line come first, in the swarm of the v1 info hash
+        let mut found = FoundPeers {
+            peers: config.peers.iter().map(|&peer| (peer, 0)).collect(),
+            announced_to: None,
+            tracker_request,
+        };
+        let mut failure = Ok(());
+        if tracker_tiers.is_empty() {
+            info!("The torrent has no tracker");
+        } else {
+            let tracker_request = &mut found.tracker_request;
+            tracker_request.port = listen_port;
+            tracker_request.key = Some(shared.tracker_key.clone());
+            tracker_request.ip = external_ip;
+            if config.ip_family != Some(IpFamily::V4) {
+                tracker_request.ipv6 = net::global_ipv6().await;
+            }
+
+            let first = first_announce(
+                &http_client,
+                tracker_tiers,
+                swarms,
+                tracker_request,
+                config,
+                events,
+            )
+            .await;
+            found.add(first.peers);
+            found.announced_to = first.announced_to;
+            failure = first.failure;
+        }
+        if let Some(lookup) = dht_lookup {
+            match tokio::time::timeout(DHT_LOOKUP_TIMEOUT, lookup).await {
+                Result::Ok(Result::Ok(peers)) => {
+                    info!("Found {} peers on the DHT", peers.len());
+                    found.add(peers);
+                }
+                Result::Ok(Err(e)) => warn!("DHT lookup failed: {e}"),
+                Err(_) => warn!("DHT lookup did not finish in time"),
+            }
+        }
+        if !tracker_tiers.is_empty() && found.announced_to.is_none() && found.peers.is_empty() {
+            failure?;
+            return Ok(None);
+        }
+        Ok(Some(found))
+    }
+}
+
+// The peers a download starts with, each with the swarm it is in, and the tracker that gave us
+// peers: its announce URL and when it wants to hear from us again.
+pub struct FoundPeers {
+    pub peers: Vec<(SocketAddr, usize)>,
+    announced_to: Option<(String, Duration)>,
+    // the request that reached it, later announces build on it
+    tracker_request: TrackerRequest,
+}
+
+impl FoundPeers {
+    // Adds the peers that are not known yet.
+    fn add(&mut self, peers: Vec<(SocketAddr, usize)>) {
+        for (peer, swarm) in peers {
+            if !self.peers.iter().any(|&(known, _)| known == peer) {
+                self.peers.push((peer, swarm));
+            }
+        }
+    }
+}
+
+// Saves which pieces are on disk and what was transferred to the resume file of the torrent.
+struct Progress {
+    path: PathBuf,
+    info_hash: [u8; 20],
+    piece_map: Arc<PieceMap>,
+    storage: Arc<FileStorage>,
+    have: Arc<Have>,
+    bandwidth: Bandwidth,
+    uploaded_before: u64,
+    downloaded_before: u64,
+}
+
+impl Progress {
+    // Bytes uploaded and downloaded over all runs of the download.
+    fn transferred(&self) -> (u64, u64) {
+        (
+            self.uploaded_before + self.bandwidth.upload.transferred(),
+            self.downloaded_before + self.bandwidth.download.transferred(),
+        )
+    }
+
+    // Only what is surely on disk may be skipped by the next start, the disk task is synced first.
+    fn save(&self) {
+        // moved files are stamped where they are now
+        let locations: Vec<(String, usize)> = self
+            .piece_map
+            .files()
+            .iter()
+            .map(|(path, length)| (self.storage.location(path), *length))
+            .collect();
+        let Some(files) = resume::stamp(&locations) else {
+            return;
+        };
+        let (uploaded, downloaded) = self.transferred();
+        let resume_data = ResumeData {
+            info_hash: self.info_hash,
+            pieces: (0..self.piece_map.total_pieces())
+                .map(|piece_index| self.have.has(piece_index))
+                .collect(),
+            files,
+            uploaded,
+            downloaded,
+        };
+        if let Err(e) = resume_data.save(&self.path) {
+            warn!("the next start will check every piece again: {e:#}");
+        }
+    }
+}
+
+// Connects to the peers the torrent finds and runs a download task for each of them and for each
+// web seed. Peers are dialed in the order they are found, those of the address family that
+// connected faster so far first. The times come back from the connection tasks over a channel.
+struct Connector {
+    peer_task: PeerTask,
+    // our handshake in every swarm, peers are greeted with that of the swarm they were found in
+    handshakes: Vec<Arc<Vec<u8>>>,
+    // what the connections need of the config, not copied for every peer
+    config: Arc<Config>,
+    resolver: Resolver,
+    peer_filter: PeerFilter,
+    connection_permits: Arc<Semaphore>,
+    peers: PeerList,
+    family_stats: FamilyStats,
+}
+
+impl Connector {
+    // Runs until the downloads ended and no more peers are coming, or, once every piece is there,
+    // until the downloads ended. Aborting it aborts the downloads.
+    async fn run(
+        mut self,
+        first: Vec<(SocketAddr, usize)>,
+        web_seeds: Vec<WebSeed>,
+        mut found: mpsc::UnboundedReceiver<Vec<(SocketAddr, usize)>>,
+    ) {
+        let mut downloads = JoinSet::new();
+        let (connect_time, mut connect_times) = mpsc::unbounded_channel();
+        self.connect(&mut downloads, first, &connect_time);
+        for seed in web_seeds {
+            let span = info_span!("web_seed", url = %seed.url());
+            downloads.spawn(
+                self.peer_task
+                    .clone()
+                    .download_from_web_seed(seed)
+                    .instrument(span),
+            );
+        }
+        let mut finding = true;
+        loop {
+            if downloads.is_empty() && (!finding || self.peer_task.have.complete()) {
+                return;
+            }
+            tokio::select! {
+                _ = downloads.join_next(), if !downloads.is_empty() => {}
+                peers = found.recv(), if finding => match peers {
+                    Some(peers) if !self.peer_task.have.complete() => {
+                        self.connect(&mut downloads, peers, &connect_time);
+                    }
+                    Some(_) => {}
+                    None => finding = false,
+                },
+                Some((peer, took)) = connect_times.recv() => self.family_stats.record(&peer, took),
+            }
+        }
+    }
+
+    // Starts a download from each of the peers that is new and not blocked, it waits for a
+    // connection permit first.
+    fn connect(
+        &self,
+        downloads: &mut JoinSet<()>,
+        mut peers: Vec<(SocketAddr, usize)>,
+        connect_time: &mpsc::UnboundedSender<(SocketAddr, Duration)>,
+    ) {
+        self.family_stats.order(&mut peers, self.config.ip_family);
+        for (peer, swarm) in peers {
+            let peer_manager = &self.peer_task.peer_manager;
+            if !peer_manager.add(peer) {
+                continue;
+            }
+            if !self.peer_filter.allows_outgoing(&peer.ip()) {
+                debug!("Not connecting to blocked peer {peer}");
+                peer_manager.blocked(peer);
+                continue;
+            }
+            let encoded_handshake = self.handshakes[swarm].clone();
+            let peer_task = self.peer_task.clone();
+            let config = self.config.clone();
+            let resolver = self.resolver.clone();
+            let connection_permits = self.connection_permits.clone();
+            let connected_peers = self.peers.clone();
+            let connect_time = connect_time.clone();
+            downloads.spawn(
+                async move {
+                    let Result::Ok(_permit) = connection_permits.acquire_owned().await else {
+                        return;
+                    };
+                    // the download may have finished while this peer was waiting
+                    if peer_task.have.complete() {
+                        return;
+                    }
+                    let connection = peer_task.peer_manager.connect(peer, || async {
+                        let connect_started = Instant::now();
+                        let mut stream = net::connect_peer(&peer.to_string(), &config, &resolver)
+                            .await
+                            .context("Connecting")?;
+                        let _ = connect_time.send((peer, connect_started.elapsed()));
+                        let handshake = exchange_handshake(&mut stream, &encoded_handshake)
                             .await
-                            .unwrap();
+                            .context("Handshake")?;
+                        Ok((stream, handshake.peer_id))
+                    });
+                    let Some((stream, peer_id)) = connection.await else {
+                        return;
+                    };
+
+                    let connected = connected_peers.connected(
+                        peer,
+                        peer_id::client(&peer_id),
+                        &peer_task.bandwidth,
+                        &peer_task.events,
+                    );
+                    peer_task.for_peer(&connected).download(stream).await;
+                    peer_task.peer_manager.disconnected(peer);
+                }
+                .instrument(info_span!("peer", addr = %peer)),
+            );
+        }
+    }
+}
 
-                        let _ = framed.next().await.unwrap().unwrap();
+// Takes the peers that connect to us: they are downloaded from until we have everything, after
+// that they are uploaded to.
+async fn accept_peers(
+    mut incoming: Incoming,
+    peer_task: PeerTask,
+    uploader: Uploader,
+    connection_permits: Arc<Semaphore>,
+    peers: PeerList,
+) {
+    while let Some((stream, addr, peer_id)) = incoming.recv().await {
+        if peer_task.peer_manager.is_banned(&addr.ip()) {
+            continue;
+        }
+        let Result::Ok(permit) = connection_permits.clone().try_acquire_owned() else {
+            debug!("Refused peer {addr}, too many connections");
+            continue;
+        };
+        debug!("Accepted connection from peer {addr}");
+        let peer = peers.connected(
+            addr,
+            peer_id::client(&peer_id),
+            &peer_task.bandwidth,
+            &peer_task.events,
+        );
+        let span = info_span!("peer", %addr);
+        if uploader.have.complete() {
+            let uploader = uploader.for_peer(&peer);
+            tokio::spawn(
+                async move {
+                    uploader.upload(stream).await;
+                    drop(permit);
+                }
+                .instrument(span),
+            );
+        } else {
+            let peer_task = peer_task.for_peer(&peer);
+            tokio::spawn(
+                async move {
+                    peer_task.download(stream).await;
+                    drop((permit, peer));
+                }
+                .instrument(span),
+            );
+        }
+    }
+}
 
-                        let max_request_block_size = 2_usize.pow(13);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::peers::Bitfield;
 
-                        loop {
-                            let piece_index = pieces_to_download.lock().unwrap().pop();
-                            if piece_index.is_none() {
-                                break;
-                            }
+    fn multi_file_torrent(paths: &[&[&str]]) -> Torrent {
+        Torrent {
+            info: Info {
+                name: "multi".to_string(),
+                piece_length: 16,
+                pieces: Hashes(vec![[0; 20]]),
+                file_type: FileType::MultiFile {
+                    files: paths
+                        .iter()
+                        .map(|path| TorrentFile {
+                            length: 8,
+                            path: path.iter().map(|part| part.to_string()).collect(),
+                            attr: None,
+                        })
+                        .collect(),
+                },
+                meta_version: None,
+                file_tree: None,
+                private: None,
+            },
+            announce: "http://tracker.example/announce".to_string(),
+            announce_list: None,
+            url_list: Vec::new(),
+            piece_layers: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_bytes: None,
+        }
+    }
 
-                            let piece_index = piece_index.unwrap();
+    #[test]
+    fn file_in_place_of_directory_names_the_path() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(directory.path().join("sub"), b"not a directory").unwrap();
+        let torrent = multi_file_torrent(&[&["a.bin"], &["sub", "b.bin"]]);
 
-                            let piece_to_download_len = if piece_index
-                                != total_pieces_to_download - 1
-                            {
-                                piece_length
-                            } else {
-                                torrent_data_len - (piece_length * (total_pieces_to_download - 1))
-                            };
+        let error = torrent
+            .reserve_space(
+                &torrent.file_paths(directory.path().to_str().unwrap()),
+                &[],
+                Allocation::Sparse,
+                DiskBackend::Files,
+            )
+            .unwrap_err();
+        let message = format!("{error:#}");
+        assert!(message.contains("could not create directory"), "{message}");
+        assert!(message.contains(&directory.path().join("sub").display().to_string()));
+        // the file before the failure was reserved completely
+        assert_eq!(
+            std::fs::read(directory.path().join("a.bin")).unwrap().len(),
+            8
+        );
+    }
 
-                            let mut piece_data: Vec<u8> = Vec::new();
-                            piece_data.reserve_exact(piece_to_download_len);
-
-                            let mut piece_downloaded_len: usize = 0;
-
-                            while piece_to_download_len != piece_downloaded_len {
-                                let this_block_data_len = std::cmp::min(
-                                    piece_to_download_len - piece_downloaded_len,
-                                    max_request_block_size,
-                                );
-
-                                let peer_msg_req_bytes = PeerRequestMsgType::new(
-                                    piece_index as u32,
-                                    piece_downloaded_len as u32,
-                                    this_block_data_len as u32,
-                                )
-                                .to_bytes();
-
-                                framed
-                                    .send(PeerMsgType::new(
-                                        PeerMsgTag::Request,
-                                        peer_msg_req_bytes.to_vec(),
-                                    ))
-                                    .await
-                                    .unwrap();
-                                let new_frame = framed.next().await.unwrap().unwrap();
-                                assert_eq!(&PeerMsgTag::Piece, new_frame.tag());
-                                piece_data.append(
-                                    &mut PeerPieceMsgType::from_bytes(new_frame.data()).block(),
-                                );
-                                piece_downloaded_len += this_block_data_len;
-                            }
-                            assert_eq!(piece_to_download_len, piece_data.len());
-
-                            let piece_hash = calc_sha1_hash(piece_data.clone());
-                            assert_eq!(pieces_hash[piece_index], piece_hash);
-
-                            let file_paths_details = &piece_mapping[&piece_index];
-                            let mut handle_mapping = file_handle_mapping.lock().unwrap();
-                            let mut piece_data_pointer = 0;
-                            for file_path_detail in file_paths_details {
-                                if !handle_mapping.contains_key(&file_path_detail.path) {
-                                    handle_mapping.insert(
-                                        file_path_detail.path.clone(),
-                                        OpenOptions::new()
-                                            .write(true)
-                                            .open(&file_path_detail.path)
-                                            .unwrap(),
-                                    );
-                                }
-
-                                let handle = &handle_mapping[&file_path_detail.path];
-                                let _ = handle.seek_write(
-                                    &piece_data[piece_data_pointer
-                                        ..piece_data_pointer + file_path_detail.length],
-                                    file_path_detail.offset as u64,
-                                );
-                                piece_data_pointer += file_path_detail.length;
-                            }
+    #[cfg(unix)]
+    #[test]
+    fn read_only_directory_names_the_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(directory.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+        // permissions do not apply to root
+        if std::fs::write(directory.path().join("probe"), b"").is_ok() {
+            return;
+        }
+        let torrent = multi_file_torrent(&[&["a.bin"]]);
+
+        let error = torrent
+            .reserve_space(
+                &torrent.file_paths(directory.path().to_str().unwrap()),
+                &[],
+                Allocation::Sparse,
+                DiskBackend::Files,
+            )
+            .unwrap_err();
+        let message = format!("{error:#}");
+        assert!(message.contains("could not preallocate file"), "{message}");
+        assert!(message.contains("a.bin"));
+        assert!(message.to_lowercase().contains("permission denied"));
+    }
+
+    #[test]
+    fn files_are_allocated_as_the_config_says() {
+        let torrent = multi_file_torrent(&[&["a.bin"], &["sub", "b.bin"]]);
+        for (allocation, length) in [
+            (Allocation::Sparse, 8),
+            (Allocation::Full, 8),
+            (Allocation::None, 0),
+        ] {
+            let directory = tempfile::tempdir().unwrap();
+            torrent
+                .reserve_space(
+                    &torrent.file_paths(directory.path().to_str().unwrap()),
+                    &[1],
+                    allocation,
+                    DiskBackend::Files,
+                )
+                .unwrap();
+            assert_eq!(
+                std::fs::metadata(directory.path().join("a.bin"))
+                    .unwrap()
+                    .len(),
+                length,
+                "{allocation:?}"
+            );
+            // files no wanted piece touches are left alone
+            assert!(!directory.path().join("sub").exists());
+        }
+    }
+
+    #[test]
+    fn peer_state_follows_bitfield_and_have() {
+        let mut peer = PeerState::new(10, Arc::default());
+        peer.update(&PeerMessage::Bitfield(Bitfield::from_bytes(vec![
+            0b1010_0000,
+            0b0100_0000,
+        ])))
+        .unwrap();
+        let advertised: Vec<usize> = (0..10).filter(|&i| peer.has(i)).collect();
+        assert_eq!(advertised, vec![0, 2, 9]);
+
+        peer.update(&PeerMessage::Have { index: 5 }).unwrap();
+        assert!(peer.has(5));
+
+        // wrong bitfield length, spare bits set, and a piece past the end
+        assert!(peer
+            .update(&PeerMessage::Bitfield(Bitfield::from_bytes(vec![0xff])))
+            .is_err());
+        assert!(peer
+            .update(&PeerMessage::Bitfield(Bitfield::from_bytes(vec![
+                0b1010_0000,
+                0b0110_0000
+            ])))
+            .is_err());
+        assert!(peer.update(&PeerMessage::Have { index: 10 }).is_err());
+    }
+
+    #[test]
+    fn piece_length_grows_with_the_size() {
+        assert_eq!(piece_length_for(0), 16 * 1024);
+        assert_eq!(piece_length_for(10 * 1024 * 1024), 16 * 1024);
+        assert_eq!(piece_length_for(700 * 1024 * 1024), 512 * 1024);
+        assert_eq!(piece_length_for(4 * 1024 * 1024 * 1024), 4 * 1024 * 1024);
+        assert_eq!(piece_length_for(1 << 40), 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn created_torrent_keeps_the_options() {
+        let source = tempfile::tempdir().unwrap();
+        let payload = source.path().join("payload.bin");
+        std::fs::write(&payload, vec![1_u8; 100_000]).unwrap();
+        let torrent = Torrent::create(
+            &payload,
+            &CreateOptions {
+                piece_length: None,
+                trackers: vec![
+                    vec!["http://a.example/announce".to_string()],
+                    vec![
+                        "udp://b.example:6969".to_string(),
+                        "http://c.example/announce".to_string(),
+                    ],
+                ],
+                private: true,
+                comment: Some("made for a test".to_string()),
+            },
+        )
+        .unwrap();
+
+        let decoded: Torrent =
+            serde_bencode::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+        assert_eq!(decoded.info.piece_length(), 16 * 1024);
+        assert_eq!(decoded.info.total_pieces(), 7);
+        assert!(decoded.info.is_private());
+        assert_eq!(decoded.announce, "http://a.example/announce");
+        assert_eq!(
+            decoded.tracker_tiers(&[]),
+            vec![
+                vec!["http://a.example/announce"],
+                vec!["udp://b.example:6969", "http://c.example/announce"],
+            ]
+        );
+        assert_eq!(decoded.comment.as_deref(), Some("made for a test"));
+        assert!(decoded.creation_date.is_some());
+        // the private flag is part of the info dictionary and so of the info hash
+        assert_eq!(decoded.calc_hash().unwrap(), torrent.calc_hash().unwrap());
+        let public = Torrent::create(&payload, &CreateOptions::default()).unwrap();
+        assert_ne!(public.calc_hash().unwrap(), torrent.calc_hash().unwrap());
+        assert_eq!(public.announce, "");
+        assert!(public.announce_list.is_none());
+    }
+
+    mod tracker_announce {
+        use super::*;
+        use crate::download::mock_tracker::{MockTracker, Reply};
+        use std::net::SocketAddrV4;
+
+        async fn announce(
+            tracker: &MockTracker,
+            request: &TrackerRequest,
+        ) -> anyhow::Result<TrackerResponse> {
+            let config = Config::default();
+            let announce_url = tracker.announce_url();
+            request_tracker(
+                &net::http_client(&config, &Resolver::new(&config)).unwrap(),
+                request.url(&announce_url),
+                &announce_url,
+                &config,
+            )
+            .await
+        }
+
+        #[tokio::test]
+        async fn query_parameters_are_encoded() {
+            let tracker = MockTracker::spawn(Reply::default());
+            let mut info_hash = [b'9'; 20];
+            info_hash[..10].copy_from_slice(b"Az\x00\xff .~%\x10\x7f");
+            let mut request = TrackerRequest::new(info_hash, 1000, *b"-RB0100-abcdefghijkl");
+            request.ip = Some("203.0.113.7".parse().unwrap());
+            announce(&tracker, &request).await.unwrap();
+
+            let announces = tracker.announces();
+            assert_eq!(announces.len(), 1);
+            assert_eq!(
+                announces[0].query,
+                "info_hash=Az%00%FF%20.~%25%10%7F9999999999&peer_id=-RB0100-abcdefghijkl\
+                 &port=6969&ip=203.0.113.7&uploaded=0&downloaded=0&left=1000&compact=1&event=started"
+            );
+            assert_eq!(announces[0].params["info_hash"], info_hash);
+        }
+
+        #[tokio::test]
+        async fn compact_peers_are_parsed() {
+            let peers: Vec<SocketAddrV4> = vec![
+                "10.10.10.5:128".parse().unwrap(),
+                "127.0.0.1:6881".parse().unwrap(),
+            ];
+            let tracker = MockTracker::spawn(Reply {
+                peers: peers.clone(),
+                complete: 3,
+                incomplete: 4,
+                interval: 900,
+                min_interval: Some(1200),
+                warning: Some("update your client".to_string()),
+                ..Default::default()
+            });
+            let request = TrackerRequest::new([1; 20], 1000, [2; 20]);
+            let response = announce(&tracker, &request).await.unwrap();
+
+            let tracker::TrackerResponseType::Success {
+                complete,
+                incomplete,
+                interval,
+                min_interval,
+                peers: received,
+                warning_message,
+                ..
+            } = response.tracker_response_type
+            else {
+                panic!("expected a peer list");
+            };
+            assert_eq!((complete, incomplete, interval), (3, 4, 900));
+            assert_eq!(min_interval, Some(1200));
+            assert_eq!(warning_message.as_deref(), Some("update your client"));
+            let received: Vec<String> = received.0.iter().map(|peer| peer.to_string()).collect();
+            assert_eq!(received, vec!["10.10.10.5:128", "127.0.0.1:6881"]);
+        }
+
+        #[tokio::test]
+        async fn dictionary_and_ipv6_peers_are_parsed() {
+            let tracker = MockTracker::spawn(Reply {
+                peers: vec!["10.10.10.5:128".parse().unwrap()],
+                compact: false,
+                peers6: vec![
+                    "[2001:db8::1]:6881".parse().unwrap(),
+                    "[::ffff:10.0.0.1]:51413".parse().unwrap(),
+                ],
+                ..Default::default()
+            });
+            let request = TrackerRequest::new([1; 20], 1000, [2; 20]);
+            let response = announce(&tracker, &request).await.unwrap();
+
+            let tracker::TrackerResponseType::Success { peers, peers6, .. } =
+                response.tracker_response_type
+            else {
+                panic!("expected a peer list");
+            };
+            let received: Vec<String> = peers
+                .with(peers6)
+                .0
+                .iter()
+                .map(|peer| peer.to_string())
+                .collect();
+            assert_eq!(
+                received,
+                vec![
+                    "10.10.10.5:128",
+                    "[2001:db8::1]:6881",
+                    "[::ffff:10.0.0.1]:51413"
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn failure_reason_is_reported() {
+            let tracker = MockTracker::spawn(Reply::default());
+            tracker.set_reply(Reply {
+                failure: Some("torrent not registered".to_string()),
+                ..Default::default()
+            });
+            let request = TrackerRequest::new([1; 20], 1000, [2; 20]);
+            let response = announce(&tracker, &request).await.unwrap();
+            assert!(matches!(
+                response.tracker_response_type,
+                tracker::TrackerResponseType::Failure { failure_reason }
+                    if failure_reason == "torrent not registered"
+            ));
+        }
+
+        #[tokio::test]
+        async fn failed_announce_is_an_error() {
+            let tracker = MockTracker::spawn(Reply::default());
+            tracker.fail_next(1);
+            let request = TrackerRequest::new([1; 20], 1000, [2; 20]);
+            assert!(announce(&tracker, &request).await.is_err());
+            assert!(announce(&tracker, &request).await.is_ok());
+            assert_eq!(tracker.announces().len(), 2);
+        }
+
+        #[tokio::test]
+        async fn announces_share_a_connection() {
+            let tracker = MockTracker::spawn(Reply::default());
+            let config = Config {
+                user_agent: "Agent/1.0".to_string(),
+                ..Default::default()
+            };
+            let client = net::http_client(&config, &Resolver::new(&config)).unwrap();
+            let request = TrackerRequest::new([1; 20], 1000, [2; 20]);
+            for _ in 0..2 {
+                let url = request.url(&tracker.announce_url());
+                request_tracker(&client, url, "tracker", &config)
+                    .await
+                    .unwrap();
+            }
+            let announces = tracker.announces();
+            assert_eq!(announces[0].from, announces[1].from);
+            assert_eq!(announces[1].headers["user-agent"], "Agent/1.0");
+        }
+
+        #[tokio::test]
+        async fn refusal_of_every_tracker_is_an_error() {
+            let tracker = MockTracker::spawn(Reply {
+                failure: Some("torrent not registered".to_string()),
+                ..Default::default()
+            });
+            let config = Config::default();
+            let client = net::http_client(&config, &Resolver::new(&config)).unwrap();
+            let mut request = TrackerRequest::new([1; 20], 1000, [2; 20]);
+            let first = first_announce(
+                &client,
+                &[vec![tracker.announce_url()]],
+                &[[1; 20]],
+                &mut request,
+                &config,
+                &Events::default(),
+            )
+            .await;
+            assert!(first.announced_to.is_none());
+            let error = first.failure.unwrap_err();
+            assert!(format!("{error:#}").contains("torrent not registered"));
+        }
+    }
+
+    mod end_to_end {
+        use super::*;
+        use crate::download::{
+            create_torrent_file, merkle,
+            mock_tracker::{Announce, MockTracker, Reply},
+            read_torrent_file,
+            test_peer::{self, Misbehavior, Seeder},
+        };
+
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Request, Response, Server, StatusCode,
+        };
+        use std::{collections::HashMap, convert::Infallible};
+
+        const PIECE_LENGTH: usize = 32 * 1024;
+
+        fn payload(len: usize) -> Arc<Vec<u8>> {
+            Arc::new((0..len).map(|i| (i * 7 + i / 251) as u8).collect())
+        }
+
+        fn torrent(payload: &[u8], file_type: FileType) -> Torrent {
+            Torrent {
+                info: Info {
+                    name: "simulated".to_string(),
+                    piece_length: PIECE_LENGTH,
+                    pieces: Hashes(payload.chunks(PIECE_LENGTH).map(calc_sha1_hash).collect()),
+                    file_type,
+                    meta_version: None,
+                    file_tree: None,
+                    private: None,
+                },
+                announce: "http://tracker.example/announce".to_string(),
+                announce_list: None,
+                url_list: Vec::new(),
+                piece_layers: None,
+                comment: None,
+                created_by: None,
+                creation_date: None,
+                info_bytes: None,
+            }
+        }
+
+        // The task that downloads every piece of the torrent into the directory.
+        fn peer_task(torrent: &Torrent, directory_path: &str, payload: &[u8]) -> PeerTask {
+            torrent
+                .reserve_space(
+                    &torrent.file_paths(directory_path),
+                    &[],
+                    Allocation::Sparse,
+                    DiskBackend::Files,
+                )
+                .unwrap();
+            let total_pieces = torrent.info.pieces.0.len();
+            let all_pieces: Vec<usize> = (0..total_pieces).collect();
+
+            let disk_io = DiskIo::spawn(
+                Arc::new(FileStorage::default()),
+                Arc::new(torrent.piece_map(&torrent.file_paths(directory_path))),
+                &Config::default(),
+                None,
+            );
+            let have = Arc::new(Have::new(total_pieces, &all_pieces));
+            PeerTask {
+                pieces: PieceQueue::spawn(
+                    all_pieces.clone(),
+                    have.clone(),
+                    vec![FilePriority::Normal; total_pieces],
+                    watch::channel(false).1,
+                    1,
+                ),
+                disk_io,
+                have,
+                pieces_hash: Arc::new(torrent.info.pieces.0.clone()),
+                pieces_hash_v2: None,
+                piece_length: PIECE_LENGTH,
+                total_pieces_to_download: total_pieces,
+                torrent_data_len: payload.len(),
+                bandwidth: Bandwidth::new(None, None),
+                status: Arc::default(),
+                peer_timeout: Duration::from_millis(500),
+                snub_timeout: Duration::from_millis(300),
+                piece_timeout: Duration::from_millis(600),
+                block_length: Config::default().block_size,
+                lazy_bitfield: false,
+                request_queue_depth: 5,
+                partial_pieces: Arc::new(PartialPieces::new(Config::default().block_size)),
+                seed: None,
+                peer_manager: Arc::new(PeerManager::new(RetryPolicy::from_config(
+                    &Config::default(),
+                ))),
+                hold: watch::channel(false).1,
+                events: Events::default(),
+            }
+        }
+
+        // Downloads the torrent from one simulated seeder per entry, returns the data on disk.
+        async fn download(
+            torrent: &mut Torrent,
+            payload: &Arc<Vec<u8>>,
+            seeders: &[Misbehavior],
+        ) -> Vec<u8> {
+            let directory = tempfile::tempdir().unwrap();
+            let directory_path = directory.path().to_str().unwrap();
+            let peer_task = peer_task(torrent, directory_path, payload);
+
+            let info_hash = torrent.calc_hash().unwrap();
+            let encoded_handshake =
+                bincode::serialize(&HandShake::new(info_hash, [1; 20])).unwrap();
+            let mut handles = Vec::new();
+            for &misbehavior in seeders {
+                let addr = test_peer::spawn(Seeder {
+                    info_hash,
+                    payload: payload.clone(),
+                    piece_length: PIECE_LENGTH,
+                    misbehavior,
+                })
+                .await;
+                let peer_task = peer_task.clone();
+                let encoded_handshake = encoded_handshake.clone();
+                handles.push(tokio::spawn(async move {
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    exchange_handshake(&mut stream, &encoded_handshake)
+                        .await
+                        .unwrap();
+                    peer_task.download(stream).await;
+                }));
+            }
+            tokio::time::timeout(Duration::from_secs(30), join_all(handles))
+                .await
+                .unwrap();
+            peer_task.disk_io.sync_all().await.unwrap();
+            assert!(peer_task.have.complete());
+
+            torrent
+                .file_paths(directory_path)
+                .iter()
+                .flat_map(|(path, _)| std::fs::read(path).unwrap())
+                .collect()
+        }
+
+        #[tokio::test]
+        async fn single_file_from_one_seeder() {
+            let payload = payload(3 * PIECE_LENGTH + 1000);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            assert!(download(&mut torrent, &payload, &[Misbehavior::None]).await == *payload);
+        }
+
+        #[tokio::test]
+        async fn multi_file_from_several_seeders() {
+            let payload = payload(40_000 + 1 + 70_000);
+            let files = [
+                (vec!["a.bin"], 40_000),
+                (vec!["sub", "b.bin"], 1),
+                (vec!["sub", "c.bin"], 70_000),
+            ];
+            let mut torrent = torrent(
+                &payload,
+                FileType::MultiFile {
+                    files: files
+                        .iter()
+                        .map(|(path, length)| TorrentFile {
+                            length: *length,
+                            path: path.iter().map(|part| part.to_string()).collect(),
+                            attr: None,
+                        })
+                        .collect(),
+                },
+            );
+            let seeders = [Misbehavior::None; 3];
+            assert!(download(&mut torrent, &payload, &seeders).await == *payload);
+        }
+
+        #[tokio::test]
+        async fn silent_choking_peer_is_dropped() {
+            let payload = payload(2 * PIECE_LENGTH);
+            let torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let directory = tempfile::tempdir().unwrap();
+            let peer_task = peer_task(&torrent, directory.path().to_str().unwrap(), &payload);
+            let info_hash = torrent.calc_hash().unwrap();
+            let addr = test_peer::spawn(Seeder {
+                info_hash,
+                payload: payload.clone(),
+                piece_length: PIECE_LENGTH,
+                misbehavior: Misbehavior::SilentChoke,
+            })
+            .await;
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            exchange_handshake(
+                &mut stream,
+                &bincode::serialize(&HandShake::new(info_hash, [1; 20])).unwrap(),
+            )
+            .await
+            .unwrap();
+            // it has every piece we miss, only its silence gets it dropped
+            let error = tokio::time::timeout(
+                Duration::from_secs(5),
+                peer_task.exchange_pieces(stream, None),
+            )
+            .await
+            .unwrap()
+            .err()
+            .unwrap();
+            assert!(format!("{error:#}").contains("sent nothing"), "{error:#}");
+        }
+
+        #[tokio::test]
+        async fn peers_found_later_are_downloaded_from() {
+            let payload = payload(2 * PIECE_LENGTH);
+            let torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let directory = tempfile::tempdir().unwrap();
+            let directory_path = directory.path().to_str().unwrap();
+            let peer_task = peer_task(&torrent, directory_path, &payload);
+            let info_hash = torrent.calc_hash().unwrap();
+            let connector = Connector {
+                peer_task: peer_task.clone(),
+                handshakes: vec![Arc::new(
+                    bincode::serialize(&HandShake::new(info_hash, [1; 20])).unwrap(),
+                )],
+                config: Arc::default(),
+                resolver: Resolver::new(&Config::default()),
+                peer_filter: PeerFilter::default(),
+                connection_permits: Arc::new(Semaphore::new(4)),
+                peers: PeerList::default(),
+                family_stats: FamilyStats::default(),
+            };
+            let (found, found_receiver) = mpsc::unbounded_channel();
+            let connecting = tokio::spawn(connector.run(Vec::new(), Vec::new(), found_receiver));
+
+            let addr = test_peer::spawn(Seeder {
+                info_hash,
+                payload: payload.clone(),
+                piece_length: PIECE_LENGTH,
+                misbehavior: Misbehavior::None,
+            })
+            .await;
+            found.send(vec![(addr, 0)]).unwrap();
+            // more peers could still come, the connector ends since every piece is there
+            tokio::time::timeout(Duration::from_secs(30), connecting)
+                .await
+                .unwrap()
+                .unwrap();
+            peer_task.disk_io.sync_all().await.unwrap();
+            assert_eq!(
+                std::fs::read(&torrent.file_paths(directory_path)[0].0).unwrap(),
+                *payload
+            );
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn download_from_an_ipv6_peer() {
+            let payload = payload(70_000);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            torrent.announce = String::new();
+            let seeder = test_peer::spawn_on(
+                "[::1]:0",
+                Seeder {
+                    info_hash: torrent.calc_hash().unwrap(),
+                    payload: payload.clone(),
+                    piece_length: PIECE_LENGTH,
+                    misbehavior: Misbehavior::None,
+                },
+            )
+            .await;
+            let directory = tempfile::tempdir().unwrap();
+            let config = Config {
+                download_dir: directory.path().to_path_buf(),
+                listen_port: Some(0),
+                peers: vec![seeder],
+                dht: false,
+                ..Default::default()
+            };
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                torrent.run(&config, &Control::new()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+            let downloaded =
+                std::fs::read(directory.path().join("simulated").join("simulated")).unwrap();
+            assert!(downloaded == *payload);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn skipped_files_are_left_out() {
+            let payload = payload(40_000 + 1 + 70_000 + 40_000);
+            let files = [
+                (vec!["a.bin"], 40_000),
+                (vec!["b.bin"], 1),
+                (vec!["c.bin"], 70_000),
+                (vec!["d.bin"], 40_000),
+            ];
+            let mut torrent = torrent(
+                &payload,
+                FileType::MultiFile {
+                    files: files
+                        .iter()
+                        .map(|(path, length)| TorrentFile {
+                            length: *length,
+                            path: path.iter().map(|part| part.to_string()).collect(),
+                            attr: None,
+                        })
+                        .collect(),
+                },
+            );
+            torrent.announce = String::new();
+            let seeder = test_peer::spawn(Seeder {
+                info_hash: torrent.calc_hash().unwrap(),
+                payload: payload.clone(),
+                piece_length: PIECE_LENGTH,
+                misbehavior: Misbehavior::None,
+            })
+            .await;
+            let directory = tempfile::tempdir().unwrap();
+            let config = Config {
+                download_dir: directory.path().to_path_buf(),
+                listen_port: Some(0),
+                ip_family: Some(IpFamily::V4),
+                peers: vec![seeder],
+                dht: false,
+                file_priorities: HashMap::from([
+                    (0, FilePriority::High),
+                    (2, FilePriority::Skip),
+                    (3, FilePriority::Skip),
+                ]),
+                ..Default::default()
+            };
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                torrent.run(&config, &Control::new()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+            let directory = directory.path().join("simulated");
+            let a = std::fs::read(directory.join("a.bin")).unwrap();
+            assert!(a == payload[..40_000]);
+            let b = std::fs::read(directory.join("b.bin")).unwrap();
+            assert_eq!(b, payload[40_000..40_001]);
+            // c.bin shares its first piece with b.bin, nothing of d.bin is wanted
+            assert!(directory.join("c.bin").exists());
+            assert!(!directory.join("d.bin").exists());
+            let resume_data = ResumeData::load(&directory.with_extension("resume"))
+                .unwrap()
+                .unwrap();
+            assert_eq!(resume_data.pieces, [true, true, false, false, false]);
+
+            let wrong = Config {
+                file_priorities: HashMap::from([(4, FilePriority::Skip)]),
+                ..config
+            };
+            assert!(torrent.run(&wrong, &Control::new()).await.is_err());
+        }
+
+        #[tokio::test]
+        async fn pieces_of_misbehaving_seeders_are_requeued() {
+            let payload = payload(8 * PIECE_LENGTH);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let seeders = [
+                Misbehavior::Corrupt,
+                Misbehavior::Stall,
+                Misbehavior::ChokeAfter(3),
+                Misbehavior::DisconnectMidPiece,
+                Misbehavior::None,
+            ];
+            assert!(download(&mut torrent, &payload, &seeders).await == *payload);
+        }
+
+        #[tokio::test]
+        async fn pieces_of_a_snubbing_peer_go_to_other_peers() {
+            let payload = payload(4 * PIECE_LENGTH);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let seeders = [Misbehavior::Snub, Misbehavior::None];
+            assert!(download(&mut torrent, &payload, &seeders).await == *payload);
+        }
+
+        #[tokio::test]
+        async fn pieces_of_a_slow_peer_go_to_other_peers() {
+            let payload = payload(4 * PIECE_LENGTH);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let seeders = [Misbehavior::Trickle, Misbehavior::None];
+            let started = Instant::now();
+            assert!(download(&mut torrent, &payload, &seeders).await == *payload);
+            // the slow peer alone takes three seconds for a piece
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+
+        #[tokio::test]
+        async fn blocks_of_a_piece_come_from_several_peers() {
+            // the peer that chokes us keeps the block it sent, the other one sends the rest
+            let payload = payload(PIECE_LENGTH);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let seeders = [Misbehavior::ChokeAfter(1), Misbehavior::None];
+            let started = Instant::now();
+            assert!(download(&mut torrent, &payload, &seeders).await == *payload);
+            assert!(started.elapsed() < Duration::from_millis(500));
+        }
+
+        #[tokio::test]
+        async fn the_rest_of_short_blocks_is_requested_again() {
+            let payload = payload(2 * PIECE_LENGTH + 100);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let seeders = [Misbehavior::ShortBlocks];
+            assert!(download(&mut torrent, &payload, &seeders).await == *payload);
+        }
+
+        #[tokio::test]
+        async fn requests_resume_after_being_unchoked_again() {
+            let payload = payload(4 * PIECE_LENGTH);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let seeders = [Misbehavior::ChokeBriefly(3)];
+            assert!(download(&mut torrent, &payload, &seeders).await == *payload);
+        }
+
+        #[tokio::test]
+        async fn aborted_peer_task_returns_its_piece() {
+            let payload = payload(2 * PIECE_LENGTH);
+            let torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let directory = tempfile::tempdir().unwrap();
+            let directory_path = directory.path().to_str().unwrap();
+            torrent
+                .reserve_space(
+                    &torrent.file_paths(directory_path),
+                    &[],
+                    Allocation::Sparse,
+                    DiskBackend::Files,
+                )
+                .unwrap();
+            let have = Arc::new(Have::new(2, &[0, 1]));
+            let pieces = PieceQueue::spawn(
+                vec![0, 1],
+                have.clone(),
+                vec![FilePriority::Normal; 2],
+                watch::channel(false).1,
+                1,
+            );
+            let peer_task = PeerTask {
+                pieces: pieces.clone(),
+                disk_io: DiskIo::spawn(
+                    Arc::new(FileStorage::default()),
+                    Arc::new(torrent.piece_map(&torrent.file_paths(directory_path))),
+                    &Config::default(),
+                    None,
+                ),
+                have,
+                pieces_hash: Arc::new(torrent.info.pieces.0.clone()),
+                pieces_hash_v2: None,
+                piece_length: PIECE_LENGTH,
+                total_pieces_to_download: 2,
+                torrent_data_len: payload.len(),
+                bandwidth: Bandwidth::new(None, None),
+                status: Arc::default(),
+                peer_timeout: Duration::from_secs(60),
+                snub_timeout: SNUB_TIMEOUT,
+                piece_timeout: SNUB_TIMEOUT,
+                block_length: Config::default().block_size,
+                lazy_bitfield: false,
+                request_queue_depth: 5,
+                partial_pieces: Arc::new(PartialPieces::new(Config::default().block_size)),
+                seed: None,
+                peer_manager: Arc::new(PeerManager::new(RetryPolicy::from_config(
+                    &Config::default(),
+                ))),
+                hold: watch::channel(false).1,
+                events: Events::default(),
+            };
+
+            let info_hash = torrent.calc_hash().unwrap();
+            let addr = test_peer::spawn(Seeder {
+                info_hash,
+                payload: payload.clone(),
+                piece_length: PIECE_LENGTH,
+                misbehavior: Misbehavior::Stall,
+            })
+            .await;
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let encoded_handshake =
+                bincode::serialize(&HandShake::new(info_hash, [1; 20])).unwrap();
+            exchange_handshake(&mut stream, &encoded_handshake)
+                .await
+                .unwrap();
+            let task = tokio::spawn(peer_task.download(stream));
+
+            // wait until the stalled task took a piece, then abort it
+            while pieces.queued().await.len() == 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            task.abort();
+            assert!(task.await.unwrap_err().is_cancelled());
+            let mut left = pieces.queued().await;
+            left.sort_unstable();
+            assert_eq!(left, vec![0, 1]);
+        }
+
+        #[tokio::test]
+        async fn blocks_answered_out_of_order_are_reassembled() {
+            let payload = payload(3 * PIECE_LENGTH + 9000);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let seeders = [Misbehavior::AnswerInReverse];
+            assert!(download(&mut torrent, &payload, &seeders).await == *payload);
+        }
+
+        #[tokio::test]
+        async fn only_advertised_pieces_are_requested() {
+            let payload = payload(5 * PIECE_LENGTH + 10);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            // the partial seeder closes the connection when asked for a piece it lacks
+            let seeders = [
+                Misbehavior::EvenPiecesOnly,
+                Misbehavior::HaveAfterInterested,
+            ];
+            assert!(download(&mut torrent, &payload, &seeders).await == *payload);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn added_tracker_is_used_when_announce_is_dead() {
+            let payload = payload(2 * PIECE_LENGTH + 10);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            // nothing listens on port 1
+            torrent.announce = "http://127.0.0.1:1/announce".to_string();
+            let info_hash = torrent.calc_hash().unwrap();
+            let seeder = test_peer::spawn(Seeder {
+                info_hash,
+                payload: payload.clone(),
+                piece_length: PIECE_LENGTH,
+                misbehavior: Misbehavior::None,
+            })
+            .await;
+            let SocketAddr::V4(seeder) = seeder else {
+                unreachable!("the seeder listens on 127.0.0.1")
+            };
+            let tracker = MockTracker::spawn(Reply {
+                peers: vec![seeder],
+                ..Default::default()
+            });
+
+            let directory = tempfile::tempdir().unwrap();
+            let config = Config {
+                download_dir: directory.path().to_path_buf(),
+                listen_port: Some(0),
+                ip_family: Some(IpFamily::V4),
+                trackers: vec![tracker.announce_url(), torrent.announce.clone()],
+                dht: false,
+                ..Default::default()
+            };
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                torrent.run(&config, &Control::new()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+            let events = |announces: &[Announce]| -> Vec<Vec<u8>> {
+                announces
+                    .iter()
+                    .map(|announce| announce.params["event"].clone())
+                    .collect()
+            };
+            let announces = tracker.announces();
+            assert_eq!(
+                events(&announces),
+                [&b"started"[..], b"completed", b"stopped"]
+            );
+            assert_eq!(
+                announces[0].params["left"],
+                payload.len().to_string().as_bytes()
+            );
+            assert_eq!(announces[2].params["left"], b"0");
+            assert_eq!(
+                announces[2].params["downloaded"],
+                payload.len().to_string().as_bytes()
+            );
+
+            // a download that is complete from the start is never completed again
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                torrent.run(&config, &Control::new()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            let announces = tracker.announces();
+            assert_eq!(events(&announces[3..]), [&b"started"[..], b"stopped"]);
+            assert_eq!(announces[3].params["left"], b"0");
+            // the dead tracker was not added a second time
+            assert_eq!(torrent.tracker_tiers(&config.trackers).len(), 2);
+            assert_eq!(torrent.announce_list, None);
+            assert_eq!(torrent.calc_hash().unwrap(), info_hash);
+            let downloaded =
+                std::fs::read(directory.path().join("simulated").join("simulated")).unwrap();
+            assert!(downloaded == *payload);
+        }
+
+        // A hybrid torrent of files with the given lengths, every file but the last is padded to
+        // a piece boundary. Returns it with its v1 data, padding included.
+        fn hybrid_torrent(lengths: &[usize]) -> (Torrent, Arc<Vec<u8>>) {
+            let leaves = PIECE_LENGTH / merkle::BLOCK_SIZE;
+            let mut payload = Vec::new();
+            let mut files = Vec::new();
+            let mut file_tree = HashMap::new();
+            let mut piece_layers = HashMap::new();
+            for (i, &length) in lengths.iter().enumerate() {
+                let name = format!("file{i}");
+                let data: Vec<u8> = (0..length).map(|j| (i + j * 7 + j / 251) as u8).collect();
+                let pieces_root = if length <= PIECE_LENGTH {
+                    merkle::root(
+                        &data,
+                        length.div_ceil(merkle::BLOCK_SIZE).next_power_of_two(),
+                    )
+                } else {
+                    let layer: Vec<[u8; 32]> = data
+                        .chunks(PIECE_LENGTH)
+                        .map(|piece| merkle::root(piece, leaves))
+                        .collect();
+                    // the layer is padded with the roots of pieces of zero leaves
+                    let mut hashes = layer.clone();
+                    hashes.resize(layer.len().next_power_of_two(), merkle::root(&[], leaves));
+                    while hashes.len() > 1 {
+                        hashes = hashes
+                            .chunks(2)
+                            .map(|pair| Sha256::digest(pair.concat()).into())
+                            .collect();
+                    }
+                    piece_layers.insert(hashes[0].to_vec(), Value::Bytes(layer.concat()));
+                    hashes[0]
+                };
+                file_tree.insert(
+                    name.clone().into_bytes(),
+                    Value::Dict(HashMap::from([(
+                        Vec::new(),
+                        Value::Dict(HashMap::from([
+                            (b"length".to_vec(), Value::Int(length as i64)),
+                            (b"pieces root".to_vec(), Value::Bytes(pieces_root.to_vec())),
+                        ])),
+                    )])),
+                );
+                files.push(TorrentFile {
+                    length,
+                    path: vec![name],
+                    attr: None,
+                });
+                payload.extend(data);
+
+                let padding = payload.len().next_multiple_of(PIECE_LENGTH) - payload.len();
+                if i + 1 < lengths.len() && padding > 0 {
+                    files.push(TorrentFile {
+                        length: padding,
+                        path: vec![".pad".to_string(), padding.to_string()],
+                        attr: Some("p".to_string()),
+                    });
+                    payload.resize(payload.len() + padding, 0);
+                }
+            }
+            let mut torrent = torrent(&payload, FileType::MultiFile { files });
+            torrent.info.name = "hybrid".to_string();
+            torrent.info.meta_version = Some(2);
+            torrent.info.file_tree = Some(Value::Dict(file_tree));
+            torrent.piece_layers = Some(Value::Dict(piece_layers));
+            (torrent, Arc::new(payload))
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn hybrid_torrent_is_downloaded_from_its_v2_swarm() {
+            let (torrent, payload) = hybrid_torrent(&[PIECE_LENGTH + 5000, 100, 3 * PIECE_LENGTH]);
+            // both info hashes survive a round trip through the .torrent file
+            let mut torrent: Torrent =
+                serde_bencode::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+            let swarms = torrent.swarms().unwrap();
+            assert_eq!(swarms.len(), 2);
+            assert_eq!(swarms[0], torrent.calc_hash().unwrap());
+
+            // only the v2 swarm has a seeder, the v1 hash would not get past its handshake
+            let SocketAddr::V4(seeder) = test_peer::spawn(Seeder {
+                info_hash: swarms[1],
+                payload: payload.clone(),
+                piece_length: PIECE_LENGTH,
+                misbehavior: Misbehavior::None,
+            })
+            .await
+            else {
+                unreachable!("the seeder listens on 127.0.0.1")
+            };
+            let tracker = MockTracker::spawn(Reply::default());
+            tracker.set_swarm_reply(
+                swarms[1],
+                Reply {
+                    peers: vec![seeder],
+                    ..Default::default()
+                },
+            );
+            torrent.announce = tracker.announce_url();
+
+            let directory = tempfile::tempdir().unwrap();
+            let config = Config {
+                download_dir: directory.path().to_path_buf(),
+                listen_port: Some(0),
+                ip_family: Some(IpFamily::V4),
+                dht: false,
+                ..Default::default()
+            };
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                torrent.run(&config, &Control::new()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+            let announced: Vec<Vec<u8>> = tracker
+                .announces()
+                .iter()
+                .map(|announce| announce.params["info_hash"].clone())
+                .collect();
+            assert!(announced.contains(&swarms[0].to_vec()));
+            assert!(announced.contains(&swarms[1].to_vec()));
+            let downloaded: Vec<u8> = torrent
+                .file_paths(&torrent.download_directory(&config).unwrap())
+                .iter()
+                .flat_map(|(path, _)| std::fs::read(path).unwrap())
+                .collect();
+            assert!(downloaded == *payload);
+            assert!(torrent.missing_pieces(&config).unwrap().is_empty());
+        }
+
+        // An HTTP server answering range requests for the files, by URL path.
+        fn serve_files(files: HashMap<String, Vec<u8>>) -> SocketAddr {
+            let files = Arc::new(files);
+            let make_service = make_service_fn(move |_| {
+                let files = files.clone();
+                async move {
+                    Result::<_, Infallible>::Ok(service_fn(move |request: Request<Body>| {
+                        let files = files.clone();
+                        async move {
+                            let Some(file) = files.get(request.uri().path()) else {
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::NOT_FOUND;
+                                return Result::<_, Infallible>::Ok(response);
+                            };
+                            let range = request.headers()["range"].to_str().unwrap();
+                            let (first, last) = range
+                                .strip_prefix("bytes=")
+                                .and_then(|range| range.split_once('-'))
+                                .unwrap();
+                            let range = first.parse().unwrap()..=last.parse().unwrap();
+                            let mut response = Response::new(Body::from(file[range].to_vec()));
+                            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                            Result::<_, Infallible>::Ok(response)
                         }
-                    }));
+                    }))
                 }
+            });
+            let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+            let addr = server.local_addr();
+            tokio::spawn(server);
+            addr
+        }
 
-                join_all(handle_vec).await;
-                println!("Downloaded file {}", self.info.name.clone());
+        #[tokio::test(flavor = "multi_thread")]
+        async fn pieces_spanning_files_come_from_a_web_seed() {
+            let payload = payload(40_000 + 1 + 70_000);
+            let files = [
+                (vec!["a.bin"], 40_000),
+                (vec!["sub dir", "b.bin"], 1),
+                (vec!["sub dir", "c.bin"], 70_000),
+            ];
+            let mut torrent = torrent(
+                &payload,
+                FileType::MultiFile {
+                    files: files
+                        .iter()
+                        .map(|(path, length)| TorrentFile {
+                            length: *length,
+                            path: path.iter().map(|part| part.to_string()).collect(),
+                            attr: None,
+                        })
+                        .collect(),
+                },
+            );
+            torrent.announce = String::new();
+            let mut offset = 0;
+            let mut served = HashMap::new();
+            for (path, length) in &files {
+                let url_path = format!("/files/simulated/{}", path.join("/").replace(' ', "%20"));
+                served.insert(url_path, payload[offset..offset + length].to_vec());
+                offset += length;
             }
-            tracker::TrackerResponseType::Failure { failure_reason } => {
-                println!("Tracker {announce} could not be connected due to: {failure_reason}\n");
+            let addr = serve_files(served);
+            // the first one does not have the files
+            torrent.url_list = vec![
+                format!("http://{addr}/missing/"),
+                format!("http://{addr}/files"),
+            ];
+
+            let directory = tempfile::tempdir().unwrap();
+            let config = Config {
+                download_dir: directory.path().to_path_buf(),
+                listen_port: Some(0),
+                ip_family: Some(IpFamily::V4),
+                dht: false,
+                ..Default::default()
+            };
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                torrent.run(&config, &Control::new()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            let downloaded: Vec<u8> = torrent
+                .file_paths(&torrent.download_directory(&config).unwrap())
+                .iter()
+                .flat_map(|(path, _)| std::fs::read(path).unwrap())
+                .collect();
+            assert!(downloaded == *payload);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn peers_of_a_later_announce_are_connected_to() {
+            let payload = payload(2 * PIECE_LENGTH + 10);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            let info_hash = torrent.calc_hash().unwrap();
+            let SocketAddr::V4(seeder) = test_peer::spawn(Seeder {
+                info_hash,
+                payload: payload.clone(),
+                piece_length: PIECE_LENGTH,
+                misbehavior: Misbehavior::None,
+            })
+            .await
+            else {
+                unreachable!("the seeder listens on 127.0.0.1")
+            };
+            // no peers yet, and asked again in a second
+            let tracker = Arc::new(MockTracker::spawn(Reply {
+                interval: 0,
+                min_interval: Some(1),
+                tracker_id: Some("session 1".to_string()),
+                ..Default::default()
+            }));
+            torrent.announce = tracker.announce_url();
+
+            let directory = tempfile::tempdir().unwrap();
+            let config = Config {
+                download_dir: directory.path().to_path_buf(),
+                listen_port: Some(0),
+                ip_family: Some(IpFamily::V4),
+                dht: false,
+                tracker_headers: vec![("Cookie".to_string(), "uid=7; pass=secret".to_string())],
+                ..Default::default()
+            };
+            let download = tokio::spawn({
+                let config = config.clone();
+                async move { torrent.run(&config, &Control::new()).await }
+            });
+            while tracker.announces().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
             }
+            tracker.set_reply(Reply {
+                peers: vec![seeder],
+                ..Default::default()
+            });
+            tokio::time::timeout(Duration::from_secs(30), download)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+
+            let announces = tracker.announces();
+            assert_eq!(announces.len(), 4);
+            assert_eq!(announces[0].params["event"], b"started");
+            // the regular announce has no event and nothing downloaded yet
+            assert!(!announces[1].params.contains_key("event"));
+            assert_eq!(
+                announces[1].params["left"],
+                payload.len().to_string().as_bytes()
+            );
+            assert_eq!(announces[2].params["event"], b"completed");
+            assert_eq!(announces[3].params["event"], b"stopped");
+            // the tracker id of the first answer is sent back, the key stays the same
+            assert!(!announces[0].params.contains_key("trackerid"));
+            for announce in &announces {
+                assert_eq!(announce.params["key"], announces[0].params["key"]);
+                assert_eq!(announce.headers["cookie"], "uid=7; pass=secret");
+            }
+            for announce in &announces[1..] {
+                assert_eq!(announce.params["trackerid"], b"session 1");
+            }
+            let downloaded =
+                std::fs::read(directory.path().join("simulated").join("simulated")).unwrap();
+            assert!(downloaded == *payload);
+        }
+
+        // Creates a torrent for a directory, seeds it from where it is with one session and
+        // downloads it with a second one in the same process, over 127.0.0.1 and without a
+        // tracker.
+        #[tokio::test(flavor = "multi_thread")]
+        async fn loopback_self_transfer() {
+            let source = tempfile::tempdir().unwrap();
+            let payload_dir = source.path().join("payload");
+            std::fs::create_dir_all(payload_dir.join("sub")).unwrap();
+            let contents = [
+                ("a.bin", payload(100_000)),
+                ("empty", payload(0)),
+                ("sub/b.bin", payload(50_001)),
+            ];
+            for (name, data) in &contents {
+                std::fs::write(payload_dir.join(name), data.as_slice()).unwrap();
+            }
+            let torrent_file = source.path().join("payload.torrent");
+            create_torrent_file(
+                &payload_dir,
+                &CreateOptions {
+                    piece_length: Some(16 * 1024),
+                    ..Default::default()
+                },
+                &torrent_file,
+            )
+            .unwrap();
+
+            let seed_port = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let mut seeding = read_torrent_file(&torrent_file).unwrap();
+            let seed_config = Config {
+                download_dir: source.path().to_path_buf(),
+                listen_port: Some(seed_port),
+                ip_family: Some(IpFamily::V4),
+                seed: true,
+                dht: false,
+                ..Default::default()
+            };
+            let seeder =
+                tokio::spawn(async move { seeding.run(&seed_config, &Control::new()).await });
+            let seed_addr = SocketAddr::from(([127, 0, 0, 1], seed_port));
+            while TcpStream::connect(seed_addr).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+
+            let target = tempfile::tempdir().unwrap();
+            let mut downloading = read_torrent_file(&torrent_file).unwrap();
+            let download_config = Config {
+                download_dir: target.path().to_path_buf(),
+                listen_port: Some(0),
+                ip_family: Some(IpFamily::V4),
+                peers: vec![seed_addr],
+                dht: false,
+                ..Default::default()
+            };
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                downloading.run(&download_config, &Control::new()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            seeder.abort();
+
+            for (name, data) in &contents {
+                let downloaded = std::fs::read(target.path().join("payload").join(name)).unwrap();
+                assert!(downloaded == data.as_slice(), "{name} differs");
+            }
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn peers_beyond_the_connection_limit_wait_their_turn() {
+            let payload = payload(3 * PIECE_LENGTH);
+            let mut torrent = torrent(
+                &payload,
+                FileType::SingleFile {
+                    length: payload.len(),
+                },
+            );
+            torrent.announce = String::new();
+            let info_hash = torrent.calc_hash().unwrap();
+            let mut peers = Vec::new();
+            for _ in 0..3 {
+                peers.push(
+                    test_peer::spawn(Seeder {
+                        info_hash,
+                        payload: payload.clone(),
+                        piece_length: PIECE_LENGTH,
+                        misbehavior: Misbehavior::None,
+                    })
+                    .await,
+                );
+            }
+
+            let directory = tempfile::tempdir().unwrap();
+            let config = Config {
+                download_dir: directory.path().to_path_buf(),
+                listen_port: Some(0),
+                ip_family: Some(IpFamily::V4),
+                peers,
+                max_connections: 1,
+                dht: false,
+                ..Default::default()
+            };
+            tokio::time::timeout(
+                Duration::from_secs(30),
+                torrent.run(&config, &Control::new()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            let downloaded =
+                std::fs::read(directory.path().join("simulated").join("simulated")).unwrap();
+            assert!(downloaded == *payload);
         }
-        Ok(())
     }
 }
@@ -1,24 +1,43 @@
-use std::fmt;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize,
 };
 
+// The port number we tell trackers (and the router when mapping ports) that we are listening on.
+pub const LISTEN_PORT: u16 = 6969;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Event {
     //The first request to the tracker must include the event key with this value.
     Started,
 
     //Must be sent to the tracker if the client is shutting down gracefully.
-    _Stopped,
+    Stopped,
 
     // Must be sent to the tracker when the download completes.
     // However, must not be sent if the download was already 100% complete when the client started.
     // Presumably, this is to allow the tracker to increment the "completed downloads" metric based solely on this event.
-    _Completed,
+    Completed,
+}
+
+impl Event {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Stopped => "stopped",
+            Event::Completed => "completed",
+        }
+    }
 }
 
-pub struct TrackerRequest<'a> {
+#[derive(Clone)]
+pub struct TrackerRequest {
     // urlencoded 20-byte SHA1 hash of the value of the info key from the Metainfo file.
     // Note that the value will be a bencoded dictionary, given the definition of the info key above.
     pub info_hash: [u8; 20],
@@ -29,12 +48,21 @@ pub struct TrackerRequest<'a> {
     // However, one may rightly presume that it must at least be unique for your local machine,
     // thus should probably incorporate things like process ID and perhaps a timestamp recorded at startup.
     // See peer_id below for common client encodings of this field.
-    pub peer_id: &'a str,
+    pub peer_id: [u8; 20],
 
     // The port number that the client is listening on. Ports reserved for BitTorrent are typically 6881-6889.
     // Clients may choose to give up if it cannot establish a port within this range.
     pub port: u16,
 
+    // Optional. The true IP address of the client machine, in dotted quad format or rfc3513 defined hexed IPv6 address.
+    // In general this parameter is not necessary as the address of the client can be determined from the IP address
+    // from which the HTTP request came. We only send it when the router told us our external address.
+    pub ip: Option<IpAddr>,
+
+    // Our global IPv6 address (BEP 7), so the tracker can hand it to IPv6 capable peers even
+    // when the announce itself goes over IPv4. The port is the same for both families.
+    pub ipv6: Option<Ipv6Addr>,
+
     //  The total amount uploaded (since the client sent the 'started' event to the tracker) in base ten ASCII.
     //  While not explicitly stated in the official specification, the concensus is that this should be the total number of bytes uploaded.
     pub uploaded: usize,
@@ -55,26 +83,48 @@ pub struct TrackerRequest<'a> {
     //"compact=1" or simply send a compact response unless the request contains "compact=0" (in which case they will refuse the request.)
     pub compact: u8,
 
+    // Optional. An additional identification that is not shared with any other peers, so that the
+    // tracker can tell us apart when our IP address changes. The same for every announce of the
+    // session unless one is configured.
+    pub key: Option<String>,
+
+    // Optional. The tracker id of a previous answer of the tracker, sent back on every announce
+    // after it.
+    pub tracker_id: Option<String>,
+
     //Indicates that the tracker can omit peer id field in peers dictionary. This option is ignored if compact is enabled.
     // pub no_peer_id: usize,
 
     // If specified, must be one of started, completed, stopped, (or empty which is the same as not being specified).
     // If not specified, then this request is one performed at regular intervals.
-    pub event: Event,
+    pub event: Option<Event>,
+}
+
+// The announce URL as it can be shown to the user. Private trackers put the passkey of the user
+// in the query, so the query is left out.
+pub fn redacted(announce: &str) -> String {
+    match announce.split_once('?') {
+        Some((base_url, _)) => format!("{base_url}?..."),
+        None => announce.split('#').next().unwrap_or_default().to_string(),
+    }
 }
 
 // The tracker responds with "text/plain" document consisting of a bencoded dictionary
-impl<'a> TrackerRequest<'a> {
-    pub fn new(info_hash: [u8; 20], total_size: usize, peer_id: &'a str) -> Self {
+impl TrackerRequest {
+    pub fn new(info_hash: [u8; 20], total_size: usize, peer_id: [u8; 20]) -> Self {
         TrackerRequest {
             info_hash,
             peer_id,
-            port: 6969,
+            port: LISTEN_PORT,
+            ip: None,
+            ipv6: None,
             uploaded: 0,
             downloaded: 0,
             left: total_size,
             compact: 1,
-            event: Event::Started,
+            key: None,
+            tracker_id: None,
+            event: Some(Event::Started),
         }
     }
     pub fn url(&self, base_url: &str) -> String {
@@ -85,18 +135,36 @@ impl<'a> TrackerRequest<'a> {
         // Thus had to make url manually
 
         let url_encoded_info_hash = urlencoding::encode_binary(&self.info_hash);
+        // The announce URL may carry parameters of its own (often a passkey), they are kept as
+        // they are and ours are added after them. A fragment would hide our parameters from the
+        // tracker, so it is dropped.
+        let base_url = base_url.split('#').next().unwrap_or_default();
         let mut url = String::new();
         url.push_str(base_url);
-        url.push('?');
+        if !base_url.contains('?') {
+            url.push('?');
+        } else if !base_url.ends_with(['?', '&']) {
+            url.push('&');
+        }
         url.push_str("info_hash=");
         url.push_str(&url_encoded_info_hash);
         url.push('&');
         url.push_str("peer_id=");
-        url.push_str(self.peer_id);
+        url.push_str(&urlencoding::encode_binary(&self.peer_id));
         url.push('&');
         url.push_str("port=");
         url.push_str(&self.port.to_string());
         url.push('&');
+        if let Some(ip) = self.ip {
+            url.push_str("ip=");
+            url.push_str(&ip.to_string());
+            url.push('&');
+        }
+        if let Some(ipv6) = self.ipv6 {
+            url.push_str("ipv6=");
+            url.push_str(&urlencoding::encode(&ipv6.to_string()));
+            url.push('&');
+        }
         url.push_str("uploaded=");
         url.push_str(&self.uploaded.to_string());
         url.push('&');
@@ -108,59 +176,106 @@ impl<'a> TrackerRequest<'a> {
         url.push('&');
         url.push_str("compact=");
         url.push_str(&self.compact.to_string());
+        if let Some(key) = &self.key {
+            url.push_str("&key=");
+            url.push_str(&urlencoding::encode(key));
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            url.push_str("&trackerid=");
+            url.push_str(&urlencoding::encode(tracker_id));
+        }
+        if let Some(event) = self.event {
+            url.push_str("&event=");
+            url.push_str(event.as_str());
+        }
         url
     }
 }
 
-#[derive(Debug)]
-pub struct Peer {
-    pub ip_addr: String,
-    pub port: u16,
+// How long to wait before the next regular announce. The tracker's interval is used, but never
+// less than its min interval, and a tracker answering with 0 is not asked again right away.
+pub fn reannounce_interval(interval: usize, min_interval: Option<usize>) -> Duration {
+    Duration::from_secs(interval.max(min_interval.unwrap_or(0)).max(1) as u64)
 }
-impl Peer {
-    fn from_bytes(peer_bytes: &[u8; 6]) -> Peer {
-        let mut ip_addr = String::new();
-        for i in 0..4 {
-            ip_addr
-                .push_str(&u8::from_be_bytes(peer_bytes[i..i + 1].try_into().unwrap()).to_string());
-            if i != 3 {
-                ip_addr.push('.');
-            }
-        }
-        let port = u16::from_be_bytes(peer_bytes[4..6].try_into().unwrap());
-        Peer { ip_addr, port }
+
+// The peers of an announce response, in whichever of its models the tracker sent them:
+//
+// - compact = 1 replaces the peers list with a string of 6 bytes per peer (BEP 23). The first
+//   four bytes are the host (in network byte order), the last two bytes are the port (again in
+//   network byte order). For example, a client at the IP 10.10.10.5 listening on port 128 would
+//   be coded as a string containing the bytes 0A 0A 0A 05 00 80 (10 10 10 5 0 128).
+// - trackers that ignore compact send a list of dictionaries with the keys ip, port and peer id.
+// - IPv6 peers come as a compact string of 18 bytes per peer under the key peers6 (BEP 7).
+//
+// Dictionary entries whose ip is a hostname rather than an address are left out.
+#[derive(Debug, Default)]
+pub struct Peers(pub Vec<SocketAddr>);
+
+impl Peers {
+    // Peers of both address families, IPv4 first.
+    pub fn with(mut self, other: Peers) -> Peers {
+        self.0.extend(other.0);
+        self
     }
 }
-// Setting compact to 1 indicates that the peers list is replaced by a peers string with 6 bytes per peer.
-// The first four bytes are the host (in network byte order), the last two bytes are the port (again in network byte order)
-// So in compact = 1 case peers cannot be a dictionary, it has to be a byte string
-// For example, a client at the IP 10.10.10.5 listening on port 128
-// would be coded as a string containing the following bytes 0A 0A 0A 05 00 80 (10 10 10 5 0 128)
-#[derive(Debug)]
-pub struct Peers(pub Vec<Peer>);
 
-struct PeersVisitor;
+// An entry of the dictionary model, the peer id is not needed.
+#[derive(Deserialize)]
+struct PeerDictionary {
+    ip: String,
+    port: u16,
+}
+
+struct PeersVisitor {
+    // bytes of an address in the compact string, 4 for peers and 16 for peers6
+    ip_length: usize,
+}
 
 impl<'de> Visitor<'de> for PeersVisitor {
     type Value = Peers;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a Peer list string of size that is a multiple of 6")
+        write!(
+            formatter,
+            "a peer string of size that is a multiple of {} or a list of peer dictionaries",
+            self.ip_length + 2
+        )
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        if v.len() % 6 != 0 {
+        let peer_length = self.ip_length + 2;
+        if !v.len().is_multiple_of(peer_length) {
             return Err(E::custom(format!("length is {}", v.len())));
         }
         Result::Ok(Peers(
-            v.chunks_exact(6)
-                .map(|x| Peer::from_bytes(x.try_into().unwrap()))
+            v.chunks_exact(peer_length)
+                .map(|peer| {
+                    let (ip, port) = peer.split_at(self.ip_length);
+                    let ip = match ip.len() {
+                        4 => IpAddr::from(<[u8; 4]>::try_from(ip).unwrap()),
+                        _ => IpAddr::from(<[u8; 16]>::try_from(ip).unwrap()),
+                    };
+                    SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+                })
                 .collect(),
         ))
     }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let mut peers = Vec::new();
+        while let Some(peer) = seq.next_element::<PeerDictionary>()? {
+            if let Result::Ok(ip) = peer.ip.parse::<IpAddr>() {
+                peers.push(SocketAddr::new(ip, peer.port));
+            }
+        }
+        Result::Ok(Peers(peers))
+    }
 }
 
 impl<'de> Deserialize<'de> for Peers {
@@ -168,13 +283,21 @@ impl<'de> Deserialize<'de> for Peers {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(PeersVisitor)
+        deserializer.deserialize_any(PeersVisitor { ip_length: 4 })
     }
 }
 
+fn deserialize_peers6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(PeersVisitor { ip_length: 16 })
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
 pub enum TrackerResponseType {
+    #[allow(dead_code)]
     Success {
         // number of peers with the entire file, i.e. seeders (integer)
         complete: usize,
@@ -185,13 +308,25 @@ pub enum TrackerResponseType {
         // Interval in seconds that the client should wait between sending regular requests to the tracker
         interval: usize,
 
+        // Optional. Minimum announce interval, re-announces must not happen more often than this.
+        #[serde(default, rename = "min interval")]
+        min_interval: Option<usize>,
+
+        // absent from responses that only have IPv6 peers
+        #[serde(default)]
         peers: Peers,
 
+        #[serde(default, deserialize_with = "deserialize_peers6")]
+        peers6: Peers,
+
         //A string that the client should send back on its next announcements.
         //If absent and a previous announce sent a tracker id, do not discard the old value; keep using it.
-        #[serde(skip)]
-        #[serde(rename = "tracker id")]
-        tracker_id: String,
+        #[serde(default, rename = "tracker id")]
+        tracker_id: Option<String>,
+
+        // Optional. Like the failure reason, but the response is processed normally.
+        #[serde(default, rename = "warning message")]
+        warning_message: Option<String>,
     },
     Failure {
         // The value is a human-readable error message as to why the request failed (string).
@@ -243,3 +378,59 @@ impl HandShake {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(base_url: &str) -> String {
+        TrackerRequest::new([0xab; 20], 10, [b'p'; 20]).url(base_url)
+    }
+
+    const QUERY: &str = "info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB\
+        &peer_id=pppppppppppppppppppp&port=6969&uploaded=0&downloaded=0&left=10&compact=1&event=started";
+
+    #[test]
+    fn parameters_are_added_to_existing_query() {
+        assert_eq!(
+            url("https://tracker.example/announce"),
+            format!("https://tracker.example/announce?{QUERY}")
+        );
+        // the passkey is not encoded again
+        assert_eq!(
+            url("https://tracker.example/announce.php?passkey=ab%2Fc123"),
+            format!("https://tracker.example/announce.php?passkey=ab%2Fc123&{QUERY}")
+        );
+        assert_eq!(
+            url("https://tracker.example/announce.php?"),
+            format!("https://tracker.example/announce.php?{QUERY}")
+        );
+        assert_eq!(
+            url("https://tracker.example/announce.php?passkey=abc123&"),
+            format!("https://tracker.example/announce.php?passkey=abc123&{QUERY}")
+        );
+        assert_eq!(
+            url("https://tracker.example/announce?passkey=abc123#top"),
+            format!("https://tracker.example/announce?passkey=abc123&{QUERY}")
+        );
+    }
+
+    #[test]
+    fn passkey_is_not_shown() {
+        assert_eq!(
+            redacted("https://tracker.example/announce.php?passkey=abc123"),
+            "https://tracker.example/announce.php?..."
+        );
+        assert_eq!(
+            redacted("udp://tracker.example:1337/announce"),
+            "udp://tracker.example:1337/announce"
+        );
+    }
+
+    #[test]
+    fn reannounce_respects_the_min_interval() {
+        assert_eq!(reannounce_interval(1800, None), Duration::from_secs(1800));
+        assert_eq!(reannounce_interval(60, Some(300)), Duration::from_secs(300));
+        assert_eq!(reannounce_interval(0, None), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,369 @@
+use crate::download::{
+    bandwidth::Bandwidth,
+    choker::Choker,
+    control::ConnectedPeer,
+    disk_io::DiskIo,
+    have::{Announced, Have},
+    peers::{self, PeerFrameCodec, PeerMessage, KEEP_ALIVE_INTERVAL, MAX_BLOCK_LENGTH},
+    piece_map::PieceMap,
+};
+use crate::error::RustyBitError;
+use anyhow::bail;
+use futures_util::SinkExt;
+use std::{
+    net::SocketAddr,
+    pin::pin,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{net::TcpStream, sync::watch};
+use tokio_util::codec::Framed;
+use tracing::debug;
+
+// A peer that sent nothing for this long is disconnected.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Serves the pieces we have to a peer that connected to us: it gets our bitfield, is unchoked
+// once it says it is interested and the choker gives it an upload slot, and every request for a
+// piece we have is answered from disk while it is unchoked.
+#[derive(Clone)]
+pub struct Uploader {
+    pub piece_map: Arc<PieceMap>,
+    pub disk_io: DiskIo,
+    pub have: Arc<Have>,
+    pub bandwidth: Bandwidth,
+    // the connection the uploader serves, listed as long as the upload goes on
+    pub peer: Option<Arc<ConnectedPeer>>,
+    // every upload of the torrent ends once true is sent or the sender is dropped
+    pub stop: watch::Receiver<bool>,
+    pub choker: Arc<Choker>,
+    pub lazy_bitfield: bool,
+}
+
+impl Uploader {
+    // The uploader of one connection, its bytes count for the peer.
+    pub fn for_peer(&self, peer: &Arc<ConnectedPeer>) -> Uploader {
+        Uploader {
+            bandwidth: peer.bandwidth.clone(),
+            peer: Some(peer.clone()),
+            ..self.clone()
+        }
+    }
+
+    // Serves the peer until it disconnects, logs why the connection ended otherwise.
+    pub async fn upload(self, stream: TcpStream) {
+        let peer = self.describe(stream.peer_addr().ok());
+        let codec = PeerFrameCodec::for_pieces(self.piece_map.total_pieces());
+        if let Err(e) = self.serve(Framed::new(stream, codec), None).await {
+            debug!("Stopped uploading to peer {peer}: {e:#}");
+        }
+    }
+
+    /*
+     * Keeps serving a connection we just finished downloading over. A bitfield is only allowed
+     * right after the handshake, so the pieces the peer was not told about while we downloaded
+     * are announced with have messages, and since any interested message was sent while we were
+     * downloading the peer counts as interested.
+     */
+    pub async fn upload_after_download(
+        self,
+        framed: Framed<TcpStream, PeerFrameCodec>,
+        announced: Announced,
+    ) {
+        let peer = self.describe(framed.get_ref().peer_addr().ok());
+        if let Err(e) = self.serve(framed, Some(announced)).await {
+            debug!("Stopped uploading to peer {peer}: {e:#}");
+        }
+    }
+
+    async fn serve(
+        &self,
+        mut framed: Framed<TcpStream, PeerFrameCodec>,
+        announced: Option<Announced>,
+    ) -> anyhow::Result<()> {
+        let slot = self.choker.join(self.bandwidth.clone());
+        let mut unchoked = slot.unchoked.clone();
+        let mut choked = true;
+        // pieces the peer has, they are not announced to it
+        let mut peer_has = vec![false; self.piece_map.total_pieces()];
+        // waiting starts before the pieces verified so far are announced, none is missed
+        let mut changed = pin!(self.have.changed());
+        let mut announced = match announced {
+            Some(mut announced) => {
+                self.announce(&mut framed, &mut announced, &peer_has)
+                    .await?;
+                slot.set_interested(true);
+                announced
+            }
+            None => {
+                let mut announced = Announced::new(self.piece_map.total_pieces());
+                framed
+                    .send(PeerMessage::Bitfield(
+                        announced.bitfield(&self.have, self.lazy_bitfield),
+                    ))
+                    .await?;
+                announced
+            }
+        };
+
+        let mut stop = self.stop.clone();
+        loop {
+            let frame = tokio::select! {
+                frame = peers::next_frame(&mut framed, KEEP_ALIVE_INTERVAL, IDLE_TIMEOUT) => frame?,
+                _ = &mut changed => {
+                    changed.set(self.have.changed());
+                    self.announce(&mut framed, &mut announced, &peer_has).await?;
+                    continue;
+                }
+                Ok(()) = unchoked.changed() => {
+                    choked = !*unchoked.borrow_and_update();
+                    if let Some(peer) = &self.peer {
+                        peer.status.choking_peer.store(choked, Ordering::Relaxed);
+                    }
+                    let message = if choked {
+                        PeerMessage::Choke
+                    } else {
+                        PeerMessage::Unchoke
+                    };
+                    framed.send(message).await?;
+                    continue;
+                }
+                // the guard on the value is not kept, the future would not be Send
+                _ = async { stop.wait_for(|&stop| stop).await.map(|_| ()) } => return Ok(()),
+            };
+            let Some(frame) = frame else {
+                break;
+            };
+            match frame {
+                PeerMessage::Interested | PeerMessage::NotInterested => {
+                    let interested = frame == PeerMessage::Interested;
+                    slot.set_interested(interested);
+                    if let Some(peer) = &self.peer {
+                        peer.status
+                            .peer_interested
+                            .store(interested, Ordering::Relaxed);
+                    }
+                }
+                // requests that were in flight when we choked are dropped
+                PeerMessage::Bitfield(bitfield) => {
+                    for (piece_index, has) in peer_has.iter_mut().enumerate() {
+                        *has = bitfield.has(piece_index);
+                    }
+                }
+                PeerMessage::Have { index } => {
+                    if let Some(has) = peer_has.get_mut(index as usize) {
+                        *has = true;
+                    }
+                }
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } if !choked => {
+                    let block = self.read_block(index, begin, length).await?;
+                    self.bandwidth.upload.acquire(block.len()).await;
+                    framed
+                        .send(PeerMessage::Piece {
+                            index,
+                            begin,
+                            block,
+                        })
+                        .await?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // The address of the peer, and its client when the connection is listed.
+    fn describe(&self, addr: Option<SocketAddr>) -> String {
+        let addr = addr.map(|addr| addr.to_string()).unwrap_or_default();
+        match &self.peer {
+            Some(peer) => format!("{addr} ({})", peer.client),
+            None => addr,
+        }
+    }
+
+    // Sends have messages for the pieces verified since the peer was last told, but not for
+    // those it has itself.
+    async fn announce(
+        &self,
+        framed: &mut Framed<TcpStream, PeerFrameCodec>,
+        announced: &mut Announced,
+        peer_has: &[bool],
+    ) -> anyhow::Result<()> {
+        let pieces = announced.unannounced(&self.have);
+        let mut sent = false;
+        for &piece_index in pieces.iter().filter(|&&i| !peer_has[i]) {
+            framed
+                .feed(PeerMessage::Have {
+                    index: piece_index as u32,
+                })
+                .await?;
+            sent = true;
+        }
+        if sent {
+            framed.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn read_block(&self, index: u32, begin: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+        let (piece_index, begin, length) = (index as usize, begin as usize, length as usize);
+        if piece_index >= self.piece_map.total_pieces() || !self.have.has(piece_index) {
+            bail!(RustyBitError::PeerProtocol(format!(
+                "peer requested piece {piece_index} which we don't have"
+            )));
+        }
+        if length == 0
+            || length > MAX_BLOCK_LENGTH
+            || begin + length > self.piece_map.piece_range(piece_index).len()
+        {
+            bail!(RustyBitError::PeerProtocol(format!(
+                "peer requested {length} bytes at {begin} of piece {piece_index}"
+            )));
+        }
+        self.disk_io.read_block(piece_index, begin, length).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::download::{peers::Bitfield, storage::test_backend::MemoryStorage};
+    use futures_util::StreamExt;
+
+    // The uploads end when the sender is dropped.
+    fn uploader(have: Have) -> (Uploader, watch::Sender<bool>) {
+        let storage = MemoryStorage::default();
+        {
+            let mut files = storage.files.lock().unwrap();
+            files.insert("a".to_string(), (0..25).collect());
+            files.insert("b".to_string(), (25..40).collect());
+        }
+        let piece_map = Arc::new(PieceMap::new(
+            10,
+            vec![("a".to_string(), 25), ("b".to_string(), 15)],
+        ));
+        let (stop, stop_receiver) = watch::channel(false);
+        let uploader = Uploader {
+            piece_map: piece_map.clone(),
+            disk_io: DiskIo::spawn(Arc::new(storage), piece_map, &Config::default(), None),
+            have: Arc::new(have),
+            bandwidth: Bandwidth::new(None, None),
+            peer: None,
+            stop: stop_receiver,
+            choker: Arc::new(Choker::new(4)),
+            lazy_bitfield: false,
+        };
+        (uploader, stop)
+    }
+
+    #[tokio::test]
+    async fn blocks_are_read_across_files() {
+        let (uploader, _stop) = uploader(Have::new(4, &[]));
+        let block = uploader.read_block(2, 3, 6).await.unwrap();
+        assert_eq!(block, (23..29).collect::<Vec<u8>>());
+        assert_eq!(
+            Announced::new(4).bitfield(&uploader.have, false),
+            Bitfield::from_bytes(vec![0xf0])
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_requests_are_refused() {
+        let (uploader, _stop) = uploader(Have::new(4, &[1]));
+        // a piece we don't have
+        assert!(uploader.read_block(1, 0, 4).await.is_err());
+        // past the end of the short last piece
+        assert!(uploader.read_block(3, 8, 4).await.is_err());
+        // a piece that doesn't exist
+        assert!(uploader.read_block(4, 0, 1).await.is_err());
+        assert_eq!(
+            Announced::new(4).bitfield(&uploader.have, false),
+            Bitfield::from_bytes(vec![0xb0])
+        );
+    }
+
+    #[tokio::test]
+    async fn verified_pieces_are_announced() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (uploader, _stop) = uploader(Have::new(4, &[1, 2, 3]));
+        let have = uploader.have.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            uploader.upload(stream).await;
+        });
+
+        let mut peer = Framed::new(
+            TcpStream::connect(addr).await.unwrap(),
+            PeerFrameCodec::default(),
+        );
+        let bitfield = peer.next().await.unwrap().unwrap();
+        assert_eq!(
+            bitfield,
+            PeerMessage::Bitfield(Bitfield::from_bytes(vec![0x80]))
+        );
+        peer.send(PeerMessage::Have { index: 2 }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the peer has piece 2 already
+        have.set(2);
+        have.set(1);
+        let announced = peer.next().await.unwrap().unwrap();
+        assert_eq!(announced, PeerMessage::Have { index: 1 });
+        peer.send(PeerMessage::Interested).await.unwrap();
+        assert_eq!(peer.next().await.unwrap().unwrap(), PeerMessage::Unchoke);
+    }
+
+    #[tokio::test]
+    async fn finished_download_keeps_serving_the_peer() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (uploader, stop) = uploader(Have::new(4, &[]));
+        let upload = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            uploader
+                .upload_after_download(
+                    Framed::new(stream, PeerFrameCodec::default()),
+                    Announced::new(4),
+                )
+                .await;
+        });
+
+        let mut peer = Framed::new(
+            TcpStream::connect(addr).await.unwrap(),
+            PeerFrameCodec::default(),
+        );
+        for piece_index in 0..4_u32 {
+            let have = peer.next().await.unwrap().unwrap();
+            assert_eq!(have, PeerMessage::Have { index: piece_index });
+        }
+        let unchoke = peer.next().await.unwrap().unwrap();
+        assert_eq!(unchoke, PeerMessage::Unchoke);
+
+        peer.send(PeerMessage::Request {
+            index: 3,
+            begin: 0,
+            length: 10,
+        })
+        .await
+        .unwrap();
+        let piece = peer.next().await.unwrap().unwrap();
+        assert_eq!(
+            piece,
+            PeerMessage::Piece {
+                index: 3,
+                begin: 0,
+                block: (30..40).collect(),
+            }
+        );
+
+        // seeding ends, the connection is closed
+        stop.send(true).unwrap();
+        upload.await.unwrap();
+        assert!(peer.next().await.is_none());
+    }
+}
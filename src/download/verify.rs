@@ -0,0 +1,382 @@
+use crate::download::{merkle::PieceHashesV2, piece_map::PieceMap, storage::Storage};
+use crate::error::RustyBitError;
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::{
+    io::ErrorKind,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+// Bytes read from a file at a time.
+const READ_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+// Bytes of pieces a hashing thread checks before it takes the next batch. The batches are taken
+// in torrent order, so the threads read the disk roughly front to back together.
+const BATCH_SIZE: usize = 64 * 1024 * 1024;
+
+// Finds the pieces that are not on disk yet by hashing what is there.
+//
+// The pieces are cut into batches that one thread per core hashes. Every batch is read from front
+// to back in large chunks, the bytes are handed to the piece they belong to as they flow past. A
+// chunk can end many pieces and a piece can start in one file and end in another, so the hasher
+// of the current piece is carried over from chunk to chunk and from file to file. Missing or
+// short files only fail the pieces they should have held. Pieces of hybrid torrents have to match
+// their v2 hashes as well. checked is told how many pieces are done after each one, from any of
+// the threads.
+pub fn missing_pieces(
+    piece_map: &PieceMap,
+    storage: &dyn Storage,
+    pieces_hash: &[[u8; 20]],
+    pieces_hash_v2: Option<&PieceHashesV2>,
+    checked: &(dyn Fn(usize) + Sync),
+) -> anyhow::Result<Vec<usize>> {
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+    missing_pieces_in_batches(
+        piece_map,
+        storage,
+        pieces_hash,
+        pieces_hash_v2,
+        checked,
+        BATCH_SIZE,
+        threads,
+    )
+}
+
+fn missing_pieces_in_batches(
+    piece_map: &PieceMap,
+    storage: &dyn Storage,
+    pieces_hash: &[[u8; 20]],
+    pieces_hash_v2: Option<&PieceHashesV2>,
+    checked: &(dyn Fn(usize) + Sync),
+    batch_size: usize,
+    threads: usize,
+) -> anyhow::Result<Vec<usize>> {
+    let total_pieces = piece_map.total_pieces();
+    let batch_pieces = (batch_size / piece_map.piece_length()).max(1);
+    let batches = total_pieces.div_ceil(batch_pieces);
+    let next_batch = AtomicUsize::new(0);
+    let pieces_checked = AtomicUsize::new(0);
+    let missing = Mutex::new(Vec::new());
+
+    let hashed = || checked(pieces_checked.fetch_add(1, Ordering::Relaxed) + 1);
+    let check_batches = || -> anyhow::Result<()> {
+        let mut buf = vec![0; READ_BUFFER_SIZE.min(piece_map.total_length())];
+        loop {
+            let batch = next_batch.fetch_add(1, Ordering::Relaxed);
+            if batch >= batches {
+                return Ok(());
+            }
+            let pieces = batch * batch_pieces..((batch + 1) * batch_pieces).min(total_pieces);
+            let mut hasher = PieceHasher::new(
+                piece_map,
+                pieces_hash,
+                pieces_hash_v2,
+                pieces.start,
+                &hashed,
+            );
+            if let Err(e) = read_pieces(piece_map, storage, pieces, &mut buf, &mut hasher) {
+                // the other threads stop after their current batch
+                next_batch.store(batches, Ordering::Relaxed);
+                return Err(e);
+            }
+            missing.lock().unwrap().extend(hasher.missing);
+        }
+    };
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(batches))
+            .map(|_| scope.spawn(check_batches))
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("hashing does not panic"))
+    })?;
+
+    let mut missing = missing.into_inner().unwrap();
+    missing.sort_unstable();
+    Ok(missing)
+}
+
+// Feeds the data of the pieces to the hasher, what cannot be read is skipped.
+fn read_pieces(
+    piece_map: &PieceMap,
+    storage: &dyn Storage,
+    pieces: Range<usize>,
+    buf: &mut [u8],
+    hasher: &mut PieceHasher,
+) -> anyhow::Result<()> {
+    let range =
+        piece_map.piece_range(pieces.start).start..piece_map.piece_range(pieces.end - 1).end;
+    for location in piece_map.range_locations(range) {
+        let path = piece_map.path(location.file_index);
+        let length = location.length as usize;
+        let mut remaining = length;
+        while remaining > 0 {
+            let to_read = remaining.min(buf.len());
+            let offset = location.offset + (length - remaining) as u64;
+            let read = match storage.read_at(path, offset, &mut buf[..to_read]) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => {
+                    return Err(RustyBitError::disk(path)(e))
+                        .with_context(|| format!("Reading {path}"))
+                }
+            };
+            hasher.update(&buf[..read]);
+            remaining -= read;
+        }
+        // what the file is missing
+        hasher.skip(remaining);
+    }
+    Ok(())
+}
+
+// Hashes the torrent's data piece by piece as it is fed in torrent order, from the start of a
+// piece on.
+struct PieceHasher<'a> {
+    piece_map: &'a PieceMap,
+    pieces_hash: &'a [[u8; 20]],
+    pieces_hash_v2: Option<&'a PieceHashesV2>,
+    // called after every piece
+    hashed: &'a dyn Fn(),
+    // the current piece, only kept for the v2 hashes that are not computed incrementally
+    piece: Vec<u8>,
+    piece_index: usize,
+    // torrent offset of the next byte
+    offset: usize,
+    hasher: Sha1,
+    // part of the current piece could not be read
+    broken: bool,
+    missing: Vec<usize>,
+}
+
+impl<'a> PieceHasher<'a> {
+    fn new(
+        piece_map: &'a PieceMap,
+        pieces_hash: &'a [[u8; 20]],
+        pieces_hash_v2: Option<&'a PieceHashesV2>,
+        piece_index: usize,
+        hashed: &'a dyn Fn(),
+    ) -> PieceHasher<'a> {
+        PieceHasher {
+            piece_map,
+            pieces_hash,
+            pieces_hash_v2,
+            hashed,
+            piece: Vec::new(),
+            piece_index,
+            offset: piece_map.piece_range(piece_index).start,
+            hasher: Sha1::new(),
+            broken: false,
+            missing: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = self.advance(data.len());
+            self.hasher.update(&data[..take]);
+            if self.pieces_hash_v2.is_some() {
+                self.piece.extend_from_slice(&data[..take]);
+            }
+            data = &data[take..];
+            self.finish_if_complete();
+        }
+    }
+
+    fn skip(&mut self, mut length: usize) {
+        while length > 0 {
+            length -= self.advance(length);
+            self.broken = true;
+            self.finish_if_complete();
+        }
+    }
+
+    // Moves over up to length bytes of the current piece, returns how many.
+    fn advance(&mut self, length: usize) -> usize {
+        let piece_end = self.piece_map.piece_range(self.piece_index).end;
+        let take = length.min(piece_end - self.offset);
+        self.offset += take;
+        take
+    }
+
+    fn finish_if_complete(&mut self) {
+        if self.offset != self.piece_map.piece_range(self.piece_index).end {
+            return;
+        }
+        let hash: [u8; 20] = self.hasher.finalize_reset().into();
+        let v2_matches = self
+            .pieces_hash_v2
+            .is_none_or(|hashes| hashes.verify(self.piece_index, &self.piece));
+        if self.broken || hash != self.pieces_hash[self.piece_index] || !v2_matches {
+            self.missing.push(self.piece_index);
+        }
+        self.piece.clear();
+        self.broken = false;
+        self.piece_index += 1;
+        (self.hashed)();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::storage::FileStorage;
+    use rand::{Rng, SeedableRng};
+    use std::{
+        fs::File,
+        io::{Read, Seek, SeekFrom},
+        path::Path,
+        time::Instant,
+    };
+
+    // The straightforward way: a seek and a read per piece and file.
+    fn missing_pieces_naive(piece_map: &PieceMap, pieces_hash: &[[u8; 20]]) -> Vec<usize> {
+        (0..piece_map.total_pieces())
+            .filter(|&piece_index| {
+                let mut piece = Vec::new();
+                for location in piece_map.locations(piece_index) {
+                    let mut part = vec![0; location.length as usize];
+                    let read =
+                        File::open(piece_map.path(location.file_index)).and_then(|mut file| {
+                            file.seek(SeekFrom::Start(location.offset))?;
+                            file.read_exact(&mut part)
+                        });
+                    if read.is_err() {
+                        return true;
+                    }
+                    piece.extend(part);
+                }
+                let hash: [u8; 20] = Sha1::digest(&piece).into();
+                hash != pieces_hash[piece_index]
+            })
+            .collect()
+    }
+
+    // Writes random files of the given lengths, returns their map and the piece hashes.
+    fn write_files(
+        directory: &Path,
+        piece_length: usize,
+        lengths: &[usize],
+    ) -> (PieceMap, Vec<[u8; 20]>) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(lengths.len() as u64);
+        let mut data = Vec::new();
+        let mut files = Vec::new();
+        for (i, &length) in lengths.iter().enumerate() {
+            let contents: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            let path = directory.join(format!("file{i}"));
+            std::fs::write(&path, &contents).unwrap();
+            data.extend(contents);
+            files.push((path.to_str().unwrap().to_string(), length));
+        }
+        let pieces_hash = data
+            .chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        (PieceMap::new(piece_length, files), pieces_hash)
+    }
+
+    #[test]
+    fn same_result_as_naive_check() {
+        let directory = tempfile::tempdir().unwrap();
+        let lengths = [100, 0, 3, 250, 17, 64, 0, 1000];
+        for piece_length in [1, 7, 64, 300, 4096] {
+            let (piece_map, pieces_hash) = write_files(directory.path(), piece_length, &lengths);
+            // a flipped byte in the middle, a truncated file and a deleted one
+            let mut contents = std::fs::read(piece_map.path(3)).unwrap();
+            contents[120] ^= 1;
+            std::fs::write(piece_map.path(3), contents).unwrap();
+            File::options()
+                .write(true)
+                .open(piece_map.path(5))
+                .unwrap()
+                .set_len(40)
+                .unwrap();
+            std::fs::remove_file(piece_map.path(2)).unwrap();
+
+            let naive = missing_pieces_naive(&piece_map, &pieces_hash);
+            assert!(!naive.is_empty());
+            // a piece or a few per batch, and everything in one batch
+            for (batch_size, threads) in [(1, 4), (3 * piece_length, 2), (BATCH_SIZE, 1)] {
+                let missing = missing_pieces_in_batches(
+                    &piece_map,
+                    &FileStorage::default(),
+                    &pieces_hash,
+                    None,
+                    &|_| {},
+                    batch_size,
+                    threads,
+                )
+                .unwrap();
+                assert_eq!(missing, naive, "{batch_size} bytes per batch");
+            }
+        }
+    }
+
+    #[test]
+    fn complete_files_miss_nothing() {
+        let directory = tempfile::tempdir().unwrap();
+        let (piece_map, pieces_hash) = write_files(directory.path(), 16, &[33, 16, 0, 5]);
+        let checked = std::sync::Mutex::new(Vec::new());
+        assert_eq!(
+            missing_pieces(
+                &piece_map,
+                &FileStorage::default(),
+                &pieces_hash,
+                None,
+                &|pieces| checked.lock().unwrap().push(pieces)
+            )
+            .unwrap(),
+            Vec::<usize>::new()
+        );
+        assert_eq!(checked.into_inner().unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    // cargo test --release verify_benchmark -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn verify_benchmark() {
+        let directory = tempfile::tempdir().unwrap();
+        let lengths: Vec<usize> = (0..64).map(|i| 4 * 1024 * 1024 + i * 1000).collect();
+        let (piece_map, pieces_hash) = write_files(directory.path(), 16 * 1024, &lengths);
+
+        let started = Instant::now();
+        let naive = missing_pieces_naive(&piece_map, &pieces_hash);
+        let naive_time = started.elapsed();
+        let started = Instant::now();
+        let sequential = missing_pieces_in_batches(
+            &piece_map,
+            &FileStorage::default(),
+            &pieces_hash,
+            None,
+            &|_| {},
+            BATCH_SIZE,
+            1,
+        )
+        .unwrap();
+        let sequential_time = started.elapsed();
+        let started = Instant::now();
+        let parallel = missing_pieces(
+            &piece_map,
+            &FileStorage::default(),
+            &pieces_hash,
+            None,
+            &|_| {},
+        )
+        .unwrap();
+        let parallel_time = started.elapsed();
+
+        assert_eq!(naive, sequential);
+        assert_eq!(naive, parallel);
+        println!(
+            "{} pieces: naive {naive_time:?}, sequential {sequential_time:?}, parallel {parallel_time:?}",
+            piece_map.total_pieces()
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use crate::download::session::TorrentSource;
+use crate::error::RustyBitError;
+use anyhow::Context;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/*
+ * A watch directory: .torrent files and .magnet files, a text file holding a magnet link, dropped
+ * into it are added to the session. Afterwards the file is renamed with .added or, if it could
+ * not be added, with .invalid appended, so that it is not picked up again and the user can tell
+ * what happened. The directory is listed every few seconds, a file is only read once its size
+ * stayed the same between two listings so that one still being copied in is not read half way.
+ */
+
+// How often the directory is listed.
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct Watcher {
+    dir: PathBuf,
+    // size of each candidate at the last listing
+    sizes: HashMap<PathBuf, u64>,
+    // files that were handled but could not be renamed
+    done: HashSet<PathBuf>,
+}
+
+impl Watcher {
+    pub fn new(dir: PathBuf) -> Watcher {
+        Watcher {
+            dir,
+            sizes: HashMap::new(),
+            done: HashSet::new(),
+        }
+    }
+
+    // Lists the directory every WATCH_INTERVAL and adds what is new, never returns.
+    pub async fn run(mut self, add: impl Fn(TorrentSource) -> anyhow::Result<()>) {
+        info!("Watching {} for torrents", self.dir.display());
+        let mut ticks = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            ticks.tick().await;
+            self.poll(&add);
+        }
+    }
+
+    // Adds the files whose size did not change since the last listing.
+    pub fn poll(&mut self, add: &impl Fn(TorrentSource) -> anyhow::Result<()>) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("cannot list watch directory {}: {e}", self.dir.display());
+                return;
+            }
+        };
+        let mut sizes = HashMap::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(kind) = Kind::of(&path) else {
+                continue;
+            };
+            if self.done.contains(&path) {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            if self.sizes.get(&path) != Some(&metadata.len()) {
+                sizes.insert(path, metadata.len());
+                continue;
+            }
+            let added = kind.read(&path).and_then(|source| match add(source) {
+                // a file with a torrent of the session is done with as well
+                Err(e)
+                    if matches!(
+                        RustyBitError::find(&e),
+                        Some(RustyBitError::Duplicate { .. })
+                    ) =>
+                {
+                    info!("{e:#}");
+                    Ok(())
+                }
+                added => added,
+            });
+            let suffix = match added {
+                Ok(()) => {
+                    info!("Added {}", path.display());
+                    "added"
+                }
+                Err(e) => {
+                    warn!("could not add {}: {e:#}", path.display());
+                    "invalid"
+                }
+            };
+            if let Err(e) = rename_with_suffix(&path, suffix) {
+                // left in place it would be added over and over
+                warn!("{e:#}, ignoring it from now on");
+                self.done.insert(path);
+            }
+        }
+        self.sizes = sizes;
+    }
+}
+
+enum Kind {
+    Torrent,
+    Magnet,
+}
+
+impl Kind {
+    fn of(path: &Path) -> Option<Kind> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "torrent" => Some(Kind::Torrent),
+            "magnet" => Some(Kind::Magnet),
+            _ => None,
+        }
+    }
+
+    fn read(&self, path: &Path) -> anyhow::Result<TorrentSource> {
+        let read = || format!("Reading {}", path.display());
+        Ok(match self {
+            Kind::Torrent => TorrentSource::Bytes(fs::read(path).with_context(read)?),
+            Kind::Magnet => {
+                TorrentSource::Magnet(fs::read_to_string(path).with_context(read)?.trim().into())
+            }
+        })
+    }
+}
+
+// dir/linux.torrent becomes dir/linux.torrent.added
+fn rename_with_suffix(path: &Path, suffix: &str) -> anyhow::Result<()> {
+    let mut renamed = path.as_os_str().to_os_string();
+    renamed.push(".");
+    renamed.push(suffix);
+    fs::rename(path, &renamed).with_context(|| format!("Renaming {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn dropped_files_are_added_once_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let added = Mutex::new(Vec::new());
+        let add = |source: TorrentSource| match source {
+            TorrentSource::Bytes(bytes) if bytes == b"d4:infoe" => {
+                added.lock().unwrap().push("torrent".to_string());
+                Ok(())
+            }
+            TorrentSource::Magnet(link) => {
+                added.lock().unwrap().push(link);
+                Ok(())
+            }
+            _ => anyhow::bail!("not a torrent"),
+        };
+        let mut watcher = Watcher::new(dir.path().to_path_buf());
+
+        fs::write(dir.path().join("good.torrent"), b"d4:info").unwrap();
+        fs::write(dir.path().join("bad.TORRENT"), b"garbage").unwrap();
+        fs::write(dir.path().join("link.magnet"), "magnet:?xt=urn:btih:00\n").unwrap();
+        fs::write(dir.path().join("notes.txt"), "not for us").unwrap();
+        watcher.poll(&add);
+        assert!(added.lock().unwrap().is_empty());
+
+        // still being written
+        fs::write(dir.path().join("good.torrent"), b"d4:infoe").unwrap();
+        watcher.poll(&add);
+        assert_eq!(*added.lock().unwrap(), vec!["magnet:?xt=urn:btih:00"]);
+        watcher.poll(&add);
+        assert_eq!(
+            *added.lock().unwrap(),
+            vec!["magnet:?xt=urn:btih:00", "torrent"]
+        );
+
+        let mut files: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "bad.TORRENT.invalid",
+                "good.torrent.added",
+                "link.magnet.added",
+                "notes.txt"
+            ]
+        );
+        watcher.poll(&add);
+        assert_eq!(added.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::download::piece_map::PieceMap;
+use anyhow::{bail, Context};
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Deserializer};
+use std::sync::Arc;
+
+/*
+ * An HTTP server that has the files of a torrent (BEP 19). Pieces are fetched from it with range
+ * requests, a piece that spans several files takes one request per file.
+ */
+pub struct WebSeed {
+    url: String,
+    // URL of every file of the torrent, in torrent order
+    file_urls: Vec<String>,
+    piece_map: Arc<PieceMap>,
+    client: reqwest::Client,
+}
+
+// The url-list key of a torrent is a single URL or a list of them, empty URLs are left out.
+pub fn deserialize_url_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlList {
+        One(String),
+        Many(Vec<String>),
+    }
+    let urls = match UrlList::deserialize(deserializer)? {
+        UrlList::One(url) => vec![url],
+        UrlList::Many(urls) => urls,
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
+}
+
+// Where a file of the torrent is on a web seed. A single file torrent's URL names the file unless
+// it ends in a slash, files of a multi file torrent are below the torrent's name.
+fn file_url(url: &str, name: &str, path: Option<&[String]>) -> String {
+    let mut file_url = url.to_string();
+    if path.is_some() && !file_url.ends_with('/') {
+        file_url.push('/');
+    }
+    if file_url.ends_with('/') {
+        file_url.push_str(&urlencoding::encode(name));
+        for component in path.unwrap_or_default() {
+            file_url.push('/');
+            file_url.push_str(&urlencoding::encode(component));
+        }
+    }
+    file_url
+}
+
+impl WebSeed {
+    // files holds the path of every file below the torrent's name, None for a single file
+    // torrent.
+    pub fn new(
+        url: &str,
+        name: &str,
+        files: Option<Vec<Vec<String>>>,
+        piece_map: Arc<PieceMap>,
+        client: reqwest::Client,
+    ) -> WebSeed {
+        let file_urls = match files {
+            None => vec![file_url(url, name, None)],
+            Some(files) => files
+                .iter()
+                .map(|path| file_url(url, name, Some(path)))
+                .collect(),
+        };
+        WebSeed {
+            url: url.to_string(),
+            file_urls,
+            piece_map,
+            client,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // The data of a piece, not checked against its hash yet.
+    pub async fn fetch_piece(&self, piece_index: usize) -> anyhow::Result<Vec<u8>> {
+        let mut piece = Vec::with_capacity(self.piece_map.piece_range(piece_index).len());
+        for location in self.piece_map.locations(piece_index) {
+            let file_url = &self.file_urls[location.file_index as usize];
+            let first = location.offset;
+            let last = location.offset + location.length as u64 - 1;
+            let response = self
+                .client
+                .get(file_url)
+                .header(header::RANGE, format!("bytes={first}-{last}"))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .with_context(|| format!("Requesting {file_url}"))?;
+            let status = response.status();
+            let body = response
+                .bytes()
+                .await
+                .with_context(|| format!("Reading {file_url}"))?;
+            // a server that ignores the range sends the whole file
+            let part = match status {
+                StatusCode::PARTIAL_CONTENT => &body[..],
+                _ => body
+                    .get(first as usize..=last as usize)
+                    .with_context(|| format!("{file_url} is shorter than the torrent says"))?,
+            };
+            if part.len() != location.length as usize {
+                bail!(
+                    "{file_url} sent {} bytes, asked for {}",
+                    part.len(),
+                    location.length
+                );
+            }
+            piece.extend_from_slice(part);
+        }
+        Ok(piece)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_urls_follow_bep_19() {
+        assert_eq!(
+            file_url("http://seed.example/linux.iso", "linux.iso", None),
+            "http://seed.example/linux.iso"
+        );
+        assert_eq!(
+            file_url("http://seed.example/files/", "linux.iso", None),
+            "http://seed.example/files/linux.iso"
+        );
+        let path = vec!["sub dir".to_string(), "a.bin".to_string()];
+        assert_eq!(
+            file_url("http://seed.example/files", "album", Some(&path)),
+            "http://seed.example/files/album/sub%20dir/a.bin"
+        );
+        assert_eq!(
+            file_url("http://seed.example/files/", "album", Some(&path)),
+            "http://seed.example/files/album/sub%20dir/a.bin"
+        );
+    }
+
+    #[test]
+    fn url_list_is_one_url_or_a_list() {
+        #[derive(Deserialize)]
+        struct Metainfo {
+            #[serde(rename = "url-list", deserialize_with = "deserialize_url_list")]
+            url_list: Vec<String>,
+        }
+        let one: Metainfo = serde_bencode::from_bytes(b"d8:url-list14:http://a.test/e").unwrap();
+        assert_eq!(one.url_list, ["http://a.test/"]);
+        let many: Metainfo =
+            serde_bencode::from_bytes(b"d8:url-listl14:http://a.test/0:14:http://b.test/ee")
+                .unwrap();
+        assert_eq!(many.url_list, ["http://a.test/", "http://b.test/"]);
+    }
+}
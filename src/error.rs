@@ -0,0 +1,94 @@
+use std::{error::Error, io};
+use thiserror::Error;
+
+/*
+ * What went wrong at the root of a failure. Functions keep returning anyhow::Result and add
+ * context on the way up, the RustyBitError underneath is found again with RustyBitError::find so
+ * callers can tell a dead tracker from a misbehaving peer or a full disk.
+ */
+#[derive(Debug, Error)]
+pub enum RustyBitError {
+    #[error("tracker {tracker} failed")]
+    Tracker {
+        // the announce URL with any passkey redacted
+        tracker: String,
+        #[source]
+        source: Box<dyn Error + Send + Sync>,
+    },
+
+    // a peer sent something the peer wire protocol does not allow
+    #[error("peer broke the protocol: {0}")]
+    PeerProtocol(String),
+
+    #[error("disk I/O failed")]
+    Disk {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("{what} is not valid bencode")]
+    Bencode {
+        what: String,
+        #[source]
+        source: serde_bencode::Error,
+    },
+
+    // the torrent was added to the session before and is not stopped yet
+    #[error("{name} is already in the session")]
+    Duplicate { name: String, info_hash: [u8; 20] },
+}
+
+impl RustyBitError {
+    // The RustyBitError an error was caused by, under any context added to it.
+    pub fn find(error: &anyhow::Error) -> Option<&RustyBitError> {
+        error.chain().find_map(|cause| cause.downcast_ref())
+    }
+
+    pub fn tracker<E: Into<Box<dyn Error + Send + Sync>>>(
+        tracker: &str,
+    ) -> impl FnOnce(E) -> RustyBitError + '_ {
+        move |source| RustyBitError::Tracker {
+            tracker: tracker.to_string(),
+            source: source.into(),
+        }
+    }
+
+    pub fn disk(path: &str) -> impl FnOnce(io::Error) -> RustyBitError + '_ {
+        move |source| RustyBitError::Disk {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn bencode(what: &str) -> impl FnOnce(serde_bencode::Error) -> RustyBitError + '_ {
+        move |source| RustyBitError::Bencode {
+            what: what.to_string(),
+            source,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn the_root_error_is_found_under_context() {
+        let error = Err::<(), _>(io::Error::other("no space left"))
+            .map_err(RustyBitError::disk("a.bin"))
+            .context("Writing piece 3")
+            .unwrap_err();
+        assert!(matches!(
+            RustyBitError::find(&error),
+            Some(RustyBitError::Disk { path, .. }) if path == "a.bin"
+        ));
+        assert_eq!(
+            format!("{error:#}"),
+            "Writing piece 3: disk I/O failed: no space left"
+        );
+
+        assert!(RustyBitError::find(&anyhow::anyhow!("something else")).is_none());
+    }
+}
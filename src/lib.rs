@@ -1,2 +1,14 @@
+pub mod config;
+pub mod config_file;
+pub mod daemon;
 pub mod download;
+pub mod error;
 pub mod helper;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+pub use download::session::{
+    BlockedConnections, Event, PeerInfo, Progress, Session, TorrentHandle, TorrentSource,
+    TorrentState,
+};
+pub use error::RustyBitError;
@@ -1,47 +1,538 @@
+use clap::{Parser, Subcommand};
 use rusty_bit::{
-    download::download_using_file,
-    helper::{self, print_single_ln},
+    config::ConfigArgs,
+    config_file,
+    daemon::{self, Daemon},
+    download::{
+        create_torrent_file, magnet_link, progress::ProgressMeter, torrent_info, verify_download,
+        CreateOptions,
+    },
+    helper::print_single_ln,
+    Session, TorrentHandle, TorrentSource, TorrentState,
 };
+use std::{
+    io::IsTerminal,
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser)]
+#[command(name = "rusty-bit", version, about = "A BitTorrent client")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Download a torrent from a .torrent file or a magnet link")]
+    Download {
+        #[arg(value_name = "FILE|MAGNET", value_parser = torrent_source)]
+        source: TorrentSource,
+        #[command(flatten)]
+        options: ConfigArgs,
+    },
+    #[cfg(feature = "tui")]
+    #[command(about = "Download torrents side by side in a terminal dashboard")]
+    Tui {
+        #[arg(value_name = "FILE|MAGNET", value_parser = torrent_source, required = true)]
+        sources: Vec<TorrentSource>,
+        #[command(flatten)]
+        options: ConfigArgs,
+    },
+    #[command(about = "Download in the background, controlled over Transmission's RPC protocol")]
+    Daemon {
+        #[arg(
+            value_name = "FILE|MAGNET",
+            value_parser = torrent_source,
+            help = "Torrents to start with"
+        )]
+        sources: Vec<TorrentSource>,
+        #[arg(
+            long,
+            value_name = "ADDR",
+            default_value = daemon::DEFAULT_RPC_ADDRESS,
+            help = "Where RPC requests are answered, only this machine can reach the default"
+        )]
+        rpc_address: SocketAddr,
+        #[arg(
+            long,
+            value_name = "USER:PASSWORD",
+            value_parser = parse_credentials,
+            help = "Credentials RPC requests need, with basic authentication"
+        )]
+        rpc_auth: Option<String>,
+        #[command(flatten)]
+        options: ConfigArgs,
+    },
+    #[command(about = "Show what a .torrent file contains")]
+    Info {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+    },
+    #[command(about = "Make a .torrent file for a file or a directory")]
+    Create {
+        #[arg(value_name = "PATH")]
+        source: PathBuf,
+        #[arg(
+            short,
+            long,
+            value_name = "FILE",
+            help = "Where the .torrent file goes, the name of the source with .torrent by default"
+        )]
+        output: Option<PathBuf>,
+        #[arg(
+            long = "tracker",
+            value_name = "URL[,URL...]",
+            help = "Announce URLs of a tier, trackers of the same tier are separated by commas"
+        )]
+        trackers: Vec<String>,
+        #[arg(
+            long,
+            value_name = "BYTES",
+            help = "Bytes in each piece, picked from the total size by default"
+        )]
+        piece_length: Option<usize>,
+        #[arg(long, help = "Only let the trackers hand out peers")]
+        private: bool,
+        #[arg(long)]
+        comment: Option<String>,
+    },
+    #[command(about = "Print a magnet link for a .torrent file")]
+    Magnet {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+    },
+    #[command(about = "Check a download against the piece hashes of its .torrent file")]
+    Verify {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+        #[command(flatten)]
+        options: ConfigArgs,
+    },
+    #[command(about = "Look at the settings of the config file")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    #[command(
+        about = "Print the settings downloads run with, the config file and the options together"
+    )]
+    Show {
+        #[command(flatten)]
+        options: ConfigArgs,
+    },
+}
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    let command = Cli::parse().command;
+    // the dashboard draws over the whole terminal, log lines would end up in it
+    #[cfg(feature = "tui")]
+    let log = !matches!(command, Command::Tui { .. });
+    #[cfg(not(feature = "tui"))]
+    let log = true;
+    if log {
+        init_logging();
+    }
+    let result = match command {
+        Command::Download { source, options } => download(source, options).await,
+        #[cfg(feature = "tui")]
+        Command::Tui { sources, options } => tui(sources, options).await,
+        Command::Daemon {
+            sources,
+            rpc_address,
+            rpc_auth,
+            options,
+        } => run_daemon(sources, rpc_address, rpc_auth, options).await,
+        Command::Info { file } => torrent_info(&file).map(|info| print!("{info}")),
+        Command::Magnet { file } => magnet_link(&file).map(|link| println!("{link}")),
+        Command::Verify { file, options } => verify(file, options),
+        Command::Config {
+            command: ConfigCommand::Show { options },
+        } => show_config(options),
+        Command::Create {
+            source,
+            output,
+            trackers,
+            piece_length,
+            private,
+            comment,
+        } => create(
+            source,
+            output,
+            CreateOptions {
+                piece_length,
+                trackers: trackers
+                    .iter()
+                    .map(|tier| tier.split(',').map(str::to_string).collect())
+                    .collect(),
+                private,
+                comment,
+            },
+        ),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            println!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn download(source: TorrentSource, options: ConfigArgs) -> anyhow::Result<()> {
     println!(
         r"
-______          _          ______ _ _   
-| ___ \        | |         | ___ (_) |  
-| |_/ /   _ ___| |_ _   _  | |_/ /_| |_ 
+______          _          ______ _ _
+| ___ \        | |         | ___ (_) |
+| |_/ /   _ ___| |_ _   _  | |_/ /_| |_
 |    / | | / __| __| | | | | ___ \ | __|
-| |\ \ |_| \__ \ |_| |_| | | |_/ / | |_ 
+| |\ \ |_| \__ \ |_| |_| | | |_/ / | |_
 \_| \_\__,_|___/\__|\__, | \____/|_|\__|
-                     __/ |              
-                    |___/               
+                     __/ |
+                    |___/
 "
     );
-
-    loop {
+    let session = start_session(options)?;
+    let torrent = session.add_torrent(source)?;
+    let mut meter = ProgressMeter::new(Instant::now());
+    let state = tokio::select! {
+        state = torrent.wait() => state,
+        _ = show_progress(&torrent, &mut meter) => unreachable!("the progress is shown until the download ends"),
+        _ = tokio::signal::ctrl_c() => {
+            // the downloaded pieces are saved and the tracker is told that we leave, a second
+            // Ctrl-C does not wait for that
+            println!();
+            println!("Shutting down, press Ctrl-C again to quit right away");
+            tokio::select! {
+                _ = torrent.pause() => {}
+                _ = tokio::signal::ctrl_c() => anyhow::bail!("Quit without saving the progress"),
+            }
+            println!("Progress saved, the next start continues where this one stopped");
+            torrent.progress().state
+        }
+    };
+    println!("{}", meter.update(&torrent.progress(), Instant::now()));
+    let blocked = session.blocked_connections();
+    if blocked.outgoing + blocked.incoming > 0 {
         println!(
-            "\nWhat would you like to do:\n\
-        1) Download using .torrent file\n\
-        2) Quit Rusty-Bit\n"
+            "The IP filter blocked {} outgoing and {} incoming connections",
+            blocked.outgoing, blocked.incoming
         );
-        print_single_ln("Choose your preferred download method or quit the program: ");
-        let chosen_option = helper::read_string();
-        match chosen_option.as_str() {
-            "1" => {
-                let download_result = download_using_file().await;
-                if download_result.is_ok() {
-                    println!("Download completed, exiting...");
-                    println!("See you later");
-                } else {
-                    println!("Download failed, reason: {:?}", download_result.err());
-                }
-                break;
-            }
-            "2" => {
-                println!("See you later");
-                break;
-            }
-            _ => println!("Option should be a number from the table given above!! Try again.\n"),
+    }
+    // the port mapping goes away with the last torrent
+    session.close().await;
+    match state {
+        TorrentState::Failed(reason) => anyhow::bail!("Download failed, reason: {reason}"),
+        TorrentState::Paused => {
+            println!("See you later");
+            Ok(())
+        }
+        _ => {
+            println!("Download completed, exiting...");
+            println!("See you later");
+            Ok(())
         }
     }
 }
+
+// Logs to stderr at the level RUST_LOG asks for, e.g. RUST_LOG=rusty_bit=debug for every
+// connection and announce, info and above by default.
+fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+// A session with the config of the options, serving its metrics if they were asked for.
+fn start_session(options: ConfigArgs) -> anyhow::Result<Session> {
+    let session = Session::new(options.into_config()?);
+    if let Some(addr) = session.serve_metrics()? {
+        tracing::info!("Serving metrics on http://{addr}/metrics");
+    }
+    Ok(session)
+}
+
+// A magnet link, or else the path of a .torrent file.
+fn torrent_source(source: &str) -> Result<TorrentSource, String> {
+    if source.starts_with("magnet:") {
+        return Ok(TorrentSource::Magnet(source.to_string()));
+    }
+    Ok(TorrentSource::File(source.into()))
+}
+
+#[cfg(feature = "tui")]
+async fn tui(sources: Vec<TorrentSource>, options: ConfigArgs) -> anyhow::Result<()> {
+    let session = start_session(options)?;
+    let torrents = sources
+        .into_iter()
+        .map(|source| session.add_torrent(source))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let result = rusty_bit::tui::run(torrents).await;
+    session.close().await;
+    result
+}
+
+async fn run_daemon(
+    sources: Vec<TorrentSource>,
+    rpc_address: SocketAddr,
+    rpc_auth: Option<String>,
+    options: ConfigArgs,
+) -> anyhow::Result<()> {
+    let daemon = Daemon::new(start_session(options)?, rpc_auth.as_deref());
+    for source in sources {
+        daemon.add_torrent(source)?;
+    }
+    daemon::run(daemon, rpc_address).await
+}
+
+fn parse_credentials(credentials: &str) -> Result<String, String> {
+    match credentials.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(credentials.to_string()),
+        _ => Err("expected USER:PASSWORD".to_string()),
+    }
+}
+
+// Redraws the status line of the torrent every second on a terminal, otherwise prints it every
+// half minute. Never returns.
+async fn show_progress(torrent: &TorrentHandle, meter: &mut ProgressMeter) {
+    let terminal = std::io::stdout().is_terminal();
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    for tick in 0_u64.. {
+        ticks.tick().await;
+        let line = meter.update(&torrent.progress(), Instant::now());
+        if terminal {
+            // the cursor goes back to the start so that messages printed meanwhile overwrite
+            // the line instead of following it
+            print_single_ln(&format!("\r{line}\x1b[K\r"));
+        } else if tick % 30 == 0 {
+            println!("{line}");
+        }
+    }
+}
+
+fn create(source: PathBuf, output: Option<PathBuf>, options: CreateOptions) -> anyhow::Result<()> {
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let mut name = source
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("{} has no name", source.display()))?
+                .to_os_string();
+            name.push(".torrent");
+            PathBuf::from(name)
+        }
+    };
+    let info = create_torrent_file(&source, &options, &output)?;
+    println!("Created {}", output.display());
+    print!("{info}");
+    Ok(())
+}
+
+fn show_config(options: ConfigArgs) -> anyhow::Result<()> {
+    if let Some(path) = config_file::default_path() {
+        println!("# default config file: {}", path.display());
+    }
+    print!("{}", config_file::show(&options.into_config()?)?);
+    Ok(())
+}
+
+fn verify(file: PathBuf, options: ConfigArgs) -> anyhow::Result<()> {
+    let verification = verify_download(&file, &options.into_config()?)?;
+    let missing = verification.missing_pieces.len();
+    println!(
+        "{} of {} pieces are complete",
+        verification.total_pieces - missing,
+        verification.total_pieces
+    );
+    if missing > 0 {
+        anyhow::bail!("Missing pieces: {:?}", verification.missing_pieces);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use rusty_bit::config::{FilePriority, SaveTorrent};
+
+    #[test]
+    fn the_command_line_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn download_options_make_up_the_config() {
+        let out = std::env::temp_dir();
+        let cli = Cli::try_parse_from([
+            "rusty-bit",
+            "download",
+            "linux.torrent",
+            "--out",
+            out.to_str().unwrap(),
+            "--download-limit",
+            "100",
+            "--peer",
+            "10.0.0.1:6881",
+            "--no-dht",
+            "--no-port-mapping",
+            "--block",
+            "192.0.2.0/24",
+            "--tracker-cookie",
+            "uid=7",
+            "--tracker-cookie",
+            "pass=secret",
+            "--tracker-header",
+            "X-Api-Key: abc",
+            "--file-priority",
+            "2=skip",
+            "--file-priority",
+            "0=high",
+        ])
+        .unwrap();
+        let Command::Download { source, options } = cli.command else {
+            panic!("not the download command");
+        };
+        assert_eq!(source, TorrentSource::File("linux.torrent".into()));
+        let config = options.into_config().unwrap();
+        assert_eq!(config.download_dir, out);
+        assert_eq!(config.download_limit, Some(100 * 1024));
+        assert_eq!(config.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert!(!config.dht);
+        assert!(!config.port_mapping);
+        assert_eq!(
+            config.tracker_headers,
+            vec![
+                ("X-Api-Key".to_string(), "abc".to_string()),
+                ("Cookie".to_string(), "uid=7; pass=secret".to_string()),
+            ]
+        );
+        assert!(config.ip_filter.blocks(&"192.0.2.7".parse().unwrap()));
+        assert_eq!(config.file_priorities[&2], FilePriority::Skip);
+        assert_eq!(config.file_priorities[&0], FilePriority::High);
+
+        // the download directory has to exist
+        assert!(Cli::try_parse_from([
+            "rusty-bit",
+            "download",
+            "linux.torrent",
+            "--out",
+            out.join("rusty-bit-missing").to_str().unwrap(),
+        ])
+        .is_err());
+
+        // sizes and times too large to count in
+        for (option, value) in [("--read-cache", u64::MAX), ("--seed-time", u64::MAX / 2)] {
+            let cli = Cli::try_parse_from([
+                "rusty-bit",
+                "download",
+                "linux.torrent",
+                option,
+                &value.to_string(),
+            ])
+            .unwrap();
+            let Command::Download { options, .. } = cli.command else {
+                panic!("not the download command");
+            };
+            assert!(options.into_config().is_err());
+        }
+
+        // the .torrent file of a magnet link goes next to the download unless a file is given
+        for (option, expected) in [
+            (None, None),
+            (Some("--save-torrent"), Some(SaveTorrent::NextToDownload)),
+            (
+                Some("--save-torrent=linux.torrent"),
+                Some(SaveTorrent::Path("linux.torrent".into())),
+            ),
+        ] {
+            let cli = Cli::try_parse_from(
+                ["rusty-bit", "download", "linux.torrent"]
+                    .into_iter()
+                    .chain(option),
+            )
+            .unwrap();
+            let Command::Download { options, .. } = cli.command else {
+                panic!("not the download command");
+            };
+            assert_eq!(options.into_config().unwrap().save_torrent, expected);
+        }
+
+        // a magnet link, with a tracker of our own besides those of the link
+        let magnet = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567";
+        let cli = Cli::try_parse_from([
+            "rusty-bit",
+            "download",
+            magnet,
+            "--tracker",
+            "udp://open.tracker.example:1337/announce",
+        ])
+        .unwrap();
+        let Command::Download { source, options } = cli.command else {
+            panic!("not the download command");
+        };
+        assert_eq!(source, TorrentSource::Magnet(magnet.to_string()));
+        assert_eq!(
+            options.into_config().unwrap().trackers,
+            vec!["udp://open.tracker.example:1337/announce".to_string()]
+        );
+
+        // alternative limits need a window
+        assert!(Cli::try_parse_from([
+            "rusty-bit",
+            "download",
+            "linux.torrent",
+            "--alt-download-limit",
+            "10",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn the_command_line_wins_over_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            "download-limit = 50\nupload-limit = 20\ndht = false\nseed = true\nverify-writes = true\n",
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from([
+            "rusty-bit",
+            "config",
+            "show",
+            "--config",
+            path.to_str().unwrap(),
+            "--download-limit",
+            "100",
+            "--no-seed",
+            "--dht",
+        ])
+        .unwrap();
+        let Command::Config {
+            command: ConfigCommand::Show { options },
+        } = cli.command
+        else {
+            panic!("not the config show command");
+        };
+        let config = options.into_config().unwrap();
+        assert_eq!(config.download_limit, Some(100 * 1024));
+        assert_eq!(config.upload_limit, Some(20 * 1024));
+        // switches turn off what the file turns on and the other way around
+        assert!(!config.seed);
+        assert!(config.dht);
+        assert!(config.verify_writes);
+    }
+}
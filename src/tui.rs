@@ -0,0 +1,348 @@
+use crate::download::progress::{self, ProgressMeter, Speeds};
+use crate::{PeerInfo, Progress, TorrentHandle};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Cell, Paragraph, Row, Table, TableState, Wrap},
+    DefaultTerminal, Frame,
+};
+use std::time::{Duration, Instant};
+
+/*
+ * A terminal dashboard for the torrents of a session: the torrents with their state and speeds,
+ * the pieces of the selected torrent and the peers it exchanges pieces with, with the rates, the
+ * client and the state of the exchange of each. The keys pause, resume and remove the selected torrent, quitting pauses
+ * every torrent so that the next start continues where this one stopped.
+ */
+
+// How often the numbers are sampled and the screen is redrawn.
+const REFRESH: Duration = Duration::from_millis(500);
+
+const HELP: &str = "up/down select  p pause  r resume  d remove  q quit";
+
+struct Entry {
+    torrent: TorrentHandle,
+    meter: ProgressMeter,
+    progress: Progress,
+    speeds: Speeds,
+    // fastest first
+    peers: Vec<PeerInfo>,
+}
+
+impl Entry {
+    fn new(torrent: TorrentHandle, now: Instant) -> Entry {
+        let progress = torrent.progress();
+        Entry {
+            torrent,
+            meter: ProgressMeter::new(now),
+            progress,
+            speeds: Speeds::default(),
+            peers: Vec::new(),
+        }
+    }
+
+    fn refresh(&mut self, now: Instant) {
+        self.progress = self.torrent.progress();
+        self.speeds = self.meter.sample(&self.progress, now);
+        self.peers = self.torrent.peers();
+        self.peers.sort_by(|a, b| {
+            (b.download_rate + b.upload_rate).total_cmp(&(a.download_rate + a.upload_rate))
+        });
+    }
+}
+
+// Shows the dashboard until q is pressed, then pauses the torrents that are left.
+pub async fn run(torrents: Vec<TorrentHandle>) -> anyhow::Result<()> {
+    let now = Instant::now();
+    let mut entries: Vec<Entry> = torrents
+        .into_iter()
+        .map(|torrent| Entry::new(torrent, now))
+        .collect();
+    let mut terminal = ratatui::init();
+    let result = show(&mut terminal, &mut entries).await;
+    ratatui::restore();
+    for entry in &entries {
+        entry.torrent.pause().await;
+    }
+    result
+}
+
+async fn show(terminal: &mut DefaultTerminal, entries: &mut Vec<Entry>) -> anyhow::Result<()> {
+    let mut table = TableState::default().with_selected(0);
+    let mut refreshed_at = Instant::now();
+    let mut redraw = true;
+    loop {
+        let now = Instant::now();
+        if now.duration_since(refreshed_at) >= REFRESH {
+            for entry in entries.iter_mut() {
+                entry.refresh(now);
+            }
+            refreshed_at = now;
+            redraw = true;
+        }
+        if redraw {
+            terminal.draw(|frame| draw(frame, entries, &mut table))?;
+            redraw = false;
+        }
+
+        let timeout = REFRESH.saturating_sub(refreshed_at.elapsed());
+        let event = tokio::task::block_in_place(|| -> std::io::Result<Option<Event>> {
+            Ok(if event::poll(timeout)? {
+                Some(event::read()?)
+            } else {
+                None
+            })
+        })?;
+        let Some(Event::Key(key)) = event else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let selected = table
+            .selected()
+            .filter(|&selected| selected < entries.len());
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Up | KeyCode::Char('k') => table.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => {
+                if selected.is_some_and(|selected| selected + 1 < entries.len()) {
+                    table.select_next();
+                }
+            }
+            KeyCode::Char('p') => {
+                if let Some(selected) = selected {
+                    entries[selected].torrent.pause().await;
+                }
+            }
+            KeyCode::Char('r') => {
+                if let Some(selected) = selected {
+                    entries[selected].torrent.resume();
+                }
+            }
+            KeyCode::Char('d') | KeyCode::Delete => {
+                if let Some(selected) = selected {
+                    entries.remove(selected).torrent.stop().await;
+                    if selected >= entries.len() {
+                        table.select(entries.len().checked_sub(1));
+                    }
+                }
+            }
+            _ => continue,
+        }
+        redraw = true;
+    }
+}
+
+fn draw(frame: &mut Frame, entries: &[Entry], table: &mut TableState) {
+    let [torrents_area, pieces_area, peers_area, help_area] = Layout::vertical([
+        Constraint::Length(entries.len().clamp(1, 10) as u16 + 3),
+        Constraint::Length(8),
+        Constraint::Min(4),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let rows = entries.iter().map(|entry| {
+        let progress = &entry.progress;
+        Row::new([
+            Cell::from(entry.torrent.name().to_string()),
+            Cell::from(progress::state_label(&progress.state)),
+            Cell::from(format!("{:.1}%", 100.0 * progress::fraction_done(progress))),
+            Cell::from(progress::format_rate(entry.speeds.download)),
+            Cell::from(progress::format_rate(entry.speeds.upload)),
+            Cell::from(progress.peers.to_string()),
+            Cell::from(
+                entry
+                    .speeds
+                    .eta
+                    .map_or("-".to_string(), progress::format_duration),
+            ),
+        ])
+    });
+    frame.render_stateful_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(11),
+                Constraint::Length(7),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Length(5),
+                Constraint::Length(8),
+            ],
+        )
+        .header(header([
+            "Name", "State", "Done", "Down", "Up", "Peers", "ETA",
+        ]))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title("Torrents")),
+        torrents_area,
+        table,
+    );
+
+    let selected = table.selected().and_then(|selected| entries.get(selected));
+    let pieces = selected
+        .map(|entry| entry.torrent.pieces())
+        .unwrap_or_default();
+    let cells = (pieces_area.width.saturating_sub(2) as usize)
+        * (pieces_area.height.saturating_sub(2) as usize);
+    frame.render_widget(
+        Paragraph::new(piece_map(&pieces, cells))
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered().title(format!("Pieces ({})", pieces.len()))),
+        pieces_area,
+    );
+
+    let peers = selected.map_or(&[][..], |entry| &entry.peers[..]);
+    let rows = peers.iter().map(|peer| {
+        Row::new([
+            peer.addr.to_string(),
+            peer.client.clone(),
+            progress::format_rate(peer.download_rate),
+            progress::format_rate(peer.upload_rate),
+            peer.downloaded.to_string(),
+            peer.uploaded.to_string(),
+            peer.requests_in_flight.to_string(),
+            peer_flags(peer),
+            progress::format_duration(peer.connected_for),
+        ])
+    });
+    frame.render_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Length(47),
+                Constraint::Fill(1),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Length(4),
+                Constraint::Length(5),
+                Constraint::Length(8),
+            ],
+        )
+        .header(header([
+            "Address",
+            "Client",
+            "Down",
+            "Up",
+            "Downloaded",
+            "Uploaded",
+            "Reqs",
+            "Flags",
+            "Age",
+        ]))
+        .block(Block::bordered().title(format!("Peers ({})", peers.len()))),
+        peers_area,
+    );
+
+    frame.render_widget(Line::from(HELP), help_area);
+}
+
+fn header<const N: usize>(titles: [&'static str; N]) -> Row<'static> {
+    Row::new(titles).style(Style::new().add_modifier(Modifier::BOLD))
+}
+
+/*
+ * The state of the exchange with a peer in a few letters, like other clients show it:
+ *
+ *     D  we are interested and the peer unchokes us, d  it chokes us
+ *     U  the peer is interested and we unchoke it, u  we choke it
+ *     S  the peer snubbed us
+ */
+fn peer_flags(peer: &PeerInfo) -> String {
+    let mut flags = String::new();
+    if peer.interested {
+        flags.push(if peer.choking_us { 'd' } else { 'D' });
+    }
+    if peer.peer_interested {
+        flags.push(if peer.choking_peer { 'u' } else { 'U' });
+    }
+    if peer.snubbed {
+        flags.push('S');
+    }
+    flags
+}
+
+// One character per cell, each cell standing for a run of pieces: full when every piece of the
+// run is on disk, shaded when some are, a dot when none are.
+fn piece_map(pieces: &[bool], cells: usize) -> String {
+    if pieces.is_empty() || cells == 0 {
+        return String::new();
+    }
+    let cells = cells.min(pieces.len());
+    (0..cells)
+        .map(|cell| {
+            let run = &pieces[cell * pieces.len() / cells..(cell + 1) * pieces.len() / cells];
+            match run.iter().filter(|&&have| have).count() {
+                0 => '.',
+                done if done == run.len() => '#',
+                _ => '+',
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pieces_are_merged_into_the_cells() {
+        let pieces = [true, true, true, false, false, false, true, false];
+        assert_eq!(piece_map(&pieces, 20), "###...#.");
+        assert_eq!(piece_map(&pieces, 4), "#+.+");
+        assert_eq!(piece_map(&pieces, 0), "");
+        assert_eq!(piece_map(&[], 4), "");
+    }
+
+    #[test]
+    fn peer_flags_show_the_exchange() {
+        let peer = PeerInfo {
+            addr: "127.0.0.1:6881".parse().unwrap(),
+            client: String::new(),
+            downloaded: 0,
+            uploaded: 0,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            requests_in_flight: 0,
+            choking_us: false,
+            interested: true,
+            choking_peer: true,
+            peer_interested: true,
+            snubbed: true,
+            connected_for: Duration::ZERO,
+        };
+        assert_eq!(peer_flags(&peer), "DuS");
+        let idle = PeerInfo {
+            interested: false,
+            peer_interested: false,
+            snubbed: false,
+            ..peer
+        };
+        assert_eq!(peer_flags(&idle), "");
+    }
+
+    #[test]
+    fn an_empty_session_is_drawn() {
+        let mut terminal =
+            ratatui::Terminal::new(ratatui::backend::TestBackend::new(80, 24)).unwrap();
+        let mut table = TableState::default().with_selected(0);
+        terminal.draw(|frame| draw(frame, &[], &mut table)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("Torrents"));
+        assert!(screen.contains("Peers (0)"));
+        assert!(screen.contains(HELP));
+    }
+}